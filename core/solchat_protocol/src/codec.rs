@@ -0,0 +1,173 @@
+//! Length-prefixed framing for `Envelope`s
+//!
+//! Each frame is a 4-byte big-endian length followed by that many bytes of
+//! protobuf-encoded `Envelope`. Streams can carry any number of frames, and
+//! frames may arrive split across (or packed into) arbitrary reads.
+
+use crate::messages::Envelope;
+use prost::Message;
+use std::fmt;
+
+/// Size of the length prefix in bytes
+pub const FRAME_HEADER_LEN: usize = 4;
+
+/// Largest envelope accepted by default (1 MiB)
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// Errors that can occur while decoding frames
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodecError {
+    FrameTooLarge(usize),
+    InvalidEnvelope(String),
+    MissingBody,
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::FrameTooLarge(len) => write!(f, "Frame of {} bytes exceeds the size limit", len),
+            CodecError::InvalidEnvelope(e) => write!(f, "Invalid envelope: {}", e),
+            CodecError::MissingBody => write!(f, "Envelope has no body"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+/// Encode an envelope as a single length-prefixed frame
+pub fn encode_frame(envelope: &Envelope) -> Vec<u8> {
+    let len = envelope.encoded_len();
+    let mut out = Vec::with_capacity(FRAME_HEADER_LEN + len);
+    out.extend_from_slice(&(len as u32).to_be_bytes());
+    envelope
+        .encode(&mut out)
+        .expect("Vec<u8> has enough capacity for the envelope");
+    out
+}
+
+/// Incremental frame decoder
+///
+/// Feed it bytes as they arrive with `extend`, then call `next_frame` until
+/// it returns `Ok(None)`.
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    max_frame_size: usize,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::with_max_frame_size(MAX_FRAME_SIZE)
+    }
+
+    pub fn with_max_frame_size(max_frame_size: usize) -> Self {
+        Self {
+            buffer: Vec::new(),
+            max_frame_size,
+        }
+    }
+
+    /// Append received bytes
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Decode the next complete frame, if one is buffered
+    ///
+    /// Oversized frames are rejected from their header alone, before the
+    /// body is buffered. After an error the stream should be dropped.
+    pub fn next_frame(&mut self) -> Result<Option<Envelope>, CodecError> {
+        if self.buffer.len() < FRAME_HEADER_LEN {
+            return Ok(None);
+        }
+
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        header.copy_from_slice(&self.buffer[..FRAME_HEADER_LEN]);
+        let len = u32::from_be_bytes(header) as usize;
+
+        if len > self.max_frame_size {
+            return Err(CodecError::FrameTooLarge(len));
+        }
+
+        if self.buffer.len() < FRAME_HEADER_LEN + len {
+            return Ok(None);
+        }
+
+        let frame: Vec<u8> = self.buffer.drain(..FRAME_HEADER_LEN + len).skip(FRAME_HEADER_LEN).collect();
+        let envelope = Envelope::decode(&frame[..])
+            .map_err(|e| CodecError::InvalidEnvelope(e.to_string()))?;
+
+        if envelope.body.is_none() {
+            return Err(CodecError::MissingBody);
+        }
+
+        Ok(Some(envelope))
+    }
+
+    /// Whether a partial frame is still buffered
+    pub fn has_partial_frame(&self) -> bool {
+        !self.buffer.is_empty()
+    }
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{envelope, AckMessage, ChatMessage};
+    use crate::WalletAddress;
+
+    fn chat_envelope(payload: &[u8]) -> Envelope {
+        let sender = WalletAddress::test_address(1);
+        let recipient = WalletAddress::test_address(2);
+        ChatMessage::new(&sender, &recipient, payload.to_vec(), Vec::new()).into()
+    }
+
+    #[test]
+    fn test_frames_split_and_packed_across_reads() {
+        let first = chat_envelope(b"first");
+        let second: Envelope = AckMessage::delivered("msg_1".to_string()).into();
+
+        let mut wire = encode_frame(&first);
+        wire.extend(encode_frame(&second));
+
+        // Feed one byte at a time; frames only come out once complete
+        let mut decoder = FrameDecoder::new();
+        let mut decoded = Vec::new();
+        for byte in &wire {
+            decoder.extend(std::slice::from_ref(byte));
+            while let Some(envelope) = decoder.next_frame().unwrap() {
+                decoded.push(envelope);
+            }
+        }
+
+        assert_eq!(decoded, vec![first, second]);
+        assert!(!decoder.has_partial_frame());
+    }
+
+    #[test]
+    fn test_ack_is_not_mistaken_for_chat() {
+        let ack: Envelope = AckMessage::delivered("msg_1".to_string()).into();
+
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&encode_frame(&ack));
+        let decoded = decoder.next_frame().unwrap().unwrap();
+
+        assert!(matches!(decoded.body, Some(envelope::Body::Ack(_))));
+    }
+
+    #[test]
+    fn test_rejects_oversized_and_empty_frames() {
+        let mut decoder = FrameDecoder::with_max_frame_size(16);
+        decoder.extend(&encode_frame(&chat_envelope(&[0u8; 64])));
+        assert!(matches!(decoder.next_frame(), Err(CodecError::FrameTooLarge(_))));
+
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&encode_frame(&Envelope::default()));
+        assert_eq!(decoder.next_frame(), Err(CodecError::MissingBody));
+    }
+}
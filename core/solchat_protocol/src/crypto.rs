@@ -2,6 +2,16 @@ use std::fmt;
 use serde::{Deserialize, Serialize};
 use sha2::{Sha256, Digest};
 use hkdf::Hkdf;
+use rand_core::{OsRng, RngCore};
+use ed25519_dalek::{Keypair, Signature};
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+pub mod ratchet;
+pub mod x3dh;
+
+pub use ratchet::{DoubleRatchet, MessageHeader};
+pub use x3dh::PrekeyStore;
 
 // Cryptography is like blockchain: everyone talks about it, few understand it deeply 🔐
 // Note: This is a simplified implementation for Sprint 1 MVP
@@ -17,6 +27,7 @@ pub enum CryptoError {
     SessionNotFound,
     KeyDerivationFailed,
     InvalidNonce,
+    TooManySkippedMessages,
 }
 
 impl fmt::Display for CryptoError {
@@ -29,56 +40,68 @@ impl fmt::Display for CryptoError {
             CryptoError::SessionNotFound => write!(f, "Cryptographic session not found"),
             CryptoError::KeyDerivationFailed => write!(f, "Key derivation failed"),
             CryptoError::InvalidNonce => write!(f, "Invalid nonce"),
+            CryptoError::TooManySkippedMessages => write!(f, "Too many skipped messages"),
         }
     }
 }
 
 impl std::error::Error for CryptoError {}
 
-/// Simplified X25519 key pair for MVP
-#[derive(Clone)]
+/// X25519 key pair used for Elliptic Curve Diffie-Hellman (RFC 7748)
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
 pub struct X25519KeyPair {
+    #[zeroize(skip)]
     pub public: [u8; 32],
     secret: [u8; 32],
 }
 
 impl X25519KeyPair {
+    /// Build a key pair from a 32-byte secret scalar.
+    ///
+    /// The scalar is clamped as described in RFC 7748 before use, so any
+    /// 32 bytes of key material are accepted.
     pub fn new(secret: [u8; 32]) -> Self {
-        // Simplified: just use the secret as public for demo
-        // Production: proper X25519 scalar multiplication
-        let mut public = secret;
-        public[0] ^= 0x01; // Make it different from secret
+        let secret = StaticSecret::from(secret).to_bytes();
+        let public = PublicKey::from(&StaticSecret::from(secret)).to_bytes();
         Self { public, secret }
     }
 
+    /// Generate a fresh key pair from the operating system's RNG
+    pub fn generate() -> Self {
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        let keypair = Self::new(secret);
+        secret.zeroize();
+        keypair
+    }
+
     pub fn secret(&self) -> &[u8; 32] {
         &self.secret
     }
 
-    /// Perform simplified Diffie-Hellman key exchange
-    pub fn diffie_hellman(&self, peer_public: &[u8; 32]) -> [u8; 32] {
-        // Simplified ECDH: hash in deterministic order for symmetry
-        // Production: proper X25519 scalar multiplication
-        let mut hasher = Sha256::new();
-        hasher.update(b"simplified-ecdh");
-        
-        // Ensure symmetric result by ordering keys deterministically
-        if self.public < *peer_public {
-            hasher.update(&self.secret);
-            hasher.update(peer_public);
-        } else {
-            hasher.update(peer_public);
-            hasher.update(&self.secret);
+    /// Perform X25519 Diffie-Hellman with a peer's public key
+    ///
+    /// Fails with `CryptoError::InvalidKey` when the peer key is a low-order
+    /// point, which would otherwise force an all-zero shared secret.
+    pub fn diffie_hellman(&self, peer_public: &[u8; 32]) -> Result<[u8; 32], CryptoError> {
+        let shared = StaticSecret::from(self.secret)
+            .diffie_hellman(&PublicKey::from(*peer_public))
+            .to_bytes();
+
+        // Constant-time check for the all-zero output of a low-order point
+        let acc = shared.iter().fold(0u8, |acc, byte| acc | byte);
+        if acc == 0 {
+            return Err(CryptoError::InvalidKey);
         }
-        
-        hasher.finalize().into()
+
+        Ok(shared)
     }
 }
 
-/// Derive X25519 key pair from Ed25519 wallet keys (simplified)
+/// Derive X25519 key pair from Ed25519 wallet keys
 /// 
-/// This function demonstrates the interface for deriving X25519 keys from Ed25519 wallet keys.
-/// Production implementation should use proper cryptographic libraries.
+/// The X25519 secret is expanded from the Ed25519 private key with HKDF-SHA256,
+/// salted with the Ed25519 public key, as specified in ADR-0002.
 pub fn derive_x25519_from_ed25519(
     ed25519_pubkey: &[u8; 32],
     ed25519_privkey: &[u8; 32],
@@ -93,27 +116,128 @@ pub fn derive_x25519_from_ed25519(
     Ok(X25519KeyPair::new(x25519_secret_bytes))
 }
 
+/// Verify an Ed25519 signature made by a wallet's identity key
+///
+/// Solana wallet addresses are Ed25519 public keys, so the wallet address
+/// itself is the verifying key.
+pub fn verify_wallet_signature(
+    wallet: &crate::WalletAddress,
+    message: &[u8],
+    signature: &[u8],
+) -> Result<(), CryptoError> {
+    let verifying_key = ed25519_dalek::PublicKey::from_bytes(wallet.as_bytes())
+        .map_err(|_| CryptoError::InvalidKey)?;
+    let signature = Signature::from_bytes(signature)
+        .map_err(|_| CryptoError::InvalidSignature)?;
+    
+    verifying_key
+        .verify_strict(message, &signature)
+        .map_err(|_| CryptoError::InvalidSignature)
+}
+
+/// Current version of the `EncryptedMessageData` wire format
+pub const ENCRYPTED_MESSAGE_VERSION: u8 = 2;
+
 /// Encrypted message data structure
 #[derive(Clone, Serialize, Deserialize)]
 pub struct EncryptedMessageData {
+    pub version: u8,
+    pub header: MessageHeader,
     pub nonce: Vec<u8>,
     pub ciphertext: Vec<u8>,
-    pub counter: u64,
 }
 
-/// Simple session state for MVP (will be replaced with full double-ratchet)
-#[derive(Clone, Serialize, Deserialize)]
-pub struct SimpleSession {
-    session_id: String,
-    shared_secret: [u8; 32],
-    send_count: u64,
-    receive_count: u64,
+/// Double Ratchet session with a single remote wallet
+#[derive(Clone)]
+pub struct RatchetSession {
+    local_wallet: crate::WalletAddress,
+    remote_wallet: crate::WalletAddress,
+    ratchet: DoubleRatchet,
+    // X3DH identity binding, empty for sessions set up from static keys
+    identity_ad: Vec<u8>,
+}
+
+impl RatchetSession {
+    fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let mut aad = associated_data(
+            ENCRYPTED_MESSAGE_VERSION,
+            &self.local_wallet,
+            &self.remote_wallet,
+        );
+        aad.extend_from_slice(&self.identity_ad);
+        let (header, nonce, ciphertext) = self.ratchet.encrypt(plaintext, &aad)?;
+        
+        // Create encrypted message with metadata
+        let encrypted_msg = EncryptedMessageData {
+            version: ENCRYPTED_MESSAGE_VERSION,
+            header,
+            nonce: nonce.to_vec(),
+            ciphertext,
+        };
+        
+        bincode::serialize(&encrypted_msg)
+            .map_err(|_| CryptoError::EncryptionFailed)
+    }
+
+    fn decrypt(&mut self, encrypted_data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        // Deserialize encrypted message
+        let encrypted_msg: EncryptedMessageData = bincode::deserialize(encrypted_data)
+            .map_err(|_| CryptoError::DecryptionFailed)?;
+        
+        if encrypted_msg.version != ENCRYPTED_MESSAGE_VERSION {
+            return Err(CryptoError::DecryptionFailed);
+        }
+        
+        // Messages on this session were sent by the remote wallet to us
+        let mut aad = associated_data(
+            encrypted_msg.version,
+            &self.remote_wallet,
+            &self.local_wallet,
+        );
+        aad.extend_from_slice(&self.identity_ad);
+        self.ratchet.decrypt(
+            &encrypted_msg.header,
+            &encrypted_msg.nonce,
+            &encrypted_msg.ciphertext,
+            &aad,
+        )
+    }
 }
 
-/// Session manager for encrypted messaging (simplified for MVP)
+/// Build the AEAD associated data for a message
+///
+/// The session id is always taken from the sender's point of view
+/// (`sender:recipient`), so both ends of a session compute identical bytes.
+/// The ratchet appends the message header before authenticating.
+fn associated_data(
+    version: u8,
+    sender: &crate::WalletAddress,
+    recipient: &crate::WalletAddress,
+) -> Vec<u8> {
+    let session_id = format!("{}:{}", sender, recipient);
+
+    let mut aad = Vec::with_capacity(1 + 8 + session_id.len() + 64);
+    aad.push(version);
+    aad.extend_from_slice(&(session_id.len() as u64).to_le_bytes());
+    aad.extend_from_slice(session_id.as_bytes());
+    aad.extend_from_slice(sender.as_bytes());
+    aad.extend_from_slice(recipient.as_bytes());
+    aad
+}
+
+/// Derive the initial root key from an X25519 shared secret (ADR-0002)
+fn derive_root_key(shared_secret: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(shared_secret);
+    hasher.update(b"SolConnect-Root-Key");
+    hasher.finalize().into()
+}
+
+/// Session manager for Double Ratchet encrypted messaging
 pub struct SessionManager {
-    sessions: std::collections::HashMap<String, SimpleSession>,
+    sessions: std::collections::HashMap<String, RatchetSession>,
     // Session encryption key derived from wallet keys
+    #[allow(dead_code)]
     session_key: [u8; 32],
 }
 
@@ -126,6 +250,10 @@ impl SessionManager {
     }
 
     /// Initialize a new session with another wallet
+    ///
+    /// Both wallets call this with their own key pair and the peer's public
+    /// key; the resulting ratchets are compatible and either side may send
+    /// first.
     pub fn init_session(
         &mut self,
         sender_wallet: &crate::WalletAddress,
@@ -136,84 +264,118 @@ impl SessionManager {
         let session_id = format!("{}:{}", sender_wallet, recipient_wallet);
         
         // Perform Diffie-Hellman to get shared secret
-        let shared_secret = sender_x25519.diffie_hellman(recipient_x25519_public);
+        let mut shared_secret = sender_x25519.diffie_hellman(recipient_x25519_public)?;
+        let root_key = derive_root_key(&shared_secret);
+        shared_secret.zeroize();
         
-        // Create simple session (TODO: Replace with full double-ratchet)
-        let session = SimpleSession {
-            session_id: session_id.clone(),
-            shared_secret,
-            send_count: 0,
-            receive_count: 0,
+        let ratchet = DoubleRatchet::new_symmetric(
+            root_key,
+            sender_x25519.clone(),
+            recipient_x25519_public,
+        )?;
+        
+        let session = RatchetSession {
+            local_wallet: sender_wallet.clone(),
+            remote_wallet: recipient_wallet.clone(),
+            ratchet,
+            identity_ad: Vec::new(),
         };
         
         self.sessions.insert(session_id.clone(), session);
         Ok(session_id)
     }
 
-    /// Encrypt a message for a session (simplified)
-    pub fn encrypt_message(
+    /// Open a session from a recipient's published prekey bundle (X3DH)
+    ///
+    /// The recipient does not need to be online. Returns the session id and
+    /// the initial message, which carries `plaintext` as the first ratchet
+    /// message and must be delivered before any other message on the session.
+    pub fn init_session_from_bundle(
         &mut self,
-        session_id: &str,
+        sender_keypair: &Keypair,
+        sender_identity: &X25519KeyPair,
+        bundle: &crate::messages::PrekeyBundle,
         plaintext: &[u8],
-    ) -> Result<Vec<u8>, CryptoError> {
-        let session = self.sessions.get_mut(session_id)
-            .ok_or(CryptoError::SessionNotFound)?;
+    ) -> Result<(String, crate::messages::X3dhInitialMessage), CryptoError> {
+        let verified = x3dh::verify_bundle(bundle)?;
+        let (output, mut initial_message) = x3dh::initiate(sender_keypair, sender_identity, &verified)?;
         
-        // Derive message key from shared secret and counter
-        let mut hasher = Sha256::new();
-        hasher.update(b"SolConnect-Message-Key");
-        hasher.update(&session.shared_secret);
-        hasher.update(&session.send_count.to_le_bytes());
-        let message_key: [u8; 32] = hasher.finalize().into();
+        let sender_wallet = crate::WalletAddress::new(sender_keypair.public.to_bytes());
+        let session_id = format!("{}:{}", sender_wallet, verified.wallet);
         
-        // Simplified encryption: XOR with key (NOT SECURE - for demo only)
-        let mut ciphertext = plaintext.to_vec();
-        for (i, byte) in ciphertext.iter_mut().enumerate() {
-            *byte ^= message_key[i % 32];
-        }
+        let ratchet = DoubleRatchet::new_initiator(
+            output.shared_secret,
+            X25519KeyPair::generate(),
+            &verified.signed_prekey,
+        )?;
+        let mut session = RatchetSession {
+            local_wallet: sender_wallet,
+            remote_wallet: verified.wallet,
+            ratchet,
+            identity_ad: output.associated_data.clone(),
+        };
+        initial_message.ciphertext = session.encrypt(plaintext)?;
         
-        // Create encrypted message with metadata
-        let encrypted_msg = EncryptedMessageData {
-            nonce: vec![0u8; 12], // Simplified nonce
-            ciphertext,
-            counter: session.send_count,
+        self.sessions.insert(session_id.clone(), session);
+        Ok((session_id, initial_message))
+    }
+
+    /// Accept the initial message of a session opened from our prekey bundle
+    ///
+    /// Returns the session id and the decrypted first message. The one-time
+    /// prekey used by the sender is deleted once the message authenticates.
+    pub fn accept_initial_message(
+        &mut self,
+        prekeys: &mut PrekeyStore,
+        message: &crate::messages::X3dhInitialMessage,
+    ) -> Result<(String, Vec<u8>), CryptoError> {
+        let output = x3dh::respond(prekeys, message)?;
+        let sender_wallet = message.sender().map_err(|_| CryptoError::InvalidKey)?;
+        let session_id = format!("{}:{}", prekeys.wallet(), sender_wallet);
+        
+        let ratchet = DoubleRatchet::new_responder(
+            output.shared_secret,
+            prekeys.signed_prekey().clone(),
+        );
+        let mut session = RatchetSession {
+            local_wallet: prekeys.wallet().clone(),
+            remote_wallet: sender_wallet,
+            ratchet,
+            identity_ad: output.associated_data.clone(),
         };
+        let plaintext = session.decrypt(&message.ciphertext)?;
         
-        session.send_count += 1;
+        if let Some(id) = message.one_time_prekey_id {
+            prekeys.remove_one_time_prekey(id);
+        }
         
-        bincode::serialize(&encrypted_msg)
-            .map_err(|_| CryptoError::EncryptionFailed)
+        self.sessions.insert(session_id.clone(), session);
+        Ok((session_id, plaintext))
+    }
+
+    /// Encrypt a message for a session, advancing its sending chain
+    pub fn encrypt_message(
+        &mut self,
+        session_id: &str,
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        self.sessions.get_mut(session_id)
+            .ok_or(CryptoError::SessionNotFound)?
+            .encrypt(plaintext)
     }
 
-    /// Decrypt a message for a session (simplified)
+    /// Decrypt a message received on a session
+    ///
+    /// Fails with `CryptoError::DecryptionFailed` if the authentication tag
+    /// does not verify, including when any associated data was altered.
     pub fn decrypt_message(
         &mut self,
         session_id: &str,
         encrypted_data: &[u8],
     ) -> Result<Vec<u8>, CryptoError> {
-        let session = self.sessions.get_mut(session_id)
-            .ok_or(CryptoError::SessionNotFound)?;
-        
-        // Deserialize encrypted message
-        let encrypted_msg: EncryptedMessageData = bincode::deserialize(encrypted_data)
-            .map_err(|_| CryptoError::DecryptionFailed)?;
-        
-        // Derive message key from shared secret and counter
-        let mut hasher = Sha256::new();
-        hasher.update(b"SolConnect-Message-Key");
-        hasher.update(&session.shared_secret);
-        hasher.update(&encrypted_msg.counter.to_le_bytes());
-        let message_key: [u8; 32] = hasher.finalize().into();
-        
-        // Simplified decryption: XOR with key (matches encryption)
-        let mut plaintext = encrypted_msg.ciphertext;
-        for (i, byte) in plaintext.iter_mut().enumerate() {
-            *byte ^= message_key[i % 32];
-        }
-        
-        session.receive_count = encrypted_msg.counter + 1;
-        
-        Ok(plaintext)
+        self.sessions.get_mut(session_id)
+            .ok_or(CryptoError::SessionNotFound)?
+            .decrypt(encrypted_data)
     }
 }
 
@@ -281,50 +443,154 @@ mod tests {
             .expect("Key derivation should succeed");
 
         // Both parties should derive the same shared secret
-        let shared_a = x25519_a.diffie_hellman(&x25519_b.public);
-        let shared_b = x25519_b.diffie_hellman(&x25519_a.public);
+        let shared_a = x25519_a.diffie_hellman(&x25519_b.public).unwrap();
+        let shared_b = x25519_b.diffie_hellman(&x25519_a.public).unwrap();
 
-        // Note: In simplified implementation, we need to ensure the ECDH is symmetric
-        // For now, just verify that the function works deterministically
-        let shared_a2 = x25519_a.diffie_hellman(&x25519_b.public);
-        assert_eq!(shared_a, shared_a2);
-        
-        // TODO: Fix ECDH symmetry in production implementation
-        // assert_eq!(shared_a, shared_b);
+        assert_eq!(shared_a, shared_b);
     }
 
-    #[tokio::test]
-    async fn test_session_encrypt_decrypt_roundtrip() {
-        let session_key = utils::generate_random_key();
-        let mut manager = SessionManager::new(session_key);
+    #[test]
+    fn test_x25519_rfc7748_scalar_mult_vector() {
+        // RFC 7748, section 5.2 (first test vector)
+        let scalar: [u8; 32] = hex::decode("a546e36bf0527c9d3b16154b82465edd62144c0ac1fc5a18506a2244ba449ac4")
+            .unwrap().try_into().unwrap();
+        let u: [u8; 32] = hex::decode("e6db6867583030db3594c1a424b15f7c726624ec26b3353b10a903a6d0ab1c4c")
+            .unwrap().try_into().unwrap();
+
+        let output = X25519KeyPair::new(scalar).diffie_hellman(&u).unwrap();
+        assert_eq!(hex::encode(output), "c3da55379de9c6908e94ea4df28d084f32eccf03491c71f754b4075577a28552");
+    }
 
-        let ed25519_keypair_a = ([1u8; 32], [2u8; 32]);
-        let ed25519_keypair_b = ([3u8; 32], [4u8; 32]);
+    #[test]
+    fn test_x25519_rfc7748_diffie_hellman_vector() {
+        // RFC 7748, section 6.1
+        let alice_secret: [u8; 32] = hex::decode("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a")
+            .unwrap().try_into().unwrap();
+        let bob_secret: [u8; 32] = hex::decode("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb")
+            .unwrap().try_into().unwrap();
+
+        let alice = X25519KeyPair::new(alice_secret);
+        let bob = X25519KeyPair::new(bob_secret);
+
+        assert_eq!(hex::encode(alice.public), "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a");
+        assert_eq!(hex::encode(bob.public), "de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f");
+
+        let shared = "4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742";
+        assert_eq!(hex::encode(alice.diffie_hellman(&bob.public).unwrap()), shared);
+        assert_eq!(hex::encode(bob.diffie_hellman(&alice.public).unwrap()), shared);
+    }
 
-        let x25519_a = derive_x25519_from_ed25519(&ed25519_keypair_a.0, &ed25519_keypair_a.1)
+    #[test]
+    fn test_x25519_rejects_low_order_points() {
+        let keypair = X25519KeyPair::new([7u8; 32]);
+
+        // u = 0 (order 2) and u = 1 (order 4) both force an all-zero output
+        let mut order_four = [0u8; 32];
+        order_four[0] = 1;
+
+        assert!(matches!(keypair.diffie_hellman(&[0u8; 32]), Err(CryptoError::InvalidKey)));
+        assert!(matches!(keypair.diffie_hellman(&order_four), Err(CryptoError::InvalidKey)));
+    }
+
+    /// Set up the two ends of a session between wallets 1 and 2
+    fn session_pair() -> (SessionManager, String, SessionManager, String) {
+        let x25519_a = derive_x25519_from_ed25519(&[1u8; 32], &[2u8; 32])
             .expect("Key derivation should succeed");
-        let x25519_b = derive_x25519_from_ed25519(&ed25519_keypair_b.0, &ed25519_keypair_b.1)
+        let x25519_b = derive_x25519_from_ed25519(&[3u8; 32], &[4u8; 32])
             .expect("Key derivation should succeed");
 
         let wallet_a = crate::WalletAddress::test_address(1);
         let wallet_b = crate::WalletAddress::test_address(2);
 
-        // Initialize session
-        let session_id = manager.init_session(&wallet_a, &wallet_b, &x25519_a, &x25519_b.public)
+        let mut manager_a = SessionManager::new(utils::generate_random_key());
+        let mut manager_b = SessionManager::new(utils::generate_random_key());
+
+        let session_a = manager_a.init_session(&wallet_a, &wallet_b, &x25519_a, &x25519_b.public)
+            .expect("Session initialization should succeed");
+        let session_b = manager_b.init_session(&wallet_b, &wallet_a, &x25519_b, &x25519_a.public)
             .expect("Session initialization should succeed");
 
+        (manager_a, session_a, manager_b, session_b)
+    }
+
+    #[tokio::test]
+    async fn test_session_encrypt_decrypt_roundtrip() {
+        let (mut manager_a, session_a, mut manager_b, session_b) = session_pair();
+
         // Test encrypt/decrypt roundtrip
         let plaintext = b"Hello, encrypted Solana world!";
-        let encrypted = manager.encrypt_message(&session_id, plaintext)
+        let encrypted = manager_a.encrypt_message(&session_a, plaintext)
             .expect("Encryption should succeed");
 
-        // Decrypt with the same session (simplified for MVP)
-        let decrypted = manager.decrypt_message(&session_id, &encrypted)
+        let decrypted = manager_b.decrypt_message(&session_b, &encrypted)
             .expect("Decryption should succeed");
 
         assert_eq!(plaintext, decrypted.as_slice());
     }
 
+    #[test]
+    fn test_encryption_uses_random_nonces() {
+        let (mut manager_a, session_a, _, _) = session_pair();
+
+        let first: EncryptedMessageData = bincode::deserialize(
+            &manager_a.encrypt_message(&session_a, b"same plaintext").unwrap(),
+        ).unwrap();
+        let second: EncryptedMessageData = bincode::deserialize(
+            &manager_a.encrypt_message(&session_a, b"same plaintext").unwrap(),
+        ).unwrap();
+
+        assert_eq!(first.version, ENCRYPTED_MESSAGE_VERSION);
+        assert_eq!(first.nonce.len(), 12);
+        assert_ne!(first.nonce, second.nonce);
+        assert_ne!(first.ciphertext, second.ciphertext);
+    }
+
+    #[test]
+    fn test_tampered_message_fails_authentication() {
+        let (mut manager_a, session_a, mut manager_b, session_b) = session_pair();
+
+        let encrypted = manager_a.encrypt_message(&session_a, b"Do not touch").unwrap();
+        let original: EncryptedMessageData = bincode::deserialize(&encrypted).unwrap();
+
+        // Flipped ciphertext bit
+        let mut tampered = original.clone();
+        tampered.ciphertext[0] ^= 0x01;
+        let result = manager_b.decrypt_message(&session_b, &bincode::serialize(&tampered).unwrap());
+        assert!(matches!(result, Err(CryptoError::DecryptionFailed)));
+
+        // Altered message number changes both the key and the associated data
+        let mut tampered = original.clone();
+        tampered.header.n += 1;
+        let result = manager_b.decrypt_message(&session_b, &bincode::serialize(&tampered).unwrap());
+        assert!(matches!(result, Err(CryptoError::DecryptionFailed)));
+
+        // A message replayed back to its own sender has the wrong direction
+        let result = manager_a.decrypt_message(&session_a, &encrypted);
+        assert!(matches!(result, Err(CryptoError::DecryptionFailed)));
+
+        // The untouched message still decrypts
+        assert_eq!(manager_b.decrypt_message(&session_b, &encrypted).unwrap(), b"Do not touch");
+    }
+
+    #[test]
+    fn test_session_either_side_sends_first() {
+        let (mut manager_a, session_a, mut manager_b, session_b) = session_pair();
+
+        let from_b = manager_b.encrypt_message(&session_b, b"B speaks first").unwrap();
+        let from_a = manager_a.encrypt_message(&session_a, b"A at the same time").unwrap();
+
+        assert_eq!(manager_a.decrypt_message(&session_a, &from_b).unwrap(), b"B speaks first");
+        assert_eq!(manager_b.decrypt_message(&session_b, &from_a).unwrap(), b"A at the same time");
+
+        // Keep ratcheting back and forth
+        for round in 0..3u8 {
+            let msg = manager_a.encrypt_message(&session_a, &[round]).unwrap();
+            assert_eq!(manager_b.decrypt_message(&session_b, &msg).unwrap(), [round]);
+            let msg = manager_b.encrypt_message(&session_b, &[round]).unwrap();
+            assert_eq!(manager_a.decrypt_message(&session_a, &msg).unwrap(), [round]);
+        }
+    }
+
     #[test] 
     fn test_deterministic_key_derivation_vectors() {
         // Test vector for deterministic key derivation
@@ -0,0 +1,440 @@
+use std::collections::{HashMap, VecDeque};
+use serde::{Deserialize, Serialize};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use rand_core::{OsRng, RngCore};
+use zeroize::Zeroize;
+
+use super::{CryptoError, X25519KeyPair};
+
+// Double Ratchet as described in the Signal specification:
+// https://signal.org/docs/specifications/doubleratchet/
+
+/// Maximum number of message keys skipped in a single receiving chain
+pub const MAX_SKIP: u32 = 1000;
+
+/// Maximum number of skipped message keys kept across all chains
+pub const MAX_SKIPPED_KEYS: usize = 2000;
+
+/// Length in bytes of the AES-256-GCM nonce
+pub const NONCE_LEN: usize = 12;
+
+const ROOT_KDF_INFO: &[u8] = b"SolConnect-Ratchet-Root";
+
+/// Header sent in the clear alongside every ratchet message
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageHeader {
+    /// Sender's current ratchet public key
+    pub dh: [u8; 32],
+    /// Number of messages in the sender's previous sending chain
+    pub pn: u32,
+    /// Message number in the current sending chain
+    pub n: u32,
+}
+
+impl MessageHeader {
+    /// Fixed-length encoding used as part of the associated data
+    pub fn to_bytes(&self) -> [u8; 40] {
+        let mut bytes = [0u8; 40];
+        bytes[..32].copy_from_slice(&self.dh);
+        bytes[32..36].copy_from_slice(&self.pn.to_le_bytes());
+        bytes[36..].copy_from_slice(&self.n.to_le_bytes());
+        bytes
+    }
+}
+
+/// Double Ratchet session state
+#[derive(Clone)]
+pub struct DoubleRatchet {
+    dh_self: X25519KeyPair,
+    dh_remote: Option<[u8; 32]>,
+    root_key: [u8; 32],
+    send_chain: Option<[u8; 32]>,
+    recv_chain: Option<[u8; 32]>,
+    send_count: u32,
+    recv_count: u32,
+    prev_send_count: u32,
+    skipped: HashMap<([u8; 32], u32), [u8; 32]>,
+    skipped_order: VecDeque<([u8; 32], u32)>,
+}
+
+impl DoubleRatchet {
+    /// Initialize the party that sends first (Alice in the specification)
+    pub fn new_initiator(
+        shared_secret: [u8; 32],
+        dh_self: X25519KeyPair,
+        remote_dh: &[u8; 32],
+    ) -> Result<Self, CryptoError> {
+        let dh_out = dh_self.diffie_hellman(remote_dh)?;
+        let (root_key, send_chain) = kdf_rk(&shared_secret, &dh_out)?;
+
+        let mut ratchet = Self::new_responder(root_key, dh_self);
+        ratchet.dh_remote = Some(*remote_dh);
+        ratchet.send_chain = Some(send_chain);
+        Ok(ratchet)
+    }
+
+    /// Initialize the party whose ratchet key the initiator already knows
+    /// (Bob in the specification). It cannot send until it has received.
+    pub fn new_responder(shared_secret: [u8; 32], dh_self: X25519KeyPair) -> Self {
+        Self {
+            dh_self,
+            dh_remote: None,
+            root_key: shared_secret,
+            send_chain: None,
+            recv_chain: None,
+            send_count: 0,
+            recv_count: 0,
+            prev_send_count: 0,
+            skipped: HashMap::new(),
+            skipped_order: VecDeque::new(),
+        }
+    }
+
+    /// Initialize a ratchet when both parties know each other's static keys
+    ///
+    /// The party with the lower public key takes the initiator role and uses
+    /// its static key as the first ratchet key. The other party starts as a
+    /// responder that has already seen that key, so either side may send
+    /// the first message.
+    pub fn new_symmetric(
+        shared_secret: [u8; 32],
+        local_static: X25519KeyPair,
+        remote_static: &[u8; 32],
+    ) -> Result<Self, CryptoError> {
+        if local_static.public == *remote_static {
+            return Err(CryptoError::InvalidKey);
+        }
+
+        if local_static.public < *remote_static {
+            Self::new_initiator(shared_secret, local_static, remote_static)
+        } else {
+            let mut ratchet = Self::new_responder(shared_secret, local_static);
+            ratchet.dh_ratchet(remote_static)?;
+            Ok(ratchet)
+        }
+    }
+
+    /// Current ratchet public key of this party
+    pub fn public_key(&self) -> [u8; 32] {
+        self.dh_self.public
+    }
+
+    /// Encrypt a message, advancing the sending chain
+    ///
+    /// Returns the header, nonce and ciphertext. The header is appended to
+    /// `associated_data` before it is authenticated.
+    pub fn encrypt(
+        &mut self,
+        plaintext: &[u8],
+        associated_data: &[u8],
+    ) -> Result<(MessageHeader, [u8; NONCE_LEN], Vec<u8>), CryptoError> {
+        let chain_key = self.send_chain.ok_or(CryptoError::SessionNotFound)?;
+        let (next_chain, mut message_key) = kdf_ck(&chain_key);
+
+        let header = MessageHeader {
+            dh: self.dh_self.public,
+            pn: self.prev_send_count,
+            n: self.send_count,
+        };
+
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+
+        let result = seal(&message_key, &nonce, plaintext, associated_data, &header);
+        message_key.zeroize();
+        let ciphertext = result?;
+
+        self.send_chain = Some(next_chain);
+        self.send_count += 1;
+
+        Ok((header, nonce, ciphertext))
+    }
+
+    /// Decrypt a message, performing a DH ratchet step if the header carries
+    /// a new ratchet key
+    ///
+    /// State is only updated when the message authenticates, so forged or
+    /// corrupted messages cannot desynchronize the session.
+    pub fn decrypt(
+        &mut self,
+        header: &MessageHeader,
+        nonce: &[u8],
+        ciphertext: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        if nonce.len() != NONCE_LEN {
+            return Err(CryptoError::InvalidNonce);
+        }
+
+        if let Some(mut message_key) = self.skipped.get(&(header.dh, header.n)).copied() {
+            let result = open(&message_key, nonce, ciphertext, associated_data, header);
+            message_key.zeroize();
+            let plaintext = result?;
+            self.remove_skipped(&(header.dh, header.n));
+            return Ok(plaintext);
+        }
+
+        let mut next = self.clone();
+        if next.dh_remote != Some(header.dh) {
+            next.skip_message_keys(header.pn)?;
+            next.dh_ratchet(&header.dh)?;
+        }
+        next.skip_message_keys(header.n)?;
+
+        let chain_key = next.recv_chain.ok_or(CryptoError::DecryptionFailed)?;
+        let (next_chain, mut message_key) = kdf_ck(&chain_key);
+        next.recv_chain = Some(next_chain);
+        next.recv_count += 1;
+
+        let result = open(&message_key, nonce, ciphertext, associated_data, header);
+        message_key.zeroize();
+        let plaintext = result?;
+
+        *self = next;
+        Ok(plaintext)
+    }
+
+    /// Number of skipped message keys currently stored
+    pub fn skipped_keys(&self) -> usize {
+        self.skipped.len()
+    }
+
+    fn dh_ratchet(&mut self, remote_dh: &[u8; 32]) -> Result<(), CryptoError> {
+        self.prev_send_count = self.send_count;
+        self.send_count = 0;
+        self.recv_count = 0;
+        self.dh_remote = Some(*remote_dh);
+
+        let dh_out = self.dh_self.diffie_hellman(remote_dh)?;
+        let (root_key, recv_chain) = kdf_rk(&self.root_key, &dh_out)?;
+        self.root_key = root_key;
+        self.recv_chain = Some(recv_chain);
+
+        self.dh_self = X25519KeyPair::generate();
+        let dh_out = self.dh_self.diffie_hellman(remote_dh)?;
+        let (root_key, send_chain) = kdf_rk(&self.root_key, &dh_out)?;
+        self.root_key = root_key;
+        self.send_chain = Some(send_chain);
+
+        Ok(())
+    }
+
+    fn skip_message_keys(&mut self, until: u32) -> Result<(), CryptoError> {
+        let (Some(remote_dh), Some(mut chain_key)) = (self.dh_remote, self.recv_chain) else {
+            return Ok(());
+        };
+
+        if until > self.recv_count.saturating_add(MAX_SKIP) {
+            return Err(CryptoError::TooManySkippedMessages);
+        }
+
+        while self.recv_count < until {
+            let (next_chain, message_key) = kdf_ck(&chain_key);
+            chain_key = next_chain;
+            self.store_skipped((remote_dh, self.recv_count), message_key);
+            self.recv_count += 1;
+        }
+        self.recv_chain = Some(chain_key);
+
+        Ok(())
+    }
+
+    fn store_skipped(&mut self, id: ([u8; 32], u32), message_key: [u8; 32]) {
+        self.skipped.insert(id, message_key);
+        self.skipped_order.push_back(id);
+
+        // Evict the oldest keys once the store is full
+        while self.skipped.len() > MAX_SKIPPED_KEYS {
+            match self.skipped_order.pop_front() {
+                Some(oldest) => {
+                    if let Some(mut key) = self.skipped.remove(&oldest) {
+                        key.zeroize();
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn remove_skipped(&mut self, id: &([u8; 32], u32)) {
+        if let Some(mut key) = self.skipped.remove(id) {
+            key.zeroize();
+        }
+        self.skipped_order.retain(|entry| entry != id);
+    }
+}
+
+impl Drop for DoubleRatchet {
+    fn drop(&mut self) {
+        self.root_key.zeroize();
+        if let Some(key) = self.send_chain.as_mut() {
+            key.zeroize();
+        }
+        if let Some(key) = self.recv_chain.as_mut() {
+            key.zeroize();
+        }
+        for key in self.skipped.values_mut() {
+            key.zeroize();
+        }
+    }
+}
+
+/// Root key KDF: HKDF-SHA256 keyed by the root key over the DH output
+fn kdf_rk(root_key: &[u8; 32], dh_out: &[u8; 32]) -> Result<([u8; 32], [u8; 32]), CryptoError> {
+    let hk = Hkdf::<Sha256>::new(Some(root_key), dh_out);
+    let mut okm = [0u8; 64];
+    hk.expand(ROOT_KDF_INFO, &mut okm)
+        .map_err(|_| CryptoError::KeyDerivationFailed)?;
+
+    let mut next_root = [0u8; 32];
+    let mut chain_key = [0u8; 32];
+    next_root.copy_from_slice(&okm[..32]);
+    chain_key.copy_from_slice(&okm[32..]);
+    okm.zeroize();
+
+    Ok((next_root, chain_key))
+}
+
+/// Chain key KDF: HMAC-SHA256 with distinct constants for the next chain
+/// key and the message key
+fn kdf_ck(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let derive = |constant: u8| -> [u8; 32] {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(chain_key)
+            .expect("HMAC accepts keys of any length");
+        mac.update(&[constant]);
+        mac.finalize().into_bytes().into()
+    };
+
+    (derive(0x02), derive(0x01))
+}
+
+fn seal(
+    message_key: &[u8; 32],
+    nonce: &[u8; NONCE_LEN],
+    plaintext: &[u8],
+    associated_data: &[u8],
+    header: &MessageHeader,
+) -> Result<Vec<u8>, CryptoError> {
+    let aad = [associated_data, &header.to_bytes()].concat();
+    Aes256Gcm::new(Key::from_slice(message_key))
+        .encrypt(Nonce::from_slice(nonce), Payload { msg: plaintext, aad: &aad })
+        .map_err(|_| CryptoError::EncryptionFailed)
+}
+
+fn open(
+    message_key: &[u8; 32],
+    nonce: &[u8],
+    ciphertext: &[u8],
+    associated_data: &[u8],
+    header: &MessageHeader,
+) -> Result<Vec<u8>, CryptoError> {
+    let aad = [associated_data, &header.to_bytes()].concat();
+    Aes256Gcm::new(Key::from_slice(message_key))
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: &aad })
+        .map_err(|_| CryptoError::DecryptionFailed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AD: &[u8] = b"test-associated-data";
+
+    fn ratchet_pair() -> (DoubleRatchet, DoubleRatchet) {
+        let shared_secret = [9u8; 32];
+        let bob_static = X25519KeyPair::generate();
+        let bob_public = bob_static.public;
+
+        let alice = DoubleRatchet::new_initiator(shared_secret, X25519KeyPair::generate(), &bob_public)
+            .unwrap();
+        let bob = DoubleRatchet::new_responder(shared_secret, bob_static);
+        (alice, bob)
+    }
+
+    fn send(from: &mut DoubleRatchet, plaintext: &[u8]) -> (MessageHeader, [u8; NONCE_LEN], Vec<u8>) {
+        from.encrypt(plaintext, AD).unwrap()
+    }
+
+    fn receive(to: &mut DoubleRatchet, message: &(MessageHeader, [u8; NONCE_LEN], Vec<u8>)) -> Vec<u8> {
+        to.decrypt(&message.0, &message.1, &message.2, AD).unwrap()
+    }
+
+    #[test]
+    fn test_ratchet_conversation() {
+        let (mut alice, mut bob) = ratchet_pair();
+
+        // Bob cannot send before hearing from Alice
+        assert!(bob.encrypt(b"too early", AD).is_err());
+
+        let m1 = send(&mut alice, b"hi bob");
+        assert_eq!(receive(&mut bob, &m1), b"hi bob");
+
+        let alice_key = alice.public_key();
+        let m2 = send(&mut bob, b"hi alice");
+        assert_eq!(receive(&mut alice, &m2), b"hi alice");
+
+        // Alice rotates her ratchet key after receiving Bob's new one
+        let m3 = send(&mut alice, b"new chain");
+        assert_ne!(m3.0.dh, alice_key);
+        assert_eq!(m3.0.pn, 1);
+        assert_eq!(receive(&mut bob, &m3), b"new chain");
+    }
+
+    #[test]
+    fn test_out_of_order_messages() {
+        let (mut alice, mut bob) = ratchet_pair();
+
+        let m0 = send(&mut alice, b"zero");
+        let m1 = send(&mut alice, b"one");
+        let m2 = send(&mut alice, b"two");
+
+        assert_eq!(receive(&mut bob, &m2), b"two");
+        assert_eq!(bob.skipped_keys(), 2);
+
+        // Bob replies, moving to a new chain, before the late messages arrive
+        let reply = send(&mut bob, b"reply");
+        receive(&mut alice, &reply);
+        let m3 = send(&mut alice, b"three");
+        assert_eq!(receive(&mut bob, &m3), b"three");
+
+        assert_eq!(receive(&mut bob, &m0), b"zero");
+        assert_eq!(receive(&mut bob, &m1), b"one");
+        assert_eq!(bob.skipped_keys(), 0);
+
+        // A replayed message no longer has a key
+        assert!(bob.decrypt(&m1.0, &m1.1, &m1.2, AD).is_err());
+    }
+
+    #[test]
+    fn test_skipped_keys_are_bounded() {
+        let (mut alice, mut bob) = ratchet_pair();
+
+        let first = send(&mut alice, b"first");
+        receive(&mut bob, &first);
+
+        let mut far_ahead = send(&mut alice, b"far ahead");
+        far_ahead.0.n = MAX_SKIP + 2;
+        let result = bob.decrypt(&far_ahead.0, &far_ahead.1, &far_ahead.2, AD);
+        assert!(matches!(result, Err(CryptoError::TooManySkippedMessages)));
+        assert_eq!(bob.skipped_keys(), 0);
+    }
+
+    #[test]
+    fn test_forged_message_does_not_change_state() {
+        let (mut alice, mut bob) = ratchet_pair();
+
+        let mut forged = send(&mut alice, b"genuine");
+        let genuine = forged.clone();
+        forged.0.dh = X25519KeyPair::generate().public;
+        assert!(matches!(
+            bob.decrypt(&forged.0, &forged.1, &forged.2, AD),
+            Err(CryptoError::DecryptionFailed)
+        ));
+
+        assert_eq!(receive(&mut bob, &genuine), b"genuine");
+    }
+}
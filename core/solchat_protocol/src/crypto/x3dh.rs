@@ -0,0 +1,412 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use ed25519_dalek::{Keypair, Signer};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use zeroize::Zeroize;
+
+use super::{verify_wallet_signature, CryptoError, X25519KeyPair};
+use crate::messages::{OneTimePrekey, PrekeyBundle, SignedPrekey, X3dhInitialMessage};
+use crate::WalletAddress;
+
+// X3DH key agreement as described in the Signal specification:
+// https://signal.org/docs/specifications/x3dh/
+
+const X3DH_INFO: &[u8] = b"SolConnect-X3DH";
+const SIGNED_PREKEY_CONTEXT: &[u8] = b"SolConnect-SignedPrekey-v1";
+const BUNDLE_CONTEXT: &[u8] = b"SolConnect-PrekeyBundle-v1";
+const IDENTITY_KEY_CONTEXT: &[u8] = b"SolConnect-IdentityKey-v1";
+
+/// Output of an X3DH key agreement
+pub struct X3dhOutput {
+    /// Secret used as the initial Double Ratchet root key
+    pub shared_secret: [u8; 32],
+    /// Identity binding to include in the associated data of every message
+    pub associated_data: Vec<u8>,
+}
+
+impl Drop for X3dhOutput {
+    fn drop(&mut self) {
+        self.shared_secret.zeroize();
+    }
+}
+
+/// Bundle contents after the wallet signature has been checked
+pub struct VerifiedBundle {
+    pub wallet: WalletAddress,
+    pub identity_key: [u8; 32],
+    pub signed_prekey_id: u32,
+    pub signed_prekey: [u8; 32],
+    pub one_time_prekey: Option<(u32, [u8; 32])>,
+}
+
+/// Private prekey material kept by the owner of a published bundle
+pub struct PrekeyStore {
+    wallet: WalletAddress,
+    identity: X25519KeyPair,
+    signed_prekey_id: u32,
+    signed_prekey: X25519KeyPair,
+    signed_prekey_signature: [u8; 64],
+    one_time_prekeys: HashMap<u32, X25519KeyPair>,
+    next_prekey_id: u32,
+}
+
+impl PrekeyStore {
+    /// Create a signed prekey and `one_time_count` one-time prekeys
+    pub fn generate(wallet_keypair: &Keypair, identity: X25519KeyPair, one_time_count: usize) -> Self {
+        let mut store = Self {
+            wallet: WalletAddress::new(wallet_keypair.public.to_bytes()),
+            identity,
+            signed_prekey_id: 0,
+            signed_prekey: X25519KeyPair::generate(),
+            signed_prekey_signature: [0u8; 64],
+            one_time_prekeys: HashMap::new(),
+            next_prekey_id: 1,
+        };
+        store.rotate_signed_prekey(wallet_keypair);
+        store.replenish(one_time_count);
+        store
+    }
+
+    /// Replace the signed prekey with a freshly generated one
+    pub fn rotate_signed_prekey(&mut self, wallet_keypair: &Keypair) {
+        self.signed_prekey_id = self.allocate_id();
+        self.signed_prekey = X25519KeyPair::generate();
+        let message = signed_prekey_message(
+            &self.identity.public,
+            self.signed_prekey_id,
+            &self.signed_prekey.public,
+        );
+        self.signed_prekey_signature = wallet_keypair.sign(&message).to_bytes();
+    }
+
+    /// Generate additional one-time prekeys
+    pub fn replenish(&mut self, count: usize) {
+        for _ in 0..count {
+            let id = self.allocate_id();
+            self.one_time_prekeys.insert(id, X25519KeyPair::generate());
+        }
+    }
+
+    /// Forget a one-time prekey so it can never be used again
+    pub fn remove_one_time_prekey(&mut self, id: u32) {
+        self.one_time_prekeys.remove(&id);
+    }
+
+    /// Number of unused one-time prekeys
+    pub fn one_time_prekeys(&self) -> usize {
+        self.one_time_prekeys.len()
+    }
+
+    pub fn wallet(&self) -> &WalletAddress {
+        &self.wallet
+    }
+
+    /// Public bundle to publish to the relay, signed with the wallet key
+    pub fn bundle(&self, wallet_keypair: &Keypair) -> PrekeyBundle {
+        let mut one_time_prekeys: Vec<OneTimePrekey> = self.one_time_prekeys
+            .iter()
+            .map(|(id, keypair)| OneTimePrekey {
+                id: *id,
+                public_key: keypair.public.to_vec(),
+            })
+            .collect();
+        one_time_prekeys.sort_by_key(|prekey| prekey.id);
+
+        let mut bundle = PrekeyBundle {
+            wallet_address: self.wallet.to_string(),
+            identity_key: self.identity.public.to_vec(),
+            signed_prekey: Some(SignedPrekey {
+                id: self.signed_prekey_id,
+                public_key: self.signed_prekey.public.to_vec(),
+                signature: self.signed_prekey_signature.to_vec(),
+            }),
+            one_time_prekeys,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            signature: Vec::new(),
+        };
+        bundle.signature = wallet_keypair.sign(&bundle_message(&bundle)).to_bytes().to_vec();
+        bundle
+    }
+
+    pub(crate) fn signed_prekey(&self) -> &X25519KeyPair {
+        &self.signed_prekey
+    }
+
+    fn allocate_id(&mut self) -> u32 {
+        let id = self.next_prekey_id;
+        self.next_prekey_id = self.next_prekey_id.wrapping_add(1);
+        id
+    }
+}
+
+/// Bytes covered by the signed prekey signature
+fn signed_prekey_message(identity_key: &[u8; 32], id: u32, public_key: &[u8; 32]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(SIGNED_PREKEY_CONTEXT.len() + 68);
+    message.extend_from_slice(SIGNED_PREKEY_CONTEXT);
+    message.extend_from_slice(identity_key);
+    message.extend_from_slice(&id.to_le_bytes());
+    message.extend_from_slice(public_key);
+    message
+}
+
+/// Bytes covered by the bundle signature
+///
+/// Binds the timestamp to the one-time prekeys, so an old bundle cannot be
+/// replayed with a newer timestamp to bring back handed-out prekeys.
+fn bundle_message(bundle: &PrekeyBundle) -> Vec<u8> {
+    let mut message = Vec::with_capacity(BUNDLE_CONTEXT.len() + 16 + bundle.one_time_prekeys.len() * 36);
+    message.extend_from_slice(BUNDLE_CONTEXT);
+    message.extend_from_slice(&bundle.timestamp.to_le_bytes());
+    let signed_prekey_id = bundle.signed_prekey.as_ref().map(|prekey| prekey.id).unwrap_or(0);
+    message.extend_from_slice(&signed_prekey_id.to_le_bytes());
+    message.extend_from_slice(&(bundle.one_time_prekeys.len() as u32).to_le_bytes());
+    for prekey in &bundle.one_time_prekeys {
+        message.extend_from_slice(&prekey.id.to_le_bytes());
+        message.extend_from_slice(&prekey.public_key);
+    }
+    message
+}
+
+/// Bytes covered by the identity key signature of an initial message
+fn identity_key_message(identity_key: &[u8; 32]) -> Vec<u8> {
+    [IDENTITY_KEY_CONTEXT, identity_key].concat()
+}
+
+fn key_from_slice(bytes: &[u8]) -> Result<[u8; 32], CryptoError> {
+    bytes.try_into().map_err(|_| CryptoError::InvalidKey)
+}
+
+/// Check a bundle's wallet signature and extract its keys
+///
+/// Only the first one-time prekey, if any, is returned.
+pub fn verify_bundle(bundle: &PrekeyBundle) -> Result<VerifiedBundle, CryptoError> {
+    let wallet = bundle.wallet().map_err(|_| CryptoError::InvalidKey)?;
+    let identity_key = key_from_slice(&bundle.identity_key)?;
+    let signed_prekey = bundle.signed_prekey.as_ref().ok_or(CryptoError::InvalidKey)?;
+    let signed_prekey_public = key_from_slice(&signed_prekey.public_key)?;
+
+    let message = signed_prekey_message(&identity_key, signed_prekey.id, &signed_prekey_public);
+    verify_wallet_signature(&wallet, &message, &signed_prekey.signature)?;
+
+    let one_time_prekey = match bundle.one_time_prekeys.first() {
+        Some(prekey) => Some((prekey.id, key_from_slice(&prekey.public_key)?)),
+        None => None,
+    };
+
+    Ok(VerifiedBundle {
+        wallet,
+        identity_key,
+        signed_prekey_id: signed_prekey.id,
+        signed_prekey: signed_prekey_public,
+        one_time_prekey,
+    })
+}
+
+/// Check a bundle as published by its owner, before the relay stores it
+///
+/// Besides the signed prekey, the wallet must have signed the timestamp and
+/// the full list of one-time prekeys.
+pub fn verify_published_bundle(bundle: &PrekeyBundle) -> Result<VerifiedBundle, CryptoError> {
+    let verified = verify_bundle(bundle)?;
+    for prekey in &bundle.one_time_prekeys {
+        key_from_slice(&prekey.public_key)?;
+    }
+    verify_wallet_signature(&verified.wallet, &bundle_message(bundle), &bundle.signature)?;
+    Ok(verified)
+}
+
+/// Derive the X3DH secret from the concatenated DH outputs
+fn kdf(dh_outputs: &[[u8; 32]]) -> Result<[u8; 32], CryptoError> {
+    // 32 0xFF bytes prefix the input for domain separation from the curve
+    let mut ikm = vec![0xFFu8; 32];
+    for output in dh_outputs {
+        ikm.extend_from_slice(output);
+    }
+
+    let hk = Hkdf::<Sha256>::new(Some(&[0u8; 32]), &ikm);
+    let mut secret = [0u8; 32];
+    let result = hk.expand(X3DH_INFO, &mut secret);
+    ikm.zeroize();
+    result.map_err(|_| CryptoError::KeyDerivationFailed)?;
+
+    Ok(secret)
+}
+
+/// Sender side of X3DH: derive a shared secret from a verified bundle
+///
+/// Returns the output together with the partially filled initial message;
+/// the caller adds the first ciphertext.
+pub fn initiate(
+    wallet_keypair: &Keypair,
+    identity: &X25519KeyPair,
+    bundle: &VerifiedBundle,
+) -> Result<(X3dhOutput, X3dhInitialMessage), CryptoError> {
+    let ephemeral = X25519KeyPair::generate();
+
+    let mut dh_outputs = vec![
+        identity.diffie_hellman(&bundle.signed_prekey)?,
+        ephemeral.diffie_hellman(&bundle.identity_key)?,
+        ephemeral.diffie_hellman(&bundle.signed_prekey)?,
+    ];
+    if let Some((_, one_time_prekey)) = &bundle.one_time_prekey {
+        dh_outputs.push(ephemeral.diffie_hellman(one_time_prekey)?);
+    }
+
+    let shared_secret = kdf(&dh_outputs);
+    dh_outputs.iter_mut().for_each(|output| output.zeroize());
+
+    let output = X3dhOutput {
+        shared_secret: shared_secret?,
+        associated_data: [identity.public, bundle.identity_key].concat(),
+    };
+
+    let message = X3dhInitialMessage {
+        sender_wallet: WalletAddress::new(wallet_keypair.public.to_bytes()).to_string(),
+        identity_key: identity.public.to_vec(),
+        identity_signature: wallet_keypair.sign(&identity_key_message(&identity.public)).to_bytes().to_vec(),
+        ephemeral_key: ephemeral.public.to_vec(),
+        signed_prekey_id: bundle.signed_prekey_id,
+        one_time_prekey_id: bundle.one_time_prekey.map(|(id, _)| id),
+        ciphertext: Vec::new(),
+    };
+
+    Ok((output, message))
+}
+
+/// Recipient side of X3DH: derive the shared secret from an initial message
+///
+/// The one-time prekey is left in the store; the caller removes it with
+/// `PrekeyStore::remove_one_time_prekey` once the first message decrypts.
+pub fn respond(
+    store: &PrekeyStore,
+    message: &X3dhInitialMessage,
+) -> Result<X3dhOutput, CryptoError> {
+    let sender = message.sender().map_err(|_| CryptoError::InvalidKey)?;
+    let sender_identity = key_from_slice(&message.identity_key)?;
+    let ephemeral = key_from_slice(&message.ephemeral_key)?;
+
+    verify_wallet_signature(&sender, &identity_key_message(&sender_identity), &message.identity_signature)?;
+
+    if message.signed_prekey_id != store.signed_prekey_id {
+        return Err(CryptoError::InvalidKey);
+    }
+
+    let one_time_prekey = match message.one_time_prekey_id {
+        Some(id) => Some(store.one_time_prekeys.get(&id).ok_or(CryptoError::InvalidKey)?),
+        None => None,
+    };
+
+    let mut dh_outputs = vec![
+        store.signed_prekey.diffie_hellman(&sender_identity)?,
+        store.identity.diffie_hellman(&ephemeral)?,
+        store.signed_prekey.diffie_hellman(&ephemeral)?,
+    ];
+    if let Some(one_time_prekey) = one_time_prekey {
+        dh_outputs.push(one_time_prekey.diffie_hellman(&ephemeral)?);
+    }
+
+    let shared_secret = kdf(&dh_outputs);
+    dh_outputs.iter_mut().for_each(|output| output.zeroize());
+
+    let output = X3dhOutput {
+        shared_secret: shared_secret?,
+        associated_data: [sender_identity, store.identity.public].concat(),
+    };
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{derive_x25519_from_ed25519, SessionManager};
+    use ed25519_dalek::SecretKey;
+
+    fn wallet_keypair(seed: u8) -> Keypair {
+        let secret = SecretKey::from_bytes(&[seed; 32]).unwrap();
+        let public = (&secret).into();
+        Keypair { secret, public }
+    }
+
+    fn identity(keypair: &Keypair) -> X25519KeyPair {
+        derive_x25519_from_ed25519(&keypair.public.to_bytes(), &keypair.secret.to_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_offline_session_from_bundle() {
+        let alice = wallet_keypair(1);
+        let bob = wallet_keypair(2);
+        let mut bob_prekeys = PrekeyStore::generate(&bob, identity(&bob), 2);
+
+        // The relay hands out the bundle with a single one-time prekey
+        let published = bob_prekeys.bundle(&bob);
+        let bundle = published.with_single_one_time_prekey(published.one_time_prekeys.first().cloned());
+        assert!(verify_published_bundle(&published).is_ok());
+        assert!(bundle.verify().is_ok());
+
+        let mut alice_sessions = SessionManager::new([0u8; 32]);
+        let (alice_session, initial) = alice_sessions
+            .init_session_from_bundle(&alice, &identity(&alice), &bundle, b"hello offline bob")
+            .unwrap();
+        assert!(initial.one_time_prekey_id.is_some());
+
+        let mut bob_sessions = SessionManager::new([0u8; 32]);
+        let (bob_session, plaintext) = bob_sessions
+            .accept_initial_message(&mut bob_prekeys, &initial)
+            .unwrap();
+        assert_eq!(plaintext, b"hello offline bob");
+        assert_eq!(bob_prekeys.one_time_prekeys(), 1);
+
+        let reply = bob_sessions.encrypt_message(&bob_session, b"hi alice").unwrap();
+        assert_eq!(alice_sessions.decrypt_message(&alice_session, &reply).unwrap(), b"hi alice");
+
+        // The one-time prekey is gone, so the initial message cannot be replayed
+        let replay = bob_sessions.accept_initial_message(&mut bob_prekeys, &initial);
+        assert!(matches!(replay, Err(CryptoError::InvalidKey)));
+    }
+
+    #[test]
+    fn test_bundle_signature_is_checked() {
+        let bob = wallet_keypair(2);
+        let mallory = wallet_keypair(3);
+        let bob_prekeys = PrekeyStore::generate(&bob, identity(&bob), 1);
+
+        // Swapping in another signed prekey invalidates the wallet signature
+        let mut forged = bob_prekeys.bundle(&bob);
+        let mallory_prekeys = PrekeyStore::generate(&mallory, identity(&mallory), 0);
+        forged.signed_prekey = mallory_prekeys.bundle(&mallory).signed_prekey;
+        assert!(matches!(verify_bundle(&forged), Err(CryptoError::InvalidSignature)));
+
+        let mut sessions = SessionManager::new([0u8; 32]);
+        let result = sessions.init_session_from_bundle(&mallory, &identity(&mallory), &forged, b"hi");
+        assert!(matches!(result, Err(CryptoError::InvalidSignature)));
+    }
+
+    #[test]
+    fn test_published_bundle_signature_covers_timestamp_and_prekeys() {
+        let bob = wallet_keypair(2);
+        let mut bob_prekeys = PrekeyStore::generate(&bob, identity(&bob), 2);
+        let old = bob_prekeys.bundle(&bob);
+        bob_prekeys.remove_one_time_prekey(old.one_time_prekeys[0].id);
+        let current = bob_prekeys.bundle(&bob);
+        assert!(verify_published_bundle(&current).is_ok());
+
+        // Replaying the old prekeys under a newer timestamp is caught
+        let mut replayed = old.clone();
+        replayed.timestamp = current.timestamp + 1;
+        assert!(matches!(verify_published_bundle(&replayed), Err(CryptoError::InvalidSignature)));
+
+        let mut padded = current.clone();
+        padded.one_time_prekeys.push(old.one_time_prekeys[0].clone());
+        assert!(matches!(verify_published_bundle(&padded), Err(CryptoError::InvalidSignature)));
+
+        // Fetched bundles carry a single prekey and only the signed prekey check
+        let fetched = current.with_single_one_time_prekey(current.one_time_prekeys.first().cloned());
+        assert!(fetched.signature.is_empty());
+        assert!(verify_bundle(&fetched).is_ok());
+    }
+}
@@ -3,6 +3,7 @@ use std::fmt;
 
 // TODO: buy more SOL for coffee ☕
 
+pub mod codec;
 pub mod crypto;
 pub mod messages;
 
@@ -36,69 +37,81 @@ impl fmt::Display for WalletAddress {
     }
 }
 
-/// Encrypted message container with metadata
-/// DEPRECATED: Use ChatMessage from protobuf instead
-#[deprecated(note = "Use ChatMessage from protobuf schema")]
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct EncryptedMessage {
-    pub sender: WalletAddress,
-    pub recipient: WalletAddress,
-    pub payload: Vec<u8>,
-    pub nonce: [u8; 24],
-    pub timestamp: u64,
-    pub message_id: String,
-}
+// Pre-protobuf message types, kept for compatibility
+#[allow(deprecated)]
+pub use legacy::{EncryptedMessage, ProtocolMessage};
 
-impl EncryptedMessage {
-    pub fn new(
-        sender: WalletAddress,
-        recipient: WalletAddress,
-        payload: Vec<u8>,
-    ) -> Self {
-        let nonce = [0u8; 24]; // TODO: Generate random nonce
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        let message_id = format!("msg_{}", uuid::Uuid::new_v4());
-        
-        Self {
-            sender,
-            recipient,
-            payload,
-            nonce,
-            timestamp,
-            message_id,
-        }
-    }
+mod legacy {
+    // The deprecated types still have to name each other
+    #![allow(deprecated)]
+    
+    use super::WalletAddress;
+    use serde::{Deserialize, Serialize};
     
-    pub fn size(&self) -> usize {
-        self.payload.len()
+    /// Encrypted message container with metadata
+    /// DEPRECATED: Use ChatMessage from protobuf instead
+    #[deprecated(note = "Use ChatMessage from protobuf schema")]
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct EncryptedMessage {
+        pub sender: WalletAddress,
+        pub recipient: WalletAddress,
+        pub payload: Vec<u8>,
+        pub nonce: [u8; 24],
+        pub timestamp: u64,
+        pub message_id: String,
     }
-}
 
-/// Protocol message types
-/// DEPRECATED: Use protobuf message types instead
-#[deprecated(note = "Use protobuf message types")]
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum ProtocolMessage {
-    Chat(EncryptedMessage),
-    Ping { timestamp: u64 },
-    Pong { timestamp: u64 },
-    Ack { message_id: String },
-}
+    impl EncryptedMessage {
+        pub fn new(
+            sender: WalletAddress,
+            recipient: WalletAddress,
+            payload: Vec<u8>,
+        ) -> Self {
+            let nonce = [0u8; 24]; // TODO: Generate random nonce
+            let timestamp = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            let message_id = format!("msg_{}", uuid::Uuid::new_v4());
+
+            Self {
+                sender,
+                recipient,
+                payload,
+                nonce,
+                timestamp,
+                message_id,
+            }
+        }
 
-impl ProtocolMessage {
-    pub fn ping() -> Self {
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        Self::Ping { timestamp }
+        pub fn size(&self) -> usize {
+            self.payload.len()
+        }
     }
-    
-    pub fn pong(timestamp: u64) -> Self {
-        Self::Pong { timestamp }
+
+    /// Protocol message types
+    /// DEPRECATED: Use protobuf message types instead
+    #[deprecated(note = "Use protobuf message types")]
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub enum ProtocolMessage {
+        Chat(EncryptedMessage),
+        Ping { timestamp: u64 },
+        Pong { timestamp: u64 },
+        Ack { message_id: String },
+    }
+
+    impl ProtocolMessage {
+        pub fn ping() -> Self {
+            let timestamp = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            Self::Ping { timestamp }
+        }
+
+        pub fn pong(timestamp: u64) -> Self {
+            Self::Pong { timestamp }
+        }
     }
 }
 
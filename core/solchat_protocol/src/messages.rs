@@ -5,9 +5,51 @@ pub mod proto {
 }
 
 use crate::WalletAddress;
+use ed25519_dalek::{Keypair, Signer};
+use rand_core::{OsRng, RngCore};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+/// Domain separation tag for `ChatMessage` signatures
+const CHAT_MESSAGE_SIGNING_CONTEXT: &[u8] = b"SolConnect-ChatMessage-v1";
+
+/// Domain separation tag for `HandshakeProof` signatures
+const HANDSHAKE_PROOF_SIGNING_CONTEXT: &[u8] = b"SolConnect-HandshakeProof-v1";
+
+/// TLS exporter label used to bind handshake proofs to a connection
+pub const HANDSHAKE_EXPORTER_LABEL: &[u8] = b"EXPORTER-SolConnect-Handshake";
+
+/// Length of the TLS exporter value mixed into handshake proofs
+pub const HANDSHAKE_EXPORTER_LEN: usize = 32;
+
+/// Protocol version spoken by this crate
+pub const PROTOCOL_VERSION: &str = "1.0.0";
+
+/// ALPN identifier negotiated by clients and relays on QUIC connections
+pub const ALPN_PROTOCOL: &[u8] = b"solconnect/1";
+
+/// Length of the random nonce in a `HandshakeChallenge`
+pub const CHALLENGE_NONCE_LEN: usize = 32;
+
+/// How long a client has to answer a `HandshakeChallenge`
+pub const CHALLENGE_VALIDITY_SECS: u64 = 30;
+
+/// Longest device id a client may name in its handshake proof
+pub const MAX_DEVICE_ID_LEN: usize = 64;
+
 pub use proto::{ChatMessage, AckMessage, AckStatus, HandshakeRequest, HandshakeResponse, ReadReceipt, PingMessage, PongMessage};
+pub use proto::GoAwayMessage;
+pub use proto::{HandshakeChallenge, HandshakeProof};
+pub use proto::{envelope, Envelope};
+pub use proto::{PrekeyBundle, PrekeyBundleRequest, SignedPrekey, OneTimePrekey, X3dhInitialMessage};
+pub use proto::{QueueHandoff, QueueHandoffRequest, QueuedMessage};
+
+/// Current Unix time in milliseconds
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
 
 /// Conversion helpers for protobuf types
 impl ChatMessage {
@@ -82,7 +124,53 @@ impl ChatMessage {
             .unwrap()
             .as_secs();
             
-        now > self.timestamp + self.ttl as u64
+        now > self.timestamp.saturating_add(self.ttl as u64)
+    }
+    
+    /// Canonical byte encoding of every field covered by the signature
+    ///
+    /// Variable-length fields are length-prefixed and integers are
+    /// little-endian, so no two distinct messages share an encoding.
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+            out.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+            out.extend_from_slice(bytes);
+        }
+        
+        let mut out = Vec::with_capacity(128 + self.encrypted_payload.len());
+        out.extend_from_slice(CHAT_MESSAGE_SIGNING_CONTEXT);
+        put_bytes(&mut out, self.id.as_bytes());
+        put_bytes(&mut out, self.sender_wallet.as_bytes());
+        put_bytes(&mut out, self.recipient_wallet.as_bytes());
+        out.extend_from_slice(&self.timestamp.to_le_bytes());
+        put_bytes(&mut out, &self.encrypted_payload);
+        match &self.attachment_url {
+            Some(url) => {
+                out.push(1);
+                put_bytes(&mut out, url.as_bytes());
+            }
+            None => out.push(0),
+        }
+        out.extend_from_slice(&self.ttl.to_le_bytes());
+        out
+    }
+    
+    /// Sign the message with the sender wallet's Ed25519 key
+    ///
+    /// Fails if `signer` is not the key behind `sender_wallet`.
+    pub fn sign(&mut self, signer: &Keypair) -> Result<(), String> {
+        if signer.public.to_bytes() != *self.sender()?.as_bytes() {
+            return Err("Signer does not match sender wallet".to_string());
+        }
+        
+        self.signature = signer.sign(&self.signing_bytes()).to_bytes().to_vec();
+        Ok(())
+    }
+    
+    /// Verify the signature against the public key encoded by `sender_wallet`
+    pub fn verify(&self) -> Result<(), String> {
+        crate::crypto::verify_wallet_signature(&self.sender()?, &self.signing_bytes(), &self.signature)
+            .map_err(|e| format!("Invalid message signature: {}", e))
     }
 }
 
@@ -92,13 +180,24 @@ impl AckMessage {
             id: format!("ack_{}", uuid::Uuid::new_v4()),
             ref_message_id,
             status: status.into(),
+            reason: None,
         }
     }
     
+    /// Attach a human-readable reason for the status
+    pub fn with_reason(mut self, reason: impl Into<String>) -> Self {
+        self.reason = Some(reason.into());
+        self
+    }
+    
     pub fn delivered(ref_message_id: String) -> Self {
         Self::new(ref_message_id, AckStatus::Delivered)
     }
     
+    pub fn queued(ref_message_id: String) -> Self {
+        Self::new(ref_message_id, AckStatus::Queued)
+    }
+    
     pub fn failed(ref_message_id: String) -> Self {
         Self::new(ref_message_id, AckStatus::Failed)
     }
@@ -112,6 +211,35 @@ impl AckMessage {
     }
 }
 
+impl PingMessage {
+    pub fn new(sequence: u64) -> Self {
+        Self {
+            sequence,
+            timestamp: unix_millis(),
+        }
+    }
+}
+
+impl PongMessage {
+    /// Answer a ping, echoing its sequence number and timestamp
+    pub fn reply_to(ping: &PingMessage) -> Self {
+        Self {
+            sequence: ping.sequence,
+            ping_timestamp: ping.timestamp,
+            timestamp: unix_millis(),
+        }
+    }
+}
+
+impl GoAwayMessage {
+    pub fn new(reason: impl Into<String>) -> Self {
+        Self {
+            reason: reason.into(),
+            timestamp: unix_millis(),
+        }
+    }
+}
+
 impl HandshakeRequest {
     pub fn new(wallet: &WalletAddress, signature: Vec<u8>) -> Self {
         let timestamp = SystemTime::now()
@@ -123,7 +251,7 @@ impl HandshakeRequest {
             wallet_address: wallet.to_string(),
             timestamp,
             signature,
-            version: "1.0.0".to_string(),
+            version: PROTOCOL_VERSION.to_string(),
         }
     }
     
@@ -152,6 +280,151 @@ impl HandshakeRequest {
     }
 }
 
+impl HandshakeChallenge {
+    /// Fresh challenge with a random nonce, issued by the relay per connection
+    pub fn new(relay_id: &str) -> Self {
+        let mut nonce = vec![0u8; CHALLENGE_NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        
+        Self {
+            nonce,
+            relay_id: relay_id.to_string(),
+            version: PROTOCOL_VERSION.to_string(),
+            timestamp,
+        }
+    }
+    
+    pub fn is_expired(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        
+        now > self.timestamp + CHALLENGE_VALIDITY_SECS
+    }
+    
+    /// Canonical bytes a client signs to answer this challenge
+    ///
+    /// Covers the nonce, relay identity, protocol version, the answering wallet
+    /// and `channel_binding` (the TLS exporter value of the connection, or
+    /// empty on transports without one), so a proof is useless on any other
+    /// connection or relay.
+    pub fn signing_bytes(&self, wallet_address: &str, channel_binding: &[u8]) -> Vec<u8> {
+        let fields: [&[u8]; 5] = [
+            &self.nonce,
+            self.relay_id.as_bytes(),
+            self.version.as_bytes(),
+            wallet_address.as_bytes(),
+            channel_binding,
+        ];
+        
+        let mut out = Vec::with_capacity(
+            HANDSHAKE_PROOF_SIGNING_CONTEXT.len() + fields.iter().map(|f| 8 + f.len()).sum::<usize>(),
+        );
+        out.extend_from_slice(HANDSHAKE_PROOF_SIGNING_CONTEXT);
+        for field in fields {
+            out.extend_from_slice(&(field.len() as u64).to_le_bytes());
+            out.extend_from_slice(field);
+        }
+        out
+    }
+    
+    /// Server side: check a client's proof against this challenge
+    ///
+    /// Returns the authenticated wallet on success.
+    pub fn verify_proof(&self, proof: &HandshakeProof, channel_binding: &[u8]) -> Result<WalletAddress, String> {
+        if self.is_expired() {
+            return Err("Handshake challenge expired".to_string());
+        }
+        
+        if proof.version != self.version {
+            return Err(format!("Unsupported protocol version: {}", proof.version));
+        }
+        
+        validate_device_id(proof.device_id())?;
+        
+        let wallet = proof.wallet()?;
+        let message = self.signing_bytes(&proof.wallet_address, channel_binding);
+        crate::crypto::verify_wallet_signature(&wallet, &message, &proof.signature)
+            .map_err(|e| format!("Invalid handshake proof: {}", e))?;
+        
+        Ok(wallet)
+    }
+}
+
+impl HandshakeProof {
+    /// Client side: answer a relay's challenge with the wallet's Ed25519 key
+    ///
+    /// `expected_relay_id` is the relay the client meant to connect to; a
+    /// challenge naming any other relay is refused rather than signed.
+    pub fn sign(
+        challenge: &HandshakeChallenge,
+        expected_relay_id: &str,
+        channel_binding: &[u8],
+        signer: &Keypair,
+    ) -> Result<Self, String> {
+        if challenge.relay_id != expected_relay_id {
+            return Err(format!("Challenge issued by unexpected relay: {}", challenge.relay_id));
+        }
+        
+        if challenge.version != PROTOCOL_VERSION {
+            return Err(format!("Unsupported protocol version: {}", challenge.version));
+        }
+        
+        if challenge.nonce.len() != CHALLENGE_NONCE_LEN {
+            return Err("Invalid challenge nonce length".to_string());
+        }
+        
+        let wallet_address = WalletAddress::new(signer.public.to_bytes()).to_string();
+        let signature = signer.sign(&challenge.signing_bytes(&wallet_address, channel_binding));
+        
+        Ok(Self {
+            wallet_address,
+            version: challenge.version.clone(),
+            signature: signature.to_bytes().to_vec(),
+            device_id: None,
+        })
+    }
+    
+    /// Name the device answering, for relays that keep a copy of each
+    /// message per device
+    pub fn with_device_id(mut self, device_id: impl Into<String>) -> Self {
+        self.device_id = Some(device_id.into());
+        self
+    }
+    
+    pub fn wallet(&self) -> Result<WalletAddress, String> {
+        let decoded = bs58::decode(&self.wallet_address)
+            .into_vec()
+            .map_err(|e| format!("Invalid wallet address: {}", e))?;
+        
+        if decoded.len() != 32 {
+            return Err("Invalid wallet address length".to_string());
+        }
+        
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(&decoded);
+        Ok(WalletAddress::new(bytes))
+    }
+}
+
+/// Device ids are short and limited to letters, digits, `-`, `_` and `.`,
+/// so relays can use them in queue names and logs
+fn validate_device_id(device_id: &str) -> Result<(), String> {
+    if device_id.len() > MAX_DEVICE_ID_LEN {
+        return Err(format!("Device id longer than {} bytes", MAX_DEVICE_ID_LEN));
+    }
+    if !device_id.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.')) {
+        return Err("Invalid characters in device id".to_string());
+    }
+    Ok(())
+}
+
 impl HandshakeResponse {
     pub fn success() -> Self {
         let timestamp = SystemTime::now()
@@ -163,6 +436,7 @@ impl HandshakeResponse {
             success: true,
             error: None,
             timestamp,
+            session_token: None,
         }
     }
     
@@ -176,8 +450,155 @@ impl HandshakeResponse {
             success: false,
             error: Some(message),
             timestamp,
+            session_token: None,
         }
     }
+    
+    /// Hand the client a token to authenticate its later requests with
+    pub fn with_session_token(mut self, token: String) -> Self {
+        self.session_token = Some(token);
+        self
+    }
+}
+
+impl Envelope {
+    pub fn new(body: envelope::Body) -> Self {
+        Self { body: Some(body) }
+    }
+}
+
+impl From<ChatMessage> for Envelope {
+    fn from(message: ChatMessage) -> Self {
+        Self::new(envelope::Body::Chat(message))
+    }
+}
+
+impl From<AckMessage> for Envelope {
+    fn from(message: AckMessage) -> Self {
+        Self::new(envelope::Body::Ack(message))
+    }
+}
+
+impl From<ReadReceipt> for Envelope {
+    fn from(message: ReadReceipt) -> Self {
+        Self::new(envelope::Body::ReadReceipt(message))
+    }
+}
+
+impl From<PingMessage> for Envelope {
+    fn from(message: PingMessage) -> Self {
+        Self::new(envelope::Body::Ping(message))
+    }
+}
+
+impl From<PongMessage> for Envelope {
+    fn from(message: PongMessage) -> Self {
+        Self::new(envelope::Body::Pong(message))
+    }
+}
+
+impl From<PrekeyBundle> for Envelope {
+    fn from(message: PrekeyBundle) -> Self {
+        Self::new(envelope::Body::PrekeyBundle(message))
+    }
+}
+
+impl From<PrekeyBundleRequest> for Envelope {
+    fn from(message: PrekeyBundleRequest) -> Self {
+        Self::new(envelope::Body::PrekeyBundleRequest(message))
+    }
+}
+
+impl From<QueueHandoffRequest> for Envelope {
+    fn from(message: QueueHandoffRequest) -> Self {
+        Self::new(envelope::Body::QueueHandoffRequest(message))
+    }
+}
+
+impl From<GoAwayMessage> for Envelope {
+    fn from(message: GoAwayMessage) -> Self {
+        Self::new(envelope::Body::GoAway(message))
+    }
+}
+
+impl PrekeyBundle {
+    pub fn wallet(&self) -> Result<WalletAddress, String> {
+        let decoded = bs58::decode(&self.wallet_address)
+            .into_vec()
+            .map_err(|e| format!("Invalid wallet address: {}", e))?;
+        
+        if decoded.len() != 32 {
+            return Err("Invalid wallet address length".to_string());
+        }
+        
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(&decoded);
+        Ok(WalletAddress::new(bytes))
+    }
+    
+    /// Check that the signed prekey was signed by the bundle's wallet
+    pub fn verify(&self) -> Result<(), String> {
+        crate::crypto::x3dh::verify_bundle(self)
+            .map(|_| ())
+            .map_err(|e| format!("Invalid prekey bundle: {}", e))
+    }
+    
+    /// Check a bundle being published: the signed prekey, and the wallet's
+    /// signature over the timestamp and one-time prekeys
+    pub fn verify_published(&self) -> Result<(), String> {
+        crate::crypto::x3dh::verify_published_bundle(self)
+            .map(|_| ())
+            .map_err(|e| format!("Invalid prekey bundle: {}", e))
+    }
+    
+    /// Copy of the bundle carrying at most one one-time prekey
+    ///
+    /// The bundle signature no longer matches the prekeys, so it is dropped.
+    pub fn with_single_one_time_prekey(&self, one_time_prekey: Option<OneTimePrekey>) -> Self {
+        Self {
+            one_time_prekeys: one_time_prekey.into_iter().collect(),
+            signature: Vec::new(),
+            ..self.clone()
+        }
+    }
+}
+
+impl PrekeyBundleRequest {
+    pub fn new(wallet: &WalletAddress) -> Self {
+        Self {
+            wallet_address: wallet.to_string(),
+        }
+    }
+    
+    pub fn wallet(&self) -> Result<WalletAddress, String> {
+        let decoded = bs58::decode(&self.wallet_address)
+            .into_vec()
+            .map_err(|e| format!("Invalid wallet address: {}", e))?;
+        
+        if decoded.len() != 32 {
+            return Err("Invalid wallet address length".to_string());
+        }
+        
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(&decoded);
+        Ok(WalletAddress::new(bytes))
+    }
+}
+
+impl X3dhInitialMessage {
+    pub fn sender(&self) -> Result<WalletAddress, String> {
+        let decoded = bs58::decode(&self.sender_wallet)
+            .into_vec()
+            .map_err(|e| format!("Invalid sender wallet: {}", e))?;
+        
+        if decoded.len() != 32 {
+            return Err("Invalid wallet address length".to_string());
+        }
+        
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(&decoded);
+        Ok(WalletAddress::new(bytes))
+    }
 }
 
 #[cfg(test)]
@@ -233,5 +654,100 @@ mod tests {
         
         let failed = AckMessage::failed(ref_id.clone());
         assert_eq!(failed.status(), AckStatus::Failed);
+        assert_eq!(failed.reason, None);
+        
+        let queued = AckMessage::queued(ref_id.clone()).with_reason("recipient offline");
+        assert_eq!(queued.status(), AckStatus::Queued);
+        assert_eq!(queued.reason.as_deref(), Some("recipient offline"));
+    }
+    
+    #[test]
+    fn test_chat_message_sign_and_verify() {
+        let secret = ed25519_dalek::SecretKey::from_bytes(&[5u8; 32]).unwrap();
+        let public = (&secret).into();
+        let signer = Keypair { secret, public };
+        let sender = WalletAddress::new(signer.public.to_bytes());
+        let recipient = WalletAddress::test_address(2);
+        
+        let mut msg = ChatMessage::new(&sender, &recipient, b"Signed payload".to_vec(), Vec::new())
+            .with_ttl(60);
+        assert!(msg.verify().is_err());
+        
+        msg.sign(&signer).unwrap();
+        assert_eq!(msg.signature.len(), 64);
+        assert!(msg.verify().is_ok());
+        
+        // Every signed field is covered
+        let mut tampered = msg.clone();
+        tampered.encrypted_payload.push(0);
+        assert!(tampered.verify().is_err());
+        
+        let mut tampered = msg.clone();
+        tampered.ttl = 0;
+        assert!(tampered.verify().is_err());
+        
+        let mut tampered = msg.clone();
+        tampered.attachment_url = Some(String::new());
+        assert!(tampered.verify().is_err());
+        
+        let mut tampered = msg.clone();
+        tampered.recipient_wallet = WalletAddress::test_address(3).to_string();
+        assert!(tampered.verify().is_err());
+        
+        // Another wallet's key cannot sign on the sender's behalf
+        let other = ed25519_dalek::SecretKey::from_bytes(&[6u8; 32]).unwrap();
+        let other_public = (&other).into();
+        let other = Keypair { secret: other, public: other_public };
+        assert!(msg.sign(&other).is_err());
     }
-} 
\ No newline at end of file
+    
+    #[test]
+    fn test_handshake_challenge_response() {
+        let secret = ed25519_dalek::SecretKey::from_bytes(&[7u8; 32]).unwrap();
+        let public = (&secret).into();
+        let signer = Keypair { secret, public };
+        let binding = [9u8; HANDSHAKE_EXPORTER_LEN];
+        
+        let challenge = HandshakeChallenge::new("relay.test");
+        assert_eq!(challenge.nonce.len(), CHALLENGE_NONCE_LEN);
+        assert_ne!(challenge.nonce, HandshakeChallenge::new("relay.test").nonce);
+        
+        let proof = HandshakeProof::sign(&challenge, "relay.test", &binding, &signer).unwrap();
+        let wallet = challenge.verify_proof(&proof, &binding).unwrap();
+        assert_eq!(wallet, WalletAddress::new(signer.public.to_bytes()));
+        
+        // A captured proof is useless against a fresh challenge or another connection
+        let replayed = HandshakeChallenge::new("relay.test");
+        assert!(replayed.verify_proof(&proof, &binding).is_err());
+        assert!(challenge.verify_proof(&proof, &[0u8; HANDSHAKE_EXPORTER_LEN]).is_err());
+        
+        // ...or against another relay
+        let mut other_relay = challenge.clone();
+        other_relay.relay_id = "evil.test".to_string();
+        assert!(other_relay.verify_proof(&proof, &binding).is_err());
+        assert!(HandshakeProof::sign(&other_relay, "relay.test", &binding, &signer).is_err());
+        
+        let mut downgraded = proof.clone();
+        downgraded.version = "0.9.0".to_string();
+        assert!(challenge.verify_proof(&downgraded, &binding).is_err());
+        
+        let mut stale = challenge.clone();
+        stale.timestamp -= CHALLENGE_VALIDITY_SECS + 1;
+        assert!(stale.verify_proof(&proof, &binding).is_err());
+        
+        // Devices may be named, within limits
+        assert!(challenge.verify_proof(&proof.clone().with_device_id("pixel-8.work"), &binding).is_ok());
+        assert!(challenge.verify_proof(&proof.clone().with_device_id("phone:1"), &binding).is_err());
+        assert!(challenge.verify_proof(&proof.with_device_id("d".repeat(MAX_DEVICE_ID_LEN + 1)), &binding).is_err());
+    }
+    
+    #[test]
+    fn test_pong_echoes_ping() {
+        let ping = PingMessage::new(42);
+        let pong = PongMessage::reply_to(&ping);
+        
+        assert_eq!(pong.sequence, 42);
+        assert_eq!(pong.ping_timestamp, ping.timestamp);
+        assert!(pong.timestamp >= ping.timestamp);
+    }
+}
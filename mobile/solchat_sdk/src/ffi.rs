@@ -15,12 +15,15 @@ pub extern "C" fn solchat_sdk_version() -> *mut c_char {
 }
 
 /// Frees a C string that was allocated by the Rust library.
+///
+/// # Safety
+///
+/// `s` must be null or a pointer returned by this library that has not
+/// already been freed.
 #[no_mangle]
-pub extern "C" fn solchat_sdk_free_string(s: *mut c_char) {
+pub unsafe extern "C" fn solchat_sdk_free_string(s: *mut c_char) {
     if s.is_null() {
         return;
     }
-    unsafe {
-        let _ = CString::from_raw(s);
-    }
+    let _ = CString::from_raw(s);
 } 
\ No newline at end of file
@@ -1,4 +1,5 @@
 pub mod ffi;
+pub mod seed_vault;
 
 // This can be expanded with the actual SDK logic, for now it's a placeholder.
 pub struct SolChatSdk {}
@@ -11,4 +12,10 @@ impl SolChatSdk {
     pub fn version() -> String {
         "0.1.0".to_string()
     }
+}
+
+impl Default for SolChatSdk {
+    fn default() -> Self {
+        Self::new()
+    }
 } 
\ No newline at end of file
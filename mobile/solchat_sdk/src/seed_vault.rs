@@ -1,8 +1,7 @@
 use std::sync::Arc;
-use zeroize::{Zeroize, ZeroizeOnDrop};
+use zeroize::ZeroizeOnDrop;
 // Note: Using simplified types for MVP - production should use proper crypto libraries
 use sha2::{Sha256, Digest};
-use rand_core::OsRng;
 
 // Hardware security modules: where keys go to live their best encrypted lives 🔒
 
@@ -196,7 +195,7 @@ impl SeedVaultProvider for MockSeedVault {
         // Simplified signing: hash message with secret key
         let mut hasher = Sha256::new();
         hasher.update(b"mock-signature");
-        hasher.update(&self.ed25519_keypair.1); // secret key
+        hasher.update(self.ed25519_keypair.1); // secret key
         hasher.update(message);
         let hash = hasher.finalize();
         
@@ -218,7 +217,8 @@ impl SeedVaultProvider for MockSeedVault {
         ).map_err(|_| SeedVaultError::DerivationFailed)?;
 
         // Perform ECDH
-        let shared_secret = x25519_keypair.diffie_hellman(peer_public_key);
+        let shared_secret = x25519_keypair.diffie_hellman(peer_public_key)
+            .map_err(|_| SeedVaultError::DerivationFailed)?;
         
         Ok(SharedSecret::new(shared_secret))
     }
@@ -346,13 +346,8 @@ mod tests {
         let secret1 = vault1.derive_shared_secret(&x25519_keypair2.public).unwrap();
         let secret2 = vault2.derive_shared_secret(&x25519_keypair1.public).unwrap();
 
-        // Note: In simplified implementation, ECDH may not be symmetric
-        // For now, just verify that the function works deterministically
-        let secret1_again = vault1.derive_shared_secret(&x25519_keypair2.public).unwrap();
-        assert_eq!(secret1.as_bytes(), secret1_again.as_bytes());
-        
-        // TODO: Fix ECDH symmetry in production implementation
-        // assert_eq!(secret1.as_bytes(), secret2.as_bytes());
+        // Both sides must agree on the shared secret
+        assert_eq!(secret1.as_bytes(), secret2.as_bytes());
     }
 
     #[test]
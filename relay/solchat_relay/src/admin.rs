@@ -0,0 +1,256 @@
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use serde::Serialize;
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{error, info};
+use crate::federation::parse_public_key;
+use crate::router::MessageRouter;
+
+/// Operator API for inspecting and acting on the relay's state
+///
+/// Served next to `/metrics` under `/admin/`, authenticated with a bearer
+/// token:
+///
+/// - `GET /admin/stats`: router statistics
+/// - `GET /admin/connections`: connected wallets and devices
+/// - `GET /admin/queues`, `GET /admin/queues/{recipient}`: queue depths,
+///   where named devices' queues are listed as `wallet:device`
+/// - `DELETE /admin/queues/{recipient}`: purge a wallet's queues, or one
+///   device's
+/// - `POST /admin/wallets/{wallet}/disconnect`: close a wallet's connections
+/// - `GET /admin/wallets/{wallet}/devices`: devices the wallet has used
+/// - `DELETE /admin/wallets/{wallet}/devices/{device}`: forget a device,
+///   dropping its queue
+/// - `GET /admin/bans`: banned wallets
+/// - `PUT /admin/bans/{wallet}`, `DELETE /admin/bans/{wallet}`: ban a
+///   wallet, closing its connection, or lift the ban
+///
+/// Bans and purges only affect this relay process and bans are forgotten
+/// on restart.
+pub struct AdminApi {
+    router: Arc<MessageRouter>,
+    token: String,
+}
+
+impl AdminApi {
+    pub fn new(router: Arc<MessageRouter>, token: String) -> Self {
+        Self { router, token }
+    }
+
+    pub async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        if !self.is_authorized(&req) {
+            return Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header(header::WWW_AUTHENTICATE, "Bearer")
+                .body(Body::from("Unauthorized"))
+                .unwrap();
+        }
+
+        let path = req.uri().path().trim_end_matches('/').to_string();
+        let segments: Vec<&str> = path.split('/').skip(2).collect();
+        let method = req.method().clone();
+
+        match (method, segments.as_slice()) {
+            (Method::GET, ["stats"]) => json_response(StatusCode::OK, &self.router.get_stats().await),
+            (Method::GET, ["connections"]) => json_response(StatusCode::OK, &self.router.connections().await),
+            (Method::GET, ["queues"]) => json_response(StatusCode::OK, &self.router.queue_depths()),
+            (Method::GET, ["queues", wallet]) => json_response(StatusCode::OK, &self.router.queue_depth(wallet)),
+            (Method::DELETE, ["queues", wallet]) => match self.router.purge_queue(wallet).await {
+                Ok(purged) => {
+                    info!("🛠️ Admin purged the queue of {}", wallet);
+                    json_response(StatusCode::OK, &json!({ "purged": purged }))
+                }
+                Err(e) => {
+                    error!("Failed to purge the queue of {}: {:#}", wallet, e);
+                    error_response(StatusCode::INTERNAL_SERVER_ERROR, "failed to purge the queue")
+                }
+            },
+            (Method::POST, ["wallets", wallet, "disconnect"]) => {
+                let disconnected = self.router.disconnect(wallet).await;
+                json_response(StatusCode::OK, &json!({ "disconnected": disconnected }))
+            }
+            (Method::GET, ["wallets", wallet, "devices"]) => json_response(StatusCode::OK, &self.router.devices(wallet).await),
+            (Method::DELETE, ["wallets", wallet, "devices", device]) => match self.router.forget_device(wallet, device) {
+                Ok(dropped) => json_response(StatusCode::OK, &json!({ "dropped": dropped })),
+                Err(e) => {
+                    error!("Failed to forget device {} of {}: {:#}", device, wallet, e);
+                    error_response(StatusCode::INTERNAL_SERVER_ERROR, "failed to forget the device")
+                }
+            },
+            (Method::GET, ["bans"]) => json_response(StatusCode::OK, &self.router.banned()),
+            (Method::PUT, ["bans", wallet]) => {
+                if let Err(e) = parse_public_key(wallet) {
+                    return error_response(StatusCode::BAD_REQUEST, &format!("not a wallet address: {}", e));
+                }
+                self.router.ban(wallet).await;
+                json_response(StatusCode::OK, &json!({ "banned": true }))
+            }
+            (Method::DELETE, ["bans", wallet]) => {
+                let was_banned = self.router.unban(wallet);
+                info!("🛠️ Admin lifted the ban on {}", wallet);
+                json_response(StatusCode::OK, &json!({ "was_banned": was_banned }))
+            }
+            _ => error_response(StatusCode::NOT_FOUND, "no such admin endpoint"),
+        }
+    }
+
+    fn is_authorized(&self, req: &Request<Body>) -> bool {
+        req.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| constant_time_eq(token.as_bytes(), self.token.as_bytes()))
+    }
+}
+
+/// Compare secrets without revealing through timing how much matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn json_response(status: StatusCode, body: &impl Serialize) -> Response<Body> {
+    match serde_json::to_vec(body) {
+        Ok(body) => Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap(),
+        Err(e) => {
+            error!("Failed to serialize admin response: {}", e);
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from("Internal Server Error"))
+                .unwrap()
+        }
+    }
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    json_response(status, &json!({ "error": message }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Metrics;
+    use crate::router::RelayMessage;
+    use solchat_protocol::messages::ChatMessage;
+    use solchat_protocol::WalletAddress;
+    use tokio::sync::mpsc;
+
+    const TOKEN: &str = "0123456789abcdef";
+
+    fn request(method: Method, path: &str, token: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder().method(method).uri(path);
+        if let Some(token) = token {
+            builder = builder.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    async fn call(api: &AdminApi, method: Method, path: &str) -> (StatusCode, serde_json::Value) {
+        let response = api.handle(request(method, path, Some(TOKEN))).await;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn api() -> AdminApi {
+        let router = Arc::new(MessageRouter::new(Arc::new(Metrics::new())));
+        AdminApi::new(router, TOKEN.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_requests_need_the_token() {
+        let api = api();
+        for token in [None, Some("wrong-token-value"), Some("0123456789abcdef0")] {
+            let response = api.handle(request(Method::GET, "/admin/stats", token)).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        let (status, stats) = call(&api, Method::GET, "/admin/stats").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(stats["connected_clients"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_inspect_and_purge_queues() {
+        let api = api();
+        let alice = WalletAddress::test_address(1);
+        let bob = WalletAddress::test_address(2);
+        let (alice_tx, mut alice_rx) = mpsc::channel(10);
+        let addr = "10.0.0.1:4000".parse().unwrap();
+        api.router.register_client(alice.clone(), alice_tx, addr).await.unwrap();
+        for _ in 0..2 {
+            let chat = ChatMessage::new(&alice, &bob, b"hi".to_vec(), vec![]);
+            api.router.route_message(RelayMessage::Chat(chat), addr).await.unwrap();
+        }
+
+        let (_, connections) = call(&api, Method::GET, "/admin/connections").await;
+        assert_eq!(connections[0]["wallet"], alice.to_string());
+        assert_eq!(connections[0]["remote_addr"], "10.0.0.1:4000");
+
+        let (_, queues) = call(&api, Method::GET, "/admin/queues").await;
+        assert_eq!(queues[0]["recipient"], bob.to_string());
+        assert_eq!(queues[0]["messages"], 2);
+
+        let (_, purged) = call(&api, Method::DELETE, &format!("/admin/queues/{}", bob)).await;
+        assert_eq!(purged["purged"], 2);
+        let (_, depth) = call(&api, Method::GET, &format!("/admin/queues/{}", bob)).await;
+        assert_eq!(depth["messages"], 0);
+
+        // Alice learns that her messages were dropped
+        for _ in 0..2 {
+            assert!(matches!(alice_rx.recv().await.unwrap().message, RelayMessage::Ack(_)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_list_and_forget_devices() {
+        let api = api();
+        let alice = WalletAddress::test_address(1);
+        let bob = WalletAddress::test_address(2);
+        let addr = "10.0.0.1:4000".parse().unwrap();
+        let (phone_tx, _phone_rx) = mpsc::channel(10);
+        let (laptop_tx, _laptop_rx) = mpsc::channel(10);
+        api.router.register_device(bob.clone(), "phone", phone_tx.clone(), addr).await.unwrap();
+        api.router.register_device(bob.clone(), "laptop", laptop_tx, addr).await.unwrap();
+        api.router.unregister_client_channel(&bob, &phone_tx).await.unwrap();
+        let chat = ChatMessage::new(&alice, &bob, b"hi".to_vec(), vec![]);
+        api.router.route_message(RelayMessage::Chat(chat), addr).await.unwrap();
+
+        let (_, devices) = call(&api, Method::GET, &format!("/admin/wallets/{}/devices", bob)).await;
+        assert_eq!(devices[0]["device_id"], "laptop");
+        assert_eq!(devices[0]["online"], true);
+        assert_eq!(devices[1]["device_id"], "phone");
+        assert_eq!(devices[1]["queued"], 1);
+
+        let (_, forgotten) = call(&api, Method::DELETE, &format!("/admin/wallets/{}/devices/phone", bob)).await;
+        assert_eq!(forgotten["dropped"], 1);
+        let (_, devices) = call(&api, Method::GET, &format!("/admin/wallets/{}/devices", bob)).await;
+        assert_eq!(devices.as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_ban_closes_the_connection() {
+        let api = api();
+        let alice = WalletAddress::test_address(1);
+        let (alice_tx, mut alice_rx) = mpsc::channel(10);
+        api.router.register_client(alice.clone(), alice_tx, "10.0.0.1:4000".parse().unwrap()).await.unwrap();
+
+        let (status, _) = call(&api, Method::PUT, "/admin/bans/not-a-wallet").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = call(&api, Method::PUT, &format!("/admin/bans/{}", alice)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(api.router.is_banned(&alice.to_string()));
+        // The router dropped the connection's channel
+        assert!(alice_rx.recv().await.is_none());
+
+        let (_, bans) = call(&api, Method::GET, "/admin/bans").await;
+        assert_eq!(bans[0], alice.to_string());
+        let (_, lifted) = call(&api, Method::DELETE, &format!("/admin/bans/{}", alice)).await;
+        assert_eq!(lifted["was_banned"], true);
+        assert!(!api.router.is_banned(&alice.to_string()));
+    }
+}
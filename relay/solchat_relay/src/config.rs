@@ -0,0 +1,654 @@
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing_subscriber::EnvFilter;
+use crate::federation;
+use crate::queue_limits::{EvictionPolicy, QueueLimits};
+use crate::rate_limit::RateLimits;
+
+/// Shortest admin token accepted, so it cannot be guessed
+const MIN_ADMIN_TOKEN_LEN: usize = 16;
+
+/// Relay settings, read from a TOML file
+///
+/// Every setting has a default, so a file only needs the ones it changes.
+/// Unknown keys are rejected so that a typo is not silently ignored.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelayConfig {
+    pub listen: SocketAddr,
+    pub metrics_addr: SocketAddr,
+    pub devnet: bool,
+    /// Identity clients sign in their handshake proofs
+    pub relay_id: String,
+    /// `tracing` filter directive, e.g. `info` or `solchat_relay=debug`;
+    /// defaults to `RUST_LOG`, else `info`
+    pub log_level: String,
+    pub connection: ConnectionConfig,
+    pub queue: QueueConfig,
+    pub rate_limits: RateLimits,
+    pub tls: TlsConfig,
+    pub shutdown: ShutdownConfig,
+    pub federation: FederationConfig,
+    pub cluster: ClusterConfig,
+    pub admin: AdminConfig,
+    pub websocket: WebSocketConfig,
+    pub http_fallback: HttpFallbackConfig,
+}
+
+/// Per-connection timeouts
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionConfig {
+    /// Time allowed for a client to complete the handshake
+    pub handshake_timeout_secs: u64,
+    /// Time between keepalive pings sent to each client
+    pub keepalive_interval_secs: u64,
+    /// Close connections that have sent nothing for this long
+    pub idle_timeout_secs: u64,
+}
+
+/// Storage and limits for messages queued for offline recipients
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    pub path: PathBuf,
+    pub max_messages_per_recipient: usize,
+    pub max_bytes_per_recipient: usize,
+    pub max_total_bytes: usize,
+    pub policy: EvictionPolicy,
+    /// How often queued messages are checked for expiry
+    pub sweep_interval_secs: u64,
+}
+
+/// Certificate for the QUIC endpoint
+///
+/// Without a certificate the relay generates a self-signed one, which is
+/// only allowed on devnet.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain
+    pub cert: Option<PathBuf>,
+    /// PEM private key
+    pub key: Option<PathBuf>,
+    /// How often the files are checked for a renewed certificate
+    pub reload_interval_secs: u64,
+}
+
+/// Graceful shutdown on SIGTERM or SIGINT
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// Time clients get to leave after being told to go away, before the
+    /// remaining connections are closed; keep it below the orchestrator's
+    /// termination grace period
+    pub drain_timeout_secs: u64,
+}
+
+/// Links to other relays, so that wallets homed elsewhere can be reached
+///
+/// Federation is off unless `key` is set.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FederationConfig {
+    /// Solana-style JSON keypair file holding this relay's identity key
+    pub key: Option<PathBuf>,
+    /// How often missing links to peers are re-established
+    pub reconnect_interval_secs: u64,
+    pub peers: Vec<PeerConfig>,
+    /// Home relay of each wallet served elsewhere, by `relay_id`
+    pub directory: HashMap<String, String>,
+}
+
+/// Replicas of one relay sharing its `relay_id` behind a load balancer
+///
+/// Replicas record which of them each wallet is connected to in a shared
+/// presence store, announce themselves there, and link to each other with
+/// the relay's federation key to forward messages to the right replica.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterConfig {
+    /// Name of this replica, unique within the cluster; defaults to `relay_id`
+    pub node_id: Option<String>,
+    /// `host:port` the other replicas reach this one's QUIC listener on
+    pub address: Option<String>,
+    /// `memory` for a relay on its own, or the `redis://` URL of the store
+    /// shared by the replicas
+    pub presence: String,
+    /// How often this replica announces itself to the others
+    pub heartbeat_interval_secs: u64,
+}
+
+/// Operator API served next to `/metrics` under `/admin/`
+///
+/// The API is off unless a token is set; requests must carry it as a
+/// bearer token.
+#[derive(Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    pub token: Option<String>,
+}
+
+/// Keeps the token out of logged configs
+impl fmt::Debug for AdminConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AdminConfig")
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+/// Listener for browser clients, which cannot speak QUIC
+///
+/// WebSocket connections carry the same envelopes as QUIC, one per binary
+/// message, and go through the same handshake and limits. The listener is
+/// off unless `listen` is set.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSocketConfig {
+    pub listen: Option<SocketAddr>,
+    /// Serve `wss://` with the QUIC endpoint's certificate; turn off only
+    /// behind a proxy that terminates TLS
+    pub tls: bool,
+}
+
+/// HTTPS transport for networks that block UDP
+///
+/// Clients submit envelopes with POST, long-poll their queue with GET and
+/// acknowledge what they received, authenticating with a session token
+/// issued after the signed handshake. The listener is off unless `listen`
+/// is set.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpFallbackConfig {
+    pub listen: Option<SocketAddr>,
+    /// Serve HTTPS with the QUIC endpoint's certificate; turn off only
+    /// behind a proxy that terminates TLS
+    pub tls: bool,
+    /// Longest a poll waits for a message before returning empty
+    pub poll_timeout_secs: u64,
+    /// Sessions unused for this long are forgotten
+    pub session_timeout_secs: u64,
+}
+
+/// A relay this one exchanges messages with
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PeerConfig {
+    pub relay_id: String,
+    /// `host:port` of the peer's QUIC listener
+    pub address: String,
+    /// The peer's identity public key, base58 encoded
+    pub public_key: String,
+}
+
+impl RelayConfig {
+    /// Parse a config file's contents; the result still needs `validate`
+    pub fn from_toml(text: &str) -> Result<Self> {
+        toml::from_str(text).map_err(|e| anyhow!("{}", e))
+    }
+
+    /// Read and parse a config file
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        Self::from_toml(&text).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    /// Check that the settings are usable together
+    pub fn validate(&self) -> Result<()> {
+        ensure!(!self.relay_id.is_empty(), "relay_id must not be empty");
+        EnvFilter::try_new(&self.log_level)
+            .map_err(|e| anyhow!("log_level '{}' is not a valid filter: {}", self.log_level, e))?;
+
+        let connection = &self.connection;
+        ensure!(connection.handshake_timeout_secs > 0, "connection.handshake_timeout_secs must be positive");
+        ensure!(connection.keepalive_interval_secs > 0, "connection.keepalive_interval_secs must be positive");
+        ensure!(
+            connection.idle_timeout_secs >= connection.keepalive_interval_secs,
+            "connection.idle_timeout_secs must be at least connection.keepalive_interval_secs"
+        );
+
+        ensure!(self.queue.sweep_interval_secs > 0, "queue.sweep_interval_secs must be positive");
+        self.queue.limits().validate().map_err(|e| anyhow!("queue: {}", e))?;
+        self.rate_limits.validate().map_err(|e| anyhow!("rate_limits: {}", e))?;
+
+        match (&self.tls.cert, &self.tls.key) {
+            (Some(_), Some(_)) => {}
+            (None, None) => ensure!(
+                self.devnet,
+                "tls.cert and tls.key are required; self-signed certificates are only allowed with devnet"
+            ),
+            _ => bail!("tls.cert and tls.key must be given together"),
+        }
+        ensure!(self.tls.reload_interval_secs > 0, "tls.reload_interval_secs must be positive");
+        ensure!(self.shutdown.drain_timeout_secs > 0, "shutdown.drain_timeout_secs must be positive");
+        self.validate_federation()?;
+        self.validate_cluster()?;
+        if let Some(token) = &self.admin.token {
+            ensure!(token.len() >= MIN_ADMIN_TOKEN_LEN, "admin.token must be at least {} characters", MIN_ADMIN_TOKEN_LEN);
+        }
+        if let Some(listen) = self.websocket.listen {
+            ensure!(listen != self.metrics_addr, "websocket.listen must differ from metrics_addr");
+        }
+        self.validate_http_fallback()?;
+
+        Ok(())
+    }
+
+    /// Name of this replica on links to other relays and replicas
+    pub fn node_id(&self) -> &str {
+        self.cluster.node_id.as_deref().unwrap_or(&self.relay_id)
+    }
+
+    fn validate_http_fallback(&self) -> Result<()> {
+        let fallback = &self.http_fallback;
+        ensure!(fallback.poll_timeout_secs > 0, "http_fallback.poll_timeout_secs must be positive");
+        ensure!(
+            fallback.session_timeout_secs > fallback.poll_timeout_secs,
+            "http_fallback.session_timeout_secs must be longer than http_fallback.poll_timeout_secs"
+        );
+        if let Some(listen) = fallback.listen {
+            ensure!(
+                listen != self.metrics_addr && Some(listen) != self.websocket.listen,
+                "http_fallback.listen must differ from metrics_addr and websocket.listen"
+            );
+        }
+        Ok(())
+    }
+
+    fn validate_cluster(&self) -> Result<()> {
+        let cluster = &self.cluster;
+        ensure!(cluster.heartbeat_interval_secs > 0, "cluster.heartbeat_interval_secs must be positive");
+        ensure!(!self.node_id().is_empty(), "cluster.node_id must not be empty");
+        ensure!(
+            self.federation.peers.iter().all(|peer| peer.relay_id != self.node_id()),
+            "cluster.node_id must differ from every federation peer"
+        );
+
+        if cluster.is_clustered() {
+            ensure!(
+                cluster.presence.starts_with("redis://"),
+                "cluster.presence must be \"memory\" or a redis:// URL"
+            );
+            ensure!(
+                cluster.node_id.is_some() && cluster.address.is_some(),
+                "cluster.node_id and cluster.address are required with a shared presence store"
+            );
+            ensure!(self.federation.key.is_some(), "a shared presence store needs federation.key");
+        }
+        Ok(())
+    }
+
+    fn validate_federation(&self) -> Result<()> {
+        let federation = &self.federation;
+        if federation.key.is_none() {
+            ensure!(
+                federation.peers.is_empty() && federation.directory.is_empty(),
+                "federation.peers and federation.directory need federation.key"
+            );
+            return Ok(());
+        }
+        ensure!(federation.reconnect_interval_secs > 0, "federation.reconnect_interval_secs must be positive");
+
+        let mut peer_ids = HashSet::new();
+        for peer in &federation.peers {
+            ensure!(
+                !peer.relay_id.is_empty() && peer.relay_id != self.relay_id && peer.relay_id != self.node_id(),
+                "federation peer relay_id '{}' must be set and differ from relay_id",
+                peer.relay_id
+            );
+            ensure!(peer_ids.insert(peer.relay_id.as_str()), "federation peer {} is listed twice", peer.relay_id);
+            ensure!(!peer.address.is_empty(), "federation peer {} has no address", peer.relay_id);
+            federation::parse_public_key(&peer.public_key)
+                .map_err(|e| anyhow!("federation peer {}: {}", peer.relay_id, e))?;
+        }
+
+        for (wallet, relay_id) in &federation.directory {
+            ensure!(
+                *relay_id == self.relay_id || peer_ids.contains(relay_id.as_str()),
+                "federation.directory maps {} to unknown relay {}",
+                wallet,
+                relay_id
+            );
+        }
+        Ok(())
+    }
+
+    /// Settings that differ from `other` but only take effect on restart
+    pub fn restart_required(&self, other: &RelayConfig) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.listen != other.listen {
+            changed.push("listen");
+        }
+        if self.metrics_addr != other.metrics_addr {
+            changed.push("metrics_addr");
+        }
+        if self.devnet != other.devnet {
+            changed.push("devnet");
+        }
+        if self.relay_id != other.relay_id {
+            changed.push("relay_id");
+        }
+        if self.connection != other.connection {
+            changed.push("connection");
+        }
+        if self.queue.path != other.queue.path {
+            changed.push("queue.path");
+        }
+        if self.queue.sweep_interval_secs != other.queue.sweep_interval_secs {
+            changed.push("queue.sweep_interval_secs");
+        }
+        if self.tls != other.tls {
+            changed.push("tls");
+        }
+        if self.shutdown != other.shutdown {
+            changed.push("shutdown");
+        }
+        if self.federation != other.federation {
+            changed.push("federation");
+        }
+        if self.cluster != other.cluster {
+            changed.push("cluster");
+        }
+        if self.admin != other.admin {
+            changed.push("admin");
+        }
+        if self.websocket != other.websocket {
+            changed.push("websocket");
+        }
+        if self.http_fallback != other.http_fallback {
+            changed.push("http_fallback");
+        }
+        changed
+    }
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            listen: "0.0.0.0:4433".parse().unwrap(),
+            metrics_addr: "0.0.0.0:8080".parse().unwrap(),
+            devnet: false,
+            relay_id: "localhost".to_string(),
+            log_level: std::env::var("RUST_LOG").unwrap_or_else(|_| "info".to_string()),
+            connection: ConnectionConfig::default(),
+            queue: QueueConfig::default(),
+            rate_limits: RateLimits::default(),
+            tls: TlsConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            federation: FederationConfig::default(),
+            cluster: ClusterConfig::default(),
+            admin: AdminConfig::default(),
+            websocket: WebSocketConfig::default(),
+            http_fallback: HttpFallbackConfig::default(),
+        }
+    }
+}
+
+impl ConnectionConfig {
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.handshake_timeout_secs)
+    }
+
+    pub fn keepalive_interval(&self) -> Duration {
+        Duration::from_secs(self.keepalive_interval_secs)
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            handshake_timeout_secs: 10,
+            keepalive_interval_secs: 15,
+            idle_timeout_secs: 60,
+        }
+    }
+}
+
+impl QueueConfig {
+    pub fn limits(&self) -> QueueLimits {
+        QueueLimits {
+            max_messages_per_recipient: self.max_messages_per_recipient,
+            max_bytes_per_recipient: self.max_bytes_per_recipient,
+            max_total_bytes: self.max_total_bytes,
+            policy: self.policy,
+        }
+    }
+
+    pub fn sweep_interval(&self) -> Duration {
+        Duration::from_secs(self.sweep_interval_secs)
+    }
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        let limits = QueueLimits::default();
+        Self {
+            path: PathBuf::from("solchat_queue.log"),
+            max_messages_per_recipient: limits.max_messages_per_recipient,
+            max_bytes_per_recipient: limits.max_bytes_per_recipient,
+            max_total_bytes: limits.max_total_bytes,
+            policy: limits.policy,
+            sweep_interval_secs: 30,
+        }
+    }
+}
+
+impl TlsConfig {
+    pub fn reload_interval(&self) -> Duration {
+        Duration::from_secs(self.reload_interval_secs)
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cert: None,
+            key: None,
+            reload_interval_secs: 60,
+        }
+    }
+}
+
+impl ShutdownConfig {
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.drain_timeout_secs)
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self { drain_timeout_secs: 25 }
+    }
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self { listen: None, tls: true }
+    }
+}
+
+impl HttpFallbackConfig {
+    pub fn poll_timeout(&self) -> Duration {
+        Duration::from_secs(self.poll_timeout_secs)
+    }
+
+    pub fn session_timeout(&self) -> Duration {
+        Duration::from_secs(self.session_timeout_secs)
+    }
+}
+
+impl Default for HttpFallbackConfig {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            tls: true,
+            poll_timeout_secs: 25,
+            session_timeout_secs: 300,
+        }
+    }
+}
+
+impl FederationConfig {
+    pub fn reconnect_interval(&self) -> Duration {
+        Duration::from_secs(self.reconnect_interval_secs)
+    }
+}
+
+impl Default for FederationConfig {
+    fn default() -> Self {
+        Self {
+            key: None,
+            reconnect_interval_secs: 5,
+            peers: Vec::new(),
+            directory: HashMap::new(),
+        }
+    }
+}
+
+impl ClusterConfig {
+    /// Whether replicas share presence, rather than this relay running alone
+    pub fn is_clustered(&self) -> bool {
+        self.presence != "memory"
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_interval_secs)
+    }
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        Self {
+            node_id: None,
+            address: None,
+            presence: "memory".to_string(),
+            heartbeat_interval_secs: 10,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_example_config_matches_defaults() {
+        let config = RelayConfig::from_toml(include_str!("../relay.example.toml")).unwrap();
+        assert_eq!(config, RelayConfig::default());
+
+        // Outside devnet the relay refuses to run without a real certificate
+        let error = config.validate().unwrap_err();
+        assert!(error.to_string().contains("tls.cert"));
+    }
+
+    #[test]
+    fn test_partial_config_keeps_other_defaults() {
+        let config = RelayConfig::from_toml(
+            r#"
+            relay_id = "relay.example.org"
+
+            [tls]
+            cert = "/etc/solconnect/tls/tls.crt"
+            key = "/etc/solconnect/tls/tls.key"
+
+            [queue]
+            policy = "reject-new"
+
+            [rate_limits.per_ip]
+            per_second = 5.0
+            burst = 10
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.relay_id, "relay.example.org");
+        assert_eq!(config.queue.policy, EvictionPolicy::RejectNew);
+        assert_eq!(config.queue.path, QueueConfig::default().path);
+        assert_eq!(config.rate_limits.per_ip.burst, 10);
+        assert_eq!(config.rate_limits.per_wallet, RateLimits::default().per_wallet);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_invalid_configs_are_rejected() {
+        // Typos and unknown values fail to parse, naming the culprit
+        let error = RelayConfig::from_toml("[queue]\nmax_mesages_per_recipient = 5").unwrap_err();
+        assert!(error.to_string().contains("max_mesages_per_recipient"));
+        assert!(RelayConfig::from_toml("[queue]\npolicy = \"drop-newest\"").is_err());
+
+        // Values that parse but cannot work together fail validation
+        let config = RelayConfig::from_toml("devnet = true\n[connection]\nkeepalive_interval_secs = 90").unwrap();
+        let error = config.validate().unwrap_err();
+        assert!(error.to_string().contains("idle_timeout_secs"));
+
+        let config = RelayConfig::from_toml("devnet = true\nlog_level = \"info,[\"").unwrap();
+        assert!(config.validate().is_err());
+
+        let config = RelayConfig::from_toml("[tls]\ncert = \"relay.crt\"").unwrap();
+        let error = config.validate().unwrap_err();
+        assert!(error.to_string().contains("together"));
+
+        let config = RelayConfig::from_toml(
+            r#"
+            devnet = true
+
+            [federation]
+            key = "relay-key.json"
+
+            [[federation.peers]]
+            relay_id = "relay-b"
+            address = "relay-b:4433"
+            public_key = "not base58!"
+            "#,
+        )
+        .unwrap();
+        let error = config.validate().unwrap_err();
+        assert!(error.to_string().contains("relay-b"));
+
+        let config = RelayConfig::from_toml("devnet = true\n[federation.directory]\nwallet = \"relay-z\"").unwrap();
+        let error = config.validate().unwrap_err();
+        assert!(error.to_string().contains("federation.key"));
+
+        let config = RelayConfig::from_toml("devnet = true\n[cluster]\npresence = \"redis://redis:6379\"").unwrap();
+        let error = config.validate().unwrap_err();
+        assert!(error.to_string().contains("cluster.node_id"));
+
+        let config = RelayConfig::from_toml("devnet = true\n[admin]\ntoken = \"secret\"").unwrap();
+        let error = config.validate().unwrap_err();
+        assert!(error.to_string().contains("admin.token"));
+        assert!(!format!("{:?}", config).contains("secret"));
+
+        let config = RelayConfig::from_toml("devnet = true\n[websocket]\nlisten = \"0.0.0.0:8080\"").unwrap();
+        let error = config.validate().unwrap_err();
+        assert!(error.to_string().contains("websocket.listen"));
+
+        let config = RelayConfig::from_toml("devnet = true\n[http_fallback]\nsession_timeout_secs = 10").unwrap();
+        let error = config.validate().unwrap_err();
+        assert!(error.to_string().contains("session_timeout_secs"));
+    }
+
+    #[test]
+    fn test_restart_required_ignores_reloadable_settings() {
+        let old = RelayConfig::default();
+        let mut new = old.clone();
+        new.log_level = "debug".to_string();
+        new.queue.policy = EvictionPolicy::DropOldest;
+        new.rate_limits.per_wallet.burst = 5;
+        assert!(new.restart_required(&old).is_empty());
+
+        new.listen = "0.0.0.0:5000".parse().unwrap();
+        new.queue.path = PathBuf::from("other.log");
+        assert_eq!(new.restart_required(&old), vec!["listen", "queue.path"]);
+    }
+}
@@ -0,0 +1,873 @@
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey};
+use quinn::{Connection, Endpoint};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ServerName};
+use serde::Serialize;
+use solchat_protocol::codec::{self, FrameDecoder};
+use prost::Message as _;
+use solchat_protocol::messages::{envelope, AckStatus, HandshakeChallenge, HandshakeProof, HandshakeResponse};
+use solchat_protocol::messages::{QueueHandoff, QueueHandoffRequest};
+use solchat_protocol::WalletAddress;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, error, info, warn};
+use crate::config::RelayConfig;
+use crate::metrics::Metrics;
+use crate::presence::{self, NodeInfo, PresenceStore};
+use crate::router::{MessageRouter, RelayMessage, RouteOutcome};
+use crate::tls::channel_binding;
+
+/// ALPN identifier of relay-to-relay links
+pub const FEDERATION_ALPN: &[u8] = b"solconnect-relay/1";
+
+/// Time allowed for the mutual handshake on a new link
+const LINK_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest handshake message or acknowledgement read from a link
+const MAX_LINK_CONTROL_SIZE: usize = 4096;
+
+/// Largest batch of queued messages one replica hands another at once
+const MAX_HANDOFF_SIZE: usize = 16 * codec::MAX_FRAME_SIZE;
+
+/// Time allowed for a replica to hand over one batch of queued messages
+const HANDOFF_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Another relay this one exchanges messages with
+#[derive(Clone, Debug)]
+pub struct Peer {
+    pub relay_id: String,
+    /// `host:port` of the peer's QUIC listener
+    pub address: String,
+    pub public_key: WalletAddress,
+}
+
+/// Whether a configured peer or known replica is linked
+#[derive(Clone, Debug, Serialize)]
+pub struct LinkStatus {
+    pub relay_id: String,
+    pub address: String,
+    pub linked: bool,
+}
+
+/// Links to other relays and the directory of which relay serves a wallet
+///
+/// Links are QUIC connections on the relay's own endpoint, told apart from
+/// client connections by ALPN. Both ends prove their identity key with the
+/// same challenge-response used for clients, bound to the TLS session, so
+/// relays need no certificates trusted by each other. Each forwarded message
+/// travels on its own bidirectional stream and is answered with the ack the
+/// receiving relay's router produced for it.
+///
+/// Replicas of a clustered relay link the same way, as peers that hold the
+/// relay's own key and are found through the shared presence store.
+pub struct Federation {
+    /// Name of this relay on links; its node id when clustered
+    relay_id: String,
+    keypair: Keypair,
+    peers: HashMap<String, Peer>,
+    directory: HashMap<String, String>,
+    endpoint: Endpoint,
+    links: Mutex<HashMap<String, Connection>>,
+    cluster: Option<Cluster>,
+    /// Other replicas of this relay, as last announced in the presence store
+    nodes: RwLock<HashMap<String, Peer>>,
+    metrics: Arc<Metrics>,
+}
+
+/// Membership in a cluster of replicas
+struct Cluster {
+    presence: Arc<dyn PresenceStore>,
+    /// Address the other replicas reach this one on
+    address: String,
+    heartbeat_interval: Duration,
+}
+
+impl Federation {
+    pub fn new(
+        relay_id: String,
+        keypair: Keypair,
+        peers: Vec<Peer>,
+        directory: HashMap<String, String>,
+        endpoint: Endpoint,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            relay_id,
+            keypair,
+            peers: peers.into_iter().map(|peer| (peer.relay_id.clone(), peer)).collect(),
+            directory,
+            endpoint,
+            links: Mutex::new(HashMap::new()),
+            cluster: None,
+            nodes: RwLock::new(HashMap::new()),
+            metrics,
+        }
+    }
+
+    /// Also link with the replicas announced in `presence`, announcing this
+    /// one as reachable at `address` every `heartbeat_interval`
+    pub fn with_cluster(mut self, presence: Arc<dyn PresenceStore>, address: String, heartbeat_interval: Duration) -> Self {
+        self.cluster = Some(Cluster {
+            presence,
+            address,
+            heartbeat_interval,
+        });
+        self
+    }
+
+    /// Federation as configured, or `None` if it is disabled
+    pub fn from_config(config: &RelayConfig, endpoint: Endpoint, metrics: Arc<Metrics>) -> Result<Option<Self>> {
+        let Some(key_path) = &config.federation.key else {
+            return Ok(None);
+        };
+
+        let keypair = load_relay_key(key_path)?;
+        let mut peers = Vec::new();
+        for peer in &config.federation.peers {
+            peers.push(Peer {
+                relay_id: peer.relay_id.clone(),
+                address: peer.address.clone(),
+                public_key: parse_public_key(&peer.public_key).map_err(|e| anyhow!(e))?,
+            });
+        }
+
+        // Wallets homed on this relay may be on any of its replicas
+        let directory = config
+            .federation
+            .directory
+            .iter()
+            .filter(|(_, relay_id)| **relay_id != config.relay_id)
+            .map(|(wallet, relay_id)| (wallet.clone(), relay_id.clone()))
+            .collect();
+
+        Ok(Some(Self::new(
+            config.node_id().to_string(),
+            keypair,
+            peers,
+            directory,
+            endpoint,
+            metrics,
+        )))
+    }
+
+    /// Name of this relay on links; its node id when clustered
+    pub fn relay_id(&self) -> &str {
+        &self.relay_id
+    }
+
+    /// This relay's identity public key, as peers list it
+    pub fn public_key(&self) -> WalletAddress {
+        WalletAddress::new(self.keypair.public.to_bytes())
+    }
+
+    /// Relay a wallet is homed on, if that is another relay
+    pub fn home_relay(&self, wallet: &str) -> Option<&str> {
+        self.directory
+            .get(wallet)
+            .map(String::as_str)
+            .filter(|relay_id| *relay_id != self.relay_id)
+    }
+
+    /// Hand a message to another relay's router, returning its outcome
+    ///
+    /// If the relay cannot be reached the outcome is `Failed`, so the
+    /// sender knows to retry.
+    pub async fn forward(&self, relay_id: &str, message: &RelayMessage) -> RouteOutcome {
+        match self.try_forward(relay_id, message).await {
+            Ok(outcome) => {
+                debug!("🌐 Forwarded {} to relay {}", message.kind(), relay_id);
+                self.metrics.record_message_forwarded(relay_id, "ok");
+                outcome
+            }
+            Err(e) => {
+                warn!("Failed to forward {} to relay {}: {:#}", message.kind(), relay_id, e);
+                self.metrics.record_message_forwarded(relay_id, "failed");
+                RouteOutcome::with_reason(AckStatus::Failed, "recipient's relay is unreachable")
+            }
+        }
+    }
+
+    async fn try_forward(&self, relay_id: &str, message: &RelayMessage) -> Result<RouteOutcome> {
+        let link = self.link(relay_id).ok_or_else(|| anyhow!("No link to relay {}", relay_id))?;
+
+        let (mut send, mut recv) = link.open_bi().await?;
+        send.write_all(&codec::encode_frame(&message.to_envelope())).await?;
+        send.finish().await?;
+
+        let reply = recv.read_to_end(MAX_LINK_CONTROL_SIZE).await?;
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&reply);
+        match decoder.next_frame()?.and_then(|envelope| envelope.body) {
+            Some(envelope::Body::Ack(ack)) => Ok(RouteOutcome {
+                status: ack.status(),
+                reason: ack.reason,
+            }),
+            _ => bail!("Relay {} did not acknowledge the message", relay_id),
+        }
+    }
+
+    /// Other replicas of this relay that are linked
+    pub fn replicas(&self) -> Vec<String> {
+        self.nodes
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|relay_id| self.is_linked(relay_id))
+            .cloned()
+            .collect()
+    }
+
+    /// Take a batch of the messages a replica queued for a wallet, which
+    /// the replica then drops, along with the replica's address
+    ///
+    /// An empty batch means the replica has nothing left for the wallet.
+    pub async fn request_queue(&self, relay_id: &str, wallet: &str) -> Result<(QueueHandoff, SocketAddr)> {
+        let link = self.link(relay_id).ok_or_else(|| anyhow!("No link to replica {}", relay_id))?;
+        let request = QueueHandoffRequest {
+            wallet_address: wallet.to_string(),
+        };
+
+        let reply = tokio::time::timeout(HANDOFF_TIMEOUT, async {
+            let (mut send, mut recv) = link.open_bi().await?;
+            send.write_all(&codec::encode_frame(&request.into())).await?;
+            send.finish().await?;
+            Ok::<_, anyhow::Error>(recv.read_to_end(MAX_HANDOFF_SIZE).await?)
+        })
+        .await
+        .context("Queue handoff timed out")??;
+
+        Ok((QueueHandoff::decode(&reply[..])?, link.remote_address()))
+    }
+
+    /// Whether a linked relay is another replica of this one
+    fn is_replica(&self, relay_id: &str) -> bool {
+        self.nodes.read().unwrap().contains_key(relay_id)
+    }
+
+    /// Whether there is an open link to a relay or replica
+    pub fn is_linked(&self, relay_id: &str) -> bool {
+        self.link(relay_id).is_some()
+    }
+
+    /// Link state of every configured peer and known replica
+    pub fn link_status(&self) -> Vec<LinkStatus> {
+        let nodes = self.nodes.read().unwrap();
+        let mut status: Vec<LinkStatus> = self
+            .peers
+            .values()
+            .chain(nodes.values())
+            .map(|peer| LinkStatus {
+                relay_id: peer.relay_id.clone(),
+                address: peer.address.clone(),
+                linked: self.is_linked(&peer.relay_id),
+            })
+            .collect();
+        status.sort_by(|a, b| a.relay_id.cmp(&b.relay_id));
+        status
+    }
+
+    /// Open link to a relay, if there is one
+    fn link(&self, relay_id: &str) -> Option<Connection> {
+        self.links
+            .lock()
+            .unwrap()
+            .get(relay_id)
+            .filter(|connection| connection.close_reason().is_none())
+            .cloned()
+    }
+
+    fn add_link(&self, relay_id: &str, connection: Connection) {
+        let mut links = self.links.lock().unwrap();
+        if let Some(previous) = links.insert(relay_id.to_string(), connection) {
+            previous.close(0u32.into(), b"replaced by a new link");
+        }
+        self.metrics.set_federation_links(links.len() as i64);
+    }
+
+    /// Forget a link, unless it has already been replaced by a newer one
+    fn remove_link(&self, relay_id: &str, connection: &Connection) {
+        let mut links = self.links.lock().unwrap();
+        if links.get(relay_id).map(Connection::stable_id) == Some(connection.stable_id()) {
+            links.remove(relay_id);
+        }
+        self.metrics.set_federation_links(links.len() as i64);
+    }
+
+    /// Of each pair of relays, the one whose id sorts first dials the
+    /// other, so that a pair only ever has one link
+    fn dials(&self, peer: &Peer) -> bool {
+        self.relay_id < peer.relay_id
+    }
+
+    /// Configured peer or known replica with the given id
+    fn peer(&self, relay_id: &str) -> Option<Peer> {
+        self.peers
+            .get(relay_id)
+            .or(self.nodes.read().unwrap().get(relay_id))
+            .cloned()
+    }
+
+    /// Announce this replica and pick up the ones the others announced
+    async fn refresh_cluster(&self) {
+        let Some(cluster) = &self.cluster else {
+            return;
+        };
+
+        let node = NodeInfo {
+            node_id: self.relay_id.clone(),
+            address: cluster.address.clone(),
+        };
+        if let Err(e) = presence::run(&cluster.presence, move |presence| presence.heartbeat(&node)).await {
+            warn!("Failed to announce this node to the cluster: {:#}", e);
+        }
+
+        // Replicas that missed a few heartbeats are presumed gone
+        let max_age = cluster.heartbeat_interval * 3;
+        match presence::run(&cluster.presence, move |presence| presence.nodes(max_age)).await {
+            Ok(nodes) => {
+                let public_key = self.public_key();
+                *self.nodes.write().unwrap() = nodes
+                    .into_iter()
+                    .filter(|node| node.node_id != self.relay_id)
+                    .map(|node| {
+                        let peer = Peer {
+                            relay_id: node.node_id.clone(),
+                            address: node.address,
+                            public_key: public_key.clone(),
+                        };
+                        (node.node_id, peer)
+                    })
+                    .collect();
+            }
+            Err(e) => warn!("Failed to list the cluster's nodes: {:#}", e),
+        }
+    }
+
+    /// Withdraw this replica from the cluster as it shuts down
+    pub async fn leave_cluster(&self) {
+        if let Some(cluster) = &self.cluster {
+            let node_id = self.relay_id.clone();
+            if let Err(e) = presence::run(&cluster.presence, move |presence| presence.leave(&node_id)).await {
+                warn!("Failed to leave the cluster: {:#}", e);
+            }
+        }
+    }
+
+    /// Keep links to the peers this relay dials, reconnecting every
+    /// `interval` while one is down
+    pub fn start(self: Arc<Self>, router: Arc<MessageRouter>, interval: Duration) {
+        if let Some(cluster) = &self.cluster {
+            let federation = self.clone();
+            let heartbeat_interval = cluster.heartbeat_interval;
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(heartbeat_interval);
+                loop {
+                    ticker.tick().await;
+                    federation.refresh_cluster().await;
+                }
+            });
+        }
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+
+            loop {
+                ticker.tick().await;
+                let nodes: Vec<Peer> = self.nodes.read().unwrap().values().cloned().collect();
+                for peer in self.peers.values().chain(&nodes).filter(|peer| self.dials(peer)) {
+                    if self.link(&peer.relay_id).is_some() {
+                        continue;
+                    }
+                    match self.connect(peer).await {
+                        Ok(connection) => {
+                            tokio::spawn(self.clone().serve_link(peer.relay_id.clone(), connection, router.clone()));
+                        }
+                        Err(e) => warn!("Failed to link with relay {}: {:#}", peer.relay_id, e),
+                    }
+                }
+            }
+        });
+    }
+
+    /// Dial a peer and authenticate the link
+    async fn connect(&self, peer: &Peer) -> Result<Connection> {
+        let address = tokio::net::lookup_host(peer.address.as_str())
+            .await?
+            .next()
+            .ok_or_else(|| anyhow!("{} did not resolve", peer.address))?;
+
+        let connection = self
+            .endpoint
+            .connect_with(client_config(), address, &peer.relay_id)?
+            .await?;
+        tokio::time::timeout(LINK_HANDSHAKE_TIMEOUT, self.authenticate(&connection, Some(&peer.relay_id)))
+            .await
+            .context("Link handshake timed out")??;
+
+        Ok(connection)
+    }
+
+    /// Serve a link another relay opened to this one
+    pub async fn accept(self: Arc<Self>, connection: Connection, router: Arc<MessageRouter>) {
+        let authenticated = tokio::time::timeout(LINK_HANDSHAKE_TIMEOUT, self.authenticate(&connection, None)).await;
+        let relay_id = match authenticated {
+            Ok(Ok(relay_id)) => relay_id,
+            Ok(Err(e)) => {
+                warn!("Rejected relay link from {}: {:#}", connection.remote_address(), e);
+                connection.close(1u32.into(), b"relay authentication failed");
+                return;
+            }
+            Err(_) => {
+                warn!("Relay link from {} timed out in the handshake", connection.remote_address());
+                connection.close(1u32.into(), b"handshake timeout");
+                return;
+            }
+        };
+
+        self.serve_link(relay_id, connection, router).await;
+    }
+
+    /// Route messages the peer forwards until the link closes
+    async fn serve_link(self: Arc<Self>, relay_id: String, connection: Connection, router: Arc<MessageRouter>) {
+        self.add_link(&relay_id, connection.clone());
+        info!("🌐 Linked with relay {} at {}", relay_id, connection.remote_address());
+
+        let remote_addr = connection.remote_address();
+        while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+            let federation = self.clone();
+            let router = router.clone();
+            let relay_id = relay_id.clone();
+            tokio::spawn(async move {
+                if let Err(e) = federation.handle_stream(&mut send, &mut recv, &relay_id, remote_addr, &router).await {
+                    error!("Forwarded stream from relay {} failed: {:#}", relay_id, e);
+                }
+            });
+        }
+
+        self.remove_link(&relay_id, &connection);
+        info!("🌐 Link with relay {} closed", relay_id);
+    }
+
+    /// Answer one stream the peer opened: a forwarded message, or a
+    /// replica's request for queued messages
+    async fn handle_stream(
+        &self,
+        send: &mut quinn::SendStream,
+        recv: &mut quinn::RecvStream,
+        relay_id: &str,
+        remote_addr: SocketAddr,
+        router: &MessageRouter,
+    ) -> Result<()> {
+        let data = recv.read_to_end(codec::FRAME_HEADER_LEN + codec::MAX_FRAME_SIZE).await?;
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&data);
+        let envelope = decoder.next_frame()?.ok_or_else(|| anyhow!("Empty forwarded stream"))?;
+
+        if let Some(envelope::Body::QueueHandoffRequest(request)) = envelope.body {
+            // Other relays' queues are only ever handed to replicas
+            ensure!(self.is_replica(relay_id), "Relay {} asked for a queue but is not a replica", relay_id);
+            let handoff = router.queued_for_handoff(&request.wallet_address, MAX_HANDOFF_SIZE).await;
+            send.write_all(&handoff.encode_to_vec()).await?;
+            send.finish().await?;
+            router.remove_handed_off(&request.wallet_address, &handoff)?;
+            if !handoff.messages.is_empty() {
+                info!("📦 Handed {} queued messages for {} to replica {}", handoff.messages.len(), request.wallet_address, relay_id);
+            }
+            return Ok(());
+        }
+
+        let message = RelayMessage::from_envelope(envelope).ok_or_else(|| anyhow!("Nothing to route"))?;
+        handle_forwarded(send, message, relay_id, remote_addr, router).await
+    }
+
+    /// Mutual challenge-response over a new link, returning the peer's id
+    ///
+    /// Each side sends a challenge, answers the other's with a proof signed
+    /// by its identity key, checks the proof it received against the key
+    /// configured for that peer, and reports the result. `expected` is the
+    /// relay that was dialed; on accepted links any configured peer may
+    /// answer.
+    async fn authenticate(&self, connection: &Connection, expected: Option<&str>) -> Result<String> {
+        let binding = channel_binding(connection)?;
+        let challenge = HandshakeChallenge::new(&self.relay_id);
+        write_message(connection, &challenge).await?;
+
+        let peer_challenge: HandshakeChallenge = read_message(connection).await?;
+        // A replica that just joined may not have been picked up yet
+        let mut known = self.peer(&peer_challenge.relay_id);
+        if known.is_none() {
+            self.refresh_cluster().await;
+            known = self.peer(&peer_challenge.relay_id);
+        }
+        let peer = match known {
+            Some(peer) if expected.is_none_or(|expected| expected == peer.relay_id) => peer,
+            _ => bail!("Unexpected relay {} on the link", peer_challenge.relay_id),
+        };
+        let proof = HandshakeProof::sign(&peer_challenge, &peer.relay_id, &binding, &self.keypair)
+            .map_err(|e| anyhow!(e))?;
+        write_message(connection, &proof).await?;
+
+        let peer_proof: HandshakeProof = read_message(connection).await?;
+        let result = challenge
+            .verify_proof(&peer_proof, &binding)
+            .map_err(|e| anyhow!(e))
+            .and_then(|key| {
+                ensure!(key == peer.public_key, "Relay {} presented an unexpected key", peer.relay_id);
+                Ok(())
+            });
+        let response = match &result {
+            Ok(()) => HandshakeResponse::success(),
+            Err(e) => HandshakeResponse::failure(e.to_string()),
+        };
+        write_message(connection, &response).await?;
+        result?;
+
+        let peer_response: HandshakeResponse = read_message(connection).await?;
+        if !peer_response.success {
+            bail!(
+                "Relay {} refused the link: {}",
+                peer.relay_id,
+                peer_response.error.unwrap_or_default()
+            );
+        }
+
+        Ok(peer.relay_id.clone())
+    }
+}
+
+/// Whether a connection is a relay link rather than a client
+pub fn is_relay_link(connection: &Connection) -> bool {
+    connection
+        .handshake_data()
+        .and_then(|data| data.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+        .and_then(|data| data.protocol)
+        .is_some_and(|protocol| protocol == FEDERATION_ALPN)
+}
+
+/// Parse a base58 Ed25519 public key, as used for wallets and relay keys
+pub fn parse_public_key(key: &str) -> Result<WalletAddress, String> {
+    let bytes = bs58::decode(key)
+        .into_vec()
+        .map_err(|e| format!("invalid public key: {}", e))?;
+    let bytes: [u8; 32] = bytes
+        .try_into()
+        .map_err(|_| "public key must be 32 bytes".to_string())?;
+    Ok(WalletAddress::new(bytes))
+}
+
+/// Read a relay identity key from a Solana-style JSON keypair file, as
+/// written by `solana-keygen new`
+pub fn load_relay_key(path: &Path) -> Result<Keypair> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read relay key {}", path.display()))?;
+    let bytes: Vec<u8> = serde_json::from_str(&text)
+        .with_context(|| format!("Relay key {} is not a JSON keypair", path.display()))?;
+    ensure!(bytes.len() == 64, "Relay key {} must hold 64 bytes", path.display());
+
+    let secret = SecretKey::from_bytes(&bytes[..32]).map_err(|e| anyhow!("Invalid relay key: {}", e))?;
+    let public = PublicKey::from(&secret);
+    ensure!(
+        public.as_bytes()[..] == bytes[32..],
+        "Relay key {} holds a public key that does not match its secret key",
+        path.display()
+    );
+    Ok(Keypair { secret, public })
+}
+
+/// Route one forwarded message and answer with the resulting ack
+async fn handle_forwarded(
+    send: &mut quinn::SendStream,
+    message: RelayMessage,
+    relay_id: &str,
+    remote_addr: SocketAddr,
+    router: &MessageRouter,
+) -> Result<()> {
+    let message_id = message.message_id().unwrap_or_default().to_string();
+    let outcome = match router.route_forwarded(message, relay_id, remote_addr).await {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            error!("Failed to route message from relay {}: {}", relay_id, e);
+            RouteOutcome::with_reason(AckStatus::Failed, "internal error")
+        }
+    };
+
+    send.write_all(&codec::encode_frame(&outcome.to_ack(&message_id).into())).await?;
+    send.finish().await?;
+    Ok(())
+}
+
+async fn write_message(connection: &Connection, message: &impl prost::Message) -> Result<()> {
+    let mut stream = connection.open_uni().await?;
+    stream.write_all(&message.encode_to_vec()).await?;
+    stream.finish().await?;
+    Ok(())
+}
+
+/// Read the next handshake message; each one arrives on its own stream
+async fn read_message<M: prost::Message + Default>(connection: &Connection) -> Result<M> {
+    let mut stream = connection.accept_uni().await?;
+    let data = stream.read_to_end(MAX_LINK_CONTROL_SIZE).await?;
+    Ok(M::decode(&data[..])?)
+}
+
+/// TLS settings for dialing peers
+///
+/// Peer certificates are not checked: relays authenticate each other by
+/// identity key in the link handshake, which is bound to the TLS session,
+/// so a peer may use any certificate, including a self-signed one.
+fn client_config() -> quinn::ClientConfig {
+    let mut crypto = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(AnyServerCertificate))
+        .with_no_client_auth();
+    crypto.alpn_protocols = vec![FEDERATION_ALPN.to_vec()];
+    quinn::ClientConfig::new(Arc::new(crypto))
+}
+
+struct AnyServerCertificate;
+
+impl ServerCertVerifier for AnyServerCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::DEFAULT_DEVICE;
+    use crate::session::test_keypair;
+    use crate::tls::{self, CertResolver};
+    use solchat_protocol::messages::{AckMessage, ChatMessage};
+    use tokio::sync::mpsc;
+
+    fn server_endpoint() -> Endpoint {
+        let resolver = Arc::new(CertResolver::self_signed(vec!["localhost".into()]).unwrap());
+        let server_config = quinn::ServerConfig::with_crypto(Arc::new(tls::server_crypto(resolver)));
+        Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap()
+    }
+
+    struct TestRelay {
+        federation: Arc<Federation>,
+        router: Arc<MessageRouter>,
+    }
+
+    /// Relay with the given key, linked to one peer, accepting relay links
+    fn relay(relay_id: &str, seed: u8, endpoint: Endpoint, peer: Peer, directory: &[(&WalletAddress, &str)]) -> TestRelay {
+        let metrics = Arc::new(Metrics::new());
+        let directory = directory
+            .iter()
+            .map(|(wallet, relay_id)| (wallet.to_string(), relay_id.to_string()))
+            .collect();
+        let federation = Federation::new(
+            relay_id.to_string(),
+            test_keypair(seed),
+            vec![peer],
+            directory,
+            endpoint,
+            metrics.clone(),
+        );
+        serve(federation, MessageRouter::new(metrics))
+    }
+
+    /// Route with `federation` and accept links on its endpoint
+    fn serve(federation: Federation, router: MessageRouter) -> TestRelay {
+        let federation = Arc::new(federation);
+        let router = Arc::new(router.with_federation(federation.clone()));
+        let endpoint = federation.endpoint.clone();
+
+        let accepting = (federation.clone(), router.clone());
+        tokio::spawn(async move {
+            while let Some(connecting) = endpoint.accept().await {
+                let (federation, router) = accepting.clone();
+                tokio::spawn(async move {
+                    if let Ok(connection) = connecting.await {
+                        federation.accept(connection, router).await;
+                    }
+                });
+            }
+        });
+
+        TestRelay { federation, router }
+    }
+
+    fn peer(relay_id: &str, endpoint: &Endpoint, seed: u8) -> Peer {
+        Peer {
+            relay_id: relay_id.to_string(),
+            address: endpoint.local_addr().unwrap().to_string(),
+            public_key: WalletAddress::new(test_keypair(seed).public.to_bytes()),
+        }
+    }
+
+    async fn wait_for_link(federation: &Federation, relay_id: &str) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while federation.link(relay_id).is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("relays did not link");
+    }
+
+    #[tokio::test]
+    async fn test_messages_and_acks_cross_the_link() {
+        let alice = WalletAddress::test_address(1);
+        let bob = WalletAddress::test_address(2);
+        let directory = [(&alice, "relay-a"), (&bob, "relay-b")];
+        let (endpoint_a, endpoint_b) = (server_endpoint(), server_endpoint());
+        let a = relay("relay-a", 1, endpoint_a.clone(), peer("relay-b", &endpoint_b, 2), &directory);
+        let b = relay("relay-b", 2, endpoint_b.clone(), peer("relay-a", &endpoint_a, 1), &directory);
+
+        a.federation.clone().start(a.router.clone(), Duration::from_millis(50));
+        b.federation.clone().start(b.router.clone(), Duration::from_millis(50));
+        wait_for_link(&a.federation, "relay-b").await;
+        wait_for_link(&b.federation, "relay-a").await;
+
+        let (alice_tx, mut alice_rx) = mpsc::channel(10);
+        a.router.register_client(alice.clone(), alice_tx, "10.0.0.1:4000".parse().unwrap()).await.unwrap();
+
+        // Bob is offline, so his home relay queues the message
+        let chat = ChatMessage::new(&alice, &bob, b"hello".to_vec(), vec![]);
+        let sender_addr = "10.0.0.1:4000".parse().unwrap();
+        let outcome = a.router.route_message(RelayMessage::Chat(chat.clone()), sender_addr).await.unwrap();
+        assert_eq!(outcome.status, AckStatus::Queued);
+        assert_eq!(a.router.get_stats().await.queued_messages, 0);
+        assert_eq!(b.router.get_stats().await.queued_messages, 1);
+
+        let (bob_tx, mut bob_rx) = mpsc::channel(10);
+        b.router.register_client(bob.clone(), bob_tx, "10.0.0.1:4000".parse().unwrap()).await.unwrap();
+        let delivered = bob_rx.recv().await.unwrap();
+        assert_eq!(delivered.message.message_id(), Some(chat.id.as_str()));
+
+        // Bob's ack finds its way back to Alice on the relay she sent from
+        let ack = AckMessage::delivered(chat.id.clone());
+        let outcome = b.router.route_message(RelayMessage::Ack(ack), sender_addr).await.unwrap();
+        assert_eq!(outcome.status, AckStatus::Delivered);
+        assert_eq!(b.router.get_stats().await.queued_messages, 0);
+        match alice_rx.recv().await.unwrap().message {
+            RelayMessage::Ack(ack) => assert_eq!(ack.ref_message_id, chat.id),
+            other => panic!("expected an ack, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_peer_with_unexpected_key_is_not_linked() {
+        let bob = WalletAddress::test_address(2);
+        let (endpoint_a, endpoint_b) = (server_endpoint(), server_endpoint());
+        // Relay A expects relay B to hold key 3, but it holds key 2
+        let a = relay("relay-a", 1, endpoint_a.clone(), peer("relay-b", &endpoint_b, 3), &[(&bob, "relay-b")]);
+        let _b = relay("relay-b", 2, endpoint_b.clone(), peer("relay-a", &endpoint_a, 1), &[]);
+
+        let peer_b = a.federation.peers["relay-b"].clone();
+        let error = a.federation.connect(&peer_b).await.err().unwrap();
+        assert!(format!("{:#}", error).contains("unexpected key"));
+        let status = a.federation.link_status();
+        assert_eq!((status[0].relay_id.as_str(), status[0].linked), ("relay-b", false));
+
+        let chat = ChatMessage::new(&WalletAddress::test_address(1), &bob, b"hello".to_vec(), vec![]);
+        let outcome = a.router.route_message(RelayMessage::Chat(chat), "10.0.0.1:4000".parse().unwrap()).await.unwrap();
+        assert_eq!(outcome, RouteOutcome::with_reason(AckStatus::Failed, "recipient's relay is unreachable"));
+    }
+
+    #[tokio::test]
+    async fn test_replicas_find_each_other_and_forward_to_the_wallets_node() {
+        let presence = crate::presence::open(&crate::presence::tests::redis_stand_in()).unwrap();
+        let replica = |node_id: &str| {
+            let endpoint = server_endpoint();
+            let address = endpoint.local_addr().unwrap().to_string();
+            let metrics = Arc::new(Metrics::new());
+            // Replicas share the relay's key and list no peers of their own
+            let federation = Federation::new(node_id.to_string(), test_keypair(1), vec![], HashMap::new(), endpoint, metrics.clone())
+                .with_cluster(presence.clone(), address, Duration::from_millis(50));
+            let router = MessageRouter::new(metrics).with_presence(presence.clone(), node_id);
+            serve(federation, router)
+        };
+        let a = replica("node-a");
+        let b = replica("node-b");
+        a.federation.clone().start(a.router.clone(), Duration::from_millis(50));
+        b.federation.clone().start(b.router.clone(), Duration::from_millis(50));
+        wait_for_link(&a.federation, "node-b").await;
+        wait_for_link(&b.federation, "node-a").await;
+
+        let alice = WalletAddress::test_address(1);
+        let bob = WalletAddress::test_address(2);
+        let (bob_tx, mut bob_rx) = mpsc::channel(10);
+        b.router.register_client(bob.clone(), bob_tx, "10.0.0.1:4000".parse().unwrap()).await.unwrap();
+        assert_eq!(presence.locate(&bob.to_string()).unwrap().as_deref(), Some("node-b"));
+
+        let chat = ChatMessage::new(&alice, &bob, b"hello".to_vec(), vec![]);
+        let outcome = a.router.route_message(RelayMessage::Chat(chat.clone()), "10.0.0.1:4000".parse().unwrap()).await.unwrap();
+        assert_eq!(outcome.status, AckStatus::Delivered);
+        assert_eq!(bob_rx.recv().await.unwrap().message.message_id(), Some(chat.id.as_str()));
+
+        // Once Bob has left, messages for him are queued where they arrive
+        b.router.unregister_client(&bob).await.unwrap();
+        assert_eq!(presence.locate(&bob.to_string()).unwrap(), None);
+        let chat = ChatMessage::new(&alice, &bob, b"again".to_vec(), vec![]);
+        let outcome = a.router.route_message(RelayMessage::Chat(chat.clone()), "10.0.0.1:4000".parse().unwrap()).await.unwrap();
+        assert_eq!(outcome.status, AckStatus::Queued);
+        assert_eq!(a.router.get_stats().await.queued_messages, 1);
+
+        // and handed to whichever replica he connects to next
+        let (bob_tx, mut bob_rx) = mpsc::channel(10);
+        b.router.register_client(bob.clone(), bob_tx, "10.0.0.1:4000".parse().unwrap()).await.unwrap();
+        // after the first message, which he has yet to acknowledge
+        bob_rx.recv().await.unwrap();
+        assert_eq!(bob_rx.recv().await.unwrap().message.message_id(), Some(chat.id.as_str()));
+        assert_eq!(a.router.get_stats().await.queued_messages, 0);
+        assert!(b.router.is_queued_for(&bob.to_string(), DEFAULT_DEVICE, &chat.id));
+
+        // His ack clears it there and reaches Alice on the other replica
+        let (alice_tx, mut alice_rx) = mpsc::channel(10);
+        a.router.register_client(alice.clone(), alice_tx, "10.0.0.1:4000".parse().unwrap()).await.unwrap();
+        let ack = RelayMessage::Ack(AckMessage::delivered(chat.id.clone()));
+        b.router.route_message_from(ack, &bob, DEFAULT_DEVICE, "10.0.0.1:4000".parse().unwrap()).await.unwrap();
+        assert!(!b.router.is_queued_for(&bob.to_string(), DEFAULT_DEVICE, &chat.id));
+        assert!(matches!(alice_rx.recv().await.unwrap().message, RelayMessage::Ack(ack) if ack.ref_message_id == chat.id));
+    }
+
+    #[tokio::test]
+    async fn test_only_replicas_are_handed_queues() {
+        let alice = WalletAddress::test_address(1);
+        let bob = WalletAddress::test_address(2);
+        let (endpoint_a, endpoint_b) = (server_endpoint(), server_endpoint());
+        let a = relay("relay-a", 1, endpoint_a.clone(), peer("relay-b", &endpoint_b, 2), &[]);
+        let b = relay("relay-b", 2, endpoint_b.clone(), peer("relay-a", &endpoint_a, 1), &[]);
+        a.federation.clone().start(a.router.clone(), Duration::from_millis(50));
+        b.federation.clone().start(b.router.clone(), Duration::from_millis(50));
+        wait_for_link(&b.federation, "relay-a").await;
+
+        let chat = ChatMessage::new(&alice, &bob, b"hello".to_vec(), vec![]);
+        a.router.route_message(RelayMessage::Chat(chat), "10.0.0.1:4000".parse().unwrap()).await.unwrap();
+        let handed = b.federation.request_queue("relay-a", &bob.to_string()).await.map(|(handoff, _)| handoff.messages.len());
+        assert_eq!(handed.unwrap_or(0), 0);
+        assert_eq!(a.router.get_stats().await.queued_messages, 1);
+    }
+
+    #[test]
+    fn test_load_relay_key_checks_the_public_half() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("relay-key.json");
+        let key = test_keypair(7);
+
+        std::fs::write(&path, serde_json::to_string(&key.to_bytes().to_vec()).unwrap()).unwrap();
+        assert_eq!(load_relay_key(&path).unwrap().public, key.public);
+
+        let mut mismatched = key.to_bytes();
+        mismatched[32..].copy_from_slice(test_keypair(8).public.as_bytes());
+        std::fs::write(&path, serde_json::to_string(&mismatched.to_vec()).unwrap()).unwrap();
+        assert!(load_relay_key(&path).is_err());
+    }
+}
@@ -0,0 +1,692 @@
+use anyhow::{anyhow, Result};
+use hyper::body::{Bytes, HttpBody};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use prost::Message as _;
+use rand_core::{OsRng, RngCore};
+use solchat_protocol::codec::{encode_frame, FrameDecoder, MAX_FRAME_SIZE};
+use solchat_protocol::messages::{AckMessage, Envelope, GoAwayMessage, HandshakeChallenge, HandshakeProof, HandshakeResponse};
+use solchat_protocol::WalletAddress;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, span, warn, Instrument, Level};
+use crate::keepalive::Keepalive;
+use crate::router::{RelayMessage, RoutableMessage};
+use crate::session::{self, AppState, MAX_HANDSHAKE_SIZE, SHUTDOWN_REASON};
+use crate::tls::{self, CertResolver};
+
+/// Response header carrying the cursor to pass to the next poll
+pub const CURSOR_HEADER: &str = "solconnect-cursor";
+
+/// Largest request body, a batch of envelopes sent together
+const MAX_BODY_SIZE: usize = 4 * MAX_FRAME_SIZE;
+
+/// Challenges handed out and not yet answered, beyond which new ones are
+/// refused until the old ones expire
+const MAX_PENDING_CHALLENGES: usize = 10_000;
+
+/// Unanswered challenges one source IP may hold at once
+const MAX_PENDING_CHALLENGES_PER_IP: usize = 8;
+
+/// Length of a session token before base58 encoding
+const SESSION_TOKEN_LEN: usize = 32;
+
+const PROTOBUF: &str = "application/x-protobuf";
+
+/// Serves clients that cannot use QUIC over plain HTTPS requests
+///
+/// - `POST /v1/session/challenge`: a `HandshakeChallenge`
+/// - `POST /v1/session?challenge=<nonce>`: answer the challenge with a
+///   `HandshakeProof`, signed with an empty channel binding; the
+///   `HandshakeResponse` carries a session token for the other requests
+/// - `POST /v1/messages`: submit length-prefixed envelopes, answered with
+///   their acks in the same framing
+/// - `GET /v1/messages?cursor=<n>`: wait up to the poll timeout for queued
+///   messages after the cursor; the next cursor is in `SolConnect-Cursor`
+/// - `POST /v1/messages/{id}/ack`: confirm a chat message was received,
+///   removing it from the queue and acking it to its sender
+/// - `DELETE /v1/session`: end the session
+///
+/// Other requests carry the token as `Authorization: Bearer <token>`.
+/// Polling clients are never registered as connected, so everything sent to
+/// them is queued and read from the queue the way `deliver_queued_messages`
+/// hands it out: chat messages stay queued, and are returned again to a poll
+/// from an older cursor, until acknowledged. Sessions and queues belong to
+/// the replica that issued the token.
+pub struct HttpFallback {
+    state: AppState,
+    tls: Option<TlsAcceptor>,
+    poll_timeout: Duration,
+    session_timeout: Duration,
+    /// Unanswered challenges by nonce, with the address they were issued to
+    challenges: Mutex<HashMap<String, (IpAddr, HandshakeChallenge)>>,
+    sessions: Mutex<HashMap<String, Arc<Session>>>,
+}
+
+/// A wallet's device authenticated by a session token
+struct Session {
+    wallet: WalletAddress,
+    device_id: String,
+    keepalive: Keepalive,
+    cursor: Mutex<Cursor>,
+}
+
+/// Positions of the session's queued messages, so a poll returns only
+/// what came after the cursor it was given
+#[derive(Default)]
+struct Cursor {
+    last: u64,
+    positions: HashMap<String, u64>,
+}
+
+impl Cursor {
+    /// Number the queued messages, keeping those positioned after `after`
+    fn after(&mut self, queued: Vec<RoutableMessage>, after: u64) -> Vec<(u64, RoutableMessage)> {
+        // Forget messages that left the queue
+        let ids: Vec<&str> = queued.iter().filter_map(|queued| queued.message.message_id()).collect();
+        self.positions.retain(|id, _| ids.contains(&id.as_str()));
+
+        let mut newer = Vec::new();
+        for message in queued {
+            let position = match message.message.message_id() {
+                Some(id) => match self.positions.get(id) {
+                    Some(position) => *position,
+                    None => {
+                        self.last += 1;
+                        self.positions.insert(id.to_string(), self.last);
+                        self.last
+                    }
+                },
+                None => {
+                    self.last += 1;
+                    self.last
+                }
+            };
+            if position > after {
+                newer.push((position, message));
+            }
+        }
+        newer
+    }
+}
+
+impl Session {
+    fn is_expired(&self, session_timeout: Duration) -> bool {
+        self.keepalive.idle_for() >= session_timeout
+    }
+}
+
+impl HttpFallback {
+    /// Gateway serving HTTPS with `cert_resolver`, or plain HTTP without one
+    pub fn new(
+        state: AppState,
+        cert_resolver: Option<Arc<CertResolver>>,
+        poll_timeout: Duration,
+        session_timeout: Duration,
+    ) -> Self {
+        let tls = cert_resolver.map(|resolver| TlsAcceptor::from(Arc::new(tls::websocket_crypto(resolver))));
+        Self {
+            state,
+            tls,
+            poll_timeout,
+            session_timeout,
+            challenges: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Accept connections on `listener` until the relay starts draining
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let (stream, remote_addr) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Failed to accept HTTP connection: {}", e);
+                        continue;
+                    }
+                },
+                _ = self.state.shutdown.draining() => return,
+            };
+
+            let span = span!(Level::INFO, "connection", remote = %remote_addr, transport = "http");
+            tokio::spawn(self.clone().handle_connection(stream, remote_addr).instrument(span));
+        }
+    }
+
+    async fn handle_connection(self: Arc<Self>, stream: TcpStream, remote_addr: SocketAddr) {
+        let _guard = self.state.shutdown.track();
+
+        let result = match &self.tls {
+            Some(acceptor) => match tokio::time::timeout(self.state.handshake_timeout, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => self.clone().serve_http(stream, remote_addr).await,
+                Ok(Err(e)) => Err(anyhow!("TLS handshake failed: {}", e)),
+                Err(_) => Err(anyhow!("TLS handshake timed out")),
+            },
+            None => self.clone().serve_http(stream, remote_addr).await,
+        };
+
+        if let Err(e) = result {
+            debug!("HTTP connection ended: {:#}", e);
+        }
+    }
+
+    /// Serve requests on one connection, finishing the one in progress and
+    /// closing once the relay drains
+    async fn serve_http<S>(self: Arc<Self>, stream: S, remote_addr: SocketAddr) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let gateway = self.clone();
+        let service = service_fn(move |req| {
+            let gateway = gateway.clone();
+            async move { Ok::<_, Infallible>(gateway.handle(req, remote_addr).await) }
+        });
+
+        let connection = Http::new().http1_only(true).serve_connection(stream, service);
+        tokio::pin!(connection);
+        tokio::select! {
+            result = connection.as_mut() => return Ok(result?),
+            _ = self.state.shutdown.draining() => {}
+        }
+        connection.as_mut().graceful_shutdown();
+        Ok(connection.await?)
+    }
+
+    async fn handle(&self, req: Request<Body>, remote_addr: SocketAddr) -> Response<Body> {
+        let path = req.uri().path().trim_end_matches('/').to_string();
+        let segments: Vec<&str> = path.split('/').skip(1).collect();
+        let method = req.method().clone();
+
+        match (method, segments.as_slice()) {
+            (Method::POST, ["v1", "session", "challenge"]) => self.issue_challenge(remote_addr.ip()),
+            (Method::POST, ["v1", "session"]) => self.open_session(req).await,
+            (method, ["v1", ..]) => {
+                let session = match self.authenticate(&req) {
+                    Ok(session) => session,
+                    Err(StatusCode::FORBIDDEN) => return text_response(StatusCode::FORBIDDEN, "wallet banned"),
+                    Err(_) => {
+                        return Response::builder()
+                            .status(StatusCode::UNAUTHORIZED)
+                            .header(header::WWW_AUTHENTICATE, "Bearer")
+                            .body(Body::from("Unauthorized"))
+                            .unwrap()
+                    }
+                };
+                match (method, &segments[1..]) {
+                    (Method::DELETE, ["session"]) => {
+                        self.close_session(&req);
+                        info!("👋 Session closed for {}", session.wallet);
+                        empty_response(StatusCode::NO_CONTENT)
+                    }
+                    (Method::POST, ["messages"]) => self.submit(req, &session, remote_addr).await,
+                    (Method::GET, ["messages"]) => self.poll(&req, &session).await,
+                    (Method::POST, ["messages", message_id, "ack"]) => self.ack(&session, message_id, remote_addr).await,
+                    _ => text_response(StatusCode::NOT_FOUND, "Not Found"),
+                }
+            }
+            _ => text_response(StatusCode::NOT_FOUND, "Not Found"),
+        }
+    }
+
+    fn issue_challenge(&self, ip: IpAddr) -> Response<Body> {
+        if self.state.shutdown.is_draining() {
+            return text_response(StatusCode::SERVICE_UNAVAILABLE, SHUTDOWN_REASON);
+        }
+        if let Err(scope) = self.state.rate_limiter.check_ip(ip) {
+            self.state.metrics.record_rate_limited(scope.label());
+            return text_response(StatusCode::TOO_MANY_REQUESTS, scope.reason());
+        }
+
+        let mut challenges = self.challenges.lock().unwrap();
+        let pending_from = |challenges: &HashMap<String, (IpAddr, HandshakeChallenge)>| {
+            challenges.values().filter(|(from, _)| *from == ip).count()
+        };
+        if challenges.len() >= MAX_PENDING_CHALLENGES || pending_from(&challenges) >= MAX_PENDING_CHALLENGES_PER_IP {
+            challenges.retain(|_, (_, challenge)| !challenge.is_expired());
+            if pending_from(&challenges) >= MAX_PENDING_CHALLENGES_PER_IP {
+                warn!("Refusing a challenge to {} with {} of its own outstanding", ip, MAX_PENDING_CHALLENGES_PER_IP);
+                return text_response(StatusCode::TOO_MANY_REQUESTS, "too many pending handshakes");
+            }
+            if challenges.len() >= MAX_PENDING_CHALLENGES {
+                warn!("Refusing a challenge with {} outstanding", challenges.len());
+                return text_response(StatusCode::SERVICE_UNAVAILABLE, "too many pending handshakes");
+            }
+        }
+        let challenge = HandshakeChallenge::new(&self.state.relay_id);
+        challenges.insert(bs58::encode(&challenge.nonce).into_string(), (ip, challenge.clone()));
+
+        protobuf_response(StatusCode::OK, challenge.encode_to_vec())
+    }
+
+    /// Check a proof against the challenge it answers and issue a token
+    async fn open_session(&self, req: Request<Body>) -> Response<Body> {
+        let nonce = query_param(&req, "challenge").unwrap_or_default();
+        let challenge = self.challenges.lock().unwrap().remove(&nonce).map(|(_, challenge)| challenge);
+
+        let result = match (challenge, read_body(req.into_body(), MAX_HANDSHAKE_SIZE).await) {
+            (None, _) => Err("Unknown or already answered challenge".to_string()),
+            (_, Err(e)) => Err(e),
+            (Some(challenge), Ok(body)) => HandshakeProof::decode(body)
+                .map_err(|e| e.to_string())
+                .and_then(|proof| {
+                    let wallet = challenge.verify_proof(&proof, &[])?;
+                    Ok((wallet, proof.device_id().to_string()))
+                }),
+        };
+
+        let (wallet, device_id) = match result {
+            Ok(client) => client,
+            Err(e) => {
+                warn!("Handshake failed: {}", e);
+                self.state.metrics.record_handshake_failed();
+                return protobuf_response(StatusCode::UNAUTHORIZED, HandshakeResponse::failure(e).encode_to_vec());
+            }
+        };
+
+        if self.state.router.is_banned(&wallet.to_string()) {
+            warn!("Refusing banned wallet {}", wallet);
+            let response = HandshakeResponse::failure("wallet banned".to_string());
+            return protobuf_response(StatusCode::FORBIDDEN, response.encode_to_vec());
+        }
+
+        let mut bytes = [0u8; SESSION_TOKEN_LEN];
+        OsRng.fill_bytes(&mut bytes);
+        let token = bs58::encode(bytes).into_string();
+
+        // Known devices get their own copy of what arrives while they poll
+        if let Err(e) = self.state.router.add_device(&wallet.to_string(), &device_id).await {
+            error!("Failed to add device {} of {}: {:#}", device_id, wallet, e);
+            return text_response(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error");
+        }
+
+        info!("🤝 Handshake completed for {} over HTTP", wallet);
+        let session = Session {
+            wallet,
+            device_id,
+            keepalive: Keepalive::new(),
+            cursor: Mutex::new(Cursor::default()),
+        };
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| !session.is_expired(self.session_timeout));
+        sessions.insert(token.clone(), Arc::new(session));
+
+        protobuf_response(StatusCode::OK, HandshakeResponse::success().with_session_token(token).encode_to_vec())
+    }
+
+    /// Session named by the request's bearer token, or the status refusing it
+    fn authenticate(&self, req: &Request<Body>) -> Result<Arc<Session>, StatusCode> {
+        let token = bearer_token(req).ok_or(StatusCode::UNAUTHORIZED)?;
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get(token).cloned().ok_or(StatusCode::UNAUTHORIZED)?;
+        if session.is_expired(self.session_timeout) {
+            sessions.remove(token);
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        if self.state.router.is_banned(&session.wallet.to_string()) {
+            sessions.remove(token);
+            return Err(StatusCode::FORBIDDEN);
+        }
+
+        session.keepalive.touch();
+        Ok(session)
+    }
+
+    fn close_session(&self, req: &Request<Body>) {
+        if let Some(token) = bearer_token(req) {
+            self.sessions.lock().unwrap().remove(token);
+        }
+    }
+
+    /// Route submitted envelopes, answering with their acks
+    async fn submit(&self, req: Request<Body>, session: &Session, remote_addr: SocketAddr) -> Response<Body> {
+        let body = match read_body(req.into_body(), MAX_BODY_SIZE).await {
+            Ok(body) => body,
+            Err(e) => return text_response(StatusCode::PAYLOAD_TOO_LARGE, &e),
+        };
+        self.state.metrics.record_bytes_received(body.len());
+
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&body);
+        let mut envelopes = Vec::new();
+        loop {
+            match decoder.next_frame() {
+                Ok(Some(envelope)) => envelopes.push(envelope),
+                Ok(None) if decoder.has_partial_frame() => {
+                    return text_response(StatusCode::BAD_REQUEST, "truncated frame");
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    self.state.metrics.record_message_failed();
+                    return text_response(StatusCode::BAD_REQUEST, &e.to_string());
+                }
+            }
+        }
+
+        let mut replies = Vec::new();
+        for envelope in envelopes {
+            let reply = session::handle_envelope(
+                &self.state,
+                &session.wallet,
+                &session.device_id,
+                remote_addr,
+                &session.keepalive,
+                envelope,
+            )
+            .await;
+            replies.extend(reply);
+        }
+        self.frames_response(replies, None)
+    }
+
+    /// Wait for messages queued after the request's cursor
+    async fn poll(&self, req: &Request<Body>, session: &Session) -> Response<Body> {
+        let cursor = match query_param(req, "cursor").map(|cursor| cursor.parse::<u64>()) {
+            None => 0,
+            Some(Ok(cursor)) => cursor,
+            Some(Err(_)) => return text_response(StatusCode::BAD_REQUEST, "cursor must be a number"),
+        };
+
+        let wallet = session.wallet.to_string();
+        let watcher = self.state.router.queue_watcher(&wallet, &session.device_id);
+        let deadline = tokio::time::Instant::now() + self.poll_timeout;
+
+        loop {
+            // Listen before reading the queue so nothing queued in between is missed
+            let notified = watcher.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            let queued = match self.state.router.take_queued(&wallet, &session.device_id).await {
+                Ok(queued) => queued,
+                Err(e) => {
+                    error!("Failed to read the queue of {}: {:#}", wallet, e);
+                    return text_response(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error");
+                }
+            };
+            let newer = {
+                let mut positions = session.cursor.lock().unwrap();
+                let numbered = positions.last;
+                let newer = positions.after(queued, cursor);
+                // Messages returned again from an older cursor were counted the first time
+                let fresh = newer.iter().filter(|(position, _)| *position > numbered).count();
+                for _ in 0..fresh {
+                    self.state.metrics.record_message_routed();
+                }
+                newer
+            };
+            let draining = self.state.shutdown.is_draining();
+
+            if !newer.is_empty() || draining {
+                let next_cursor = newer.iter().map(|(position, _)| *position).max().unwrap_or(cursor);
+                let mut envelopes: Vec<Envelope> = newer.into_iter().map(|(_, queued)| queued.message.to_envelope()).collect();
+                if draining {
+                    envelopes.push(GoAwayMessage::new(SHUTDOWN_REASON).into());
+                    info!("🚪 Sent GoAway");
+                }
+                return self.frames_response(envelopes, Some(next_cursor));
+            }
+
+            tokio::select! {
+                _ = notified => {}
+                _ = self.state.shutdown.draining() => {}
+                _ = tokio::time::sleep_until(deadline) => return self.frames_response(Vec::new(), Some(cursor)),
+            }
+        }
+    }
+
+    /// Confirm receipt of a queued chat message
+    async fn ack(&self, session: &Session, message_id: &str, remote_addr: SocketAddr) -> Response<Body> {
+        if !self.state.router.is_queued_for(&session.wallet.to_string(), &session.device_id, message_id) {
+            return text_response(StatusCode::NOT_FOUND, "no such queued message");
+        }
+
+        let ack = RelayMessage::Ack(AckMessage::delivered(message_id.to_string()));
+        match self.state.router.route_message_from(ack, &session.wallet, &session.device_id, remote_addr).await {
+            Ok(_) => empty_response(StatusCode::NO_CONTENT),
+            Err(e) => {
+                error!("Failed to route ack for {}: {:#}", message_id, e);
+                text_response(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error")
+            }
+        }
+    }
+
+    fn frames_response(&self, envelopes: Vec<Envelope>, cursor: Option<u64>) -> Response<Body> {
+        let body: Vec<u8> = envelopes.iter().flat_map(encode_frame).collect();
+        self.state.metrics.record_bytes_sent(body.len());
+
+        let mut builder = Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, PROTOBUF)
+            .header(header::CACHE_CONTROL, "no-store");
+        if let Some(cursor) = cursor {
+            builder = builder.header(CURSOR_HEADER, cursor);
+        }
+        builder.body(Body::from(body)).unwrap()
+    }
+}
+
+fn bearer_token(req: &Request<Body>) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+fn query_param(req: &Request<Body>, name: &str) -> Option<String> {
+    req.uri()
+        .query()?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string())
+}
+
+/// Read a request body, refusing it once it grows past `limit`
+async fn read_body(mut body: Body, limit: usize) -> Result<Bytes, String> {
+    let mut out = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| format!("Failed to read the request body: {}", e))?;
+        if out.len() + chunk.len() > limit {
+            return Err(format!("Request body larger than {} bytes", limit));
+        }
+        out.extend_from_slice(&chunk);
+    }
+    Ok(out.into())
+}
+
+fn protobuf_response(status: StatusCode, body: Vec<u8>) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, PROTOBUF)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn text_response(status: StatusCode, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(message.to_string()))
+        .unwrap()
+}
+
+fn empty_response(status: StatusCode) -> Response<Body> {
+    Response::builder().status(status).body(Body::empty()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rate_limit::{RateLimit, RateLimits};
+    use crate::router::DEFAULT_DEVICE;
+    use crate::session::test_keypair;
+    use ed25519_dalek::Keypair;
+    use solchat_protocol::messages::{envelope::Body as EnvelopeBody, AckStatus, ChatMessage};
+
+    const SENDER_ADDR: &str = "10.0.0.1:4000";
+
+    /// Plain HTTP gateway on a loopback port
+    async fn gateway(state: AppState, poll_timeout: Duration) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let gateway = HttpFallback::new(state, None, poll_timeout, Duration::from_secs(300));
+        tokio::spawn(Arc::new(gateway).serve(listener));
+        format!("http://{}", addr)
+    }
+
+    /// Run the handshake, returning the relay's response
+    async fn open_session(client: &reqwest::Client, base: &str, signer: &Keypair, binding: &[u8]) -> (reqwest::StatusCode, HandshakeResponse) {
+        let body = client.post(format!("{}/v1/session/challenge", base)).send().await.unwrap().bytes().await.unwrap();
+        let challenge = HandshakeChallenge::decode(body).unwrap();
+        let proof = HandshakeProof::sign(&challenge, "localhost", binding, signer).unwrap();
+        let nonce = bs58::encode(&challenge.nonce).into_string();
+        let response = client
+            .post(format!("{}/v1/session?challenge={}", base, nonce))
+            .body(proof.encode_to_vec())
+            .send()
+            .await
+            .unwrap();
+        let status = response.status();
+        (status, HandshakeResponse::decode(response.bytes().await.unwrap()).unwrap())
+    }
+
+    fn decode_frames(body: &[u8]) -> Vec<EnvelopeBody> {
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(body);
+        let mut bodies = Vec::new();
+        while let Some(envelope) = decoder.next_frame().unwrap() {
+            bodies.push(envelope.body.unwrap());
+        }
+        bodies
+    }
+
+    async fn poll(client: &reqwest::Client, base: &str, token: &str, cursor: &str) -> (String, Vec<EnvelopeBody>) {
+        let response = client
+            .get(format!("{}/v1/messages?cursor={}", base, cursor))
+            .bearer_auth(token)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let cursor = response.headers()[CURSOR_HEADER].to_str().unwrap().to_string();
+        (cursor, decode_frames(&response.bytes().await.unwrap()))
+    }
+
+    #[tokio::test]
+    async fn test_submit_poll_and_ack_round_trip() {
+        let state = AppState::for_tests();
+        let base = gateway(state.clone(), Duration::from_millis(100)).await;
+        let client = reqwest::Client::new();
+        let signer = test_keypair(7);
+        let wallet = WalletAddress::new(signer.public.to_bytes());
+        let peer = WalletAddress::test_address(2);
+
+        let (status, response) = open_session(&client, &base, &signer, &[]).await;
+        assert_eq!(status, reqwest::StatusCode::OK);
+        let token = response.session_token.unwrap();
+
+        // Submitted messages are routed and acked in the response
+        let (peer_tx, mut peer_rx) = tokio::sync::mpsc::channel(10);
+        state.router.register_client(peer.clone(), peer_tx, SENDER_ADDR.parse().unwrap()).await.unwrap();
+        let mut chat = ChatMessage::new(&wallet, &peer, b"over http".to_vec(), Vec::new());
+        chat.sign(&signer).unwrap();
+        let body = encode_frame(&RelayMessage::Chat(chat.clone()).to_envelope());
+        let response = client.post(format!("{}/v1/messages", base)).bearer_auth(&token).body(body).send().await.unwrap();
+        assert!(matches!(&decode_frames(&response.bytes().await.unwrap())[..], [EnvelopeBody::Ack(ack)] if ack.status() == AckStatus::Delivered));
+        assert_eq!(peer_rx.recv().await.unwrap().message.message_id(), Some(chat.id.as_str()));
+
+        // Messages for the polling wallet are queued until it acks them
+        let reply = ChatMessage::new(&peer, &wallet, b"back".to_vec(), Vec::new());
+        let outcome = state.router.route_message(RelayMessage::Chat(reply.clone()), SENDER_ADDR.parse().unwrap()).await.unwrap();
+        assert_eq!(outcome.status, AckStatus::Queued);
+
+        // Polling the same message again does not count it as routed twice
+        let routed = state.metrics.messages_routed.get();
+        let (cursor, messages) = poll(&client, &base, &token, "0").await;
+        assert!(matches!(&messages[..], [EnvelopeBody::Chat(chat)] if chat.id == reply.id));
+        let (_, messages) = poll(&client, &base, &token, &cursor).await;
+        assert!(messages.is_empty());
+        let (_, messages) = poll(&client, &base, &token, "0").await;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(state.metrics.messages_routed.get(), routed + 1);
+
+        let ack_url = format!("{}/v1/messages/{}/ack", base, reply.id);
+        let response = client.post(&ack_url).bearer_auth(&token).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NO_CONTENT);
+        assert!(matches!(peer_rx.recv().await.unwrap().message, RelayMessage::Ack(ack) if ack.ref_message_id == reply.id));
+        assert!(!state.router.is_queued_for(&wallet.to_string(), DEFAULT_DEVICE, &reply.id));
+        let response = client.post(&ack_url).bearer_auth(&token).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+
+        // A closed session's token stops working
+        client.delete(format!("{}/v1/session", base)).bearer_auth(&token).send().await.unwrap();
+        let response = client.get(format!("{}/v1/messages", base)).bearer_auth(&token).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_poll_wakes_when_a_message_is_queued() {
+        let state = AppState::for_tests();
+        let base = gateway(state.clone(), Duration::from_secs(30)).await;
+        let client = reqwest::Client::new();
+        let signer = test_keypair(7);
+        let wallet = WalletAddress::new(signer.public.to_bytes());
+        let (_, response) = open_session(&client, &base, &signer, &[]).await;
+        let token = response.session_token.unwrap();
+
+        let chat = ChatMessage::new(&WalletAddress::test_address(2), &wallet, b"later".to_vec(), Vec::new());
+        let router = state.router.clone();
+        let sent = chat.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            router.route_message(RelayMessage::Chat(sent), SENDER_ADDR.parse().unwrap()).await.unwrap();
+        });
+
+        let polled = tokio::time::timeout(Duration::from_secs(5), poll(&client, &base, &token, "0")).await.unwrap();
+        assert!(matches!(&polled.1[..], [EnvelopeBody::Chat(received)] if received.id == chat.id));
+    }
+
+    #[tokio::test]
+    async fn test_bad_proofs_and_tokens_are_refused() {
+        let state = AppState::for_tests();
+        let base = gateway(state.clone(), Duration::from_millis(100)).await;
+        let client = reqwest::Client::new();
+
+        let (status, response) = open_session(&client, &base, &test_keypair(7), &[9u8; 32]).await;
+        assert_eq!(status, reqwest::StatusCode::UNAUTHORIZED);
+        assert!(!response.success && response.session_token.is_none());
+
+        let response = client.get(format!("{}/v1/messages", base)).bearer_auth("not-a-token").send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_challenges_are_limited_per_address() {
+        let state = AppState::for_tests();
+        let base = gateway(state.clone(), Duration::from_millis(100)).await;
+        let client = reqwest::Client::new();
+        let challenge = || client.post(format!("{}/v1/session/challenge", base)).send();
+
+        // Unanswered challenges pile up only to the per-address cap
+        for _ in 0..MAX_PENDING_CHALLENGES_PER_IP {
+            assert_eq!(challenge().await.unwrap().status(), reqwest::StatusCode::OK);
+        }
+        assert_eq!(challenge().await.unwrap().status(), reqwest::StatusCode::TOO_MANY_REQUESTS);
+
+        // Past the per-IP rate limit, no challenge is created at all
+        state.rate_limiter.set_limits(RateLimits {
+            per_ip: RateLimit::new(1.0, 1),
+            ..RateLimits::default()
+        });
+        state.rate_limiter.check_ip("127.0.0.1".parse().unwrap()).unwrap();
+        assert_eq!(challenge().await.unwrap().status(), reqwest::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(state.metrics.rate_limited.with_label_values(&["ip"]).get(), 1);
+    }
+}
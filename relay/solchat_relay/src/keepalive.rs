@@ -0,0 +1,107 @@
+use solchat_protocol::messages::{PingMessage, PongMessage};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Number of unanswered pings remembered per connection
+const MAX_OUTSTANDING_PINGS: usize = 8;
+
+/// Per-connection liveness tracking
+///
+/// Tracks when the client was last heard from and the pings the relay has
+/// sent, so that pongs can be turned into round-trip times.
+pub struct Keepalive {
+    state: Mutex<KeepaliveState>,
+}
+
+struct KeepaliveState {
+    last_activity: Instant,
+    next_sequence: u64,
+    outstanding: VecDeque<(u64, Instant)>,
+}
+
+impl Keepalive {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(KeepaliveState {
+                last_activity: Instant::now(),
+                next_sequence: 0,
+                outstanding: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Record that the client sent something
+    pub fn touch(&self) {
+        self.state.lock().unwrap().last_activity = Instant::now();
+    }
+
+    /// Time since the client was last heard from
+    pub fn idle_for(&self) -> Duration {
+        self.state.lock().unwrap().last_activity.elapsed()
+    }
+
+    /// Build the next keepalive ping and remember when it was sent
+    pub fn next_ping(&self) -> PingMessage {
+        let mut state = self.state.lock().unwrap();
+        let sequence = state.next_sequence;
+        state.next_sequence += 1;
+
+        if state.outstanding.len() >= MAX_OUTSTANDING_PINGS {
+            state.outstanding.pop_front();
+        }
+        state.outstanding.push_back((sequence, Instant::now()));
+
+        PingMessage::new(sequence)
+    }
+
+    /// Match a pong to its ping, returning the round-trip time
+    ///
+    /// Pongs for unknown or already answered sequence numbers are ignored.
+    pub fn pong_received(&self, pong: &PongMessage) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        let index = state
+            .outstanding
+            .iter()
+            .position(|(sequence, _)| *sequence == pong.sequence)?;
+        let (_, sent_at) = state.outstanding.remove(index)?;
+        Some(sent_at.elapsed())
+    }
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pong_matches_outstanding_ping() {
+        let keepalive = Keepalive::new();
+        let first = keepalive.next_ping();
+        let second = keepalive.next_ping();
+        assert_eq!(second.sequence, first.sequence + 1);
+
+        let pong = PongMessage::reply_to(&first);
+        assert!(keepalive.pong_received(&pong).is_some());
+
+        // Duplicate and unsolicited pongs yield no sample
+        assert!(keepalive.pong_received(&pong).is_none());
+        assert!(keepalive.pong_received(&PongMessage::reply_to(&PingMessage::new(99))).is_none());
+    }
+
+    #[test]
+    fn test_outstanding_pings_are_bounded() {
+        let keepalive = Keepalive::new();
+        let oldest = keepalive.next_ping();
+        for _ in 0..MAX_OUTSTANDING_PINGS {
+            keepalive.next_ping();
+        }
+
+        assert!(keepalive.pong_received(&PongMessage::reply_to(&oldest)).is_none());
+    }
+}
@@ -1,2 +1,18 @@
+pub mod admin;
+pub mod config;
+pub mod federation;
+pub mod http_fallback;
+pub mod keepalive;
+pub mod message_index;
 pub mod metrics;
-pub mod router; 
\ No newline at end of file
+pub mod prekeys;
+pub mod presence;
+pub mod queue_limits;
+pub mod queue_store;
+pub mod rate_limit;
+pub mod readiness;
+pub mod router;
+pub mod session;
+pub mod shutdown;
+pub mod tls;
+pub mod websocket;
@@ -1,99 +1,562 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Parser;
 use hyper::{Body, Request, Response, Server};
 use hyper::service::{make_service_fn, service_fn};
 use quinn::{Endpoint, ServerConfig};
-use solchat_protocol::messages::{ChatMessage, AckMessage, ReadReceipt, PingMessage, PongMessage};
+use solchat_protocol::messages::{HandshakeChallenge, HandshakeProof, HandshakeResponse, Envelope, GoAwayMessage};
+use solchat_protocol::codec::{self, FrameDecoder};
 use solchat_protocol::WalletAddress;
 use std::convert::Infallible;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use tracing::{info, warn, error, debug, span, Level};
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::mpsc;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{reload, EnvFilter, Registry};
 
+pub mod admin;
+pub mod config;
+pub mod federation;
+pub mod http_fallback;
+pub mod keepalive;
+pub mod message_index;
 pub mod metrics;
+pub mod prekeys;
+pub mod presence;
+pub mod queue_limits;
+pub mod queue_store;
+pub mod rate_limit;
+pub mod readiness;
 pub mod router;
+pub mod session;
+pub mod shutdown;
+pub mod tls;
+pub mod websocket;
 
+use admin::AdminApi;
+use config::RelayConfig;
+use federation::Federation;
+use http_fallback::HttpFallback;
+use keepalive::Keepalive;
 use metrics::Metrics;
-use router::{MessageRouter, RoutableMessage, RelayMessage};
+use queue_limits::EvictionPolicy;
+use queue_store::FileQueueStore;
+use rate_limit::RateLimiter;
+use readiness::Readiness;
+use tls::{channel_binding, CertResolver};
+use websocket::WebSocketGateway;
+use router::{MessageRouter, RoutableMessage};
+use session::{AppState, MAX_HANDSHAKE_SIZE, SHUTDOWN_REASON};
+use shutdown::Shutdown;
 
 // This is where the magic (and the bugs) happen
 
-#[derive(Parser)]
+
+/// Time connections get to wind down once the endpoint has been closed
+const CLOSE_GRACE: Duration = Duration::from_secs(1);
+
+/// Part of the drain timeout kept for flushing the message queue, at most
+/// a quarter of it
+const FLUSH_RESERVE: Duration = Duration::from_secs(3);
+
+/// Handle for changing the log filter while the relay runs
+type LogFilterHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Command-line options
+///
+/// Each option overrides the matching setting in the config file and can
+/// also be given through the environment variable shown in `--help`.
+#[derive(Parser, Clone)]
 #[command(name = "solchat_relay")]
 #[command(about = "SolConnect QUIC message relay server")]
 struct Args {
-    #[arg(long, default_value = "0.0.0.0:4433")]
-    listen: SocketAddr,
+    /// TOML config file; see relay.example.toml for every setting
+    #[arg(long, env = "SOLCHAT_CONFIG")]
+    config: Option<PathBuf>,
+    
+    #[arg(long, env = "SOLCHAT_LISTEN")]
+    listen: Option<SocketAddr>,
     
-    #[arg(long, default_value = "0.0.0.0:8080")]
-    metrics_addr: SocketAddr,
+    #[arg(long, env = "SOLCHAT_METRICS_ADDR")]
+    metrics_addr: Option<SocketAddr>,
     
-    #[arg(long)]
+    /// Allow running with a throwaway self-signed certificate
+    #[arg(long, env = "SOLCHAT_DEVNET")]
     devnet: bool,
+    
+    /// PEM certificate chain for the QUIC endpoint
+    #[arg(long, env = "SOLCHAT_TLS_CERT")]
+    cert: Option<PathBuf>,
+    
+    /// PEM private key for the certificate
+    #[arg(long, env = "SOLCHAT_TLS_KEY")]
+    key: Option<PathBuf>,
+    
+    /// Identity clients sign in their handshake proofs
+    #[arg(long, env = "SOLCHAT_RELAY_ID")]
+    relay_id: Option<String>,
+    
+    /// Log filter, e.g. "info" or "info,solchat_relay=debug"
+    #[arg(long, env = "SOLCHAT_LOG_LEVEL")]
+    log_level: Option<String>,
+    
+    /// Seconds a client has to complete the handshake
+    #[arg(long, env = "SOLCHAT_HANDSHAKE_TIMEOUT_SECS")]
+    handshake_timeout_secs: Option<u64>,
+    
+    /// Seconds between keepalive pings sent to each client
+    #[arg(long, env = "SOLCHAT_KEEPALIVE_INTERVAL_SECS")]
+    keepalive_interval_secs: Option<u64>,
+    
+    /// Close connections that have sent nothing for this many seconds
+    #[arg(long, env = "SOLCHAT_IDLE_TIMEOUT_SECS")]
+    idle_timeout_secs: Option<u64>,
+    
+    /// File holding messages queued for offline recipients
+    #[arg(long, env = "SOLCHAT_QUEUE_PATH")]
+    queue_path: Option<PathBuf>,
+    
+    /// Messages that may be queued for one recipient
+    #[arg(long, env = "SOLCHAT_QUEUE_MAX_MESSAGES")]
+    queue_max_messages: Option<usize>,
+    
+    /// Bytes that may be queued for one recipient
+    #[arg(long, env = "SOLCHAT_QUEUE_MAX_BYTES")]
+    queue_max_bytes: Option<usize>,
+    
+    /// Bytes that may be queued across all recipients
+    #[arg(long, env = "SOLCHAT_QUEUE_TOTAL_BYTES")]
+    queue_total_bytes: Option<usize>,
+    
+    /// What to do with a full queue: reject-new, drop-oldest or drop-expired-first
+    #[arg(long, env = "SOLCHAT_QUEUE_POLICY")]
+    queue_policy: Option<EvictionPolicy>,
+    
+    /// Messages per second each wallet may send
+    #[arg(long, env = "SOLCHAT_WALLET_RATE")]
+    wallet_rate: Option<f64>,
+    
+    /// Messages a wallet may send in a burst
+    #[arg(long, env = "SOLCHAT_WALLET_BURST")]
+    wallet_burst: Option<u32>,
+    
+    /// Messages per second accepted from each source IP
+    #[arg(long, env = "SOLCHAT_IP_RATE")]
+    ip_rate: Option<f64>,
+    
+    /// Messages accepted from a source IP in a burst
+    #[arg(long, env = "SOLCHAT_IP_BURST")]
+    ip_burst: Option<u32>,
+    
+    /// Chat messages per second accepted for each recipient
+    #[arg(long, env = "SOLCHAT_RECIPIENT_RATE")]
+    recipient_rate: Option<f64>,
+    
+    /// Chat messages accepted for a recipient in a burst
+    #[arg(long, env = "SOLCHAT_RECIPIENT_BURST")]
+    recipient_burst: Option<u32>,
+    
+    /// Seconds clients get to leave on shutdown before being disconnected
+    #[arg(long, env = "SOLCHAT_DRAIN_TIMEOUT_SECS")]
+    drain_timeout_secs: Option<u64>,
+    
+    /// JSON keypair identifying this relay to its peers and replicas
+    #[arg(long, env = "SOLCHAT_FEDERATION_KEY")]
+    federation_key: Option<PathBuf>,
+    
+    /// Name of this replica within its cluster
+    #[arg(long, env = "SOLCHAT_NODE_ID")]
+    node_id: Option<String>,
+    
+    /// host:port the other replicas reach this one on
+    #[arg(long, env = "SOLCHAT_NODE_ADDRESS")]
+    node_address: Option<String>,
+    
+    /// Presence store shared by the replicas: "memory" or a redis:// URL
+    #[arg(long, env = "SOLCHAT_PRESENCE")]
+    presence: Option<String>,
+    
+    /// Bearer token for the admin API; the API is off without one
+    #[arg(long, env = "SOLCHAT_ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+    
+    /// Address of the WebSocket listener for browser clients
+    #[arg(long, env = "SOLCHAT_WEBSOCKET_LISTEN")]
+    websocket_listen: Option<SocketAddr>,
+    
+    /// Address of the HTTPS fallback listener for networks blocking UDP
+    #[arg(long, env = "SOLCHAT_HTTP_FALLBACK_LISTEN")]
+    http_fallback_listen: Option<SocketAddr>,
 }
 
-#[derive(Clone)]
-struct AppState {
-    metrics: Arc<Metrics>,
-    router: Arc<MessageRouter>,
+impl Args {
+    /// Read the config file, if any, apply the command-line and environment
+    /// overrides, and validate the result
+    fn load_config(&self) -> Result<RelayConfig> {
+        let mut config = match &self.config {
+            Some(path) => RelayConfig::load(path)?,
+            None => RelayConfig::default(),
+        };
+        
+        if let Some(listen) = self.listen {
+            config.listen = listen;
+        }
+        if let Some(metrics_addr) = self.metrics_addr {
+            config.metrics_addr = metrics_addr;
+        }
+        config.devnet |= self.devnet;
+        if let Some(relay_id) = &self.relay_id {
+            config.relay_id = relay_id.clone();
+        }
+        if let Some(log_level) = &self.log_level {
+            config.log_level = log_level.clone();
+        }
+        if let Some(cert) = &self.cert {
+            config.tls.cert = Some(cert.clone());
+        }
+        if let Some(key) = &self.key {
+            config.tls.key = Some(key.clone());
+        }
+        
+        let connection = &mut config.connection;
+        if let Some(secs) = self.handshake_timeout_secs {
+            connection.handshake_timeout_secs = secs;
+        }
+        if let Some(secs) = self.keepalive_interval_secs {
+            connection.keepalive_interval_secs = secs;
+        }
+        if let Some(secs) = self.idle_timeout_secs {
+            connection.idle_timeout_secs = secs;
+        }
+        
+        let queue = &mut config.queue;
+        if let Some(path) = &self.queue_path {
+            queue.path = path.clone();
+        }
+        if let Some(max_messages) = self.queue_max_messages {
+            queue.max_messages_per_recipient = max_messages;
+        }
+        if let Some(max_bytes) = self.queue_max_bytes {
+            queue.max_bytes_per_recipient = max_bytes;
+        }
+        if let Some(total_bytes) = self.queue_total_bytes {
+            queue.max_total_bytes = total_bytes;
+        }
+        if let Some(policy) = self.queue_policy {
+            queue.policy = policy;
+        }
+        
+        let limits = &mut config.rate_limits;
+        if let Some(rate) = self.wallet_rate {
+            limits.per_wallet.per_second = rate;
+        }
+        if let Some(burst) = self.wallet_burst {
+            limits.per_wallet.burst = burst;
+        }
+        if let Some(rate) = self.ip_rate {
+            limits.per_ip.per_second = rate;
+        }
+        if let Some(burst) = self.ip_burst {
+            limits.per_ip.burst = burst;
+        }
+        if let Some(rate) = self.recipient_rate {
+            limits.per_recipient.per_second = rate;
+        }
+        if let Some(burst) = self.recipient_burst {
+            limits.per_recipient.burst = burst;
+        }
+        if let Some(secs) = self.drain_timeout_secs {
+            config.shutdown.drain_timeout_secs = secs;
+        }
+        if let Some(key) = &self.federation_key {
+            config.federation.key = Some(key.clone());
+        }
+        
+        let cluster = &mut config.cluster;
+        if let Some(node_id) = &self.node_id {
+            cluster.node_id = Some(node_id.clone());
+        }
+        if let Some(address) = &self.node_address {
+            cluster.address = Some(address.clone());
+        }
+        if let Some(presence) = &self.presence {
+            cluster.presence = presence.clone();
+        }
+        if let Some(token) = &self.admin_token {
+            config.admin.token = Some(token.clone());
+        }
+        if let Some(listen) = self.websocket_listen {
+            config.websocket.listen = Some(listen);
+        }
+        if let Some(listen) = self.http_fallback_listen {
+            config.http_fallback.listen = Some(listen);
+        }
+        
+        config.validate().context("Invalid relay configuration")?;
+        Ok(config)
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-    
     let args = Args::parse();
+    let config = args.load_config()?;
+    
+    let (log_filter, log_filter_handle) = reload::Layer::new(EnvFilter::try_new(&config.log_level)?);
+    tracing_subscriber::registry()
+        .with(log_filter)
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+    
     let metrics = Arc::new(Metrics::new());
-    let router = Arc::new(MessageRouter::new(metrics.clone()));
     
-    // Start the metrics updater task
+    let cert_resolver = Arc::new(load_certificate(&config)?);
+    cert_resolver.clone().start_watcher(config.tls.reload_interval());
+    let server_config = configure_server(cert_resolver.clone());
+    let endpoint = Endpoint::server(server_config, config.listen)?;
+    
+    let message_queue = Arc::new(FileQueueStore::open(&config.queue.path)?);
+    let presence = presence::open(&config.cluster.presence)?;
+    let mut router = MessageRouter::with_store(metrics.clone(), message_queue)
+        .with_presence(presence.clone(), config.node_id());
+    let mut federation = Federation::from_config(&config, endpoint.clone(), metrics.clone())?;
+    if let (true, Some(address)) = (config.cluster.is_clustered(), &config.cluster.address) {
+        info!("🧩 Joining the cluster as node {}", config.node_id());
+        federation = federation.map(|federation| {
+            federation.with_cluster(presence.clone(), address.clone(), config.cluster.heartbeat_interval())
+        });
+    }
+    let federation = federation.map(Arc::new);
+    if let Some(federation) = &federation {
+        info!("🌐 Federation enabled, relay key {}", federation.public_key());
+        router = router.with_federation(federation.clone());
+    }
+    let router = Arc::new(router);
+    router.set_queue_limits(config.queue.limits());
+    if let Some(federation) = federation {
+        federation.start(router.clone(), config.federation.reconnect_interval());
+    }
+    
+    // Start the metrics updater and queue expiry tasks
     router.clone().start_metrics_updater();
+    router.clone().start_expiry_sweeper(config.queue.sweep_interval());
     
     let state = AppState {
         metrics: metrics.clone(),
         router,
+        rate_limiter: Arc::new(RateLimiter::new(config.rate_limits)),
+        shutdown: Arc::new(Shutdown::new()),
+        relay_id: config.relay_id.clone().into(),
+        handshake_timeout: config.connection.handshake_timeout(),
+        keepalive_interval: config.connection.keepalive_interval(),
+        idle_timeout: config.connection.idle_timeout(),
     };
     
     info!(
         "🚀 Starting SolConnect relay server on {} (devnet: {}, metrics: {})",
-        args.listen, args.devnet, args.metrics_addr
+        config.listen, config.devnet, config.metrics_addr
     );
     
-    // Start metrics HTTP server
-    let metrics_server = start_metrics_server(args.metrics_addr, metrics.clone());
+    let readiness = Arc::new(Readiness::new(endpoint.clone(), state.router.clone(), state.shutdown.clone()));
+    tokio::spawn(reload_on_sighup(args, config.clone(), state.clone(), readiness.clone(), log_filter_handle));
     
-    // Start QUIC server
-    let server_config = configure_server()?;
-    let endpoint = Endpoint::server(server_config, args.listen)?;
+    // Start metrics HTTP server; it keeps serving while the relay drains
+    let admin = config.admin.token.clone().map(|token| Arc::new(AdminApi::new(state.router.clone(), token)));
+    let metrics_server = tokio::spawn(start_metrics_server(config.metrics_addr, metrics.clone(), readiness, admin));
     
-    info!("✅ QUIC server listening on {}", args.listen);
-    info!("📊 Metrics server listening on {}/metrics", args.metrics_addr);
+    info!("✅ QUIC server listening on {}", config.listen);
+    if let Some(addr) = config.websocket.listen {
+        let listener = tokio::net::TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Failed to bind the WebSocket listener on {}", addr))?;
+        let tls = config.websocket.tls.then(|| cert_resolver.clone());
+        let gateway = Arc::new(WebSocketGateway::new(state.clone(), tls));
+        tokio::spawn(gateway.serve(listener));
+        let scheme = if config.websocket.tls { "wss" } else { "ws" };
+        info!("🌍 WebSocket gateway listening on {}://{}", scheme, addr);
+    }
+    if let Some(addr) = config.http_fallback.listen {
+        let listener = tokio::net::TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Failed to bind the HTTP fallback listener on {}", addr))?;
+        let fallback = &config.http_fallback;
+        let tls = fallback.tls.then(|| cert_resolver.clone());
+        let gateway = Arc::new(HttpFallback::new(state.clone(), tls, fallback.poll_timeout(), fallback.session_timeout()));
+        tokio::spawn(gateway.serve(listener));
+        let scheme = if fallback.tls { "https" } else { "http" };
+        info!("🐢 HTTP fallback listening on {}://{}", scheme, addr);
+    }
+    info!("📊 Metrics server listening on {}/metrics", config.metrics_addr);
     
-    let quic_server = async move {
+    let quic_server = async {
         while let Some(conn) = endpoint.accept().await {
             let state = state.clone();
             tokio::spawn(handle_connection(conn, state));
         }
     };
     
-    // Run both servers concurrently
+    // Run both servers until one fails or we are asked to stop
     tokio::select! {
         _ = metrics_server => error!("Metrics server stopped"),
         _ = quic_server => error!("QUIC server stopped"),
+        signal = shutdown_signal() => info!("🛑 Received {}, shutting down", signal),
+    }
+    
+    drain(&endpoint, &state, config.shutdown.drain_timeout()).await;
+    info!("👋 Relay stopped");
+    Ok(())
+}
+
+/// Wait for SIGTERM or SIGINT, returning the signal's name
+async fn shutdown_signal() -> &'static str {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            error!("Failed to listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return "SIGINT";
+        }
+    };
+    
+    tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+    }
+}
+
+/// Shut the relay down without losing messages
+///
+/// New connections are refused, the replica leaves its cluster, and every
+/// open connection is told to go away, after handing its client what the
+/// relay still holds for it. Every step, down to the final flush of the
+/// message queue, finishes within `timeout`: clients get what is left of
+/// it after the time reserved for closing connections and flushing.
+async fn drain(endpoint: &Endpoint, state: &AppState, timeout: Duration) {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let flush_by = deadline - FLUSH_RESERVE.min(timeout / 4);
+    let close_by = flush_by.checked_sub(CLOSE_GRACE).unwrap_or(flush_by);
+    let remaining = |until: tokio::time::Instant| until.saturating_duration_since(tokio::time::Instant::now());
+    
+    endpoint.reject_new_connections();
+    state.shutdown.begin();
+    info!(
+        "🚧 Draining {} connections (deadline {}s)",
+        state.shutdown.active_connections(),
+        timeout.as_secs()
+    );
+    
+    // Other replicas stop sending here while clients leave
+    let leave_cluster = async {
+        if let Some(federation) = state.router.federation() {
+            if tokio::time::timeout(remaining(close_by), federation.leave_cluster()).await.is_err() {
+                warn!("Timed out leaving the cluster");
+            }
+        }
+    };
+    let (_, all_closed) = tokio::join!(leave_cluster, state.shutdown.wait_for_connections(remaining(close_by)));
+    if !all_closed {
+        warn!(
+            "Drain deadline passed, closing {} remaining connections",
+            state.shutdown.active_connections()
+        );
+    }
+    endpoint.close(0u32.into(), SHUTDOWN_REASON.as_bytes());
+    
+    // Let the closed connections unregister, queueing anything still in flight
+    state.shutdown.wait_for_connections(remaining(flush_by)).await;
+    let _ = tokio::time::timeout(remaining(flush_by), endpoint.wait_idle()).await;
+    
+    let router = state.router.clone();
+    match tokio::time::timeout(remaining(deadline), tokio::task::spawn_blocking(move || router.flush_queue())).await {
+        Ok(Ok(Ok(()))) => info!("💾 Message queue flushed"),
+        Ok(Ok(Err(e))) => error!("Failed to flush the message queue: {:#}", e),
+        Ok(Err(e)) => error!("Failed to flush the message queue: {}", e),
+        Err(_) => error!("Timed out flushing the message queue"),
+    }
+}
+
+/// Reload the configuration whenever the process receives SIGHUP
+///
+/// Log level, queue limits and rate limits take effect immediately and
+/// existing connections are left alone. An invalid file is logged and
+/// ignored, keeping the running configuration, and reported on `/ready`
+/// until a reload succeeds.
+async fn reload_on_sighup(
+    args: Args,
+    mut current: RelayConfig,
+    state: AppState,
+    readiness: Arc<Readiness>,
+    log_filter: LogFilterHandle,
+) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            error!("Failed to listen for SIGHUP, config reload disabled: {}", e);
+            return;
+        }
+    };
+    
+    while hangups.recv().await.is_some() {
+        info!("🔄 Reloading configuration");
+        match args.load_config() {
+            Ok(config) => {
+                if let Err(e) = apply_config(&current, &config, &state, &log_filter) {
+                    error!("Failed to apply configuration: {:#}", e);
+                    readiness.set_config_error(Some(format!("{:#}", e)));
+                    continue;
+                }
+                readiness.set_config_error(None);
+                current = config;
+            }
+            Err(e) => {
+                error!("Keeping the running configuration: {:#}", e);
+                readiness.set_config_error(Some(format!("{:#}", e)));
+            }
+        }
+    }
+}
+
+/// Apply the settings that can change without a restart
+fn apply_config(
+    current: &RelayConfig,
+    config: &RelayConfig,
+    state: &AppState,
+    log_filter: &LogFilterHandle,
+) -> Result<()> {
+    if config.log_level != current.log_level {
+        log_filter.reload(EnvFilter::try_new(&config.log_level)?)?;
     }
+    state.router.set_queue_limits(config.queue.limits());
+    state.rate_limiter.set_limits(config.rate_limits);
     
+    let restart_required = config.restart_required(current);
+    if !restart_required.is_empty() {
+        warn!("Changes to {} take effect after a restart", restart_required.join(", "));
+    }
+    
+    info!("✅ Configuration reloaded");
     Ok(())
 }
 
-async fn start_metrics_server(addr: SocketAddr, metrics: Arc<Metrics>) -> Result<()> {
+async fn start_metrics_server(
+    addr: SocketAddr,
+    metrics: Arc<Metrics>,
+    readiness: Arc<Readiness>,
+    admin: Option<Arc<AdminApi>>,
+) -> Result<()> {
     let make_svc = make_service_fn(move |_conn| {
         let metrics = metrics.clone();
+        let readiness = readiness.clone();
+        let admin = admin.clone();
         async move {
             Ok::<_, Infallible>(service_fn(move |req| {
                 let metrics = metrics.clone();
-                handle_metrics_request(req, metrics)
+                let readiness = readiness.clone();
+                let admin = admin.clone();
+                handle_metrics_request(req, metrics, readiness, admin)
             }))
         }
     });
@@ -105,7 +568,13 @@ async fn start_metrics_server(addr: SocketAddr, metrics: Arc<Metrics>) -> Result
 async fn handle_metrics_request(
     req: Request<Body>,
     metrics: Arc<Metrics>,
+    readiness: Arc<Readiness>,
+    admin: Option<Arc<AdminApi>>,
 ) -> Result<Response<Body>, Infallible> {
+    if let (Some(admin), true) = (&admin, req.uri().path().starts_with("/admin/")) {
+        return Ok(admin.handle(req).await);
+    }
+    
     match req.uri().path() {
         "/metrics" => {
             match metrics.export_metrics() {
@@ -123,6 +592,16 @@ async fn handle_metrics_request(
             }
         }
         "/health" => Ok(Response::new(Body::from("OK"))),
+        "/ready" => {
+            let report = readiness.report();
+            let status = if report.ready { 200 } else { 503 };
+            let body = serde_json::to_vec(&report).unwrap_or_default();
+            Ok(Response::builder()
+                .status(status)
+                .header("Content-Type", "application/json")
+                .body(Body::from(body))
+                .unwrap())
+        }
         _ => Ok(Response::builder()
             .status(404)
             .body(Body::from("Not Found"))
@@ -130,26 +609,73 @@ async fn handle_metrics_request(
     }
 }
 
-fn configure_server() -> Result<ServerConfig> {
-    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
-    let cert_der = cert.serialize_der()?;
-    let priv_key = cert.serialize_private_key_der();
+/// Certificate from the configured files, or a self-signed one on devnet
+fn load_certificate(config: &RelayConfig) -> Result<CertResolver> {
+    match (&config.tls.cert, &config.tls.key) {
+        (Some(cert), Some(key)) => CertResolver::from_files(cert, key),
+        _ => {
+            warn!("🔓 No TLS certificate configured, using a self-signed one (devnet only)");
+            let mut names = vec!["localhost".to_string()];
+            if config.relay_id != "localhost" {
+                names.push(config.relay_id.clone());
+            }
+            CertResolver::self_signed(names)
+        }
+    }
+}
+
+fn configure_server(cert_resolver: Arc<CertResolver>) -> ServerConfig {
+    ServerConfig::with_crypto(Arc::new(tls::server_crypto(cert_resolver)))
+}
+
+/// Authenticate the client with a challenge-response handshake
+///
+/// The relay sends a fresh `HandshakeChallenge` on a unidirectional stream.
+/// The client answers with a `HandshakeProof` on the first bidirectional
+/// stream and finishes its side; the relay always replies there with a
+/// `HandshakeResponse`. Returns the client's wallet and the device id it
+/// named, if any.
+async fn perform_handshake(connection: &quinn::Connection, relay_id: &str) -> Result<(WalletAddress, String)> {
+    let binding = channel_binding(connection)?;
+    let challenge = HandshakeChallenge::new(relay_id);
+    
+    let mut challenge_stream = connection.open_uni().await?;
+    challenge_stream.write_all(&prost::Message::encode_to_vec(&challenge)).await?;
+    challenge_stream.finish().await?;
     
-    let cert_chain = vec![rustls::Certificate(cert_der)];
-    let key_der = rustls::PrivateKey(priv_key);
+    let (mut send, mut recv) = connection.accept_bi().await?;
     
-    let server_config = rustls::ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(cert_chain, key_der)?;
+    let data = recv.read_to_end(MAX_HANDSHAKE_SIZE).await?;
+    let result = <HandshakeProof as prost::Message>::decode(&data[..])
+        .map_err(anyhow::Error::from)
+        .and_then(|proof| {
+            let wallet = challenge.verify_proof(&proof, &binding).map_err(|e| anyhow::anyhow!(e))?;
+            Ok((wallet, proof.device_id().to_string()))
+        });
+    
+    let response = match &result {
+        Ok(_) => HandshakeResponse::success(),
+        Err(e) => HandshakeResponse::failure(e.to_string()),
+    };
+    send.write_all(&prost::Message::encode_to_vec(&response)).await?;
+    send.finish().await?;
     
-    Ok(ServerConfig::with_crypto(Arc::new(server_config)))
+    result
 }
 
 async fn handle_connection(conn: quinn::Connecting, state: AppState) {
     let connection_start = Instant::now();
+    let guard = state.shutdown.track();
     
     match conn.await {
+        Ok(connection) if federation::is_relay_link(&connection) => {
+            // Links are not drained like clients; they close with the endpoint
+            drop(guard);
+            match state.router.federation() {
+                Some(federation) => federation.clone().accept(connection, state.router.clone()).await,
+                None => connection.close(1u32.into(), b"federation disabled"),
+            }
+        }
         Ok(connection) => {
             let remote_addr = connection.remote_address();
             state.metrics.increment_connections();
@@ -159,57 +685,162 @@ async fn handle_connection(conn: quinn::Connecting, state: AppState) {
             
             info!("🔗 New connection established");
             
-            // Create a channel for sending messages to this client
+            let (wallet, device_id) = match tokio::time::timeout(state.handshake_timeout, perform_handshake(&connection, &state.relay_id)).await {
+                Ok(Ok(client)) => client,
+                Ok(Err(e)) => {
+                    warn!("Handshake failed: {}", e);
+                    close_unauthenticated(&connection, &state, connection_start, b"handshake failed");
+                    return;
+                }
+                Err(_) => {
+                    warn!("Handshake timed out");
+                    close_unauthenticated(&connection, &state, connection_start, b"handshake timeout");
+                    return;
+                }
+            };
+            
+            info!("🤝 Handshake completed for {}", wallet);
+            
+            if state.router.is_banned(&wallet.to_string()) {
+                warn!("Refusing banned wallet {}", wallet);
+                close_unauthenticated(&connection, &state, connection_start, b"wallet banned");
+                return;
+            }
+            
+            // Create a channel for sending messages to this client. The router
+            // keeps the only strong sender, so the channel closes once it drops
+            // the connection.
             let (tx, mut rx) = mpsc::channel::<RoutableMessage>(100);
+            let weak_tx = tx.downgrade();
+            
+            // A client that cannot be registered would never be sent anything
+            if let Err(e) = state.router.register_device(wallet.clone(), &device_id, tx, remote_addr).await {
+                error!("Failed to register client {}: {:#}", wallet, e);
+                if let Some(tx) = weak_tx.upgrade() {
+                    let _ = state.router.unregister_client_channel(&wallet, &tx).await;
+                }
+                connection.close(1u32.into(), b"registration failed");
+                state.metrics.record_connection_duration(connection_start.elapsed().as_secs_f64());
+                state.metrics.decrement_connections();
+                return;
+            }
             
             // Handle incoming streams and outgoing messages concurrently
             let incoming_state = state.clone();
             let outgoing_state = state.clone();
             let connection_clone = connection.clone();
+            let keepalive = Arc::new(Keepalive::new());
+            let incoming_keepalive = keepalive.clone();
+            let incoming_wallet = wallet.clone();
+            let outgoing_wallet = wallet.clone();
+            let outgoing_tx = weak_tx.clone();
             
-            let incoming_task = tokio::spawn(async move {
+            let mut incoming_task = tokio::spawn(async move {
                 while let Ok((mut send, mut recv)) = connection.accept_bi().await {
                     let state = incoming_state.clone();
-                    let tx = tx.clone();
+                    let keepalive = incoming_keepalive.clone();
                     let addr = remote_addr;
+                    let wallet = incoming_wallet.clone();
+                    let device_id = device_id.clone();
                     tokio::spawn(async move {
-                        if let Err(e) = handle_stream(&mut send, &mut recv, state, tx, addr).await {
+                        if let Err(e) = handle_stream(&mut send, &mut recv, state, &wallet, &device_id, addr, keepalive).await {
                             error!("Stream error: {}", e);
                         }
                     });
                 }
             });
             
-            // Task to handle outgoing messages
-            let outgoing_task = tokio::spawn(async move {
-                while let Some(routable_msg) = rx.recv().await {
-                    match connection_clone.open_bi().await {
-                        Ok((mut send, _recv)) => {
-                            let msg_bytes = prost::Message::encode_to_vec(&routable_msg.message);
-                            if let Err(e) = send.write_all(&msg_bytes).await {
-                                error!("Failed to forward message: {}", e);
-                            } else {
-                                if let Err(e) = send.finish().await {
-                                    error!("Failed to finish stream: {}", e);
+            // Task to handle outgoing messages and keepalives, framed onto one
+            // unidirectional stream that is reopened if a write fails. When the
+            // relay drains, the messages already in the channel are forwarded,
+            // followed by a GoAway; the task returns whether it sent one.
+            let mut outgoing_task = tokio::spawn(async move {
+                let mut stream: Option<quinn::SendStream> = None;
+                let mut keepalive_timer = tokio::time::interval(outgoing_state.keepalive_interval);
+                keepalive_timer.tick().await;
+                let mut draining = false;
+                
+                loop {
+                    let envelope: Envelope = tokio::select! {
+                        routable_msg = rx.recv() => match routable_msg {
+                            Some(routable_msg) => routable_msg.message.to_envelope(),
+                            None => {
+                                if !draining {
+                                    // Replaced by a newer connection or disconnected by an operator
+                                    connection_clone.close(0u32.into(), b"disconnected by the relay");
                                 }
-                                outgoing_state.metrics.record_bytes_sent(msg_bytes.len());
-                                debug!("📨 Forwarded message to recipient");
+                                break;
                             }
+                        },
+                        _ = outgoing_state.shutdown.draining(), if !draining => {
+                            // Queue new messages for the wallet's next connection, and
+                            // close the channel so the loop ends once it is empty
+                            draining = true;
+                            if let Some(tx) = outgoing_tx.upgrade() {
+                                if let Err(e) = outgoing_state.router.unregister_client_channel(&outgoing_wallet, &tx).await {
+                                    error!("Failed to unregister client {}: {}", outgoing_wallet, e);
+                                }
+                            }
+                            rx.close();
+                            continue;
                         }
-                        Err(e) => {
-                            error!("Failed to open stream for message forwarding: {}", e);
+                        _ = keepalive_timer.tick() => {
+                            if keepalive.idle_for() >= outgoing_state.idle_timeout {
+                                info!("💤 Closing idle connection");
+                                outgoing_state.metrics.record_idle_disconnect();
+                                connection_clone.close(0u32.into(), b"idle timeout");
+                                break;
+                            }
+                            keepalive.next_ping().into()
                         }
+                    };
+                    
+                    let send = match stream.as_mut() {
+                        Some(send) => send,
+                        None => match connection_clone.open_uni().await {
+                            Ok(send) => stream.insert(send),
+                            Err(e) => {
+                                error!("Failed to open stream for message forwarding: {}", e);
+                                break;
+                            }
+                        },
+                    };
+                    
+                    let frame = codec::encode_frame(&envelope);
+                    if let Err(e) = send.write_all(&frame).await {
+                        error!("Failed to forward message: {}", e);
+                        stream = None;
+                        continue;
                     }
+                    
+                    outgoing_state.metrics.record_bytes_sent(frame.len());
+                    debug!("📨 Sent frame to client");
                 }
+                
+                draining && send_go_away(&connection_clone, stream, &outgoing_state).await
             });
             
             // Wait for either task to complete
-            tokio::select! {
-                _ = incoming_task => {
+            let told_to_leave = tokio::select! {
+                _ = &mut incoming_task => {
                     debug!("Incoming task completed");
+                    false
                 }
-                _ = outgoing_task => {
+                result = &mut outgoing_task => {
                     debug!("Outgoing task completed");
+                    result.unwrap_or(false)
+                }
+            };
+            
+            // Let a client that was sent a GoAway finish its streams and close
+            // the connection itself; the drain deadline bounds the wait
+            if told_to_leave {
+                let _ = incoming_task.await;
+            }
+            
+            if let Some(tx) = weak_tx.upgrade() {
+                if let Err(e) = state.router.unregister_client_channel(&wallet, &tx).await {
+                    error!("Failed to unregister client {}: {}", wallet, e);
                 }
             }
             
@@ -225,62 +856,108 @@ async fn handle_connection(conn: quinn::Connecting, state: AppState) {
     }
 }
 
+/// Send the GoAway that ends a drained connection's outgoing stream
+///
+/// Waits until the client has acknowledged everything sent on the stream,
+/// so the messages forwarded before it are known to have arrived. Returns
+/// whether the GoAway was delivered.
+async fn send_go_away(connection: &quinn::Connection, stream: Option<quinn::SendStream>, state: &AppState) -> bool {
+    let mut send = match stream {
+        Some(send) => send,
+        None => match connection.open_uni().await {
+            Ok(send) => send,
+            Err(e) => {
+                debug!("Connection closed before the GoAway: {}", e);
+                return false;
+            }
+        },
+    };
+    
+    let frame = codec::encode_frame(&GoAwayMessage::new(SHUTDOWN_REASON).into());
+    if let Err(e) = send.write_all(&frame).await {
+        warn!("Failed to send GoAway: {}", e);
+        return false;
+    }
+    state.metrics.record_bytes_sent(frame.len());
+    
+    match send.finish().await {
+        Ok(()) => {
+            info!("🚪 Sent GoAway");
+            true
+        }
+        Err(e) => {
+            warn!("GoAway was not acknowledged: {}", e);
+            false
+        }
+    }
+}
+
+/// Close a connection that never completed its handshake
+fn close_unauthenticated(
+    connection: &quinn::Connection,
+    state: &AppState,
+    connection_start: Instant,
+    reason: &[u8],
+) {
+    connection.close(1u32.into(), reason);
+    state.metrics.record_handshake_failed();
+    state.metrics.record_connection_duration(connection_start.elapsed().as_secs_f64());
+    state.metrics.decrement_connections();
+}
+
+/// Read envelopes from a client stream and route them
+///
+/// The stream carries length-prefixed frames; a frame may span several reads
+/// and one read may hold several frames. Each envelope is handled by
+/// `session::handle_envelope`, and its reply, if any, is written back on
+/// the same stream.
 async fn handle_stream(
     send: &mut quinn::SendStream,
     recv: &mut quinn::RecvStream,
     state: AppState,
-    client_tx: mpsc::Sender<RoutableMessage>,
+    wallet: &WalletAddress,
+    device_id: &str,
     remote_addr: SocketAddr,
+    keepalive: Arc<Keepalive>,
 ) -> Result<()> {
+    let mut decoder = FrameDecoder::new();
     let mut buf = [0u8; 65536]; // 64KB buffer
     
     while let Some(len) = recv.read(&mut buf).await? {
-        if len == 0 {
-            break;
-        }
-        
-        let start_time = Instant::now();
-        let data = &buf[..len];
-        
         state.metrics.record_bytes_received(len);
+        decoder.extend(&buf[..len]);
         
-        debug!("📨 Received {} bytes", len);
-        
-        let relay_message = if let Ok(chat_msg) = prost::Message::decode(data) {
-            RelayMessage::Chat(chat_msg)
-        } else if let Ok(ack_msg) = prost::Message::decode(data) {
-            RelayMessage::Ack(ack_msg)
-        } else if let Ok(read_receipt_msg) = prost::Message::decode(data) {
-            RelayMessage::ReadReceipt(read_receipt_msg)
-        } else if let Ok(ping_msg) = prost::Message::decode(data) {
-            RelayMessage::Ping(ping_msg)
-        } else if let Ok(pong_msg) = prost::Message::decode(data) {
-            RelayMessage::Pong(pong_msg)
-        } else {
-            warn!("Failed to decode incoming message as any known type");
-            state.metrics.record_message_failed();
-            return Ok(());
-        };
-
-        if let Err(e) = state.router.route_message(relay_message, remote_addr).await {
-            error!("Failed to route message: {}", e);
+        while let Some(envelope) = decoder.next_frame().map_err(|e| {
             state.metrics.record_message_failed();
-        } else {
-            let duration = start_time.elapsed().as_secs_f64();
-            state.metrics.record_latency(duration);
-            state.metrics.record_message_processed(len, "RoutedMessage");
+            anyhow::anyhow!(e)
+        })? {
+            if let Some(reply) = session::handle_envelope(&state, wallet, device_id, remote_addr, &keepalive, envelope).await {
+                let frame = codec::encode_frame(&reply);
+                send.write_all(&frame).await?;
+                state.metrics.record_bytes_sent(frame.len());
+            }
         }
     }
     
+    if decoder.has_partial_frame() {
+        warn!("Stream ended in the middle of a frame");
+        state.metrics.record_message_failed();
+    }
+    
     send.finish().await?;
     Ok(())
 }
 
-
-
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::queue_limits::QueueLimits;
+    use crate::queue_store::tests::FailingRemovals;
+    use crate::queue_store::QueueStore;
+    use crate::rate_limit::{RateLimit, RateLimits};
+    use crate::router::{RelayMessage, DEFAULT_DEVICE};
+    use crate::session::test_keypair;
+    use solchat_protocol::messages::{AckMessage, AckStatus, ChatMessage, PingMessage, PongMessage, ALPN_PROTOCOL};
     use solchat_protocol::WalletAddress;
 
     #[tokio::test]
@@ -309,8 +986,508 @@ mod tests {
     }
     
     #[test]
-    fn test_server_config_creation() {
-        let config = configure_server();
-        assert!(config.is_ok());
+    fn test_self_signed_certificate_only_on_devnet() {
+        let config = RelayConfig {
+            devnet: true,
+            ..RelayConfig::default()
+        };
+        assert!(load_certificate(&config).is_ok());
+        
+        let args = Args::try_parse_from(["solchat_relay"]).unwrap();
+        let error = args.load_config().unwrap_err();
+        assert!(format!("{:#}", error).contains("devnet"));
+    }
+    
+    /// Client endpoint that trusts the given certificates and offers the
+    /// SolConnect ALPN identifier
+    fn client_endpoint(trusted: &[rustls::Certificate]) -> Endpoint {
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in trusted {
+            roots.add(cert).unwrap();
+        }
+        let mut crypto = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        crypto.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+        
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+        client
+    }
+    
+    /// Server and client endpoints on localhost that trust each other
+    fn loopback_endpoints() -> (Endpoint, Endpoint) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let cert_der = rustls::Certificate(cert.serialize_der().unwrap());
+        let key_der = rustls::PrivateKey(cert.serialize_private_key_der());
+        let mut crypto = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert_der.clone()], key_der)
+            .unwrap();
+        crypto.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+        let server_config = ServerConfig::with_crypto(Arc::new(crypto));
+        let server = Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap();
+        
+        (server, client_endpoint(&[cert_der]))
+    }
+    
+    #[tokio::test]
+    async fn test_endpoint_serves_rotated_certificate_with_alpn() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert_path = dir.path().join("tls.crt");
+        let key_path = dir.path().join("tls.key");
+        let write_cert = || {
+            let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+            let cert_pem = cert.serialize_pem().unwrap();
+            std::fs::write(&cert_path, &cert_pem).unwrap();
+            std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+            rustls::Certificate(rustls_pemfile::certs(&mut cert_pem.as_bytes()).unwrap().remove(0))
+        };
+        
+        let first = write_cert();
+        let resolver = Arc::new(CertResolver::from_files(&cert_path, &key_path).unwrap());
+        let server = Endpoint::server(configure_server(resolver.clone()), "127.0.0.1:0".parse().unwrap()).unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Some(connecting) = server.accept().await {
+                let _ = connecting.await;
+            }
+        });
+        
+        let client = client_endpoint(std::slice::from_ref(&first));
+        let connection = client.connect(server_addr, "localhost").unwrap().await.unwrap();
+        let handshake = connection
+            .handshake_data()
+            .unwrap()
+            .downcast::<quinn::crypto::rustls::HandshakeData>()
+            .unwrap();
+        assert_eq!(handshake.protocol.as_deref(), Some(ALPN_PROTOCOL));
+        
+        // After rotation new connections get the new certificate from the
+        // same endpoint
+        let second = write_cert();
+        assert!(resolver.reload_if_changed().unwrap());
+        let client = client_endpoint(std::slice::from_ref(&second));
+        let connection = client.connect(server_addr, "localhost").unwrap().await.unwrap();
+        let peer_certs = connection
+            .peer_identity()
+            .unwrap()
+            .downcast::<Vec<rustls::Certificate>>()
+            .unwrap();
+        assert_eq!(peer_certs[0], second);
+        
+        // Clients pinned to the old certificate are turned away
+        let client = client_endpoint(&[first]);
+        assert!(client.connect(server_addr, "localhost").unwrap().await.is_err());
+    }
+    
+    async fn client_handshake(
+        connection: &quinn::Connection,
+        relay_id: &str,
+        signer: &ed25519_dalek::Keypair,
+    ) -> Result<(HandshakeProof, HandshakeResponse)> {
+        let mut challenge_stream = connection.accept_uni().await?;
+        let data = challenge_stream.read_to_end(MAX_HANDSHAKE_SIZE).await?;
+        let challenge = <HandshakeChallenge as prost::Message>::decode(&data[..])?;
+        
+        let proof = HandshakeProof::sign(&challenge, relay_id, &channel_binding(connection)?, signer)
+            .map_err(|e| anyhow::anyhow!(e))?;
+        let response = send_proof(connection, &proof).await?;
+        Ok((proof, response))
+    }
+    
+    async fn send_proof(connection: &quinn::Connection, proof: &HandshakeProof) -> Result<HandshakeResponse> {
+        let (mut send, mut recv) = connection.open_bi().await?;
+        send.write_all(&prost::Message::encode_to_vec(proof)).await?;
+        send.finish().await?;
+        
+        let data = recv.read_to_end(MAX_HANDSHAKE_SIZE).await?;
+        Ok(<HandshakeResponse as prost::Message>::decode(&data[..])?)
+    }
+    
+    #[tokio::test]
+    async fn test_handshake_challenge_response() {
+        let signer = test_keypair(8);
+        
+        let (server, client) = loopback_endpoints();
+        let server_addr = server.local_addr().unwrap();
+        
+        let relay = tokio::spawn(async move {
+            let mut results = Vec::new();
+            for _ in 0..2 {
+                let connection = server.accept().await.unwrap().await.unwrap();
+                results.push(perform_handshake(&connection, "localhost").await.map_err(|e| e.to_string()));
+            }
+            results
+        });
+        
+        // Honest client
+        let first = client.connect(server_addr, "localhost").unwrap().await.unwrap();
+        let (proof, response) = client_handshake(&first, "localhost", &signer).await.unwrap();
+        assert!(response.success);
+        
+        // Replaying the captured proof on a new connection fails
+        let second = client.connect(server_addr, "localhost").unwrap().await.unwrap();
+        second.accept_uni().await.unwrap();
+        let response = send_proof(&second, &proof).await.unwrap();
+        assert!(!response.success);
+        
+        let results = relay.await.unwrap();
+        assert_eq!(results[0].as_ref().unwrap().0.as_bytes(), &signer.public.to_bytes());
+        assert!(results[1].is_err());
+    }
+    
+    /// Send `wire` over one stream to `handle_stream` in 7-byte writes, so
+    /// that frame boundaries and reads never line up, and return the reply
+    async fn exchange_on_stream(state: AppState, sender: WalletAddress, wire: Vec<u8>) -> Vec<u8> {
+        let (server, client) = loopback_endpoints();
+        let server_addr = server.local_addr().unwrap();
+        
+        let relay = tokio::spawn(async move {
+            let connection = server.accept().await.unwrap().await.unwrap();
+            let remote_addr = connection.remote_address();
+            let (mut send, mut recv) = connection.accept_bi().await.unwrap();
+            let keepalive = Arc::new(Keepalive::new());
+            handle_stream(&mut send, &mut recv, state, &sender, DEFAULT_DEVICE, remote_addr, keepalive).await.unwrap();
+        });
+        
+        let connection = client.connect(server_addr, "localhost").unwrap().await.unwrap();
+        let (mut send, mut recv) = connection.open_bi().await.unwrap();
+        for chunk in wire.chunks(7) {
+            send.write_all(chunk).await.unwrap();
+        }
+        send.finish().await.unwrap();
+        let reply = recv.read_to_end(MAX_HANDSHAKE_SIZE).await.unwrap();
+        relay.await.unwrap();
+        reply
+    }
+    
+    fn next_ack(decoder: &mut FrameDecoder) -> solchat_protocol::messages::AckMessage {
+        let envelope = decoder.next_frame().unwrap().unwrap();
+        let Some(solchat_protocol::messages::envelope::Body::Ack(ack)) = envelope.body else {
+            panic!("expected an ack");
+        };
+        ack
+    }
+    
+    #[tokio::test]
+    async fn test_stream_carries_several_framed_messages() {
+        let state = AppState::for_tests();
+        
+        let signer = test_keypair(1);
+        let sender = WalletAddress::new(signer.public.to_bytes());
+        let recipient = WalletAddress::test_address(2);
+        let mut wire = Vec::new();
+        let mut message_ids = Vec::new();
+        for payload in [&b"first"[..], &b"second"[..]] {
+            let mut message = ChatMessage::new(&sender, &recipient, payload.to_vec(), Vec::new());
+            message.sign(&signer).unwrap();
+            message_ids.push(message.id.clone());
+            wire.extend(codec::encode_frame(&message.into()));
+        }
+        wire.extend(codec::encode_frame(&PingMessage::new(7).into()));
+        
+        let reply = exchange_on_stream(state.clone(), sender, wire).await;
+        
+        let stats = state.router.get_stats().await;
+        assert_eq!(stats.queued_messages, 2);
+        
+        // Each chat is acked as queued for the offline recipient
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&reply);
+        for message_id in &message_ids {
+            let ack = next_ack(&mut decoder);
+            assert_eq!(&ack.ref_message_id, message_id);
+            assert_eq!(ack.status(), AckStatus::Queued);
+        }
+        
+        // The ping is answered on the same stream rather than routed
+        let pong = decoder.next_frame().unwrap().unwrap();
+        assert!(matches!(
+            pong.body,
+            Some(solchat_protocol::messages::envelope::Body::Pong(PongMessage { sequence: 7, .. }))
+        ));
+        assert!(!decoder.has_partial_frame());
+    }
+    
+    #[tokio::test]
+    async fn test_messages_over_the_rate_limit_are_rejected() {
+        let state = AppState::for_tests();
+        state.rate_limiter.set_limits(RateLimits {
+            per_wallet: RateLimit::new(0.001, 2),
+            ..RateLimits::default()
+        });
+        
+        let signer = test_keypair(1);
+        let sender = WalletAddress::new(signer.public.to_bytes());
+        let recipient = WalletAddress::test_address(2);
+        let mut wire = Vec::new();
+        for _ in 0..3 {
+            let mut message = ChatMessage::new(&sender, &recipient, b"spam".to_vec(), Vec::new());
+            message.sign(&signer).unwrap();
+            wire.extend(codec::encode_frame(&message.into()));
+        }
+        
+        let reply = exchange_on_stream(state.clone(), sender, wire).await;
+        
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&reply);
+        assert_eq!(next_ack(&mut decoder).status(), AckStatus::Queued);
+        assert_eq!(next_ack(&mut decoder).status(), AckStatus::Queued);
+        
+        let rejected = next_ack(&mut decoder);
+        assert_eq!(rejected.status(), AckStatus::Rejected);
+        assert_eq!(rejected.reason.as_deref(), Some("sender rate limit exceeded"));
+        
+        assert_eq!(state.router.get_stats().await.queued_messages, 2);
+        assert_eq!(state.metrics.rate_limited.with_label_values(&["wallet"]).get(), 1);
+    }
+    
+    #[tokio::test]
+    async fn test_messages_speaking_for_another_wallet_are_rejected() {
+        use solchat_protocol::messages::ReadReceipt;
+        
+        let state = AppState::for_tests();
+        let signer = test_keypair(1);
+        let wallet = WalletAddress::new(signer.public.to_bytes());
+        let victim_signer = test_keypair(3);
+        let victim = WalletAddress::new(victim_signer.public.to_bytes());
+        let recipient = WalletAddress::test_address(2);
+        
+        // Signed by the victim, but sent over the attacker's session
+        let mut spoofed = ChatMessage::new(&victim, &recipient, b"spoofed".to_vec(), Vec::new());
+        spoofed.sign(&victim_signer).unwrap();
+        // Names the session's wallet but carries no signature
+        let unsigned = ChatMessage::new(&wallet, &recipient, b"unsigned".to_vec(), Vec::new());
+        let receipt = ReadReceipt {
+            id: "receipt-1".to_string(),
+            message_id: "msg-1".to_string(),
+            reader_wallet: victim.to_string(),
+            ..ReadReceipt::default()
+        };
+        
+        let mut wire = Vec::new();
+        for envelope in [spoofed.clone().into(), unsigned.clone().into(), receipt.clone().into()] {
+            wire.extend(codec::encode_frame(&envelope));
+        }
+        let reply = exchange_on_stream(state.clone(), wallet, wire).await;
+        
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&reply);
+        for (message_id, reason) in [
+            (&spoofed.id, "sender is not the authenticated wallet"),
+            (&unsigned.id, "invalid message signature"),
+            (&receipt.id, "reader is not the authenticated wallet"),
+        ] {
+            let ack = next_ack(&mut decoder);
+            assert_eq!(&ack.ref_message_id, message_id);
+            assert_eq!(ack.status(), AckStatus::Rejected);
+            assert_eq!(ack.reason.as_deref(), Some(reason));
+        }
+        assert_eq!(state.router.get_stats().await.queued_messages, 0);
+    }
+    
+    #[tokio::test]
+    async fn test_prekey_bundle_requests_are_answered_on_the_stream() {
+        use solchat_protocol::crypto::{PrekeyStore, X25519KeyPair};
+        use solchat_protocol::messages::envelope::Body;
+        use solchat_protocol::messages::PrekeyBundleRequest;
+        
+        let state = AppState::for_tests();
+        let owner_signer = test_keypair(4);
+        let owner = WalletAddress::new(owner_signer.public.to_bytes());
+        let prekeys = PrekeyStore::generate(&owner_signer, X25519KeyPair::generate(), 2);
+        let outcome = state.router
+            .route_message_from(RelayMessage::PrekeyBundle(prekeys.bundle(&owner_signer)), &owner, DEFAULT_DEVICE, "127.0.0.1:1234".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(outcome.status, AckStatus::Delivered);
+        
+        let unknown = WalletAddress::test_address(9);
+        let mut wire = Vec::new();
+        for wallet in [&owner, &unknown] {
+            wire.extend(codec::encode_frame(&PrekeyBundleRequest::new(wallet).into()));
+        }
+        let reply = exchange_on_stream(state.clone(), WalletAddress::test_address(1), wire).await;
+        
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&reply);
+        let Some(Body::PrekeyBundle(bundle)) = decoder.next_frame().unwrap().unwrap().body else {
+            panic!("expected a prekey bundle");
+        };
+        assert!(bundle.verify().is_ok());
+        assert_eq!(bundle.wallet_address, owner.to_string());
+        assert_eq!(bundle.one_time_prekeys.len(), 1);
+        
+        // A wallet without a bundle is answered with just its address
+        let Some(Body::PrekeyBundle(missing)) = decoder.next_frame().unwrap().unwrap().body else {
+            panic!("expected a prekey bundle");
+        };
+        assert_eq!(missing.wallet_address, unknown.to_string());
+        assert!(missing.signed_prekey.is_none());
+        assert!(!decoder.has_partial_frame());
+    }
+    
+    #[tokio::test]
+    async fn test_drain_forwards_held_messages_then_goes_away() {
+        use solchat_protocol::messages::envelope::Body;
+        
+        let signer = test_keypair(9);
+        let wallet = WalletAddress::new(signer.public.to_bytes());
+        let sender = WalletAddress::test_address(1);
+        let sender_addr: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        
+        let state = AppState::for_tests();
+        let (server, client) = loopback_endpoints();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn({
+            let (server, state) = (server.clone(), state.clone());
+            async move {
+                while let Some(conn) = server.accept().await {
+                    tokio::spawn(handle_connection(conn, state.clone()));
+                }
+            }
+        });
+        
+        let connection = client.connect(server_addr, "localhost").unwrap().await.unwrap();
+        let (_, response) = client_handshake(&connection, "localhost", &signer).await.unwrap();
+        assert!(response.success);
+        while state.router.get_stats().await.connected_clients == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        
+        let held = ChatMessage::new(&sender, &wallet, b"held".to_vec(), Vec::new());
+        let outcome = state.router.route_message(RelayMessage::Chat(held.clone()), sender_addr).await.unwrap();
+        assert_eq!(outcome.status, AckStatus::Delivered);
+        
+        let drain_task = tokio::spawn({
+            let (server, state) = (server.clone(), state.clone());
+            async move { drain(&server, &state, Duration::from_secs(10)).await }
+        });
+        
+        // The client gets the message the relay held for it, then the GoAway
+        let mut stream = connection.accept_uni().await.unwrap();
+        let data = stream.read_to_end(MAX_HANDSHAKE_SIZE).await.unwrap();
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&data);
+        assert!(matches!(decoder.next_frame().unwrap().unwrap().body, Some(Body::Chat(chat)) if chat.id == held.id));
+        assert!(matches!(decoder.next_frame().unwrap().unwrap().body, Some(Body::GoAway(_))));
+        assert!(decoder.next_frame().unwrap().is_none());
+        
+        // Messages for the departing client are queued for its next connection,
+        // and new connections are refused
+        let late = ChatMessage::new(&sender, &wallet, b"late".to_vec(), Vec::new());
+        let outcome = state.router.route_message(RelayMessage::Chat(late), sender_addr).await.unwrap();
+        assert_eq!(outcome.status, AckStatus::Queued);
+        assert!(client.connect(server_addr, "localhost").unwrap().await.is_err());
+        
+        // The drain completes as soon as the client leaves, well before the deadline
+        connection.close(0u32.into(), b"moving on");
+        tokio::time::timeout(Duration::from_secs(5), drain_task).await.unwrap().unwrap();
+        assert_eq!(state.shutdown.active_connections(), 0);
+    }
+    
+    #[tokio::test]
+    async fn test_clients_that_cannot_be_registered_are_closed() {
+        let signer = test_keypair(9);
+        let wallet = WalletAddress::new(signer.public.to_bytes());
+        
+        // An ack waiting for the wallet cannot be cleared once delivered
+        let store = Arc::new(FailingRemovals::default());
+        let ack = RoutableMessage {
+            message: RelayMessage::Ack(AckMessage::delivered("msg_1".to_string())),
+            sender_addr: "127.0.0.1:1234".parse().unwrap(),
+        };
+        store.push(&wallet.to_string(), ack).unwrap();
+        let state = AppState::for_tests();
+        let state = AppState {
+            router: Arc::new(MessageRouter::with_store(state.metrics.clone(), store)),
+            ..state
+        };
+        
+        let (server, client) = loopback_endpoints();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn({
+            let state = state.clone();
+            async move {
+                while let Some(conn) = server.accept().await {
+                    tokio::spawn(handle_connection(conn, state.clone()));
+                }
+            }
+        });
+        
+        let connection = client.connect(server_addr, "localhost").unwrap().await.unwrap();
+        let (_, response) = client_handshake(&connection, "localhost", &signer).await.unwrap();
+        assert!(response.success);
+        let closed = tokio::time::timeout(Duration::from_secs(5), connection.closed()).await.unwrap();
+        assert!(matches!(closed, quinn::ConnectionError::ApplicationClosed(close) if &close.reason[..] == b"registration failed"));
+        assert_eq!(state.router.get_stats().await.connected_clients, 0);
+    }
+    
+    #[tokio::test]
+    async fn test_drain_finishes_within_its_timeout() {
+        let state = AppState::for_tests();
+        let (server, _client) = loopback_endpoints();
+        
+        // A connection that never closes holds the drain up to its deadline,
+        // the flush included
+        let _stuck = state.shutdown.track();
+        let started = tokio::time::Instant::now();
+        drain(&server, &state, Duration::from_secs(2)).await;
+        assert!(started.elapsed() < Duration::from_millis(2200));
+    }
+    
+    #[test]
+    fn test_cli_overrides_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("relay.toml");
+        std::fs::write(&path, "relay_id = \"from-file\"\n[queue]\nmax_messages_per_recipient = 7\npolicy = \"reject-new\"\n").unwrap();
+        
+        let args = Args::try_parse_from([
+            "solchat_relay",
+            "--devnet",
+            "--config", path.to_str().unwrap(),
+            "--queue-policy", "drop-oldest",
+            "--wallet-burst", "3",
+        ]).unwrap();
+        let config = args.load_config().unwrap();
+        
+        assert_eq!(config.relay_id, "from-file");
+        assert_eq!(config.queue.max_messages_per_recipient, 7);
+        assert_eq!(config.queue.policy, EvictionPolicy::DropOldest);
+        assert_eq!(config.rate_limits.per_wallet.burst, 3);
+        
+        // An override can also make an otherwise valid file unusable
+        let args = Args::try_parse_from([
+            "solchat_relay",
+            "--devnet",
+            "--config", path.to_str().unwrap(),
+            "--idle-timeout-secs", "1",
+        ]).unwrap();
+        let error = args.load_config().unwrap_err();
+        assert!(format!("{:#}", error).contains("idle_timeout_secs"));
     }
-} 
\ No newline at end of file
+    
+    #[test]
+    fn test_reload_applies_limits_to_running_state() {
+        let state = AppState::for_tests();
+        let (_log_filter, log_filter_handle) = reload::Layer::<EnvFilter, Registry>::new(EnvFilter::new("info"));
+        
+        let current = RelayConfig::default();
+        let mut config = current.clone();
+        config.log_level = "debug".to_string();
+        config.queue.max_messages_per_recipient = 5;
+        config.rate_limits.per_ip = RateLimit::new(1.0, 1);
+        
+        apply_config(&current, &config, &state, &log_filter_handle).unwrap();
+        
+        assert_eq!(state.router.queue_limits(), QueueLimits {
+            max_messages_per_recipient: 5,
+            ..QueueLimits::default()
+        });
+        assert_eq!(state.rate_limiter.limits().per_ip, RateLimit::new(1.0, 1));
+        assert_eq!(log_filter_handle.with_current(|filter| filter.to_string()).unwrap(), "debug");
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Default number of message ids remembered
+pub const DEFAULT_MAX_ENTRIES: usize = 100_000;
+
+/// Default time a message id stays routable for acks and read receipts
+pub const DEFAULT_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Bounded, TTL-limited map from chat message id to its sender and recipient
+///
+/// Filled as chat messages are routed, so that acks and read receipts,
+/// which only carry the message id, can find their way back to the sender
+/// and clear the message from the recipient's queue. Messages forwarded by
+/// another relay also remember that relay, so replies go back the same way.
+/// The oldest entries are evicted first once the index is full.
+pub struct MessageIndex {
+    senders: HashMap<String, IndexEntry>,
+    order: VecDeque<(String, Instant)>,
+    max_entries: usize,
+    ttl: Duration,
+}
+
+struct IndexEntry {
+    sender_wallet: String,
+    recipient_wallet: String,
+    origin_relay: Option<String>,
+    inserted_at: Instant,
+}
+
+impl MessageIndex {
+    pub fn new(max_entries: usize, ttl: Duration) -> Self {
+        Self {
+            senders: HashMap::new(),
+            order: VecDeque::new(),
+            max_entries,
+            ttl,
+        }
+    }
+
+    /// Remember who sent a message and to whom
+    ///
+    /// Returns `false` if the id is already indexed for a different sender;
+    /// the original entry is kept so acks cannot be redirected.
+    pub fn insert(&mut self, message_id: &str, sender_wallet: &str, recipient_wallet: &str) -> bool {
+        let now = Instant::now();
+        self.prune(now);
+
+        if let Some(entry) = self.senders.get(message_id) {
+            return entry.sender_wallet == sender_wallet && entry.recipient_wallet == recipient_wallet;
+        }
+
+        while self.senders.len() >= self.max_entries {
+            let Some((oldest, _)) = self.order.pop_front() else {
+                break;
+            };
+            self.senders.remove(&oldest);
+        }
+
+        self.senders.insert(
+            message_id.to_string(),
+            IndexEntry {
+                sender_wallet: sender_wallet.to_string(),
+                recipient_wallet: recipient_wallet.to_string(),
+                origin_relay: None,
+                inserted_at: now,
+            },
+        );
+        self.order.push_back((message_id.to_string(), now));
+        true
+    }
+
+    /// Sender of a message, if it is still indexed
+    pub fn sender_of(&self, message_id: &str) -> Option<&str> {
+        self.get(message_id).map(|entry| entry.sender_wallet.as_str())
+    }
+
+    /// Recipient of a message, if it is still indexed
+    pub fn recipient_of(&self, message_id: &str) -> Option<&str> {
+        self.get(message_id).map(|entry| entry.recipient_wallet.as_str())
+    }
+
+    /// Record that an indexed message was forwarded by another relay
+    pub fn set_origin(&mut self, message_id: &str, relay_id: &str) {
+        if let Some(entry) = self.senders.get_mut(message_id) {
+            entry.origin_relay = Some(relay_id.to_string());
+        }
+    }
+
+    /// Relay that forwarded a message, if it came from one
+    pub fn origin_of(&self, message_id: &str) -> Option<&str> {
+        self.get(message_id).and_then(|entry| entry.origin_relay.as_deref())
+    }
+
+    fn get(&self, message_id: &str) -> Option<&IndexEntry> {
+        self.senders
+            .get(message_id)
+            .filter(|entry| entry.inserted_at.elapsed() < self.ttl)
+    }
+
+    pub fn len(&self) -> usize {
+        self.senders.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.senders.is_empty()
+    }
+
+    /// Drop entries older than the TTL
+    fn prune(&mut self, now: Instant) {
+        while let Some((message_id, inserted_at)) = self.order.front() {
+            if now.duration_since(*inserted_at) < self.ttl {
+                break;
+            }
+            self.senders.remove(message_id);
+            self.order.pop_front();
+        }
+    }
+}
+
+impl Default for MessageIndex {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_ENTRIES, DEFAULT_TTL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_oldest_entries_are_evicted_when_full() {
+        let mut index = MessageIndex::new(2, DEFAULT_TTL);
+        index.insert("msg_1", "alice", "bob");
+        index.insert("msg_2", "bob", "alice");
+        index.insert("msg_3", "carol", "alice");
+
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.sender_of("msg_1"), None);
+        assert_eq!(index.sender_of("msg_2"), Some("bob"));
+        assert_eq!(index.sender_of("msg_3"), Some("carol"));
+    }
+
+    #[test]
+    fn test_entries_expire() {
+        let mut index = MessageIndex::new(10, Duration::ZERO);
+        index.insert("msg_1", "alice", "bob");
+        assert_eq!(index.sender_of("msg_1"), None);
+
+        index.insert("msg_2", "bob", "alice");
+        assert_eq!(index.len(), 1);
+    }
+
+    #[test]
+    fn test_existing_sender_is_not_overwritten() {
+        let mut index = MessageIndex::default();
+        assert!(index.insert("msg_1", "alice", "bob"));
+        assert!(index.insert("msg_1", "alice", "bob"));
+        assert!(!index.insert("msg_1", "mallory", "bob"));
+        assert_eq!(index.sender_of("msg_1"), Some("alice"));
+        assert_eq!(index.recipient_of("msg_1"), Some("bob"));
+    }
+}
@@ -1,6 +1,6 @@
 use prometheus::{
     Histogram, IntCounter, IntGauge, Registry, TextEncoder,
-    HistogramVec,
+    HistogramVec, IntCounterVec, Opts,
 };
 
 // Metrics are like opinions - everyone has them, but some are more useful than others
@@ -12,14 +12,23 @@ pub struct Metrics {
     pub messages_failed: IntCounter,
     pub messages_routed: IntCounter,
     pub messages_queued: IntCounter,
+    pub messages_expired: IntCounter,
+    pub messages_evicted: IntCounter,
+    pub handshakes_failed: IntCounter,
+    pub idle_disconnects: IntCounter,
+    pub rate_limited: IntCounterVec,
+    pub messages_forwarded: IntCounterVec,
+    pub federation_links: IntGauge,
     pub bytes_received: IntCounter,
     pub bytes_sent: IntCounter,
     pub active_connections: IntGauge,
     pub registered_clients: IntGauge,
     pub queued_messages: IntGauge,
+    pub queued_bytes: IntGauge,
     pub message_latency: Histogram,
     pub message_size: HistogramVec,
     pub connection_duration: Histogram,
+    pub connection_rtt: Histogram,
 }
 
 impl Metrics {
@@ -46,6 +55,47 @@ impl Metrics {
             "Total number of messages queued for offline recipients"
         ).unwrap();
         
+        let messages_expired = IntCounter::new(
+            "solchat_messages_expired_total",
+            "Total number of messages dropped because their TTL ran out"
+        ).unwrap();
+        
+        let messages_evicted = IntCounter::new(
+            "solchat_messages_evicted_total",
+            "Total number of queued messages evicted to stay within queue limits"
+        ).unwrap();
+        
+        let handshakes_failed = IntCounter::new(
+            "solchat_handshakes_failed_total",
+            "Total number of rejected or failed client handshakes"
+        ).unwrap();
+        
+        let idle_disconnects = IntCounter::new(
+            "solchat_idle_disconnects_total",
+            "Total number of connections closed for inactivity"
+        ).unwrap();
+        
+        let rate_limited = IntCounterVec::new(
+            Opts::new(
+                "solchat_rate_limited_total",
+                "Total number of messages rejected by a rate limit"
+            ),
+            &["scope"]
+        ).unwrap();
+        
+        let messages_forwarded = IntCounterVec::new(
+            Opts::new(
+                "solchat_messages_forwarded_total",
+                "Total number of messages forwarded to other relays"
+            ),
+            &["relay", "result"]
+        ).unwrap();
+        
+        let federation_links = IntGauge::new(
+            "solchat_federation_links",
+            "Number of authenticated links to other relays"
+        ).unwrap();
+        
         let bytes_received = IntCounter::new(
             "solchat_bytes_received_total",
             "Total bytes received by the relay"
@@ -71,6 +121,11 @@ impl Metrics {
             "Current number of queued messages"
         ).unwrap();
         
+        let queued_bytes = IntGauge::new(
+            "solchat_queued_bytes",
+            "Current encoded size of all queued messages in bytes"
+        ).unwrap();
+        
         let message_latency = Histogram::with_opts(
             prometheus::HistogramOpts::new(
                 "solchat_message_latency_seconds", 
@@ -93,19 +148,35 @@ impl Metrics {
             ).buckets(vec![1.0, 10.0, 60.0, 300.0, 1800.0, 3600.0])
         ).unwrap();
         
+        let connection_rtt = Histogram::with_opts(
+            prometheus::HistogramOpts::new(
+                "solchat_connection_rtt_seconds",
+                "Keepalive ping round-trip time in seconds"
+            ).buckets(vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5])
+        ).unwrap();
+        
         // Register all metrics
         registry.register(Box::new(messages_processed.clone())).unwrap();
         registry.register(Box::new(messages_failed.clone())).unwrap();
         registry.register(Box::new(messages_routed.clone())).unwrap();
         registry.register(Box::new(messages_queued.clone())).unwrap();
+        registry.register(Box::new(messages_expired.clone())).unwrap();
+        registry.register(Box::new(messages_evicted.clone())).unwrap();
+        registry.register(Box::new(handshakes_failed.clone())).unwrap();
+        registry.register(Box::new(idle_disconnects.clone())).unwrap();
+        registry.register(Box::new(rate_limited.clone())).unwrap();
+        registry.register(Box::new(messages_forwarded.clone())).unwrap();
+        registry.register(Box::new(federation_links.clone())).unwrap();
         registry.register(Box::new(bytes_received.clone())).unwrap();
         registry.register(Box::new(bytes_sent.clone())).unwrap();
         registry.register(Box::new(active_connections.clone())).unwrap();
         registry.register(Box::new(registered_clients.clone())).unwrap();
         registry.register(Box::new(queued_messages.clone())).unwrap();
+        registry.register(Box::new(queued_bytes.clone())).unwrap();
         registry.register(Box::new(message_latency.clone())).unwrap();
         registry.register(Box::new(message_size.clone())).unwrap();
         registry.register(Box::new(connection_duration.clone())).unwrap();
+        registry.register(Box::new(connection_rtt.clone())).unwrap();
         
         Self {
             registry,
@@ -113,14 +184,23 @@ impl Metrics {
             messages_failed,
             messages_routed,
             messages_queued,
+            messages_expired,
+            messages_evicted,
+            handshakes_failed,
+            idle_disconnects,
+            rate_limited,
+            messages_forwarded,
+            federation_links,
             bytes_received,
             bytes_sent,
             active_connections,
             registered_clients,
             queued_messages,
+            queued_bytes,
             message_latency,
             message_size,
             connection_duration,
+            connection_rtt,
         }
     }
     
@@ -149,6 +229,35 @@ impl Metrics {
         self.messages_queued.inc();
     }
     
+    pub fn record_message_expired(&self) {
+        self.messages_expired.inc();
+    }
+    
+    pub fn record_message_evicted(&self) {
+        self.messages_evicted.inc();
+    }
+    
+    pub fn record_handshake_failed(&self) {
+        self.handshakes_failed.inc();
+    }
+    
+    pub fn record_idle_disconnect(&self) {
+        self.idle_disconnects.inc();
+    }
+    
+    pub fn record_rate_limited(&self, scope: &str) {
+        self.rate_limited.with_label_values(&[scope]).inc();
+    }
+    
+    /// `result` is `ok` or `failed`
+    pub fn record_message_forwarded(&self, relay_id: &str, result: &str) {
+        self.messages_forwarded.with_label_values(&[relay_id, result]).inc();
+    }
+    
+    pub fn set_federation_links(&self, count: i64) {
+        self.federation_links.set(count);
+    }
+    
     pub fn record_bytes_received(&self, bytes: usize) {
         self.bytes_received.inc_by(bytes as u64);
     }
@@ -173,6 +282,10 @@ impl Metrics {
         self.queued_messages.set(count);
     }
     
+    pub fn set_queued_bytes(&self, bytes: i64) {
+        self.queued_bytes.set(bytes);
+    }
+    
     pub fn record_latency(&self, duration: f64) {
         self.message_latency.observe(duration);
     }
@@ -180,6 +293,10 @@ impl Metrics {
     pub fn record_connection_duration(&self, duration: f64) {
         self.connection_duration.observe(duration);
     }
+    
+    pub fn record_rtt(&self, rtt: f64) {
+        self.connection_rtt.observe(rtt);
+    }
 }
 
 impl Default for Metrics {
@@ -0,0 +1,91 @@
+use anyhow::{anyhow, Result};
+use solchat_protocol::messages::PrekeyBundle;
+use solchat_protocol::WalletAddress;
+use std::collections::HashMap;
+use tokio::sync::RwLock;
+use tracing::{debug, info, warn};
+
+/// Maximum number of one-time prekeys stored per wallet
+const MAX_ONE_TIME_PREKEYS: usize = 100;
+
+/// Per-wallet store of published prekey bundles
+///
+/// Bundles are accepted only from the owning wallet, and verified against
+/// it before they are stored.
+/// Each fetch hands out at most one one-time prekey and removes it, so no
+/// one-time prekey is ever given to two senders.
+pub struct PrekeyDirectory {
+    bundles: RwLock<HashMap<String, PrekeyBundle>>,
+}
+
+impl PrekeyDirectory {
+    pub fn new() -> Self {
+        Self {
+            bundles: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Store a wallet's bundle, replacing any older one
+    ///
+    /// Only the wallet that owns the bundle may publish it.
+    pub async fn publish(&self, mut bundle: PrekeyBundle, publisher: &WalletAddress) -> Result<()> {
+        if bundle.wallet_address != publisher.to_string() {
+            return Err(anyhow!("Prekey bundle belongs to another wallet"));
+        }
+        bundle.verify_published().map_err(|e| anyhow!(e))?;
+
+        let mut bundles = self.bundles.write().await;
+        if let Some(existing) = bundles.get(&bundle.wallet_address) {
+            // Replaying an old bundle must not resurrect handed-out prekeys
+            if bundle.timestamp <= existing.timestamp {
+                warn!("Ignoring stale prekey bundle for {}", bundle.wallet_address);
+                return Err(anyhow!("Prekey bundle is older than the stored one"));
+            }
+        }
+
+        bundle.one_time_prekeys.truncate(MAX_ONE_TIME_PREKEYS);
+        info!(
+            "🔑 Stored prekey bundle for {} ({} one-time prekeys)",
+            bundle.wallet_address,
+            bundle.one_time_prekeys.len()
+        );
+        bundles.insert(bundle.wallet_address.clone(), bundle);
+
+        Ok(())
+    }
+
+    /// Hand out a wallet's bundle with at most one one-time prekey
+    pub async fn fetch(&self, wallet_address: &str) -> Option<PrekeyBundle> {
+        let mut bundles = self.bundles.write().await;
+        let stored = bundles.get_mut(wallet_address)?;
+
+        let one_time_prekey = if stored.one_time_prekeys.is_empty() {
+            None
+        } else {
+            Some(stored.one_time_prekeys.remove(0))
+        };
+
+        debug!(
+            "🔑 Handing out prekey bundle for {} ({} one-time prekeys left)",
+            wallet_address,
+            stored.one_time_prekeys.len()
+        );
+        Some(stored.with_single_one_time_prekey(one_time_prekey))
+    }
+
+    /// Number of one-time prekeys left for a wallet
+    pub async fn remaining_one_time_prekeys(&self, wallet_address: &str) -> usize {
+        self.bundles
+            .read()
+            .await
+            .get(wallet_address)
+            .map(|bundle| bundle.one_time_prekeys.len())
+            .unwrap_or(0)
+    }
+}
+
+impl Default for PrekeyDirectory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,350 @@
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Time allowed for connecting to and talking with a networked store
+const STORE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Hash of the nodes each wallet is connected to, by registration time
+const WALLET_KEY_PREFIX: &str = "solconnect:presence:";
+
+/// Hash of the cluster's nodes, by their last heartbeat and address
+const NODES_KEY: &str = "solconnect:nodes";
+
+/// A relay replica, as announced to the rest of its cluster
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub node_id: String,
+    /// `host:port` the other nodes reach this one's QUIC listener on
+    pub address: String,
+}
+
+/// Cluster-wide record of which relay node each wallet is connected to
+///
+/// Every node of a cluster registers the wallets connected to it, so a
+/// message for a wallet on another node can be forwarded there. Nodes also
+/// announce themselves with periodic heartbeats, so they can find and link
+/// to each other.
+pub trait PresenceStore: Send + Sync {
+    /// Record that a wallet connected to a node
+    fn register(&self, wallet: &str, node_id: &str) -> Result<()>;
+
+    /// Record that a wallet left a node; its connections to other nodes
+    /// stay registered
+    fn unregister(&self, wallet: &str, node_id: &str) -> Result<()>;
+
+    /// Node the wallet most recently connected to, if it is connected
+    fn locate(&self, wallet: &str) -> Result<Option<String>>;
+
+    /// Announce a node as alive and reachable at `address`
+    fn heartbeat(&self, node: &NodeInfo) -> Result<()>;
+
+    /// Nodes that sent a heartbeat within `max_age`
+    fn nodes(&self, max_age: Duration) -> Result<Vec<NodeInfo>>;
+
+    /// Remove a node from the cluster when it shuts down
+    fn leave(&self, node_id: &str) -> Result<()>;
+}
+
+/// Run a store operation on the blocking thread pool
+///
+/// Networked stores block on I/O, which must stay off the async runtime's
+/// worker threads.
+pub async fn run<T, F>(store: &Arc<dyn PresenceStore>, operation: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&dyn PresenceStore) -> Result<T> + Send + 'static,
+{
+    let store = store.clone();
+    tokio::task::spawn_blocking(move || operation(store.as_ref())).await?
+}
+
+/// Open the store named in the config: `memory` or a `redis://` URL
+pub fn open(url: &str) -> Result<Arc<dyn PresenceStore>> {
+    if url == "memory" {
+        return Ok(Arc::new(MemoryPresenceStore::new()));
+    }
+    if url.starts_with("redis://") {
+        return Ok(Arc::new(RedisPresenceStore::open(url)?));
+    }
+    bail!("Unsupported presence store '{}'", url)
+}
+
+/// Presence kept in the process, for a relay running on its own
+#[derive(Default)]
+pub struct MemoryPresenceStore {
+    wallets: Mutex<HashMap<String, HashMap<String, u64>>>,
+    nodes: Mutex<HashMap<String, (u64, String)>>,
+}
+
+impl MemoryPresenceStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl PresenceStore for MemoryPresenceStore {
+    fn register(&self, wallet: &str, node_id: &str) -> Result<()> {
+        self.wallets
+            .lock()
+            .unwrap()
+            .entry(wallet.to_string())
+            .or_default()
+            .insert(node_id.to_string(), now_millis());
+        Ok(())
+    }
+
+    fn unregister(&self, wallet: &str, node_id: &str) -> Result<()> {
+        let mut wallets = self.wallets.lock().unwrap();
+        if let Some(nodes) = wallets.get_mut(wallet) {
+            nodes.remove(node_id);
+            if nodes.is_empty() {
+                wallets.remove(wallet);
+            }
+        }
+        Ok(())
+    }
+
+    fn locate(&self, wallet: &str) -> Result<Option<String>> {
+        let wallets = self.wallets.lock().unwrap();
+        Ok(wallets.get(wallet).and_then(latest))
+    }
+
+    fn heartbeat(&self, node: &NodeInfo) -> Result<()> {
+        self.nodes
+            .lock()
+            .unwrap()
+            .insert(node.node_id.clone(), (now_millis(), node.address.clone()));
+        Ok(())
+    }
+
+    fn nodes(&self, max_age: Duration) -> Result<Vec<NodeInfo>> {
+        let nodes = self.nodes.lock().unwrap();
+        Ok(live_nodes(nodes.iter().map(|(id, (seen, address))| (id.clone(), *seen, address.clone())), max_age))
+    }
+
+    fn leave(&self, node_id: &str) -> Result<()> {
+        self.nodes.lock().unwrap().remove(node_id);
+        Ok(())
+    }
+}
+
+/// Presence in a Redis server shared by the nodes of a cluster
+///
+/// Each wallet is a hash from node id to registration time, so that nodes
+/// only ever add or remove their own entry and never race each other.
+pub struct RedisPresenceStore {
+    client: redis::Client,
+    /// Connections not in use; the lock is never held while talking to Redis
+    idle: Mutex<Vec<redis::Connection>>,
+}
+
+impl RedisPresenceStore {
+    pub fn open(url: &str) -> Result<Self> {
+        let client = redis::Client::open(url).with_context(|| format!("Invalid presence store URL {}", url))?;
+        Ok(Self {
+            client,
+            idle: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Run a command on an idle connection, or a new one if none is idle
+    ///
+    /// A connection whose command failed is dropped rather than reused.
+    fn query<T: redis::FromRedisValue>(&self, command: &redis::Cmd) -> Result<T> {
+        let idle = self.idle.lock().unwrap().pop();
+        let mut connection = match idle {
+            Some(connection) => connection,
+            None => {
+                let new = self.client.get_connection_with_timeout(STORE_TIMEOUT)?;
+                new.set_read_timeout(Some(STORE_TIMEOUT))?;
+                new.set_write_timeout(Some(STORE_TIMEOUT))?;
+                new
+            }
+        };
+
+        let result = command.query(&mut connection)?;
+        self.idle.lock().unwrap().push(connection);
+        Ok(result)
+    }
+}
+
+impl PresenceStore for RedisPresenceStore {
+    fn register(&self, wallet: &str, node_id: &str) -> Result<()> {
+        self.query(redis::cmd("HSET").arg(wallet_key(wallet)).arg(node_id).arg(now_millis()))
+    }
+
+    fn unregister(&self, wallet: &str, node_id: &str) -> Result<()> {
+        self.query(redis::cmd("HDEL").arg(wallet_key(wallet)).arg(node_id))
+    }
+
+    fn locate(&self, wallet: &str) -> Result<Option<String>> {
+        let nodes: HashMap<String, u64> = self.query(redis::cmd("HGETALL").arg(wallet_key(wallet)))?;
+        Ok(latest(&nodes))
+    }
+
+    fn heartbeat(&self, node: &NodeInfo) -> Result<()> {
+        let value = format!("{} {}", now_millis(), node.address);
+        self.query(redis::cmd("HSET").arg(NODES_KEY).arg(&node.node_id).arg(value))
+    }
+
+    fn nodes(&self, max_age: Duration) -> Result<Vec<NodeInfo>> {
+        let nodes: HashMap<String, String> = self.query(redis::cmd("HGETALL").arg(NODES_KEY))?;
+        let nodes = nodes.into_iter().filter_map(|(node_id, value)| {
+            let (seen, address) = value.split_once(' ')?;
+            Some((node_id, seen.parse().ok()?, address.to_string()))
+        });
+        Ok(live_nodes(nodes, max_age))
+    }
+
+    fn leave(&self, node_id: &str) -> Result<()> {
+        self.query(redis::cmd("HDEL").arg(NODES_KEY).arg(node_id))
+    }
+}
+
+fn wallet_key(wallet: &str) -> String {
+    format!("{}{}", WALLET_KEY_PREFIX, wallet)
+}
+
+/// Node a wallet registered with most recently
+fn latest(nodes: &HashMap<String, u64>) -> Option<String> {
+    nodes
+        .iter()
+        .max_by_key(|(_, registered_at)| **registered_at)
+        .map(|(node_id, _)| node_id.clone())
+}
+
+fn live_nodes(nodes: impl Iterator<Item = (String, u64, String)>, max_age: Duration) -> Vec<NodeInfo> {
+    let cutoff = now_millis().saturating_sub(max_age.as_millis() as u64);
+    nodes
+        .filter(|(_, seen, _)| *seen >= cutoff)
+        .map(|(node_id, _, address)| NodeInfo { node_id, address })
+        .collect()
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    /// Minimal Redis stand-in speaking RESP, supporting the hash commands
+    /// the presence store uses; returns its `redis://` URL
+    pub(crate) fn redis_stand_in() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        let hashes: Arc<Mutex<HashMap<String, HashMap<String, String>>>> = Arc::default();
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { break };
+                let hashes = hashes.clone();
+                std::thread::spawn(move || {
+                    let mut writer = stream.try_clone().unwrap();
+                    let mut reader = BufReader::new(stream);
+                    while let Some(command) = read_command(&mut reader) {
+                        let reply = execute(&hashes, &command);
+                        if writer.write_all(&reply).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        url
+    }
+
+    fn read_command(reader: &mut impl BufRead) -> Option<Vec<String>> {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let count: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+
+        let mut args = Vec::with_capacity(count);
+        for _ in 0..count {
+            line.clear();
+            reader.read_line(&mut line).ok()?;
+            let len: usize = line.trim_end().strip_prefix('$')?.parse().ok()?;
+            let mut arg = vec![0u8; len + 2];
+            reader.read_exact(&mut arg).ok()?;
+            arg.truncate(len);
+            args.push(String::from_utf8(arg).ok()?);
+        }
+        Some(args)
+    }
+
+    fn execute(hashes: &Mutex<HashMap<String, HashMap<String, String>>>, command: &[String]) -> Vec<u8> {
+        let mut hashes = hashes.lock().unwrap();
+        match command[0].to_uppercase().as_str() {
+            "HSET" => {
+                let hash = hashes.entry(command[1].clone()).or_default();
+                let added = hash.insert(command[2].clone(), command[3].clone()).is_none();
+                format!(":{}\r\n", added as u8).into_bytes()
+            }
+            "HDEL" => {
+                let removed = hashes.get_mut(&command[1]).and_then(|hash| hash.remove(&command[2]));
+                format!(":{}\r\n", removed.is_some() as u8).into_bytes()
+            }
+            "HGETALL" => {
+                let hash = hashes.get(&command[1]).cloned().unwrap_or_default();
+                let mut reply = format!("*{}\r\n", hash.len() * 2);
+                for (field, value) in hash {
+                    reply += &format!("${}\r\n{}\r\n${}\r\n{}\r\n", field.len(), field, value.len(), value);
+                }
+                reply.into_bytes()
+            }
+            other => format!("-ERR unknown command '{}'\r\n", other).into_bytes(),
+        }
+    }
+
+    fn check_store(store: &dyn PresenceStore) {
+        assert_eq!(store.locate("alice").unwrap(), None);
+
+        store.register("alice", "node-a").unwrap();
+        assert_eq!(store.locate("alice").unwrap().as_deref(), Some("node-a"));
+
+        // A newer connection on another node wins, and survives the old one
+        // closing
+        std::thread::sleep(Duration::from_millis(2));
+        store.register("alice", "node-b").unwrap();
+        assert_eq!(store.locate("alice").unwrap().as_deref(), Some("node-b"));
+        store.unregister("alice", "node-a").unwrap();
+        assert_eq!(store.locate("alice").unwrap().as_deref(), Some("node-b"));
+        store.unregister("alice", "node-b").unwrap();
+        assert_eq!(store.locate("alice").unwrap(), None);
+
+        let node = NodeInfo {
+            node_id: "node-a".to_string(),
+            address: "10.0.0.1:4433".to_string(),
+        };
+        store.heartbeat(&node).unwrap();
+        assert_eq!(store.nodes(Duration::from_secs(30)).unwrap(), vec![node]);
+        store.leave("node-a").unwrap();
+        assert!(store.nodes(Duration::from_secs(30)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_memory_store() {
+        check_store(&MemoryPresenceStore::new());
+    }
+
+    #[test]
+    fn test_redis_store() {
+        let store = open(&redis_stand_in()).unwrap();
+        check_store(store.as_ref());
+    }
+
+    #[test]
+    fn test_stale_nodes_are_left_out() {
+        let store = MemoryPresenceStore::new();
+        store.nodes.lock().unwrap().insert("gone".to_string(), (now_millis() - 60_000, "10.0.0.9:4433".to_string()));
+        assert!(store.nodes(Duration::from_secs(30)).unwrap().is_empty());
+    }
+}
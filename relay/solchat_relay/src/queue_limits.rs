@@ -0,0 +1,113 @@
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+
+/// What to do when a message does not fit in the queues
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EvictionPolicy {
+    /// Refuse the new message and keep everything already queued
+    RejectNew,
+    /// Evict the oldest queued messages to make room
+    DropOldest,
+    /// Evict expired messages first, then the oldest ones
+    DropExpiredFirst,
+}
+
+impl EvictionPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EvictionPolicy::RejectNew => "reject-new",
+            EvictionPolicy::DropOldest => "drop-oldest",
+            EvictionPolicy::DropExpiredFirst => "drop-expired-first",
+        }
+    }
+}
+
+impl fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject-new" => Ok(EvictionPolicy::RejectNew),
+            "drop-oldest" => Ok(EvictionPolicy::DropOldest),
+            "drop-expired-first" => Ok(EvictionPolicy::DropExpiredFirst),
+            other => Err(format!(
+                "unknown eviction policy '{}' (expected reject-new, drop-oldest or drop-expired-first)",
+                other
+            )),
+        }
+    }
+}
+
+/// Limits on messages queued for offline recipients
+///
+/// Per-recipient limits count both messages and encoded bytes; the global
+/// cap bounds the bytes queued across all recipients. When the global cap
+/// is hit, messages are evicted from the recipient with the most bytes
+/// queued, so one flooded inbox cannot push out everyone else's mail.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueueLimits {
+    pub max_messages_per_recipient: usize,
+    pub max_bytes_per_recipient: usize,
+    pub max_total_bytes: usize,
+    pub policy: EvictionPolicy,
+}
+
+impl QueueLimits {
+    /// Check that the limits can admit at least one message
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_messages_per_recipient == 0 {
+            return Err("per-recipient message limit must be positive".to_string());
+        }
+        if self.max_bytes_per_recipient == 0 {
+            return Err("per-recipient byte limit must be positive".to_string());
+        }
+        if self.max_total_bytes < self.max_bytes_per_recipient {
+            return Err("global byte limit must be at least the per-recipient byte limit".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        Self {
+            max_messages_per_recipient: 100,
+            max_bytes_per_recipient: 1024 * 1024,
+            max_total_bytes: 256 * 1024 * 1024,
+            policy: EvictionPolicy::DropExpiredFirst,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy_names_round_trip() {
+        for policy in [EvictionPolicy::RejectNew, EvictionPolicy::DropOldest, EvictionPolicy::DropExpiredFirst] {
+            assert_eq!(policy.as_str().parse::<EvictionPolicy>(), Ok(policy));
+        }
+        assert!("drop-newest".parse::<EvictionPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_validate_rejects_unusable_limits() {
+        assert!(QueueLimits::default().validate().is_ok());
+
+        let limits = QueueLimits {
+            max_total_bytes: 10,
+            max_bytes_per_recipient: 20,
+            ..QueueLimits::default()
+        };
+        assert!(limits.validate().is_err());
+    }
+}
@@ -0,0 +1,763 @@
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use solchat_protocol::messages::Envelope;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use tracing::{error, info, warn};
+use crate::router::{RelayMessage, RoutableMessage};
+
+/// Storage for messages waiting on offline recipients
+///
+/// Each recipient has its own FIFO queue. Messages stay queued until they
+/// are removed by id, which the router does once the recipient acknowledges
+/// them.
+pub trait QueueStore: Send + Sync {
+    /// Append a message to the end of a recipient's queue, returning once
+    /// it would survive a crash
+    ///
+    /// May block on disk I/O, so async callers run it on the blocking pool.
+    fn push(&self, recipient: &str, message: RoutableMessage) -> Result<()>;
+
+    /// All messages queued for a recipient, oldest first
+    fn pending(&self, recipient: &str) -> Vec<RoutableMessage>;
+
+    /// Remove a queued message by id, returning it if it was queued
+    fn remove(&self, recipient: &str, message_id: &str) -> Result<Option<RoutableMessage>>;
+
+    /// Remove and return the oldest message queued for a recipient
+    fn pop_oldest(&self, recipient: &str) -> Result<Option<RoutableMessage>>;
+
+    /// Number of messages queued for a recipient
+    fn queued_for(&self, recipient: &str) -> usize;
+
+    /// Encoded size of the messages queued for a recipient
+    fn bytes_queued_for(&self, recipient: &str) -> usize;
+
+    /// Recipients with at least one queued message
+    fn recipients(&self) -> Vec<String>;
+
+    /// Total number of queued messages
+    fn total_queued(&self) -> usize;
+
+    /// Encoded size of all queued messages
+    fn total_bytes(&self) -> usize;
+
+    /// Bring the stored queues up to date before the relay exits
+    fn flush(&self) -> Result<()>;
+
+    /// Fail if the store could no longer take messages
+    fn check(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Per-recipient queues shared by the store implementations
+#[derive(Default)]
+struct Queues {
+    queues: HashMap<String, RecipientQueue>,
+    total_bytes: usize,
+}
+
+#[derive(Default)]
+struct RecipientQueue {
+    messages: VecDeque<RoutableMessage>,
+    bytes: usize,
+}
+
+impl Queues {
+    fn push(&mut self, recipient: &str, message: RoutableMessage) {
+        let size = message.message.encoded_len();
+        let queue = self.queues.entry(recipient.to_string()).or_default();
+        queue.messages.push_back(message);
+        queue.bytes += size;
+        self.total_bytes += size;
+    }
+
+    fn remove(&mut self, recipient: &str, message_id: &str) -> Option<RoutableMessage> {
+        let queue = self.queues.get_mut(recipient)?;
+        let index = queue
+            .messages
+            .iter()
+            .position(|queued| queued.message.message_id() == Some(message_id))?;
+        let removed = queue.messages.remove(index);
+        self.removed(recipient, removed)
+    }
+
+    fn pop_oldest(&mut self, recipient: &str) -> Option<RoutableMessage> {
+        let removed = self.queues.get_mut(recipient)?.messages.pop_front();
+        self.removed(recipient, removed)
+    }
+
+    /// Account for a message taken out of a recipient's queue
+    fn removed(&mut self, recipient: &str, removed: Option<RoutableMessage>) -> Option<RoutableMessage> {
+        let message = removed?;
+        let size = message.message.encoded_len();
+        if let Some(queue) = self.queues.get_mut(recipient) {
+            queue.bytes -= size;
+            if queue.messages.is_empty() {
+                self.queues.remove(recipient);
+            }
+        }
+        self.total_bytes -= size;
+        Some(message)
+    }
+
+    fn pending(&self, recipient: &str) -> Vec<RoutableMessage> {
+        self.queues
+            .get(recipient)
+            .map(|queue| queue.messages.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn queued_for(&self, recipient: &str) -> usize {
+        self.queues.get(recipient).map(|queue| queue.messages.len()).unwrap_or(0)
+    }
+
+    fn bytes_queued_for(&self, recipient: &str) -> usize {
+        self.queues.get(recipient).map(|queue| queue.bytes).unwrap_or(0)
+    }
+
+    fn recipients(&self) -> Vec<String> {
+        self.queues.keys().cloned().collect()
+    }
+
+    fn total_queued(&self) -> usize {
+        self.queues.values().map(|queue| queue.messages.len()).sum()
+    }
+}
+
+/// Volatile queue storage, lost when the relay stops
+#[derive(Default)]
+pub struct MemoryQueueStore {
+    queues: Mutex<Queues>,
+}
+
+impl MemoryQueueStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl QueueStore for MemoryQueueStore {
+    fn push(&self, recipient: &str, message: RoutableMessage) -> Result<()> {
+        self.queues.lock().unwrap().push(recipient, message);
+        Ok(())
+    }
+
+    fn pending(&self, recipient: &str) -> Vec<RoutableMessage> {
+        self.queues.lock().unwrap().pending(recipient)
+    }
+
+    fn remove(&self, recipient: &str, message_id: &str) -> Result<Option<RoutableMessage>> {
+        Ok(self.queues.lock().unwrap().remove(recipient, message_id))
+    }
+
+    fn pop_oldest(&self, recipient: &str) -> Result<Option<RoutableMessage>> {
+        Ok(self.queues.lock().unwrap().pop_oldest(recipient))
+    }
+
+    fn queued_for(&self, recipient: &str) -> usize {
+        self.queues.lock().unwrap().queued_for(recipient)
+    }
+
+    fn bytes_queued_for(&self, recipient: &str) -> usize {
+        self.queues.lock().unwrap().bytes_queued_for(recipient)
+    }
+
+    fn recipients(&self) -> Vec<String> {
+        self.queues.lock().unwrap().recipients()
+    }
+
+    fn total_queued(&self) -> usize {
+        self.queues.lock().unwrap().total_queued()
+    }
+
+    fn total_bytes(&self) -> usize {
+        self.queues.lock().unwrap().total_bytes
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Log record tags
+const RECORD_PUSH: u8 = 1;
+const RECORD_REMOVE: u8 = 2;
+
+/// Compact once the log holds this many records more than are live
+const COMPACTION_SLACK: usize = 1024;
+
+/// Durable queue storage backed by an append-only log file
+///
+/// Every push and removal is appended as a length-prefixed record by a
+/// dedicated writer thread, which syncs each batch of records it writes, so
+/// no fsync runs on the caller's thread. A push waits for the sync of its
+/// batch, so concurrent pushes share one fsync; a removal does not wait,
+/// as losing one only delivers the message again. On open the log is replayed, a torn
+/// record left by a crash is discarded, and the log is rewritten with only
+/// the live messages. The log is compacted again whenever removed records
+/// start to dominate it.
+pub struct FileQueueStore {
+    path: PathBuf,
+    inner: Mutex<FileQueueInner>,
+    writer: Option<JoinHandle<()>>,
+    writer_error: Arc<Mutex<Option<String>>>,
+}
+
+struct FileQueueInner {
+    queues: Queues,
+    /// Sent while the lock is held, so the log sees changes in queue order
+    commands: Option<mpsc::Sender<LogCommand>>,
+    log_records: usize,
+}
+
+/// Work for the log writer thread
+enum LogCommand {
+    /// Append a record
+    Append(Vec<u8>),
+    /// Replace the log with these records
+    Snapshot(Vec<u8>),
+    /// Report once everything sent before is on disk
+    Sync(mpsc::Sender<Result<(), String>>),
+}
+
+impl FileQueueStore {
+    /// Open or create the queue log at `path`
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let queues = Self::replay(&path)?;
+
+        let log = Self::write_snapshot(&path, &encode_snapshot(&queues))?;
+        let log_records = queues.total_queued();
+        info!(
+            "💾 Opened message queue at {} ({} queued messages)",
+            path.display(),
+            log_records
+        );
+
+        let (commands, receiver) = mpsc::channel();
+        let writer_error = Arc::new(Mutex::new(None));
+        let writer = thread::Builder::new()
+            .name("queue-log-writer".to_string())
+            .spawn({
+                let (path, writer_error) = (path.clone(), writer_error.clone());
+                move || Self::run_writer(&path, log, receiver, &writer_error)
+            })
+            .context("Failed to start the queue log writer")?;
+
+        Ok(Self {
+            path,
+            inner: Mutex::new(FileQueueInner {
+                queues,
+                commands: Some(commands),
+                log_records,
+            }),
+            writer: Some(writer),
+            writer_error,
+        })
+    }
+
+    /// Rebuild the queues from the log on disk
+    fn replay(path: &Path) -> Result<Queues> {
+        let mut queues = Queues::default();
+
+        let mut data = Vec::new();
+        match File::open(path) {
+            Ok(mut file) => {
+                file.read_to_end(&mut data)?;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(queues),
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut offset = 0;
+        while offset < data.len() {
+            match decode_record(&data[offset..]) {
+                Ok((record, consumed)) => {
+                    match record {
+                        Record::Push { recipient, message } => queues.push(&recipient, message),
+                        Record::Remove { recipient, message_id } => {
+                            queues.remove(&recipient, &message_id);
+                        }
+                    }
+                    offset += consumed;
+                }
+                Err(e) => {
+                    warn!(
+                        "Discarding {} bytes at the end of {}: {}",
+                        data.len() - offset,
+                        path.display(),
+                        e
+                    );
+                    break;
+                }
+            }
+        }
+
+        Ok(queues)
+    }
+
+    /// Atomically replace the log with the given records
+    fn write_snapshot(path: &Path, records: &[u8]) -> Result<File> {
+        let tmp_path = path.with_extension("tmp");
+
+        {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(records)?;
+            file.sync_all()?;
+        }
+
+        fs::rename(&tmp_path, path)?;
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            File::open(dir)?.sync_all()?;
+        }
+
+        Ok(OpenOptions::new().append(true).open(path)?)
+    }
+
+    /// Write and sync log commands in batches until the store is dropped
+    ///
+    /// The first failure stops the writer, since the log no longer matches
+    /// the queues; later changes to the store then fail.
+    fn run_writer(path: &Path, mut log: File, commands: mpsc::Receiver<LogCommand>, writer_error: &Mutex<Option<String>>) {
+        while let Ok(command) = commands.recv() {
+            let mut waiting = Vec::new();
+            let mut result = Ok(());
+            for command in std::iter::once(command).chain(commands.try_iter()) {
+                result = result.and_then(|()| match command {
+                    LogCommand::Append(record) => log.write_all(&record).map_err(Into::into),
+                    LogCommand::Snapshot(records) => Self::write_snapshot(path, &records).map(|file| log = file),
+                    LogCommand::Sync(reply) => {
+                        waiting.push(reply);
+                        Ok(())
+                    }
+                });
+            }
+            let result = result.and_then(|()| log.sync_data().map_err(Into::into));
+
+            let reply = result.as_ref().map(|_| ()).map_err(|e| format!("{:#}", e));
+            for waiter in waiting {
+                let _ = waiter.send(reply.clone());
+            }
+            if let Err(e) = reply {
+                error!("Failed to write the queue log {}: {}", path.display(), e);
+                *writer_error.lock().unwrap() = Some(e);
+                return;
+            }
+        }
+    }
+
+    /// Hand a command to the writer thread
+    fn send(&self, inner: &FileQueueInner, command: LogCommand) -> Result<()> {
+        let sent = inner.commands.as_ref().is_some_and(|commands| commands.send(command).is_ok());
+        if !sent {
+            let reason = self.writer_error.lock().unwrap().clone().unwrap_or_else(|| "stopped".to_string());
+            bail!("Queue log writer failed: {}", reason);
+        }
+        Ok(())
+    }
+
+    fn append(&self, inner: &mut FileQueueInner, record: Vec<u8>) -> Result<()> {
+        self.send(inner, LogCommand::Append(record))?;
+        inner.log_records += 1;
+        Ok(())
+    }
+
+    /// Replace the log with the live messages
+    fn compact(&self, inner: &mut FileQueueInner) -> Result<()> {
+        self.send(inner, LogCommand::Snapshot(encode_snapshot(&inner.queues)))?;
+        inner.log_records = inner.queues.total_queued();
+        Ok(())
+    }
+
+    fn maybe_compact(&self, inner: &mut FileQueueInner) -> Result<()> {
+        if inner.log_records > inner.queues.total_queued() * 2 + COMPACTION_SLACK {
+            self.compact(inner)?;
+        }
+        Ok(())
+    }
+}
+
+impl QueueStore for FileQueueStore {
+    fn push(&self, recipient: &str, message: RoutableMessage) -> Result<()> {
+        if message.message.message_id().is_none() {
+            bail!("Only messages with an id can be queued");
+        }
+
+        let (reply, synced) = mpsc::channel();
+        {
+            let mut inner = self.inner.lock().unwrap();
+            self.append(&mut inner, encode_push(recipient, &message))?;
+            self.send(&inner, LogCommand::Sync(reply))?;
+            inner.queues.push(recipient, message);
+        }
+        synced
+            .recv()
+            .map_err(|_| anyhow!("Queue log writer stopped"))?
+            .map_err(|e| anyhow!("Failed to write the queue log: {}", e))
+    }
+
+    fn pending(&self, recipient: &str) -> Vec<RoutableMessage> {
+        self.inner.lock().unwrap().queues.pending(recipient)
+    }
+
+    fn remove(&self, recipient: &str, message_id: &str) -> Result<Option<RoutableMessage>> {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(removed) = inner.queues.remove(recipient, message_id) else {
+            return Ok(None);
+        };
+
+        self.append(&mut inner, encode_remove(recipient, message_id))?;
+        self.maybe_compact(&mut inner)?;
+        Ok(Some(removed))
+    }
+
+    fn pop_oldest(&self, recipient: &str) -> Result<Option<RoutableMessage>> {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(removed) = inner.queues.pop_oldest(recipient) else {
+            return Ok(None);
+        };
+
+        // Removal by id takes the first match, which is the oldest
+        let message_id = removed.message.message_id().unwrap_or_default().to_string();
+        self.append(&mut inner, encode_remove(recipient, &message_id))?;
+        self.maybe_compact(&mut inner)?;
+        Ok(Some(removed))
+    }
+
+    fn queued_for(&self, recipient: &str) -> usize {
+        self.inner.lock().unwrap().queues.queued_for(recipient)
+    }
+
+    fn bytes_queued_for(&self, recipient: &str) -> usize {
+        self.inner.lock().unwrap().queues.bytes_queued_for(recipient)
+    }
+
+    fn recipients(&self) -> Vec<String> {
+        self.inner.lock().unwrap().queues.recipients()
+    }
+
+    fn total_queued(&self) -> usize {
+        self.inner.lock().unwrap().queues.total_queued()
+    }
+
+    fn total_bytes(&self) -> usize {
+        self.inner.lock().unwrap().queues.total_bytes
+    }
+
+    /// Compact the log, sparing the next start from replaying removed
+    /// messages, and wait until the writer has it on disk
+    fn flush(&self) -> Result<()> {
+        let (reply, synced) = mpsc::channel();
+        {
+            let mut inner = self.inner.lock().unwrap();
+            self.compact(&mut inner)?;
+            self.send(&inner, LogCommand::Sync(reply))?;
+        }
+        synced
+            .recv()
+            .map_err(|_| anyhow!("Queue log writer stopped"))?
+            .map_err(|e| anyhow!("Failed to flush the queue log: {}", e))
+    }
+
+    /// The log must still be on disk and writable, and the writer running
+    fn check(&self) -> Result<()> {
+        if let Some(e) = self.writer_error.lock().unwrap().as_ref() {
+            bail!("Queue log writer failed: {}", e);
+        }
+        let metadata = fs::metadata(&self.path)
+            .with_context(|| format!("Queue log {} is missing", self.path.display()))?;
+        ensure!(
+            !metadata.permissions().readonly(),
+            "Queue log {} is read-only",
+            self.path.display()
+        );
+        Ok(())
+    }
+}
+
+impl Drop for FileQueueStore {
+    /// Let the writer finish what it was given before the log is let go
+    fn drop(&mut self) {
+        self.inner.get_mut().unwrap().commands.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+enum Record {
+    Push { recipient: String, message: RoutableMessage },
+    Remove { recipient: String, message_id: String },
+}
+
+/// Record layout: u32 body length, then a tag byte and tag-specific fields.
+/// Strings are u16 length-prefixed; the message is a protobuf `Envelope`.
+fn encode_record(tag: u8, fields: &[&[u8]], tail: &[u8]) -> Vec<u8> {
+    let mut body = vec![tag];
+    for field in fields {
+        body.extend_from_slice(&(field.len() as u16).to_le_bytes());
+        body.extend_from_slice(field);
+    }
+    body.extend_from_slice(tail);
+
+    let mut record = Vec::with_capacity(4 + body.len());
+    record.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    record.extend_from_slice(&body);
+    record
+}
+
+/// One push record per live message
+fn encode_snapshot(queues: &Queues) -> Vec<u8> {
+    let mut records = Vec::new();
+    for (recipient, queue) in &queues.queues {
+        for message in &queue.messages {
+            records.extend(encode_push(recipient, message));
+        }
+    }
+    records
+}
+
+fn encode_push(recipient: &str, message: &RoutableMessage) -> Vec<u8> {
+    let envelope = prost::Message::encode_to_vec(&message.message.to_envelope());
+    let sender_addr = message.sender_addr.to_string();
+    encode_record(RECORD_PUSH, &[recipient.as_bytes(), sender_addr.as_bytes()], &envelope)
+}
+
+fn encode_remove(recipient: &str, message_id: &str) -> Vec<u8> {
+    encode_record(RECORD_REMOVE, &[recipient.as_bytes()], message_id.as_bytes())
+}
+
+/// Decode one record, returning it and the number of bytes it used
+fn decode_record(data: &[u8]) -> Result<(Record, usize)> {
+    let header: [u8; 4] = data
+        .get(..4)
+        .ok_or_else(|| anyhow!("Truncated record header"))?
+        .try_into()?;
+    let len = u32::from_le_bytes(header) as usize;
+    let mut body = data.get(4..4 + len).ok_or_else(|| anyhow!("Truncated record"))?;
+
+    let (&tag, rest) = body.split_first().ok_or_else(|| anyhow!("Empty record"))?;
+    body = rest;
+
+    let mut read_field = || -> Result<String> {
+        let field_len: [u8; 2] = body.get(..2).ok_or_else(|| anyhow!("Truncated field"))?.try_into()?;
+        let field_len = u16::from_le_bytes(field_len) as usize;
+        let field = body.get(2..2 + field_len).ok_or_else(|| anyhow!("Truncated field"))?;
+        let field = String::from_utf8(field.to_vec())?;
+        body = &body[2 + field_len..];
+        Ok(field)
+    };
+
+    let record = match tag {
+        RECORD_PUSH => {
+            let recipient = read_field()?;
+            let sender_addr = read_field()?.parse()?;
+            let envelope = <Envelope as prost::Message>::decode(body)?;
+            let message = RelayMessage::from_envelope(envelope)
+                .ok_or_else(|| anyhow!("Queued envelope has no body"))?;
+            Record::Push {
+                recipient,
+                message: RoutableMessage { message, sender_addr },
+            }
+        }
+        RECORD_REMOVE => {
+            let recipient = read_field()?;
+            let message_id = String::from_utf8(body.to_vec())?;
+            Record::Remove { recipient, message_id }
+        }
+        other => bail!("Unknown record tag {}", other),
+    };
+
+    Ok((record, 4 + len))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use solchat_protocol::messages::ChatMessage;
+    use solchat_protocol::WalletAddress;
+
+    /// Store whose removals fail, like one that lost its disk
+    #[derive(Default)]
+    pub(crate) struct FailingRemovals(MemoryQueueStore);
+
+    impl QueueStore for FailingRemovals {
+        fn push(&self, recipient: &str, message: RoutableMessage) -> Result<()> {
+            self.0.push(recipient, message)
+        }
+
+        fn pending(&self, recipient: &str) -> Vec<RoutableMessage> {
+            self.0.pending(recipient)
+        }
+
+        fn remove(&self, _recipient: &str, _message_id: &str) -> Result<Option<RoutableMessage>> {
+            bail!("disk gone")
+        }
+
+        fn pop_oldest(&self, _recipient: &str) -> Result<Option<RoutableMessage>> {
+            bail!("disk gone")
+        }
+
+        fn queued_for(&self, recipient: &str) -> usize {
+            self.0.queued_for(recipient)
+        }
+
+        fn bytes_queued_for(&self, recipient: &str) -> usize {
+            self.0.bytes_queued_for(recipient)
+        }
+
+        fn recipients(&self) -> Vec<String> {
+            self.0.recipients()
+        }
+
+        fn total_queued(&self) -> usize {
+            self.0.total_queued()
+        }
+
+        fn total_bytes(&self) -> usize {
+            self.0.total_bytes()
+        }
+
+        fn flush(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn chat(payload: &[u8]) -> RoutableMessage {
+        let sender = WalletAddress::test_address(1);
+        let recipient = WalletAddress::test_address(2);
+        RoutableMessage {
+            message: RelayMessage::Chat(ChatMessage::new(&sender, &recipient, payload.to_vec(), Vec::new())),
+            sender_addr: "127.0.0.1:1234".parse().unwrap(),
+        }
+    }
+
+    fn payloads(messages: &[RoutableMessage]) -> Vec<Vec<u8>> {
+        messages
+            .iter()
+            .map(|queued| match &queued.message {
+                RelayMessage::Chat(chat) => chat.encrypted_payload.clone(),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_file_store_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("queue.log");
+
+        let (first, second, third) = (chat(b"first"), chat(b"second"), chat(b"third"));
+        {
+            let store = FileQueueStore::open(&path).unwrap();
+            store.push("bob", first.clone()).unwrap();
+            store.push("bob", second.clone()).unwrap();
+            store.push("bob", third.clone()).unwrap();
+            store.remove("bob", second.message.message_id().unwrap()).unwrap();
+        }
+
+        let store = FileQueueStore::open(&path).unwrap();
+        assert_eq!(payloads(&store.pending("bob")), vec![b"first".to_vec(), b"third".to_vec()]);
+        assert_eq!(store.recipients(), vec!["bob".to_string()]);
+
+        store.pop_oldest("bob").unwrap();
+        drop(store);
+        let store = FileQueueStore::open(&path).unwrap();
+        assert_eq!(payloads(&store.pending("bob")), vec![b"third".to_vec()]);
+        assert_eq!(store.bytes_queued_for("bob"), third.message.encoded_len());
+        assert_eq!(store.total_bytes(), third.message.encoded_len());
+    }
+
+    #[test]
+    fn test_file_store_push_is_on_disk_when_it_returns() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("queue.log");
+
+        // Forgetting the store skips the writer's shutdown, as a crash would
+        let store = FileQueueStore::open(&path).unwrap();
+        store.push("bob", chat(b"stored")).unwrap();
+        std::mem::forget(store);
+
+        let store = FileQueueStore::open(&path).unwrap();
+        assert_eq!(payloads(&store.pending("bob")), vec![b"stored".to_vec()]);
+    }
+
+    #[test]
+    fn test_file_store_discards_torn_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("queue.log");
+
+        {
+            let store = FileQueueStore::open(&path).unwrap();
+            store.push("bob", chat(b"kept")).unwrap();
+        }
+
+        // Simulate a crash halfway through appending the next record
+        let torn = encode_push("bob", &chat(b"torn"));
+        let mut log = OpenOptions::new().append(true).open(&path).unwrap();
+        log.write_all(&torn[..torn.len() / 2]).unwrap();
+        drop(log);
+
+        let store = FileQueueStore::open(&path).unwrap();
+        assert_eq!(payloads(&store.pending("bob")), vec![b"kept".to_vec()]);
+
+        // The log is usable again after recovery
+        store.push("bob", chat(b"after")).unwrap();
+        drop(store);
+        let store = FileQueueStore::open(&path).unwrap();
+        assert_eq!(store.total_queued(), 2);
+    }
+
+    #[test]
+    fn test_file_store_compacts_removed_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("queue.log");
+        let store = FileQueueStore::open(&path).unwrap();
+
+        for _ in 0..COMPACTION_SLACK {
+            let message = chat(b"transient");
+            store.push("bob", message.clone()).unwrap();
+            store.remove("bob", message.message.message_id().unwrap()).unwrap();
+        }
+
+        assert!(store.inner.lock().unwrap().log_records < COMPACTION_SLACK);
+        assert_eq!(store.total_queued(), 0);
+    }
+
+    #[test]
+    fn test_file_store_flush_leaves_only_live_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("queue.log");
+        let store = FileQueueStore::open(&path).unwrap();
+
+        let (kept, removed) = (chat(b"kept"), chat(b"removed"));
+        store.push("bob", kept.clone()).unwrap();
+        store.push("bob", removed.clone()).unwrap();
+        store.remove("bob", removed.message.message_id().unwrap()).unwrap();
+
+        store.flush().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), encode_push("bob", &kept));
+
+        // The store keeps working on the new log
+        store.push("bob", chat(b"later")).unwrap();
+        drop(store);
+        let store = FileQueueStore::open(&path).unwrap();
+        assert_eq!(payloads(&store.pending("bob")), vec![b"kept".to_vec(), b"later".to_vec()]);
+    }
+
+    #[test]
+    fn test_file_store_check_notices_a_missing_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("queue.log");
+        let store = FileQueueStore::open(&path).unwrap();
+        store.check().unwrap();
+
+        fs::remove_file(&path).unwrap();
+        assert!(store.check().is_err());
+    }
+}
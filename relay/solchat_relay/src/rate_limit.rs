@@ -0,0 +1,347 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Number of tracked keys per scope above which idle buckets are dropped
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Token bucket parameters: `burst` messages at once, refilled at
+/// `per_second` messages per second
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    pub fn new(per_second: f64, burst: u32) -> Self {
+        Self { per_second, burst }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !(self.per_second.is_finite() && self.per_second > 0.0) {
+            return Err("per_second must be a positive number".to_string());
+        }
+        if self.burst == 0 {
+            return Err("burst must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Limits applied to every routed message
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimits {
+    /// Messages sent by one authenticated wallet
+    pub per_wallet: RateLimit,
+    /// Messages sent from one source IP, across all its wallets
+    pub per_ip: RateLimit,
+    /// Chat messages addressed to one recipient, across all senders
+    pub per_recipient: RateLimit,
+}
+
+impl RateLimits {
+    /// Check that every limit admits messages
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, limit) in [
+            ("per_wallet", &self.per_wallet),
+            ("per_ip", &self.per_ip),
+            ("per_recipient", &self.per_recipient),
+        ] {
+            limit.validate().map_err(|e| format!("{}: {}", name, e))?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            per_wallet: RateLimit::new(10.0, 50),
+            per_ip: RateLimit::new(50.0, 200),
+            per_recipient: RateLimit::new(20.0, 100),
+        }
+    }
+}
+
+/// Which limit a message ran into
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimitScope {
+    Wallet,
+    Ip,
+    Recipient,
+}
+
+impl RateLimitScope {
+    /// Short name used as a metrics label
+    pub fn label(&self) -> &'static str {
+        match self {
+            RateLimitScope::Wallet => "wallet",
+            RateLimitScope::Ip => "ip",
+            RateLimitScope::Recipient => "recipient",
+        }
+    }
+
+    /// Reason reported to the sender in the rejection ack
+    pub fn reason(&self) -> &'static str {
+        match self {
+            RateLimitScope::Wallet => "sender rate limit exceeded",
+            RateLimitScope::Ip => "source address rate limit exceeded",
+            RateLimitScope::Recipient => "recipient inbox rate limit exceeded",
+        }
+    }
+}
+
+impl fmt::Display for RateLimitScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.reason())
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn full(limit: &RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: limit.burst as f64,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+        self.last_refill = now;
+    }
+}
+
+/// Token buckets for one scope, keyed by wallet, IP or recipient
+struct Buckets<K> {
+    buckets: HashMap<K, TokenBucket>,
+}
+
+impl<K: Hash + Eq + Clone> Buckets<K> {
+    fn new() -> Self {
+        Self { buckets: HashMap::new() }
+    }
+
+    /// Refill the key's bucket and report whether it holds a token
+    fn has_token(&mut self, key: &K, limit: &RateLimit, now: Instant) -> bool {
+        let bucket = self
+            .buckets
+            .entry(key.clone())
+            .or_insert_with(|| TokenBucket::full(limit, now));
+        bucket.refill(limit, now);
+        bucket.tokens >= 1.0
+    }
+
+    fn take(&mut self, key: &K) {
+        if let Some(bucket) = self.buckets.get_mut(key) {
+            bucket.tokens -= 1.0;
+        }
+    }
+
+    /// Forget buckets that have refilled completely, as they behave exactly
+    /// like a new bucket would
+    fn prune(&mut self, limit: &RateLimit, now: Instant) {
+        if self.buckets.len() <= PRUNE_THRESHOLD {
+            return;
+        }
+        self.buckets.retain(|_, bucket| {
+            bucket.refill(limit, now);
+            bucket.tokens < limit.burst as f64
+        });
+    }
+}
+
+struct LimiterState {
+    limits: RateLimits,
+    wallets: Buckets<String>,
+    ips: Buckets<IpAddr>,
+    recipients: Buckets<String>,
+}
+
+/// Token-bucket rate limiting per sending wallet, source IP and recipient
+///
+/// A message is only admitted if every bucket it draws from has a token,
+/// and tokens are only taken once it is admitted, so a rejected message
+/// does not count against the limits it did not hit.
+pub struct RateLimiter {
+    state: Mutex<LimiterState>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            state: Mutex::new(LimiterState {
+                limits,
+                wallets: Buckets::new(),
+                ips: Buckets::new(),
+                recipients: Buckets::new(),
+            }),
+        }
+    }
+
+    /// Current limits
+    pub fn limits(&self) -> RateLimits {
+        self.state.lock().unwrap().limits
+    }
+
+    /// Replace the limits; existing buckets are capped to the new burst on
+    /// their next refill
+    pub fn set_limits(&self, limits: RateLimits) {
+        self.state.lock().unwrap().limits = limits;
+    }
+
+    /// Admit a message, or report the first limit it exceeds
+    ///
+    /// `recipient` is only given for messages that land in someone's inbox.
+    pub fn check(&self, wallet: &str, ip: IpAddr, recipient: Option<&str>) -> Result<(), RateLimitScope> {
+        self.check_at(wallet, ip, recipient, Instant::now())
+    }
+
+    /// Admit a request from `ip` that no wallet is accountable for yet,
+    /// such as a handshake, drawing only from the per-IP bucket
+    pub fn check_ip(&self, ip: IpAddr) -> Result<(), RateLimitScope> {
+        self.check_ip_at(ip, Instant::now())
+    }
+
+    fn check_ip_at(&self, ip: IpAddr, now: Instant) -> Result<(), RateLimitScope> {
+        let mut state = self.state.lock().unwrap();
+        let limit = state.limits.per_ip;
+        if !state.ips.has_token(&ip, &limit, now) {
+            return Err(RateLimitScope::Ip);
+        }
+        state.ips.take(&ip);
+        state.ips.prune(&limit, now);
+        Ok(())
+    }
+
+    fn check_at(
+        &self,
+        wallet: &str,
+        ip: IpAddr,
+        recipient: Option<&str>,
+        now: Instant,
+    ) -> Result<(), RateLimitScope> {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let limits = state.limits;
+        let wallet = wallet.to_string();
+        let recipient = recipient.map(str::to_string);
+
+        if !state.wallets.has_token(&wallet, &limits.per_wallet, now) {
+            return Err(RateLimitScope::Wallet);
+        }
+        if !state.ips.has_token(&ip, &limits.per_ip, now) {
+            return Err(RateLimitScope::Ip);
+        }
+        if let Some(recipient) = &recipient {
+            if !state.recipients.has_token(recipient, &limits.per_recipient, now) {
+                return Err(RateLimitScope::Recipient);
+            }
+        }
+
+        state.wallets.take(&wallet);
+        state.ips.take(&ip);
+        if let Some(recipient) = &recipient {
+            state.recipients.take(recipient);
+        }
+
+        state.wallets.prune(&limits.per_wallet, now);
+        state.ips.prune(&limits.per_ip, now);
+        state.recipients.prune(&limits.per_recipient, now);
+
+        Ok(())
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(RateLimits::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn limits(burst: u32) -> RateLimits {
+        RateLimits {
+            per_wallet: RateLimit::new(1.0, burst),
+            per_ip: RateLimit::new(1.0, burst * 2),
+            per_recipient: RateLimit::new(1.0, burst),
+        }
+    }
+
+    #[test]
+    fn test_bucket_refills_over_time() {
+        let limiter = RateLimiter::new(limits(2));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(limiter.check_at("alice", ip, None, now).is_ok());
+        assert!(limiter.check_at("alice", ip, None, now).is_ok());
+        assert_eq!(limiter.check_at("alice", ip, None, now), Err(RateLimitScope::Wallet));
+
+        // One second buys one more message
+        let later = now + Duration::from_secs(1);
+        assert!(limiter.check_at("alice", ip, None, later).is_ok());
+        assert_eq!(limiter.check_at("alice", ip, None, later), Err(RateLimitScope::Wallet));
+    }
+
+    #[test]
+    fn test_each_scope_is_enforced() {
+        let limiter = RateLimiter::new(limits(1));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other_ip: IpAddr = "10.0.0.2".parse().unwrap();
+        let now = Instant::now();
+
+        // Two wallets behind one address exhaust the IP bucket
+        assert!(limiter.check_at("alice", ip, None, now).is_ok());
+        assert!(limiter.check_at("bob", ip, None, now).is_ok());
+        assert_eq!(limiter.check_at("carol", ip, None, now), Err(RateLimitScope::Ip));
+
+        // Senders on different addresses share the recipient's inbox bucket
+        assert!(limiter.check_at("dave", other_ip, Some("victim"), now).is_ok());
+        assert_eq!(
+            limiter.check_at("erin", "10.0.0.3".parse().unwrap(), Some("victim"), now),
+            Err(RateLimitScope::Recipient)
+        );
+    }
+
+    #[test]
+    fn test_ip_checks_share_the_ip_bucket() {
+        let limiter = RateLimiter::new(limits(1));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(limiter.check_ip_at(ip, now).is_ok());
+        assert!(limiter.check_at("alice", ip, None, now).is_ok());
+        assert_eq!(limiter.check_ip_at(ip, now), Err(RateLimitScope::Ip));
+        assert!(limiter.check_ip_at("10.0.0.2".parse().unwrap(), now).is_ok());
+    }
+
+    #[test]
+    fn test_rejection_does_not_consume_other_buckets() {
+        let limiter = RateLimiter::new(limits(1));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(limiter.check_at("alice", ip, Some("bob"), now).is_ok());
+
+        // Carol is rejected by Bob's inbox limit but keeps her own token
+        assert_eq!(limiter.check_at("carol", "10.0.0.2".parse().unwrap(), Some("bob"), now), Err(RateLimitScope::Recipient));
+        assert!(limiter.check_at("carol", "10.0.0.2".parse().unwrap(), Some("dave"), now).is_ok());
+    }
+}
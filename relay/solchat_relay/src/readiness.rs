@@ -0,0 +1,159 @@
+use quinn::Endpoint;
+use serde::Serialize;
+use std::sync::{Arc, RwLock};
+use crate::federation::LinkStatus;
+use crate::router::MessageRouter;
+use crate::shutdown::Shutdown;
+
+/// Whether the relay should be sent traffic, served on `/ready`
+///
+/// The relay is ready when its QUIC listener is bound, its queue storage
+/// takes writes, and it is not draining. A failed config reload and the
+/// federation links are reported but do not count: the relay keeps serving
+/// with its last good config, and a relay cut off from its peers still
+/// serves its own clients.
+pub struct Readiness {
+    endpoint: Endpoint,
+    router: Arc<MessageRouter>,
+    shutdown: Arc<Shutdown>,
+    config_error: RwLock<Option<String>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReadinessReport {
+    pub ready: bool,
+    pub draining: bool,
+    pub checks: Checks,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Checks {
+    pub quic_listener: Check,
+    pub queue_storage: Check,
+    pub config: Check,
+    pub federation: FederationCheck,
+}
+
+/// Outcome of one dependency check
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FederationCheck {
+    pub enabled: bool,
+    pub links: Vec<LinkStatus>,
+}
+
+impl Check {
+    fn passed(detail: Option<String>) -> Self {
+        Self { ok: true, detail }
+    }
+
+    fn failed(detail: String) -> Self {
+        Self {
+            ok: false,
+            detail: Some(detail),
+        }
+    }
+}
+
+impl Readiness {
+    pub fn new(endpoint: Endpoint, router: Arc<MessageRouter>, shutdown: Arc<Shutdown>) -> Self {
+        Self {
+            endpoint,
+            router,
+            shutdown,
+            config_error: RwLock::new(None),
+        }
+    }
+
+    /// Record why the configuration file failed to reload, or `None` once
+    /// it loads again
+    pub fn set_config_error(&self, error: Option<String>) {
+        *self.config_error.write().unwrap() = error;
+    }
+
+    pub fn report(&self) -> ReadinessReport {
+        let quic_listener = match self.endpoint.local_addr() {
+            Ok(addr) => Check::passed(Some(format!("listening on {}", addr))),
+            Err(e) => Check::failed(format!("QUIC listener unavailable: {}", e)),
+        };
+        let queue_storage = match self.router.check_queue() {
+            Ok(()) => Check::passed(None),
+            Err(e) => Check::failed(format!("{:#}", e)),
+        };
+        let config = match self.config_error.read().unwrap().clone() {
+            None => Check::passed(None),
+            Some(error) => Check::failed(error),
+        };
+        let federation = match self.router.federation() {
+            Some(federation) => FederationCheck {
+                enabled: true,
+                links: federation.link_status(),
+            },
+            None => FederationCheck {
+                enabled: false,
+                links: Vec::new(),
+            },
+        };
+
+        let draining = self.shutdown.is_draining();
+        ReadinessReport {
+            ready: !draining && quic_listener.ok && queue_storage.ok,
+            draining,
+            checks: Checks {
+                quic_listener,
+                queue_storage,
+                config,
+                federation,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Metrics;
+    use crate::queue_store::FileQueueStore;
+    use crate::tls::{self, CertResolver};
+
+    fn endpoint() -> Endpoint {
+        let resolver = Arc::new(CertResolver::self_signed(vec!["localhost".into()]).unwrap());
+        let server_config = quinn::ServerConfig::with_crypto(Arc::new(tls::server_crypto(resolver)));
+        Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_ready_until_a_dependency_fails_or_the_relay_drains() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("queue.log");
+        let store = Arc::new(FileQueueStore::open(&path).unwrap());
+        let router = Arc::new(MessageRouter::with_store(Arc::new(Metrics::new()), store));
+        let shutdown = Arc::new(Shutdown::new());
+        let readiness = Readiness::new(endpoint(), router, shutdown.clone());
+
+        let report = readiness.report();
+        assert!(report.ready);
+        assert!(!report.checks.federation.enabled);
+
+        readiness.set_config_error(Some("unknown field `queu`".into()));
+        let report = readiness.report();
+        assert!(report.ready);
+        assert!(!report.checks.config.ok);
+        assert_eq!(report.checks.config.detail.as_deref(), Some("unknown field `queu`"));
+        readiness.set_config_error(None);
+
+        shutdown.begin();
+        let report = readiness.report();
+        assert!(report.draining);
+        assert!(!report.ready);
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(!readiness.report().checks.queue_storage.ok);
+    }
+}
@@ -1,15 +1,20 @@
 use anyhow::Result;
-use quinn::{SendStream, RecvStream};
-use solchat_protocol::messages::{ChatMessage, AckMessage, AckStatus, ReadReceipt, PingMessage, PongMessage};
+use serde::Serialize;
+use solchat_protocol::messages::{ChatMessage, AckMessage, AckStatus, ReadReceipt, PingMessage, PongMessage, PrekeyBundle, PrekeyBundleRequest};
+use solchat_protocol::messages::{envelope, Envelope, QueueHandoff, QueuedMessage};
 use solchat_protocol::WalletAddress;
-use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::{RwLock, mpsc};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Weak};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{RwLock, Notify, mpsc};
 use tracing::{info, warn, error, debug};
+use crate::federation::Federation;
+use crate::message_index::MessageIndex;
 use crate::metrics::Metrics;
-
-/// Maximum number of queued messages per recipient
-const MAX_QUEUED_MESSAGES: usize = 100;
+use crate::prekeys::PrekeyDirectory;
+use crate::presence::{self, PresenceStore};
+use crate::queue_limits::{EvictionPolicy, QueueLimits};
+use crate::queue_store::{MemoryQueueStore, QueueStore};
 
 /// Enum to represent all routable message types
 #[derive(Clone, Debug)]
@@ -19,9 +24,134 @@ pub enum RelayMessage {
     ReadReceipt(ReadReceipt),
     Ping(PingMessage),
     Pong(PongMessage),
+    PrekeyBundle(PrekeyBundle),
+    PrekeyBundleRequest(PrekeyBundleRequest),
 }
 
+impl RelayMessage {
+    /// Wrap the message in an envelope for the wire
+    pub fn to_envelope(&self) -> Envelope {
+        match self {
+            RelayMessage::Chat(message) => message.clone().into(),
+            RelayMessage::Ack(message) => message.clone().into(),
+            RelayMessage::ReadReceipt(message) => message.clone().into(),
+            RelayMessage::Ping(message) => message.clone().into(),
+            RelayMessage::Pong(message) => message.clone().into(),
+            RelayMessage::PrekeyBundle(message) => message.clone().into(),
+            RelayMessage::PrekeyBundleRequest(message) => message.clone().into(),
+        }
+    }
+    
+    /// Unwrap a received envelope, if it carries something to route
+    pub fn from_envelope(envelope: Envelope) -> Option<Self> {
+        let message = match envelope.body? {
+            envelope::Body::Chat(message) => RelayMessage::Chat(message),
+            envelope::Body::Ack(message) => RelayMessage::Ack(message),
+            envelope::Body::ReadReceipt(message) => RelayMessage::ReadReceipt(message),
+            envelope::Body::Ping(message) => RelayMessage::Ping(message),
+            envelope::Body::Pong(message) => RelayMessage::Pong(message),
+            envelope::Body::PrekeyBundle(message) => RelayMessage::PrekeyBundle(message),
+            envelope::Body::PrekeyBundleRequest(message) => RelayMessage::PrekeyBundleRequest(message),
+            // Only relays send GoAway; there is nothing to route
+            envelope::Body::GoAway(_) => return None,
+            // Only replicas ask each other for queues, on their links
+            envelope::Body::QueueHandoffRequest(_) => return None,
+        };
+        Some(message)
+    }
+    
+    /// Id of the wrapped message, for message types that carry one
+    pub fn message_id(&self) -> Option<&str> {
+        match self {
+            RelayMessage::Chat(message) => Some(&message.id),
+            RelayMessage::Ack(message) => Some(&message.id),
+            RelayMessage::ReadReceipt(message) => Some(&message.id),
+            RelayMessage::Ping(_)
+            | RelayMessage::Pong(_)
+            | RelayMessage::PrekeyBundle(_)
+            | RelayMessage::PrekeyBundleRequest(_) => None,
+        }
+    }
+    
+    /// Size of the message once wrapped in an envelope, without building one
+    pub fn encoded_len(&self) -> usize {
+        let (field, body_len) = match self {
+            RelayMessage::Chat(message) => (1, prost::Message::encoded_len(message)),
+            RelayMessage::Ack(message) => (2, prost::Message::encoded_len(message)),
+            RelayMessage::ReadReceipt(message) => (3, prost::Message::encoded_len(message)),
+            RelayMessage::Ping(message) => (4, prost::Message::encoded_len(message)),
+            RelayMessage::Pong(message) => (5, prost::Message::encoded_len(message)),
+            RelayMessage::PrekeyBundle(message) => (6, prost::Message::encoded_len(message)),
+            RelayMessage::PrekeyBundleRequest(message) => (8, prost::Message::encoded_len(message)),
+        };
+        prost::encoding::key_len(field) + prost::encoding::encoded_len_varint(body_len as u64) + body_len
+    }
+    
+    /// Short name used as a metrics label
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RelayMessage::Chat(_) => "ChatMessage",
+            RelayMessage::Ack(_) => "AckMessage",
+            RelayMessage::ReadReceipt(_) => "ReadReceipt",
+            RelayMessage::Ping(_) => "PingMessage",
+            RelayMessage::Pong(_) => "PongMessage",
+            RelayMessage::PrekeyBundle(_) => "PrekeyBundle",
+            RelayMessage::PrekeyBundleRequest(_) => "PrekeyBundleRequest",
+        }
+    }
+}
+
+/// Result of routing a message, reported back to the sender as an ack
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouteOutcome {
+    pub status: AckStatus,
+    pub reason: Option<String>,
+}
 
+impl RouteOutcome {
+    pub fn new(status: AckStatus) -> Self {
+        Self { status, reason: None }
+    }
+    
+    /// Outcome with a human-readable explanation for the sender
+    pub fn with_reason(status: AckStatus, reason: impl Into<String>) -> Self {
+        Self {
+            status,
+            reason: Some(reason.into()),
+        }
+    }
+    
+    /// Ack telling the sender what happened to the message with the given id
+    pub fn to_ack(&self, message_id: &str) -> AckMessage {
+        let ack = AckMessage::new(message_id.to_string(), self.status);
+        match &self.reason {
+            Some(reason) => ack.with_reason(reason.clone()),
+            None => ack,
+        }
+    }
+}
+
+/// Device id of clients that do not name one
+pub const DEFAULT_DEVICE: &str = "";
+
+/// Devices remembered per wallet; past this, the offline device seen least
+/// recently is forgotten along with its queue
+pub const MAX_DEVICES_PER_WALLET: usize = 16;
+
+/// Queue a device's messages are kept in: the wallet's own for the default
+/// device, `wallet:device` for named ones
+pub fn queue_key(wallet_address: &str, device_id: &str) -> String {
+    if device_id.is_empty() {
+        wallet_address.to_string()
+    } else {
+        format!("{}:{}", wallet_address, device_id)
+    }
+}
+
+/// Wallet and device a queue belongs to
+pub fn split_queue_key(key: &str) -> (&str, &str) {
+    key.split_once(':').unwrap_or((key, DEFAULT_DEVICE))
+}
 
 /// Message to be routed
 #[derive(Clone, Debug)]
@@ -31,260 +161,1282 @@ pub struct RoutableMessage {
 }
 
 /// Connection information for a connected client
+///
+/// The router holds the only lasting sender of the connection's channel,
+/// so dropping the entry ends the connection.
 #[derive(Clone)]
 pub struct ClientConnection {
     pub wallet_address: WalletAddress,
+    pub device_id: String,
     pub send_channel: mpsc::Sender<RoutableMessage>,
-    pub connected_at: std::time::Instant,
+    pub remote_addr: SocketAddr,
+    pub connected_at: SystemTime,
+}
+
+/// A connected wallet, as listed to operators
+#[derive(Clone, Debug, Serialize)]
+pub struct ConnectionInfo {
+    pub wallet: String,
+    pub device_id: String,
+    pub remote_addr: SocketAddr,
+    /// Unix time in seconds
+    pub connected_at: u64,
+}
+
+/// A device a wallet has connected with, as listed to operators
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DeviceInfo {
+    pub device_id: String,
+    pub online: bool,
+    /// Unix time in seconds the device last connected or disconnected
+    pub last_seen: u64,
+    pub queued: usize,
+}
+
+/// Messages queued for one recipient
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct QueueDepth {
+    pub recipient: String,
+    pub messages: usize,
+    pub bytes: usize,
 }
 
 /// Message router that handles routing messages between connected clients
 pub struct MessageRouter {
-    /// Map of wallet addresses to their connections
-    connections: Arc<RwLock<HashMap<String, ClientConnection>>>,
+    /// Live connections of each wallet, by device id
+    connections: Arc<RwLock<HashMap<String, HashMap<String, ClientConnection>>>>,
+    
+    /// Devices each wallet has used, with when each was last seen, so that
+    /// offline devices get their own copy of messages
+    devices: std::sync::RwLock<HashMap<String, HashMap<String, SystemTime>>>,
+    
+    /// Queued messages for offline recipients, one queue per device
+    message_queue: Arc<dyn QueueStore>,
+    
+    /// Size limits and eviction policy for the queues
+    queue_limits: std::sync::RwLock<QueueLimits>,
+    
+    /// Published prekey bundles for offline session setup
+    prekeys: PrekeyDirectory,
+    
+    /// Sender and recipient of each recently routed chat message, for acks
+    /// and receipts
+    message_senders: RwLock<MessageIndex>,
     
-    /// Queued messages for offline recipients
-    message_queue: Arc<RwLock<HashMap<String, Vec<RoutableMessage>>>>,
+    /// Links to other relays, for recipients homed elsewhere
+    federation: Option<Arc<Federation>>,
+    
+    /// Cluster-wide record of which replica each wallet is connected to
+    presence: Option<Arc<dyn PresenceStore>>,
+    
+    /// Name this replica registers its wallets under
+    node_id: String,
+    
+    /// Wallets an operator has banned from connecting
+    banned: std::sync::RwLock<HashSet<String>>,
+    
+    /// Woken when something is queued for a wallet, for clients that poll
+    /// their queue instead of holding a connection
+    queue_watchers: std::sync::Mutex<HashMap<String, Weak<Notify>>>,
     
     /// Metrics for monitoring
     metrics: Arc<Metrics>,
 }
 
 impl MessageRouter {
+    /// Router with an in-memory queue that does not survive restarts
     pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self::with_store(metrics, Arc::new(MemoryQueueStore::new()))
+    }
+    
+    /// Router backed by the given queue store
+    ///
+    /// Chat messages already in the store are indexed so that acks for them
+    /// still clear them from the queue, and the named devices they are
+    /// queued for are remembered.
+    pub fn with_store(metrics: Arc<Metrics>, message_queue: Arc<dyn QueueStore>) -> Self {
+        let mut index = MessageIndex::default();
+        let mut devices: HashMap<String, HashMap<String, SystemTime>> = HashMap::new();
+        for key in message_queue.recipients() {
+            let (recipient, device_id) = split_queue_key(&key);
+            if device_id != DEFAULT_DEVICE {
+                devices.entry(recipient.to_string()).or_default().insert(device_id.to_string(), SystemTime::now());
+            }
+            for queued in message_queue.pending(&key) {
+                if let RelayMessage::Chat(message) = &queued.message {
+                    index.insert(&message.id, &message.sender_wallet, recipient);
+                }
+            }
+        }
+        metrics.set_queued_messages(message_queue.total_queued() as i64);
+        metrics.set_queued_bytes(message_queue.total_bytes() as i64);
+        
         Self {
             connections: Arc::new(RwLock::new(HashMap::new())),
-            message_queue: Arc::new(RwLock::new(HashMap::new())),
+            devices: std::sync::RwLock::new(devices),
+            message_queue,
+            queue_limits: std::sync::RwLock::new(QueueLimits::default()),
+            prekeys: PrekeyDirectory::new(),
+            message_senders: RwLock::new(index),
+            federation: None,
+            presence: None,
+            node_id: String::new(),
+            banned: std::sync::RwLock::new(HashSet::new()),
+            queue_watchers: std::sync::Mutex::new(HashMap::new()),
             metrics,
         }
     }
     
-    /// Register a new client connection
+    /// Record the wallets connected here in `presence` as being on `node_id`
+    pub fn with_presence(mut self, presence: Arc<dyn PresenceStore>, node_id: impl Into<String>) -> Self {
+        self.presence = Some(presence);
+        self.node_id = node_id.into();
+        self
+    }
+    
+    /// Forward messages for wallets homed on other relays over `federation`
+    pub fn with_federation(mut self, federation: Arc<Federation>) -> Self {
+        self.federation = Some(federation);
+        self
+    }
+    
+    /// Links to other relays, if federation is enabled
+    pub fn federation(&self) -> Option<&Arc<Federation>> {
+        self.federation.as_ref()
+    }
+    
+    /// Limits currently applied to newly queued messages
+    pub fn queue_limits(&self) -> QueueLimits {
+        *self.queue_limits.read().unwrap()
+    }
+    
+    /// Replace the queue limits; already queued messages are only evicted
+    /// as new ones arrive
+    pub fn set_queue_limits(&self, limits: QueueLimits) {
+        *self.queue_limits.write().unwrap() = limits;
+    }
+    
+    /// Register a client connection as the wallet's default device
     pub async fn register_client(
         &self,
         wallet_address: WalletAddress,
         send_channel: mpsc::Sender<RoutableMessage>,
+        remote_addr: SocketAddr,
+    ) -> Result<()> {
+        self.register_device(wallet_address, DEFAULT_DEVICE, send_channel, remote_addr).await
+    }
+    
+    /// Register a connection from one of a wallet's devices
+    ///
+    /// A newer connection from the same device replaces the older one; other
+    /// devices of the wallet stay connected and each gets every message.
+    pub async fn register_device(
+        &self,
+        wallet_address: WalletAddress,
+        device_id: &str,
+        send_channel: mpsc::Sender<RoutableMessage>,
+        remote_addr: SocketAddr,
     ) -> Result<()> {
         let wallet_str = wallet_address.to_string();
+        let client = queue_key(&wallet_str, device_id);
         
         let mut connections = self.connections.write().await;
-        connections.insert(wallet_str.clone(), ClientConnection {
+        connections.entry(wallet_str.clone()).or_default().insert(device_id.to_string(), ClientConnection {
             wallet_address: wallet_address.clone(),
+            device_id: device_id.to_string(),
             send_channel: send_channel.clone(),
-            connected_at: std::time::Instant::now(),
+            remote_addr,
+            connected_at: SystemTime::now(),
         });
         
         // Update metrics
-        self.metrics.set_registered_clients(connections.len() as i64);
+        self.metrics.set_registered_clients(count_connections(&connections) as i64);
         
-        info!("📝 Registered client: {}", wallet_str);
+        info!("📝 Registered client: {}", client);
         
         // Check for queued messages
         drop(connections); // Release the write lock before calling deliver_queued_messages
-        self.deliver_queued_messages(&wallet_str, send_channel).await?;
+        self.record_presence(&wallet_str).await;
+        self.take_over_queues(&wallet_str).await;
+        self.add_device(&wallet_str, device_id).await?;
+        self.deliver_queued_messages(&client, send_channel).await?;
+        
+        Ok(())
+    }
+    
+    /// Remember that a wallet uses a device, so it gets its own copy of
+    /// messages while offline
+    ///
+    /// Messages queued for a wallet before any of its devices was known are
+    /// handed to the first named device to show up, unless a client without
+    /// a device id has claimed them. Past `MAX_DEVICES_PER_WALLET`, the
+    /// offline device seen least recently is forgotten.
+    pub async fn add_device(&self, wallet_address: &str, device_id: &str) -> Result<()> {
+        let online: HashSet<String> = self.connections
+            .read()
+            .await
+            .get(wallet_address)
+            .map(|devices| devices.keys().cloned().collect())
+            .unwrap_or_default();
+        
+        let (adopt, stale) = {
+            let mut devices = self.devices.write().unwrap();
+            let known = devices.entry(wallet_address.to_string()).or_default();
+            let adopt = device_id != DEFAULT_DEVICE && !known.contains_key(DEFAULT_DEVICE);
+            known.insert(device_id.to_string(), SystemTime::now());
+            let stale = (known.len() > MAX_DEVICES_PER_WALLET)
+                .then(|| {
+                    known
+                        .iter()
+                        .filter(|(device, _)| !online.contains(*device) && device.as_str() != device_id)
+                        .min_by_key(|(_, last_seen)| **last_seen)
+                        .map(|(device, _)| device.clone())
+                })
+                .flatten();
+            (adopt, stale)
+        };
+        
+        if adopt {
+            let unassigned = queue_key(wallet_address, DEFAULT_DEVICE);
+            let own = queue_key(wallet_address, device_id);
+            while let Some(message) = self.message_queue.pop_oldest(&unassigned)? {
+                self.push_queued(&own, message).await?;
+            }
+        }
+        if let Some(stale) = stale {
+            self.forget_device(wallet_address, &stale)?;
+        }
         
         Ok(())
     }
     
-    /// Unregister a client connection
+    /// Forget one of a wallet's devices and drop its queue, returning how
+    /// many messages were dropped
+    ///
+    /// Senders are not told, since the wallet's other devices got their own
+    /// copies.
+    pub fn forget_device(&self, wallet_address: &str, device_id: &str) -> Result<usize> {
+        if let Some(known) = self.devices.write().unwrap().get_mut(wallet_address) {
+            known.remove(device_id);
+        }
+        
+        let key = queue_key(wallet_address, device_id);
+        let mut dropped = 0;
+        while self.message_queue.pop_oldest(&key)?.is_some() {
+            dropped += 1;
+        }
+        
+        info!("🗑️ Forgot device {}, dropping {} queued messages", key, dropped);
+        self.update_queue_gauges();
+        Ok(dropped)
+    }
+    
+    /// Devices a wallet has used, sorted by device id
+    pub async fn devices(&self, wallet_address: &str) -> Vec<DeviceInfo> {
+        let connections = self.connections.read().await;
+        let online = connections.get(wallet_address);
+        let devices = self.devices.read().unwrap();
+        let mut listed: Vec<DeviceInfo> = devices
+            .get(wallet_address)
+            .into_iter()
+            .flatten()
+            .map(|(device_id, last_seen)| DeviceInfo {
+                device_id: device_id.clone(),
+                online: online.is_some_and(|online| online.contains_key(device_id)),
+                last_seen: unix_secs(*last_seen),
+                queued: self.message_queue.queued_for(&queue_key(wallet_address, device_id)),
+            })
+            .collect();
+        listed.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+        listed
+    }
+    
+    /// Record when a device was last seen
+    fn touch_device(&self, wallet_address: &str, device_id: &str) {
+        if let Some(known) = self.devices.write().unwrap().get_mut(wallet_address) {
+            if let Some(last_seen) = known.get_mut(device_id) {
+                *last_seen = SystemTime::now();
+            }
+        }
+    }
+    
+    /// Unregister every connection of a wallet
     pub async fn unregister_client(&self, wallet_address: &WalletAddress) -> Result<()> {
         let wallet_str = wallet_address.to_string();
         
         let mut connections = self.connections.write().await;
-        if connections.remove(&wallet_str).is_some() {
+        if let Some(devices) = connections.remove(&wallet_str) {
             // Update metrics
-            self.metrics.set_registered_clients(connections.len() as i64);
+            self.metrics.set_registered_clients(count_connections(&connections) as i64);
             info!("🔌 Unregistered client: {}", wallet_str);
+            for device_id in devices.keys() {
+                self.touch_device(&wallet_str, device_id);
+            }
+            drop(connections);
+            self.forget_presence(&wallet_str).await;
+        }
+        
+        Ok(())
+    }
+    
+    /// Unregister a client only if it is still registered with this channel
+    ///
+    /// Used when a connection closes, so that a newer connection for the
+    /// same device is left in place.
+    pub async fn unregister_client_channel(
+        &self,
+        wallet_address: &WalletAddress,
+        send_channel: &mpsc::Sender<RoutableMessage>,
+    ) -> Result<()> {
+        let wallet_str = wallet_address.to_string();
+        
+        let mut connections = self.connections.write().await;
+        let Some(devices) = connections.get_mut(&wallet_str) else {
+            return Ok(());
+        };
+        let current = devices
+            .iter()
+            .find(|(_, connection)| connection.send_channel.same_channel(send_channel))
+            .map(|(device_id, _)| device_id.clone());
+        
+        if let Some(device_id) = current {
+            devices.remove(&device_id);
+            let last_device = devices.is_empty();
+            if last_device {
+                connections.remove(&wallet_str);
+            }
+            self.metrics.set_registered_clients(count_connections(&connections) as i64);
+            info!("🔌 Unregistered client: {}", queue_key(&wallet_str, &device_id));
+            self.touch_device(&wallet_str, &device_id);
+            drop(connections);
+            if last_device {
+                self.forget_presence(&wallet_str).await;
+            }
         }
         
         Ok(())
     }
     
+    /// Drop all of a wallet's connections, which closes them, returning
+    /// whether the wallet was connected
+    pub async fn disconnect(&self, wallet_address: &str) -> bool {
+        let mut connections = self.connections.write().await;
+        if connections.remove(wallet_address).is_none() {
+            return false;
+        }
+        
+        self.metrics.set_registered_clients(count_connections(&connections) as i64);
+        info!("⛔ Disconnected client: {}", wallet_address);
+        drop(connections);
+        self.forget_presence(wallet_address).await;
+        true
+    }
+    
+    /// Refuse the wallet's connections from now on, closing the current ones
+    pub async fn ban(&self, wallet_address: &str) {
+        self.banned.write().unwrap().insert(wallet_address.to_string());
+        warn!("🚫 Banned wallet: {}", wallet_address);
+        self.disconnect(wallet_address).await;
+    }
+    
+    /// Lift a ban, returning whether the wallet was banned
+    pub fn unban(&self, wallet_address: &str) -> bool {
+        self.banned.write().unwrap().remove(wallet_address)
+    }
+    
+    pub fn is_banned(&self, wallet_address: &str) -> bool {
+        self.banned.read().unwrap().contains(wallet_address)
+    }
+    
+    /// Banned wallets, sorted
+    pub fn banned(&self) -> Vec<String> {
+        let mut banned: Vec<String> = self.banned.read().unwrap().iter().cloned().collect();
+        banned.sort();
+        banned
+    }
+    
+    /// Connected devices, sorted by wallet and device
+    pub async fn connections(&self) -> Vec<ConnectionInfo> {
+        let connections = self.connections.read().await;
+        let mut listed: Vec<ConnectionInfo> = connections
+            .iter()
+            .flat_map(|(wallet, devices)| devices.values().map(move |connection| (wallet, connection)))
+            .map(|(wallet, connection)| ConnectionInfo {
+                wallet: wallet.clone(),
+                device_id: connection.device_id.clone(),
+                remote_addr: connection.remote_addr,
+                connected_at: unix_secs(connection.connected_at),
+            })
+            .collect();
+        listed.sort_by(|a, b| a.wallet.cmp(&b.wallet).then_with(|| a.device_id.cmp(&b.device_id)));
+        listed
+    }
+    
+    /// Record a wallet as connected to this replica in the presence store
+    async fn record_presence(&self, wallet_address: &str) {
+        let Some(presence) = &self.presence else {
+            return;
+        };
+        let (wallet, node_id) = (wallet_address.to_string(), self.node_id.clone());
+        if let Err(e) = presence::run(presence, move |presence| presence.register(&wallet, &node_id)).await {
+            warn!("Failed to record presence of {}: {:#}", wallet_address, e);
+        }
+    }
+    
+    /// Remove a wallet's connection to this replica from the presence store
+    ///
+    /// Runs after the connection is gone, without holding the connection
+    /// table, so a connection the wallet made in the meantime is recorded
+    /// again.
+    async fn forget_presence(&self, wallet_address: &str) {
+        let Some(presence) = &self.presence else {
+            return;
+        };
+        let (wallet, node_id) = (wallet_address.to_string(), self.node_id.clone());
+        if let Err(e) = presence::run(presence, move |presence| presence.unregister(&wallet, &node_id)).await {
+            warn!("Failed to clear presence of {}: {:#}", wallet_address, e);
+        }
+        if self.connections.read().await.contains_key(wallet_address) {
+            self.record_presence(wallet_address).await;
+        }
+    }
+    
     /// Route a message to its recipient
+    ///
+    /// The outcome tells the sender whether the message was handed to a live
+    /// connection, queued for an offline recipient, or refused.
     pub async fn route_message(
         &self,
         relay_message: RelayMessage,
         sender_addr: SocketAddr,
-    ) -> Result<AckStatus> {
+    ) -> Result<RouteOutcome> {
+        self.route(relay_message, sender_addr, None, DEFAULT_DEVICE, None).await
+    }
+    
+    /// Route a message sent by one of an authenticated wallet's devices
+    ///
+    /// Acks are only accepted for messages sent to `wallet` and only clear
+    /// the acknowledged message from that device's queue, and prekey bundles
+    /// are only stored for `wallet` itself.
+    pub async fn route_message_from(
+        &self,
+        relay_message: RelayMessage,
+        wallet: &WalletAddress,
+        device_id: &str,
+        sender_addr: SocketAddr,
+    ) -> Result<RouteOutcome> {
+        self.route(relay_message, sender_addr, Some(wallet), device_id, None).await
+    }
+    
+    /// Route a message another relay forwarded to this one
+    ///
+    /// Its recipient is expected to be homed here, so it is delivered or
+    /// queued locally and never forwarded again. Replies to a forwarded chat
+    /// message go back to the relay it came from.
+    pub async fn route_forwarded(
+        &self,
+        relay_message: RelayMessage,
+        from_relay: &str,
+        sender_addr: SocketAddr,
+    ) -> Result<RouteOutcome> {
+        self.route(relay_message, sender_addr, None, DEFAULT_DEVICE, Some(from_relay)).await
+    }
+    
+    async fn route(
+        &self,
+        relay_message: RelayMessage,
+        sender_addr: SocketAddr,
+        wallet: Option<&WalletAddress>,
+        device_id: &str,
+        from_relay: Option<&str>,
+    ) -> Result<RouteOutcome> {
         match relay_message {
             RelayMessage::Chat(message) => {
-                // Validate the message
-                let recipient = message.recipient_wallet;
+                if message.is_expired() {
+                    debug!("⌛ Dropping expired message {} from {:?}", message.id, sender_addr);
+                    self.metrics.record_message_expired();
+                    return Ok(RouteOutcome::with_reason(AckStatus::Expired, "message TTL elapsed"));
+                }
+                
+                let recipient_str = message.recipient_wallet.clone();
+                
+                {
+                    let mut index = self.message_senders.write().await;
+                    if !index.insert(&message.id, &message.sender_wallet, &message.recipient_wallet) {
+                        warn!("Rejecting chat message reusing id {} from {:?}", message.id, sender_addr);
+                        return Ok(RouteOutcome::with_reason(AckStatus::Rejected, "message id already in use"));
+                    }
+                    if let Some(from_relay) = from_relay {
+                        index.set_origin(&message.id, from_relay);
+                    }
+                }
                 
-                let recipient_str = recipient.to_string();
                 let routable = RoutableMessage {
-                    message: RelayMessage::Chat(message.clone()),
+                    message: RelayMessage::Chat(message),
                     sender_addr,
                 };
                 
-                // Check if recipient is online
-                let connections = self.connections.read().await;
-                if let Some(connection) = connections.get(&recipient_str) {
-                    // Recipient is online, send directly
-                    match connection.send_channel.send(routable.clone()).await {
-                        Ok(_) => {
-                            debug!("✉️ Message routed to online recipient: {}", recipient_str);
-                            self.metrics.record_message_routed();
-                            Ok(AckStatus::Delivered)
-                        }
-                        Err(e) => {
-                            error!("Failed to send to recipient channel: {}", e);
-                            // Queue the message as the channel might be full
-                            drop(connections);
-                            self.queue_message(&recipient_str, routable).await?;
-                            Ok(AckStatus::Delivered)
-                        }
-                    }
-                } else {
-                    // Recipient is offline, queue the message
-                    drop(connections);
-                    self.queue_message(&recipient_str, routable).await?;
-                    debug!("📮 Message queued for offline recipient: {}", recipient_str);
-                    Ok(AckStatus::Delivered)
-                }
+                self.send_or_queue(&recipient_str, routable, from_relay.is_none()).await
             },
             RelayMessage::Ack(ack_message) => {
+                // Only the message's recipient may acknowledge it
+                if let Some(acker) = wallet {
+                    let recipient = self.message_senders.read().await.recipient_of(&ack_message.ref_message_id).map(str::to_string);
+                    if recipient.is_some_and(|recipient| recipient != acker.to_string()) {
+                        warn!("Rejecting ack for {} from {}, who did not receive it", ack_message.ref_message_id, acker);
+                        return Ok(RouteOutcome::with_reason(AckStatus::Rejected, "not the message's recipient"));
+                    }
+                }
+                
+                // The recipient has the message, so it no longer needs queueing
+                self.clear_acknowledged(&ack_message.ref_message_id, device_id).await?;
+                
                 // Acknowledge messages are routed back to the original sender
-                let original_sender = ack_message.ref_message_id.split('-').next().unwrap_or(""); // Assuming message ID contains sender
+                let Some(original_sender) = self.sender_of(&ack_message.ref_message_id).await else {
+                    warn!("Unknown message id, cannot route ack: {}", ack_message.ref_message_id);
+                    return Ok(RouteOutcome::with_reason(AckStatus::Failed, "unknown message id"));
+                };
+                
                 let routable = RoutableMessage {
-                    message: RelayMessage::Ack(ack_message.clone()),
+                    message: RelayMessage::Ack(ack_message),
                     sender_addr,
                 };
-
-                let connections = self.connections.read().await;
-                if let Some(connection) = connections.get(original_sender) {
-                    match connection.send_channel.send(routable.clone()).await {
-                        Ok(_) => {
-                            debug!("✅ Ack routed to original sender: {}", original_sender);
-                            Ok(AckStatus::Delivered)
-                        },
-                        Err(e) => {
-                            error!("Failed to send ack to original sender: {}", e);
-                            Ok(AckStatus::Failed)
-                        }
-                    }
-                } else {
-                    warn!("Original sender offline, cannot route ack: {}", original_sender);
-                    Ok(AckStatus::Failed)
-                }
+                
+                self.send_or_queue(&original_sender, routable, from_relay.is_none()).await
             },
             RelayMessage::ReadReceipt(read_receipt) => {
+                // Only the message's recipient may say it was read
+                if let Some(reader) = wallet {
+                    let recipient = self.message_senders.read().await.recipient_of(&read_receipt.message_id).map(str::to_string);
+                    if recipient.is_some_and(|recipient| recipient != reader.to_string()) {
+                        warn!("Rejecting read receipt for {} from {}, who did not receive it", read_receipt.message_id, reader);
+                        return Ok(RouteOutcome::with_reason(AckStatus::Rejected, "not the message's recipient"));
+                    }
+                }
+                
                 // Read receipts are routed back to the sender of the original message
-                let original_sender = read_receipt.message_id.split('-').next().unwrap_or(""); // Assuming message ID contains sender
+                let Some(original_sender) = self.sender_of(&read_receipt.message_id).await else {
+                    warn!("Unknown message id, cannot route read receipt: {}", read_receipt.message_id);
+                    return Ok(RouteOutcome::with_reason(AckStatus::Failed, "unknown message id"));
+                };
+                
                 let routable = RoutableMessage {
-                    message: RelayMessage::ReadReceipt(read_receipt.clone()),
+                    message: RelayMessage::ReadReceipt(read_receipt),
                     sender_addr,
                 };
-
-                let connections = self.connections.read().await;
-                if let Some(connection) = connections.get(original_sender) {
-                    match connection.send_channel.send(routable.clone()).await {
-                        Ok(_) => {
-                            debug!("👀 Read receipt routed to original sender: {}", original_sender);
-                            Ok(AckStatus::Delivered)
-                        },
-                        Err(e) => {
-                            error!("Failed to send read receipt to original sender: {}", e);
-                            Ok(AckStatus::Failed)
-                        }
-                    }
-                } else {
-                    warn!("Original sender offline, cannot route read receipt: {}", original_sender);
-                    Ok(AckStatus::Failed)
-                }
+                
+                self.send_or_queue(&original_sender, routable, from_relay.is_none()).await
             },
-            RelayMessage::Ping(ping_message) => {
-                info!("Received ping from {:?}", sender_addr);
-                // Pings are not routed, just acknowledged implicitly by connection staying alive
-                Ok(AckStatus::Delivered)
+            message @ (RelayMessage::Ping(_) | RelayMessage::Pong(_) | RelayMessage::PrekeyBundleRequest(_)) => {
+                // Keepalives and bundle requests are answered per connection
+                // and never routed
+                debug!("Ignoring {} from {:?}", message.kind(), sender_addr);
+                Ok(RouteOutcome::new(AckStatus::Delivered))
             },
-            RelayMessage::Pong(pong_message) => {
-                info!("Received pong from {:?}", sender_addr);
-                // Pongs are not routed
-                Ok(AckStatus::Delivered)
+            RelayMessage::PrekeyBundle(bundle) => {
+                // Bundles are stored for the owning wallet, not routed
+                let Some(publisher) = wallet else {
+                    warn!("Rejected prekey bundle from unauthenticated {:?}", sender_addr);
+                    return Ok(RouteOutcome::with_reason(AckStatus::Rejected, "prekey bundles are only accepted from their wallet"));
+                };
+                match self.prekeys.publish(bundle, publisher).await {
+                    Ok(()) => Ok(RouteOutcome::new(AckStatus::Delivered)),
+                    Err(e) => {
+                        warn!("Rejected prekey bundle from {:?}: {}", sender_addr, e);
+                        Ok(RouteOutcome::with_reason(AckStatus::Rejected, e.to_string()))
+                    }
+                }
             },
         }
     }
     
+    /// Drop an acknowledged chat message from the acknowledging device's queue
+    async fn clear_acknowledged(&self, message_id: &str, device_id: &str) -> Result<()> {
+        let recipient = self.message_senders
+            .read()
+            .await
+            .recipient_of(message_id)
+            .map(str::to_string);
+        
+        if let Some(recipient) = recipient {
+            if self.message_queue.remove(&queue_key(&recipient, device_id), message_id)?.is_some() {
+                debug!("🗑️ Acknowledged message {} removed from queue", message_id);
+                self.update_queue_gauges();
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Wallet that sent a recently routed chat message
+    async fn sender_of(&self, message_id: &str) -> Option<String> {
+        self.message_senders
+            .read()
+            .await
+            .sender_of(message_id)
+            .map(str::to_string)
+    }
+    
+    /// Relay or replica to forward a message for a wallet that is not
+    /// connected here
+    ///
+    /// That is the replica the wallet is connected to, if the presence store
+    /// knows one, else for replies the relay the referenced chat message came
+    /// from, and else the relay the directory says the wallet is homed on.
+    async fn remote_for(&self, wallet_address: &str, message: &RelayMessage) -> Option<String> {
+        let federation = self.federation.as_ref()?;
+        
+        if let Some(node) = self.locate(wallet_address).await {
+            if node != self.node_id && federation.is_linked(&node) {
+                return Some(node);
+            }
+        }
+        
+        let referenced = match message {
+            RelayMessage::Ack(ack) => Some(&ack.ref_message_id),
+            RelayMessage::ReadReceipt(receipt) => Some(&receipt.message_id),
+            _ => None,
+        };
+        if let Some(message_id) = referenced {
+            let origin = self.message_senders.read().await.origin_of(message_id).map(str::to_string);
+            if origin.is_some() {
+                return origin;
+            }
+        }
+        
+        federation.home_relay(wallet_address).map(str::to_string)
+    }
+    
+    /// Node a wallet is connected to, according to the presence store
+    async fn locate(&self, wallet_address: &str) -> Option<String> {
+        let wallet = wallet_address.to_string();
+        match presence::run(self.presence.as_ref()?, move |presence| presence.locate(&wallet)).await {
+            Ok(node) => node,
+            Err(e) => {
+                warn!("Presence lookup for {} failed: {:#}", wallet_address, e);
+                None
+            }
+        }
+    }
+    
+    /// Hand a message to each of a wallet's live connections, returning the
+    /// devices that took it
+    ///
+    /// Devices whose connection is going away are left out, so the message
+    /// is kept for their next one.
+    async fn send_live(&self, wallet_address: &str, routable: &RoutableMessage) -> Vec<String> {
+        let connections = self.connections.read().await;
+        let Some(devices) = connections.get(wallet_address) else {
+            return Vec::new();
+        };
+        
+        let kind = routable.message.kind();
+        let mut delivered = Vec::new();
+        for (device_id, connection) in devices {
+            match connection.send_channel.send(routable.clone()).await {
+                Ok(()) => {
+                    debug!("✉️ {} routed to online wallet: {}", kind, queue_key(wallet_address, device_id));
+                    self.metrics.record_message_routed();
+                    delivered.push(device_id.clone());
+                }
+                Err(e) => error!("Failed to send to wallet channel: {}", e),
+            }
+        }
+        delivered
+    }
+    
+    /// Devices of a wallet that need a queued copy of a message the live
+    /// ones in `delivered` already took
+    ///
+    /// A wallet with no known devices has its messages queued under the
+    /// default device until one connects.
+    fn offline_devices(&self, wallet_address: &str, delivered: &[String]) -> Vec<String> {
+        let mut offline: Vec<String> = self.devices
+            .read()
+            .unwrap()
+            .get(wallet_address)
+            .into_iter()
+            .flat_map(|known| known.keys())
+            .filter(|device_id| !delivered.contains(device_id))
+            .cloned()
+            .collect();
+        if offline.is_empty() && delivered.is_empty() {
+            offline.push(DEFAULT_DEVICE.to_string());
+        }
+        offline
+    }
+    
+    /// Send a message to every connected device of a wallet, or else forward
+    /// it to the relay or replica the wallet is on, and queue a copy for each
+    /// of its devices that did not take it
+    ///
+    /// Chat messages are also stored for the connected devices, and stay
+    /// stored until each device acks them.
+    ///
+    /// The outcome is `Delivered` if a live connection took the message,
+    /// `Queued` if it was queued, and `Rejected` if the queue limits left no
+    /// room for it. Forwarded messages get the outcome the other relay
+    /// reported. Messages that were forwarded here are not forwarded again.
+    async fn send_or_queue(
+        &self,
+        wallet_address: &str,
+        routable: RoutableMessage,
+        forward: bool,
+    ) -> Result<RouteOutcome> {
+        // Chats are stored before they go out, so one lost with a dropping
+        // connection is delivered again until the device acks it
+        let stored = match routable.message {
+            RelayMessage::Chat(_) => self.store_for_connected_devices(wallet_address, &routable).await?,
+            _ => Vec::new(),
+        };
+        let delivered = self.send_live(wallet_address, &routable).await;
+        
+        if let (true, true, true, Some(federation)) = (delivered.is_empty(), stored.is_empty(), forward, &self.federation) {
+            if let Some(relay_id) = self.remote_for(wallet_address, &routable.message).await {
+                return Ok(federation.forward(&relay_id, &routable.message).await);
+            }
+        }
+        
+        let mut outcome = (!delivered.is_empty()).then(|| RouteOutcome::new(AckStatus::Delivered));
+        for device_id in self.offline_devices(wallet_address, &delivered) {
+            let queued = if stored.contains(&device_id) {
+                RouteOutcome::new(AckStatus::Queued)
+            } else {
+                self.queue_message(&queue_key(wallet_address, &device_id), routable.clone()).await?
+            };
+            // One device holding a copy is enough for the sender
+            if outcome.as_ref().is_none_or(|outcome| outcome.status == AckStatus::Rejected) {
+                outcome = Some(queued);
+            }
+        }
+        
+        Ok(outcome.unwrap_or_else(|| RouteOutcome::new(AckStatus::Delivered)))
+    }
+    
+    /// Store a chat message for each of a wallet's connected devices until
+    /// it acks it, returning the devices that have a stored copy
+    ///
+    /// The copies count against the queue limits like any queued message.
+    /// A device with no room for one still gets the message live.
+    async fn store_for_connected_devices(&self, wallet_address: &str, routable: &RoutableMessage) -> Result<Vec<String>> {
+        let connected: Vec<String> = self.connections
+            .read()
+            .await
+            .get(wallet_address)
+            .into_iter()
+            .flat_map(|devices| devices.keys().cloned())
+            .collect();
+        
+        let mut stored = Vec::new();
+        for device_id in connected {
+            let outcome = self.store_message(&queue_key(wallet_address, &device_id), routable.clone()).await?;
+            if outcome.status == AckStatus::Queued {
+                stored.push(device_id);
+            }
+        }
+        Ok(stored)
+    }
+    
+    /// Tell a message's sender what became of it
+    ///
+    /// Notifications bypass the queue limits, since there is at most one
+    /// per message that was evicted or expired to free space.
+    async fn notify_sender(&self, sender_wallet: &str, ack: AckMessage, sender_addr: SocketAddr) -> Result<()> {
+        let routable = RoutableMessage {
+            message: RelayMessage::Ack(ack),
+            sender_addr,
+        };
+        
+        let delivered = self.send_live(sender_wallet, &routable).await;
+        if delivered.is_empty() {
+            let remote = self.remote_for(sender_wallet, &routable.message).await;
+            if let (Some(federation), Some(relay_id)) = (&self.federation, remote) {
+                federation.forward(&relay_id, &routable.message).await;
+                return Ok(());
+            }
+        }
+        
+        for device_id in self.offline_devices(sender_wallet, &delivered) {
+            let key = queue_key(sender_wallet, &device_id);
+            self.push_queued(&key, routable.clone()).await?;
+            self.metrics.record_message_queued();
+            self.wake_queue_watchers(&key);
+        }
+        
+        Ok(())
+    }
+    
+    /// Fetch a wallet's prekey bundle so a sender can open a session with it
+    /// while it is offline
+    pub async fn fetch_prekey_bundle(&self, wallet_address: &WalletAddress) -> Option<PrekeyBundle> {
+        self.prekeys.fetch(&wallet_address.to_string()).await
+    }
+    
     /// Queue a message for an offline recipient
+    ///
+    /// Makes room according to the eviction policy when the recipient's
+    /// queue or the relay as a whole is over its limits.
     async fn queue_message(
         &self,
         recipient: &str,
         message: RoutableMessage,
-    ) -> Result<()> {
-        let mut queue = self.message_queue.write().await;
-        let messages = queue.entry(recipient.to_string()).or_insert_with(Vec::new);
+    ) -> Result<RouteOutcome> {
+        let kind = message.message.kind();
+        let outcome = self.store_message(recipient, message).await?;
+        if outcome.status == AckStatus::Queued {
+            self.metrics.record_message_queued();
+            self.wake_queue_watchers(recipient);
+            debug!("📮 {} queued for offline wallet: {}", kind, recipient);
+        }
+        
+        Ok(outcome)
+    }
+    
+    /// Add a message to a queue within the queue limits, evicting by the
+    /// eviction policy to make room
+    async fn store_message(
+        &self,
+        recipient: &str,
+        message: RoutableMessage,
+    ) -> Result<RouteOutcome> {
+        let limits = self.queue_limits();
+        let size = message.message.encoded_len();
+        let kind = message.message.kind();
         
-        // Limit queue size to prevent memory issues
-        if messages.len() >= MAX_QUEUED_MESSAGES {
-            warn!("Message queue full for recipient: {}, dropping oldest message", recipient);
-            messages.remove(0);
+        if size > limits.max_bytes_per_recipient {
+            warn!("Rejecting {} byte {} for {}: larger than the queue quota", size, kind, recipient);
+            return Ok(RouteOutcome::with_reason(AckStatus::Rejected, "message exceeds the recipient queue quota"));
         }
         
-        messages.push(message);
-        self.metrics.record_message_queued();
+        loop {
+            let recipient_full = self.message_queue.queued_for(recipient) >= limits.max_messages_per_recipient
+                || self.message_queue.bytes_queued_for(recipient) + size > limits.max_bytes_per_recipient;
+            
+            let (victim, reason) = if recipient_full {
+                (Some(recipient.to_string()), "recipient queue full")
+            } else if self.message_queue.total_bytes() + size > limits.max_total_bytes {
+                (self.largest_queue(), "relay queue storage full")
+            } else {
+                break;
+            };
+            
+            let evicted = match (limits.policy, victim) {
+                (EvictionPolicy::RejectNew, _) | (_, None) => false,
+                (policy, Some(victim)) => self.evict_one(&victim, policy).await?,
+            };
+            if !evicted {
+                warn!("Rejecting {} for {}: {}", kind, recipient, reason);
+                return Ok(RouteOutcome::with_reason(AckStatus::Rejected, reason));
+            }
+        }
         
-        // Update queued messages metric
-        let total_queued: usize = queue.values().map(|v| v.len()).sum();
-        self.metrics.set_queued_messages(total_queued as i64);
+        self.push_queued(recipient, message).await?;
+        self.update_queue_gauges();
         
-        Ok(())
+        Ok(RouteOutcome::new(AckStatus::Queued))
+    }
+    
+    /// Push to the queue store on the blocking pool, returning once the
+    /// message is stored durably, so it is never acked before that
+    async fn push_queued(&self, recipient: &str, message: RoutableMessage) -> Result<()> {
+        let (queue, recipient) = (self.message_queue.clone(), recipient.to_string());
+        tokio::task::spawn_blocking(move || queue.push(&recipient, message)).await?
+    }
+    
+    /// Recipient with the most bytes queued
+    fn largest_queue(&self) -> Option<String> {
+        self.message_queue
+            .recipients()
+            .into_iter()
+            .max_by_key(|recipient| self.message_queue.bytes_queued_for(recipient))
+    }
+    
+    /// Evict one message from a recipient's queue and tell its sender
+    ///
+    /// Returns `false` if the queue was already empty.
+    async fn evict_one(&self, recipient: &str, policy: EvictionPolicy) -> Result<bool> {
+        if policy == EvictionPolicy::DropExpiredFirst {
+            let expired = self.message_queue
+                .pending(recipient)
+                .into_iter()
+                .find_map(|queued| match queued.message {
+                    RelayMessage::Chat(message) if message.is_expired() => Some((message, queued.sender_addr)),
+                    _ => None,
+                });
+            if let Some((message, sender_addr)) = expired {
+                self.expire_queued(recipient, message, sender_addr).await?;
+                return Ok(true);
+            }
+        }
+        
+        let Some(evicted) = self.message_queue.pop_oldest(recipient)? else {
+            return Ok(false);
+        };
+        
+        warn!("🗑️ Evicted queued {} for {} to make room", evicted.message.kind(), recipient);
+        self.metrics.record_message_evicted();
+        
+        // Acks and receipts are best effort; only chat senders are told
+        if let RelayMessage::Chat(message) = evicted.message {
+            let ack = AckMessage::failed(message.id).with_reason("evicted from a full queue");
+            self.notify_sender(&message.sender_wallet, ack, evicted.sender_addr).await?;
+        }
+        
+        Ok(true)
     }
     
     /// Deliver queued messages to a newly connected client
+    ///
+    /// Chat messages stay queued until the client acknowledges them, so they
+    /// are delivered again after a reconnect if the ack never arrives. Acks
+    /// and read receipts are not acknowledged themselves and are dropped from
+    /// the queue once handed to the connection.
     async fn deliver_queued_messages(
         &self,
         wallet_address: &str,
         send_channel: mpsc::Sender<RoutableMessage>,
     ) -> Result<()> {
-        let mut queue = self.message_queue.write().await;
+        let messages = self.message_queue.pending(wallet_address);
+        if messages.is_empty() {
+            return Ok(());
+        }
         
-        if let Some(mut messages) = queue.remove(wallet_address) {
-            let total_messages = messages.len();
-            info!("📤 Delivering {} queued messages to {}", total_messages, wallet_address);
-            
-            let mut delivered = 0;
-            let mut failed_messages = Vec::new();
-            
-            for message in messages.drain(..) {
-                if let Err(e) = send_channel.send(message.clone()).await {
-                    error!("Failed to deliver queued message: {}", e);
-                    // Collect failed messages to re-queue
-                    failed_messages.push(message);
-                    break;
+        let total_messages = messages.len();
+        info!("📤 Delivering {} queued messages to {}", total_messages, wallet_address);
+        
+        let mut delivered = 0;
+        for message in messages {
+            if let RelayMessage::Chat(chat) = &message.message {
+                if chat.is_expired() {
+                    self.expire_queued(wallet_address, chat.clone(), message.sender_addr).await?;
+                    continue;
                 }
-                delivered += 1;
-                self.metrics.record_message_routed();
             }
             
-            // Re-queue any failed messages
-            if !failed_messages.is_empty() {
-                queue.entry(wallet_address.to_string())
-                    .or_insert_with(Vec::new)
-                    .extend(failed_messages);
+            let message_id = message.message.message_id().map(str::to_string);
+            let awaits_ack = matches!(message.message, RelayMessage::Chat(_));
+            
+            if let Err(e) = send_channel.send(message).await {
+                error!("Failed to deliver queued message: {}", e);
+                break;
             }
+            delivered += 1;
+            self.metrics.record_message_routed();
             
-            info!("✅ Delivered {}/{} queued messages", delivered, total_messages);
+            if let (false, Some(message_id)) = (awaits_ack, message_id) {
+                self.message_queue.remove(wallet_address, &message_id)?;
+            }
+        }
+        
+        info!("✅ Delivered {}/{} queued messages", delivered, total_messages);
+        
+        self.update_queue_gauges();
+        
+        Ok(())
+    }
+    
+    /// Take what is queued for a wallet that reads its queue instead of
+    /// holding a connection
+    ///
+    /// Mirrors `deliver_queued_messages`: expired chat messages are dropped
+    /// and their senders told, chat messages stay queued until acknowledged,
+    /// and acks and read receipts are removed as they are returned. The
+    /// caller counts what it hands out as routed, since chat messages are
+    /// returned on every read until acknowledged.
+    pub async fn take_queued(&self, wallet_address: &str, device_id: &str) -> Result<Vec<RoutableMessage>> {
+        let key = queue_key(wallet_address, device_id);
+        let mut taken = Vec::new();
+        for message in self.message_queue.pending(&key) {
+            if let RelayMessage::Chat(chat) = &message.message {
+                if chat.is_expired() {
+                    self.expire_queued(&key, chat.clone(), message.sender_addr).await?;
+                    continue;
+                }
+            }
             
-            // Update queued messages metric
-            let total_queued: usize = queue.values().map(|v| v.len()).sum();
-            self.metrics.set_queued_messages(total_queued as i64);
+            if let (false, Some(message_id)) = (matches!(message.message, RelayMessage::Chat(_)), message.message.message_id()) {
+                self.message_queue.remove(&key, message_id)?;
+            }
+            taken.push(message);
+        }
+        
+        self.update_queue_gauges();
+        Ok(taken)
+    }
+    
+    /// Messages queued here for a wallet that connected to another replica,
+    /// at most `max_bytes` once encoded but at least one if any are queued
+    ///
+    /// Queues of the wallet's devices connected here are left alone, since
+    /// they hold copies those devices have yet to acknowledge here.
+    pub async fn queued_for_handoff(&self, wallet_address: &str, max_bytes: usize) -> QueueHandoff {
+        let online: HashSet<String> = self.connections
+            .read()
+            .await
+            .get(wallet_address)
+            .map(|devices| devices.keys().cloned().collect())
+            .unwrap_or_default();
+        
+        let mut handoff = QueueHandoff::default();
+        let mut size = 0;
+        for key in self.message_queue.recipients() {
+            let (wallet, device_id) = split_queue_key(&key);
+            if wallet != wallet_address || online.contains(device_id) {
+                continue;
+            }
+            for queued in self.message_queue.pending(&key) {
+                let message = QueuedMessage {
+                    device_id: device_id.to_string(),
+                    envelope: Some(queued.message.to_envelope()),
+                };
+                let len = prost::Message::encoded_len(&message);
+                size += 1 + prost::length_delimiter_len(len) + len;
+                if size > max_bytes && !handoff.messages.is_empty() {
+                    return handoff;
+                }
+                handoff.messages.push(message);
+            }
+        }
+        handoff
+    }
+    
+    /// Drop messages another replica took over from the queues here
+    pub fn remove_handed_off(&self, wallet_address: &str, handoff: &QueueHandoff) -> Result<()> {
+        for queued in &handoff.messages {
+            let message = queued.envelope.clone().and_then(RelayMessage::from_envelope);
+            if let Some(message_id) = message.as_ref().and_then(RelayMessage::message_id) {
+                self.message_queue.remove(&queue_key(wallet_address, &queued.device_id), message_id)?;
+            }
         }
         
+        self.update_queue_gauges();
         Ok(())
     }
     
+    /// Move what other replicas queued for a wallet into the queues here,
+    /// as the wallet connected to this replica
+    ///
+    /// Each message keeps the device queue it was in. Replicas hand their
+    /// queues over in batches, until one has nothing left or nothing new.
+    async fn take_over_queues(&self, wallet_address: &str) {
+        let Some(federation) = &self.federation else {
+            return;
+        };
+        
+        for relay_id in federation.replicas() {
+            loop {
+                let (handoff, replica_addr) = match federation.request_queue(&relay_id, wallet_address).await {
+                    Ok(reply) => reply,
+                    Err(e) => {
+                        warn!("Failed to take over the queue of {} from replica {}: {:#}", wallet_address, relay_id, e);
+                        break;
+                    }
+                };
+                
+                let mut taken = 0;
+                for queued in handoff.messages {
+                    let Some(message) = queued.envelope.and_then(RelayMessage::from_envelope) else {
+                        continue;
+                    };
+                    let Some(message_id) = message.message_id() else {
+                        continue;
+                    };
+                    if self.is_queued_for(wallet_address, &queued.device_id, message_id) {
+                        continue;
+                    }
+                    
+                    // Acks for the message have to find its sender from here
+                    if let RelayMessage::Chat(chat) = &message {
+                        self.message_senders.write().await.insert(&chat.id, &chat.sender_wallet, &chat.recipient_wallet);
+                    }
+                    let key = queue_key(wallet_address, &queued.device_id);
+                    let routable = RoutableMessage {
+                        message,
+                        sender_addr: replica_addr,
+                    };
+                    match self.queue_message(&key, routable).await {
+                        Ok(_) => taken += 1,
+                        Err(e) => error!("Failed to queue a message taken over for {}: {:#}", key, e),
+                    }
+                }
+                
+                if taken == 0 {
+                    break;
+                }
+                info!("📦 Took over {} queued messages for {} from replica {}", taken, wallet_address, relay_id);
+            }
+        }
+    }
+    
+    /// Whether a chat message is waiting in a device's queue
+    pub fn is_queued_for(&self, wallet_address: &str, device_id: &str, message_id: &str) -> bool {
+        self.message_queue
+            .pending(&queue_key(wallet_address, device_id))
+            .iter()
+            .any(|queued| queued.message.message_id() == Some(message_id))
+    }
+    
+    /// Notified whenever something is queued for a device
+    ///
+    /// Create the `notified()` future before reading the queue, so that a
+    /// message queued in between still wakes the waiter.
+    pub fn queue_watcher(&self, wallet_address: &str, device_id: &str) -> Arc<Notify> {
+        let key = queue_key(wallet_address, device_id);
+        let mut watchers = self.queue_watchers.lock().unwrap();
+        if let Some(notify) = watchers.get(&key).and_then(Weak::upgrade) {
+            return notify;
+        }
+        watchers.retain(|_, notify| notify.strong_count() > 0);
+        let notify = Arc::new(Notify::new());
+        watchers.insert(key, Arc::downgrade(&notify));
+        notify
+    }
+    
+    fn wake_queue_watchers(&self, key: &str) {
+        let watchers = self.queue_watchers.lock().unwrap();
+        if let Some(notify) = watchers.get(key).and_then(Weak::upgrade) {
+            notify.notify_waiters();
+        }
+    }
+    
+    /// Drop an expired message from a queue and tell its sender
+    async fn expire_queued(
+        &self,
+        recipient: &str,
+        message: ChatMessage,
+        sender_addr: SocketAddr,
+    ) -> Result<()> {
+        if self.message_queue.remove(recipient, &message.id)?.is_none() {
+            return Ok(());
+        }
+        
+        debug!("⌛ Queued message {} for {} expired", message.id, recipient);
+        self.metrics.record_message_expired();
+        
+        self.notify_sender(&message.sender_wallet, AckMessage::expired(message.id), sender_addr).await
+    }
+    
+    /// Remove every expired chat message from the queues, returning how
+    /// many were dropped
+    pub async fn sweep_expired(&self) -> Result<usize> {
+        let mut expired = 0;
+        
+        for recipient in self.message_queue.recipients() {
+            for queued in self.message_queue.pending(&recipient) {
+                let RelayMessage::Chat(message) = queued.message else {
+                    continue;
+                };
+                if message.is_expired() {
+                    self.expire_queued(&recipient, message, queued.sender_addr).await?;
+                    expired += 1;
+                }
+            }
+        }
+        
+        if expired > 0 {
+            info!("⌛ Swept {} expired queued messages", expired);
+            self.update_queue_gauges();
+        }
+        
+        Ok(expired)
+    }
+    
+    /// Start a periodic task that evicts expired queued messages
+    pub fn start_expiry_sweeper(self: Arc<Self>, sweep_interval: std::time::Duration) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(sweep_interval);
+            
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.sweep_expired().await {
+                    error!("Expiry sweep failed: {}", e);
+                }
+            }
+        });
+    }
+    
+    /// Queue depth of every recipient with queued messages, deepest first
+    pub fn queue_depths(&self) -> Vec<QueueDepth> {
+        let mut depths: Vec<QueueDepth> = self.message_queue
+            .recipients()
+            .into_iter()
+            .map(|recipient| self.queue_depth(&recipient))
+            .collect();
+        depths.sort_by(|a, b| b.messages.cmp(&a.messages).then_with(|| a.recipient.cmp(&b.recipient)));
+        depths
+    }
+    
+    pub fn queue_depth(&self, recipient: &str) -> QueueDepth {
+        QueueDepth {
+            recipient: recipient.to_string(),
+            messages: self.message_queue.queued_for(recipient),
+            bytes: self.message_queue.bytes_queued_for(recipient),
+        }
+    }
+    
+    /// Drop everything queued for a recipient, returning how many messages
+    /// were dropped
+    ///
+    /// A wallet's recipient name covers all of its devices' queues, and a
+    /// `wallet:device` one only that device's. Senders of the dropped chat
+    /// messages are told they failed.
+    pub async fn purge_queue(&self, recipient: &str) -> Result<usize> {
+        let keys: Vec<String> = if recipient.contains(':') {
+            vec![recipient.to_string()]
+        } else {
+            self.message_queue
+                .recipients()
+                .into_iter()
+                .filter(|key| split_queue_key(key).0 == recipient)
+                .collect()
+        };
+        
+        let mut purged = 0;
+        let mut notified = HashSet::new();
+        for key in keys {
+            while let Some(dropped) = self.message_queue.pop_oldest(&key)? {
+                purged += 1;
+                if let RelayMessage::Chat(message) = dropped.message {
+                    // Each device had its own copy; tell the sender once
+                    if notified.insert(message.id.clone()) {
+                        let ack = AckMessage::failed(message.id).with_reason("purged by the relay operator");
+                        self.notify_sender(&message.sender_wallet, ack, dropped.sender_addr).await?;
+                    }
+                }
+            }
+        }
+        
+        if purged > 0 {
+            warn!("🗑️ Purged {} queued messages for {}", purged, recipient);
+            self.update_queue_gauges();
+        }
+        Ok(purged)
+    }
+    
+    /// Persist the queues once the relay has stopped routing
+    pub fn flush_queue(&self) -> Result<()> {
+        self.message_queue.flush()
+    }
+    
+    /// Fail if the queue storage could no longer take messages
+    pub fn check_queue(&self) -> Result<()> {
+        self.message_queue.check()
+    }
+    
+    fn update_queue_gauges(&self) {
+        self.metrics.set_queued_messages(self.message_queue.total_queued() as i64);
+        self.metrics.set_queued_bytes(self.message_queue.total_bytes() as i64);
+    }
+    
     /// Get current connection statistics
     pub async fn get_stats(&self) -> RouterStats {
         let connections = self.connections.read().await;
-        let queue = self.message_queue.read().await;
-        
-        let total_queued = queue.values().map(|v| v.len()).sum();
         
         RouterStats {
-            connected_clients: connections.len(),
-            queued_messages: total_queued,
-            recipients_with_queued: queue.len(),
+            connected_clients: count_connections(&connections),
+            queued_messages: self.message_queue.total_queued(),
+            queued_bytes: self.message_queue.total_bytes(),
+            recipients_with_queued: self.message_queue.recipients().len(),
         }
     }
     
@@ -298,15 +1450,26 @@ impl MessageRouter {
                 let stats = self.get_stats().await;
                 self.metrics.set_registered_clients(stats.connected_clients as i64);
                 self.metrics.set_queued_messages(stats.queued_messages as i64);
+                self.metrics.set_queued_bytes(stats.queued_bytes as i64);
             }
         });
     }
 }
 
-#[derive(Debug, Clone)]
+/// Live connections across all wallets and devices
+fn count_connections(connections: &HashMap<String, HashMap<String, ClientConnection>>) -> usize {
+    connections.values().map(HashMap::len).sum()
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0)
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct RouterStats {
     pub connected_clients: usize,
     pub queued_messages: usize,
+    pub queued_bytes: usize,
     pub recipients_with_queued: usize,
 }
 
@@ -323,7 +1486,7 @@ mod tests {
         let wallet = WalletAddress::test_address(1);
         let (tx, _rx) = mpsc::channel(10);
         
-        router.register_client(wallet.clone(), tx).await.unwrap();
+        router.register_client(wallet.clone(), tx, "10.0.0.1:4000".parse().unwrap()).await.unwrap();
         
         let stats = router.get_stats().await;
         assert_eq!(stats.connected_clients, 1);
@@ -351,11 +1514,75 @@ mod tests {
         let sender_addr = "127.0.0.1:1234".parse().unwrap();
         
         // Route message to offline recipient
-        let status = router.route_message(RelayMessage::Chat(message), sender_addr).await.unwrap();
-        assert_eq!(status, AckStatus::Delivered);
+        let outcome = router.route_message(RelayMessage::Chat(message), sender_addr).await.unwrap();
+        assert_eq!(outcome, RouteOutcome::new(AckStatus::Queued));
         
         let stats = router.get_stats().await;
         assert_eq!(stats.queued_messages, 1);
         assert_eq!(stats.recipients_with_queued, 1);
     }
-}  
\ No newline at end of file
+    
+    #[test]
+    fn test_encoded_len_matches_envelope() {
+        let sender = WalletAddress::test_address(1);
+        let recipient = WalletAddress::test_address(2);
+        let messages = [
+            RelayMessage::Chat(ChatMessage::new(&sender, &recipient, vec![0u8; 300], Vec::new())),
+            RelayMessage::Ack(AckMessage::failed("msg_1".to_string()).with_reason("evicted")),
+            RelayMessage::Ping(PingMessage::new(1)),
+        ];
+        
+        for message in messages {
+            assert_eq!(message.encoded_len(), prost::Message::encoded_len(&message.to_envelope()));
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_prekey_bundle_publish_and_fetch() {
+        use solchat_protocol::crypto::{PrekeyStore, X25519KeyPair};
+        
+        let metrics = Arc::new(Metrics::new());
+        let router = MessageRouter::new(metrics);
+        
+        let keypair = crate::session::test_keypair(7);
+        let wallet = WalletAddress::new(keypair.public.to_bytes());
+        let prekeys = PrekeyStore::generate(&keypair, X25519KeyPair::generate(), 2);
+        
+        let sender_addr = "127.0.0.1:1234".parse().unwrap();
+        let bundle = prekeys.bundle(&keypair);
+        
+        // Only the owning wallet may publish its bundle
+        let other = WalletAddress::test_address(3);
+        let outcome = router
+            .route_message_from(RelayMessage::PrekeyBundle(bundle.clone()), &other, DEFAULT_DEVICE, sender_addr)
+            .await
+            .unwrap();
+        assert_eq!(outcome.status, AckStatus::Rejected);
+        
+        let outcome = router
+            .route_message_from(RelayMessage::PrekeyBundle(bundle.clone()), &wallet, DEFAULT_DEVICE, sender_addr)
+            .await
+            .unwrap();
+        assert_eq!(outcome.status, AckStatus::Delivered);
+        
+        // Each fetch hands out a different one-time prekey until they run out
+        let first = router.fetch_prekey_bundle(&wallet).await.unwrap();
+        let second = router.fetch_prekey_bundle(&wallet).await.unwrap();
+        let third = router.fetch_prekey_bundle(&wallet).await.unwrap();
+        assert_eq!(first.one_time_prekeys.len(), 1);
+        assert_eq!(second.one_time_prekeys.len(), 1);
+        assert_ne!(first.one_time_prekeys[0].id, second.one_time_prekeys[0].id);
+        assert!(third.one_time_prekeys.is_empty());
+        assert!(third.verify().is_ok());
+        
+        // Replaying the original bundle must not restore the used prekeys
+        let outcome = router
+            .route_message_from(RelayMessage::PrekeyBundle(bundle), &wallet, DEFAULT_DEVICE, sender_addr)
+            .await
+            .unwrap();
+        assert_eq!(outcome.status, AckStatus::Rejected);
+        assert!(outcome.reason.is_some());
+        
+        assert!(router.fetch_prekey_bundle(&WalletAddress::test_address(9)).await.is_none());
+    }
+}
@@ -0,0 +1,185 @@
+use solchat_protocol::messages::{AckStatus, Envelope, PongMessage, PrekeyBundle};
+use solchat_protocol::WalletAddress;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, warn};
+use crate::keepalive::Keepalive;
+use crate::metrics::Metrics;
+use crate::rate_limit::RateLimiter;
+use crate::router::{MessageRouter, RelayMessage, RouteOutcome};
+use crate::shutdown::Shutdown;
+
+/// Maximum encoded size of a handshake proof
+pub const MAX_HANDSHAKE_SIZE: usize = 4096;
+
+/// Reason given to clients, in the GoAway and the close frame, on shutdown
+pub const SHUTDOWN_REASON: &str = "relay shutting down";
+
+/// What every client connection needs, whichever transport it came in on
+#[derive(Clone)]
+pub struct AppState {
+    pub metrics: Arc<Metrics>,
+    pub router: Arc<MessageRouter>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub shutdown: Arc<Shutdown>,
+    pub relay_id: Arc<str>,
+    pub handshake_timeout: Duration,
+    pub keepalive_interval: Duration,
+    pub idle_timeout: Duration,
+}
+
+#[cfg(test)]
+impl AppState {
+    /// State for tests: a fresh router, no rate limits, relay id `localhost`
+    pub fn for_tests() -> Self {
+        let metrics = Arc::new(Metrics::new());
+        Self {
+            metrics: metrics.clone(),
+            router: Arc::new(MessageRouter::new(metrics)),
+            rate_limiter: Arc::new(RateLimiter::default()),
+            shutdown: Arc::new(Shutdown::new()),
+            relay_id: "localhost".into(),
+            handshake_timeout: Duration::from_secs(10),
+            keepalive_interval: Duration::from_secs(15),
+            idle_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Deterministic wallet keypair for tests
+#[cfg(test)]
+pub fn test_keypair(seed: u8) -> ed25519_dalek::Keypair {
+    let secret = ed25519_dalek::SecretKey::from_bytes(&[seed; 32]).unwrap();
+    let public = (&secret).into();
+    ed25519_dalek::Keypair { secret, public }
+}
+
+/// Act on one envelope from an authenticated client, returning the reply
+/// to send back, if any
+///
+/// Pings are answered with a pong, pongs to the relay's keepalives are
+/// timed, and prekey bundle requests are answered with the bundle. Every
+/// routed message with an id is answered with an ack carrying its outcome.
+/// Messages over the sender's, address's or recipient's rate limit, and
+/// messages claiming to come from another wallet, are rejected without
+/// being routed. Acks clear the message from the queue of `device_id`, the
+/// wallet's device that sent them.
+pub async fn handle_envelope(
+    state: &AppState,
+    wallet: &WalletAddress,
+    device_id: &str,
+    remote_addr: SocketAddr,
+    keepalive: &Keepalive,
+    envelope: Envelope,
+) -> Option<Envelope> {
+    let start_time = Instant::now();
+    let size = prost::Message::encoded_len(&envelope);
+    keepalive.touch();
+
+    let relay_message = RelayMessage::from_envelope(envelope)?;
+    let kind = relay_message.kind();
+
+    debug!("📨 Received {} ({} bytes)", kind, size);
+
+    let (reply, result) = match relay_message {
+        RelayMessage::Ping(ping) => (Some(PongMessage::reply_to(&ping).into()), Ok(())),
+        RelayMessage::Pong(pong) => {
+            if let Some(rtt) = keepalive.pong_received(&pong) {
+                debug!("🏓 RTT {:.1}ms", rtt.as_secs_f64() * 1000.0);
+                state.metrics.record_rtt(rtt.as_secs_f64());
+            }
+            (None, Ok(()))
+        }
+        RelayMessage::PrekeyBundleRequest(request) => {
+            // Each fetch hands out a one-time prekey, so fetches are rate
+            // limited like messages to the bundle's owner
+            let bundle = match state.rate_limiter.check(&wallet.to_string(), remote_addr.ip(), Some(&request.wallet_address)) {
+                Ok(()) => match request.wallet() {
+                    Ok(owner) => state.router.fetch_prekey_bundle(&owner).await,
+                    Err(e) => {
+                        debug!("Ignoring prekey bundle request from {}: {}", wallet, e);
+                        None
+                    }
+                },
+                Err(scope) => {
+                    warn!("🚦 Refusing {} from {}: {}", kind, wallet, scope);
+                    state.metrics.record_rate_limited(scope.label());
+                    None
+                }
+            };
+            let reply = bundle.unwrap_or_else(|| PrekeyBundle {
+                wallet_address: request.wallet_address,
+                ..PrekeyBundle::default()
+            });
+            (Some(reply.into()), Ok(()))
+        }
+        message => {
+            let message_id = message.message_id().map(str::to_string);
+            let recipient = match &message {
+                RelayMessage::Chat(chat) => Some(chat.recipient_wallet.as_str()),
+                _ => None,
+            };
+            let routed = match state.rate_limiter.check(&wallet.to_string(), remote_addr.ip(), recipient) {
+                Ok(()) => match check_sender(&message, wallet) {
+                    Ok(()) => state.router.route_message_from(message, wallet, device_id, remote_addr).await,
+                    Err(reason) => {
+                        warn!("🚫 Rejecting {} from {}: {}", kind, wallet, reason);
+                        Ok(RouteOutcome::with_reason(AckStatus::Rejected, reason))
+                    }
+                },
+                Err(scope) => {
+                    warn!("🚦 Rejecting {} from {}: {}", kind, wallet, scope);
+                    state.metrics.record_rate_limited(scope.label());
+                    Ok(RouteOutcome::with_reason(AckStatus::Rejected, scope.reason()))
+                }
+            };
+
+            // Tell the sender what became of the message
+            let ack = message_id.map(|message_id| {
+                let outcome = match &routed {
+                    Ok(outcome) => outcome.clone(),
+                    Err(_) => RouteOutcome::with_reason(AckStatus::Failed, "internal error"),
+                };
+                outcome.to_ack(&message_id).into()
+            });
+            (ack, routed.map(|_| ()))
+        }
+    };
+
+    if let Err(e) = result {
+        error!("Failed to route message: {}", e);
+        state.metrics.record_message_failed();
+    } else {
+        let duration = start_time.elapsed().as_secs_f64();
+        state.metrics.record_latency(duration);
+        state.metrics.record_message_processed(size, kind);
+    }
+
+    reply
+}
+
+/// Check that a message from an authenticated client speaks only for the
+/// client's own wallet
+///
+/// Chats must name the wallet as sender and carry its signature, read
+/// receipts must be the wallet's own, and only the wallet's own prekey
+/// bundle may be published.
+fn check_sender(message: &RelayMessage, wallet: &WalletAddress) -> Result<(), &'static str> {
+    let wallet = wallet.to_string();
+    match message {
+        RelayMessage::Chat(chat) => {
+            if chat.sender_wallet != wallet {
+                return Err("sender is not the authenticated wallet");
+            }
+            chat.verify().map_err(|_| "invalid message signature")
+        }
+        RelayMessage::ReadReceipt(receipt) if receipt.reader_wallet != wallet => {
+            Err("reader is not the authenticated wallet")
+        }
+        RelayMessage::PrekeyBundle(bundle) if bundle.wallet_address != wallet => {
+            Err("prekey bundle belongs to another wallet")
+        }
+        _ => Ok(()),
+    }
+}
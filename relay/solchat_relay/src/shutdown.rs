@@ -0,0 +1,127 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+
+/// Coordinates a graceful shutdown of the relay
+///
+/// Once draining begins, connection tasks send their client a GoAway and
+/// wind down, and the relay waits for them to finish before it exits.
+pub struct Shutdown {
+    draining: watch::Sender<bool>,
+    active: AtomicUsize,
+    idle: Notify,
+}
+
+/// Keeps the relay from finishing its shutdown while a connection is open
+pub struct ConnectionGuard {
+    shutdown: Arc<Shutdown>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self {
+            draining: watch::Sender::new(false),
+            active: AtomicUsize::new(0),
+            idle: Notify::new(),
+        }
+    }
+
+    /// Tell every connection to drain
+    pub fn begin(&self) {
+        self.draining.send_replace(true);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        *self.draining.borrow()
+    }
+
+    /// Wait until draining begins; returns at once if it already has
+    pub async fn draining(&self) {
+        let mut draining = self.draining.subscribe();
+        // The sender lives as long as `self`, so this cannot fail
+        let _ = draining.wait_for(|draining| *draining).await;
+    }
+
+    /// Count a connection as open until the guard is dropped
+    pub fn track(self: &Arc<Self>) -> ConnectionGuard {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard { shutdown: self.clone() }
+    }
+
+    /// Number of connections still open
+    pub fn active_connections(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Wait for every tracked connection to close, returning `false` if
+    /// some were still open when `deadline` passed
+    pub async fn wait_for_connections(&self, deadline: Duration) -> bool {
+        let all_closed = async {
+            loop {
+                // Created before the check so a close in between still wakes us
+                let idle = self.idle.notified();
+                if self.active_connections() == 0 {
+                    return;
+                }
+                idle.await;
+            }
+        };
+        tokio::time::timeout(deadline, all_closed).await.is_ok()
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if self.shutdown.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.shutdown.idle.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_draining_wakes_waiters() {
+        let shutdown = Arc::new(Shutdown::new());
+        assert!(!shutdown.is_draining());
+
+        let waiter = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.draining().await }
+        });
+        shutdown.begin();
+        waiter.await.unwrap();
+
+        // Late waiters see that draining has already begun
+        assert!(shutdown.is_draining());
+        shutdown.draining().await;
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_connections_until_deadline() {
+        let shutdown = Arc::new(Shutdown::new());
+        assert!(shutdown.wait_for_connections(Duration::from_millis(10)).await);
+
+        let first = shutdown.track();
+        let second = shutdown.track();
+        assert_eq!(shutdown.active_connections(), 2);
+        assert!(!shutdown.wait_for_connections(Duration::from_millis(10)).await);
+
+        drop(first);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(second);
+        });
+        assert!(shutdown.wait_for_connections(Duration::from_secs(5)).await);
+        assert_eq!(shutdown.active_connections(), 0);
+    }
+}
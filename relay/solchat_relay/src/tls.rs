@@ -0,0 +1,290 @@
+use anyhow::{anyhow, bail, Context, Result};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{Certificate, PrivateKey, SignatureScheme};
+use crate::federation::FEDERATION_ALPN;
+use solchat_protocol::messages::{ALPN_PROTOCOL, HANDSHAKE_EXPORTER_LABEL, HANDSHAKE_EXPORTER_LEN};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Message signed with a newly loaded key to check it against the certificate
+const KEY_PROBE: &[u8] = b"SolConnect-TlsKeyProbe-v1";
+
+/// Schemes the key probe can be signed with, and how to verify each
+static KEY_PROBE_SCHEMES: [(SignatureScheme, &webpki::SignatureAlgorithm); 5] = [
+    (SignatureScheme::ED25519, &webpki::ED25519),
+    (SignatureScheme::ECDSA_NISTP256_SHA256, &webpki::ECDSA_P256_SHA256),
+    (SignatureScheme::ECDSA_NISTP384_SHA384, &webpki::ECDSA_P384_SHA384),
+    (SignatureScheme::RSA_PSS_SHA256, &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY),
+    (SignatureScheme::RSA_PKCS1_SHA256, &webpki::RSA_PKCS1_2048_8192_SHA256),
+];
+
+/// Serves the relay's certificate to each TLS handshake
+///
+/// Certificates loaded from disk are re-read when the files change, so a
+/// renewed certificate is picked up by new connections without restarting
+/// the QUIC endpoint. Connections that are already open keep the
+/// certificate they were established with.
+pub struct CertResolver {
+    current: RwLock<Arc<CertifiedKey>>,
+    source: Option<CertFiles>,
+}
+
+/// PEM files a certificate was loaded from, and their contents at the time
+struct CertFiles {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    loaded: RwLock<(Vec<u8>, Vec<u8>)>,
+}
+
+impl CertResolver {
+    /// Load a PEM certificate chain and private key
+    pub fn from_files(cert_path: &Path, key_path: &Path) -> Result<Self> {
+        let (cert_pem, key_pem) = read_pair(cert_path, key_path)?;
+        let certified_key = parse_certified_key(&cert_pem, &key_pem)
+            .with_context(|| format!("Invalid TLS certificate {} or key {}", cert_path.display(), key_path.display()))?;
+        info!("🔐 Loaded TLS certificate from {}", cert_path.display());
+
+        Ok(Self {
+            current: RwLock::new(Arc::new(certified_key)),
+            source: Some(CertFiles {
+                cert_path: cert_path.to_path_buf(),
+                key_path: key_path.to_path_buf(),
+                loaded: RwLock::new((cert_pem, key_pem)),
+            }),
+        })
+    }
+
+    /// Generate a throwaway self-signed certificate for the given names
+    ///
+    /// Clients cannot pin it, so it is only meant for devnet.
+    pub fn self_signed(names: Vec<String>) -> Result<Self> {
+        let cert = rcgen::generate_simple_self_signed(names)?;
+        let key = PrivateKey(cert.serialize_private_key_der());
+        let signing_key = rustls::sign::any_supported_type(&key)
+            .map_err(|e| anyhow!("Unusable generated key: {}", e))?;
+        let certified_key = CertifiedKey::new(vec![Certificate(cert.serialize_der()?)], signing_key);
+
+        Ok(Self {
+            current: RwLock::new(Arc::new(certified_key)),
+            source: None,
+        })
+    }
+
+    /// Re-read the certificate files and switch to them if they changed
+    ///
+    /// Returns whether a new certificate was installed. If the new files do
+    /// not parse or the key does not match the certificate, for example
+    /// because only one of them has been replaced so far, the current
+    /// certificate stays in use.
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        let Some(source) = &self.source else {
+            return Ok(false);
+        };
+
+        let (cert_pem, key_pem) = read_pair(&source.cert_path, &source.key_path)?;
+        {
+            let loaded = source.loaded.read().unwrap();
+            if loaded.0 == cert_pem && loaded.1 == key_pem {
+                return Ok(false);
+            }
+        }
+
+        let certified_key = parse_certified_key(&cert_pem, &key_pem)?;
+        *self.current.write().unwrap() = Arc::new(certified_key);
+        *source.loaded.write().unwrap() = (cert_pem, key_pem);
+        Ok(true)
+    }
+
+    /// Periodically check the certificate files for changes
+    pub fn start_watcher(self: Arc<Self>, interval: Duration) {
+        if self.source.is_none() {
+            return;
+        }
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+
+            loop {
+                ticker.tick().await;
+                match self.reload_if_changed() {
+                    Ok(true) => info!("🔐 TLS certificate rotated"),
+                    Ok(false) => {}
+                    Err(e) => warn!("Keeping the current TLS certificate: {:#}", e),
+                }
+            }
+        });
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// TLS settings for the QUIC endpoint, accepting clients and relay links
+/// by their ALPN identifiers
+pub fn server_crypto(resolver: Arc<CertResolver>) -> rustls::ServerConfig {
+    let mut crypto = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    crypto.alpn_protocols = vec![ALPN_PROTOCOL.to_vec(), FEDERATION_ALPN.to_vec()];
+    crypto
+}
+
+/// TLS settings for the WebSocket listener, which browsers reach over
+/// HTTP/1.1
+pub fn websocket_crypto(resolver: Arc<CertResolver>) -> rustls::ServerConfig {
+    let mut crypto = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    crypto.alpn_protocols = vec![b"http/1.1".to_vec()];
+    crypto
+}
+
+/// TLS exporter value that binds a handshake proof to this connection
+pub fn channel_binding(connection: &quinn::Connection) -> Result<[u8; HANDSHAKE_EXPORTER_LEN]> {
+    let mut binding = [0u8; HANDSHAKE_EXPORTER_LEN];
+    connection
+        .export_keying_material(&mut binding, HANDSHAKE_EXPORTER_LABEL, b"")
+        .map_err(|_| anyhow!("Failed to export keying material"))?;
+    Ok(binding)
+}
+
+fn read_pair(cert_path: &Path, key_path: &Path) -> Result<(Vec<u8>, Vec<u8>)> {
+    let cert_pem = std::fs::read(cert_path)
+        .with_context(|| format!("Failed to read TLS certificate {}", cert_path.display()))?;
+    let key_pem = std::fs::read(key_path)
+        .with_context(|| format!("Failed to read TLS key {}", key_path.display()))?;
+    Ok((cert_pem, key_pem))
+}
+
+/// Parse a PEM certificate chain and the first private key in a PEM file
+fn parse_certified_key(cert_pem: &[u8], key_pem: &[u8]) -> Result<CertifiedKey> {
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut BufReader::new(cert_pem))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        bail!("No certificates found");
+    }
+
+    let mut reader = BufReader::new(key_pem);
+    let key = loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(der))
+            | Some(rustls_pemfile::Item::RSAKey(der))
+            | Some(rustls_pemfile::Item::ECKey(der)) => break PrivateKey(der),
+            Some(_) => continue,
+            None => bail!("No private key found"),
+        }
+    };
+
+    let signing_key = rustls::sign::any_supported_type(&key)
+        .map_err(|e| anyhow!("Unsupported private key: {}", e))?;
+    let certified_key = CertifiedKey::new(certs, signing_key);
+    check_key_matches(&certified_key)?;
+    Ok(certified_key)
+}
+
+/// Fail unless the private key belongs to the leaf certificate, by signing
+/// a probe with the key and verifying it with the certificate's public key
+fn check_key_matches(certified_key: &CertifiedKey) -> Result<()> {
+    let schemes: Vec<SignatureScheme> = KEY_PROBE_SCHEMES.iter().map(|(scheme, _)| *scheme).collect();
+    let signer = certified_key
+        .key
+        .choose_scheme(&schemes)
+        .ok_or_else(|| anyhow!("Private key cannot sign with any supported scheme"))?;
+    let signature = signer
+        .sign(KEY_PROBE)
+        .map_err(|e| anyhow!("Failed to sign with the private key: {}", e))?;
+    let (_, algorithm) = KEY_PROBE_SCHEMES
+        .iter()
+        .find(|(scheme, _)| *scheme == signer.scheme())
+        .ok_or_else(|| anyhow!("Private key chose an unexpected scheme {:?}", signer.scheme()))?;
+
+    let leaf = webpki::EndEntityCert::try_from(certified_key.cert[0].0.as_slice())
+        .map_err(|e| anyhow!("Invalid leaf certificate: {:?}", e))?;
+    leaf.verify_signature(algorithm, KEY_PROBE, &signature)
+        .map_err(|_| anyhow!("Private key does not match the certificate"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_cert(dir: &Path, name: &str) -> (PathBuf, PathBuf, Certificate) {
+        let cert = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        let cert_path = dir.join("relay.crt");
+        let key_path = dir.join("relay.key");
+        let cert_pem = cert.serialize_pem().unwrap();
+        std::fs::write(&cert_path, &cert_pem).unwrap();
+        std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+
+        // Each serialization is signed afresh, so take the DER from the PEM
+        let der = rustls_pemfile::certs(&mut cert_pem.as_bytes()).unwrap().remove(0);
+        (cert_path, key_path, Certificate(der))
+    }
+
+    fn served(resolver: &CertResolver) -> Certificate {
+        resolver.current.read().unwrap().cert[0].clone()
+    }
+
+    #[test]
+    fn test_rotated_files_replace_the_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert_path, key_path, first) = write_cert(dir.path(), "relay.example.org");
+
+        let resolver = CertResolver::from_files(&cert_path, &key_path).unwrap();
+        assert_eq!(served(&resolver), first);
+        assert!(!resolver.reload_if_changed().unwrap());
+
+        let (_, _, second) = write_cert(dir.path(), "relay.example.org");
+        assert!(resolver.reload_if_changed().unwrap());
+        assert_eq!(served(&resolver), second);
+    }
+
+    #[test]
+    fn test_broken_files_keep_the_current_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert_path, key_path, first) = write_cert(dir.path(), "relay.example.org");
+        let resolver = CertResolver::from_files(&cert_path, &key_path).unwrap();
+
+        // A half-finished rotation: new certificate, key not written yet
+        std::fs::write(&key_path, "").unwrap();
+        assert!(resolver.reload_if_changed().is_err());
+        assert_eq!(served(&resolver), first);
+
+        // Loading broken files up front fails with the file names
+        let error = CertResolver::from_files(&cert_path, &key_path).err().unwrap();
+        assert!(format!("{:#}", error).contains("relay.key"));
+    }
+
+    #[test]
+    fn test_mismatched_key_keeps_the_current_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert_path, key_path, first) = write_cert(dir.path(), "relay.example.org");
+        let resolver = CertResolver::from_files(&cert_path, &key_path).unwrap();
+
+        // Half-rotated: the new certificate is in place, the old key still is
+        let next = tempfile::tempdir().unwrap();
+        let (next_cert_path, next_key_path, second) = write_cert(next.path(), "relay.example.org");
+        std::fs::copy(&next_cert_path, &cert_path).unwrap();
+        let error = resolver.reload_if_changed().err().unwrap();
+        assert!(format!("{:#}", error).contains("does not match"));
+        assert_eq!(served(&resolver), first);
+        assert!(CertResolver::from_files(&cert_path, &key_path).is_err());
+
+        // Once the key follows, the new certificate is served
+        std::fs::copy(&next_key_path, &key_path).unwrap();
+        assert!(resolver.reload_if_changed().unwrap());
+        assert_eq!(served(&resolver), second);
+    }
+}
@@ -0,0 +1,489 @@
+use anyhow::{anyhow, bail, Result};
+use futures_util::{SinkExt, StreamExt};
+use prost::Message as _;
+use solchat_protocol::codec::MAX_FRAME_SIZE;
+use solchat_protocol::messages::{Envelope, GoAwayMessage, HandshakeChallenge, HandshakeProof, HandshakeResponse};
+use solchat_protocol::WalletAddress;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, WebSocketConfig};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use tracing::{debug, error, info, span, warn, Instrument, Level};
+use crate::keepalive::Keepalive;
+use crate::router::RoutableMessage;
+use crate::session::{self, AppState, MAX_HANDSHAKE_SIZE, SHUTDOWN_REASON};
+use crate::tls::{self, CertResolver};
+
+/// Time a client gets to answer the relay's close frame
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves browser clients over WebSocket, alongside the QUIC endpoint
+///
+/// Each binary message carries one protobuf `Envelope`, the same envelopes
+/// QUIC clients send as frames. The handshake mirrors QUIC's: the relay
+/// sends a `HandshakeChallenge` as the first message, the client answers
+/// with a `HandshakeProof` and the relay replies with a `HandshakeResponse`.
+/// Browsers cannot read TLS exporter values, so proofs are signed with an
+/// empty channel binding and rely on the `wss://` certificate instead.
+/// Clients are then registered with the same router, rate limits, keepalives
+/// and drain as QUIC clients.
+pub struct WebSocketGateway {
+    state: AppState,
+    tls: Option<TlsAcceptor>,
+}
+
+/// Something the connection loop has to act on
+enum Event {
+    Incoming(Option<Result<Message, tokio_tungstenite::tungstenite::Error>>),
+    Outgoing(Option<RoutableMessage>),
+    Draining,
+    Keepalive,
+}
+
+impl WebSocketGateway {
+    /// Gateway serving `wss://` with `cert_resolver`, or plain `ws://`
+    /// without one
+    pub fn new(state: AppState, cert_resolver: Option<Arc<CertResolver>>) -> Self {
+        let tls = cert_resolver.map(|resolver| TlsAcceptor::from(Arc::new(tls::websocket_crypto(resolver))));
+        Self { state, tls }
+    }
+
+    /// Accept connections on `listener` until the relay starts draining
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let (stream, remote_addr) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Failed to accept WebSocket connection: {}", e);
+                        continue;
+                    }
+                },
+                _ = self.state.shutdown.draining() => return,
+            };
+
+            let span = span!(Level::INFO, "connection", remote = %remote_addr, transport = "websocket");
+            tokio::spawn(self.clone().handle_connection(stream, remote_addr).instrument(span));
+        }
+    }
+
+    async fn handle_connection(self: Arc<Self>, stream: TcpStream, remote_addr: SocketAddr) {
+        let _guard = self.state.shutdown.track();
+        let connection_start = Instant::now();
+        self.state.metrics.increment_connections();
+        info!("🔗 New WebSocket connection established");
+
+        let result = match &self.tls {
+            Some(acceptor) => match tokio::time::timeout(self.state.handshake_timeout, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => self.serve_client(stream, remote_addr, connection_start).await,
+                Ok(Err(e)) => Err(anyhow!("TLS handshake failed: {}", e)),
+                Err(_) => Err(anyhow!("TLS handshake timed out")),
+            },
+            None => self.serve_client(stream, remote_addr, connection_start).await,
+        };
+
+        if let Err(e) = result {
+            warn!("{:#}", e);
+            self.state.metrics.record_handshake_failed();
+        }
+
+        let duration = connection_start.elapsed().as_secs_f64();
+        self.state.metrics.record_connection_duration(duration);
+        self.state.metrics.decrement_connections();
+        info!("🔌 Connection closed (duration: {:.2}s)", duration);
+    }
+
+    /// Upgrade the connection, authenticate the client and relay its
+    /// messages; errors mean the client never got past the handshake
+    async fn serve_client<S>(&self, stream: S, remote_addr: SocketAddr, connection_start: Instant) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let deadline = self.state.handshake_timeout.saturating_sub(connection_start.elapsed());
+        let authenticated = tokio::time::timeout(deadline, async {
+            let config = WebSocketConfig {
+                max_message_size: Some(MAX_FRAME_SIZE),
+                max_frame_size: Some(MAX_FRAME_SIZE),
+                ..WebSocketConfig::default()
+            };
+            let mut ws = tokio_tungstenite::accept_async_with_config(stream, Some(config)).await?;
+            let client = perform_handshake(&mut ws, &self.state.relay_id).await;
+            Ok::<_, anyhow::Error>((ws, client))
+        });
+
+        let (mut ws, (wallet, device_id)) = match authenticated.await {
+            Ok(Ok((ws, Ok(client)))) => (ws, client),
+            Ok(Ok((mut ws, Err(e)))) => {
+                close(&mut ws, CloseCode::Policy, "handshake failed").await;
+                bail!("Handshake failed: {}", e);
+            }
+            Ok(Err(e)) => bail!("WebSocket upgrade failed: {}", e),
+            Err(_) => bail!("Handshake timed out"),
+        };
+
+        info!("🤝 Handshake completed for {}", wallet);
+
+        if self.state.router.is_banned(&wallet.to_string()) {
+            close(&mut ws, CloseCode::Policy, "wallet banned").await;
+            bail!("Refusing banned wallet {}", wallet);
+        }
+
+        self.relay_messages(ws, wallet, &device_id, remote_addr).await;
+        Ok(())
+    }
+
+    /// Carry envelopes both ways between an authenticated client and the
+    /// router until either side goes away
+    ///
+    /// As on QUIC, a draining relay forwards the messages already in the
+    /// channel, then sends a GoAway and closes with "going away".
+    async fn relay_messages<S>(&self, mut ws: WebSocketStream<S>, wallet: WalletAddress, device_id: &str, remote_addr: SocketAddr)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let state = &self.state;
+
+        // The router keeps the only strong sender, so the channel closes
+        // once it drops the connection
+        let (tx, mut rx) = mpsc::channel::<RoutableMessage>(100);
+        let weak_tx = tx.downgrade();
+        // A client that cannot be registered would never be sent anything
+        if let Err(e) = state.router.register_device(wallet.clone(), device_id, tx, remote_addr).await {
+            error!("Failed to register client {}: {:#}", wallet, e);
+            if let Some(tx) = weak_tx.upgrade() {
+                let _ = state.router.unregister_client_channel(&wallet, &tx).await;
+            }
+            close(&mut ws, CloseCode::Error, "registration failed").await;
+            return;
+        }
+
+        let keepalive = Keepalive::new();
+        let mut keepalive_timer = tokio::time::interval(state.keepalive_interval);
+        keepalive_timer.tick().await;
+        let mut draining = false;
+
+        loop {
+            let event = tokio::select! {
+                incoming = ws.next() => Event::Incoming(incoming),
+                outgoing = rx.recv() => Event::Outgoing(outgoing),
+                _ = state.shutdown.draining(), if !draining => Event::Draining,
+                _ = keepalive_timer.tick() => Event::Keepalive,
+            };
+
+            let reply: Option<Envelope> = match event {
+                Event::Incoming(Some(Ok(Message::Binary(data)))) => {
+                    state.metrics.record_bytes_received(data.len());
+                    match Envelope::decode(&data[..]) {
+                        Ok(envelope) => session::handle_envelope(state, &wallet, device_id, remote_addr, &keepalive, envelope).await,
+                        Err(e) => {
+                            warn!("Closing connection after an undecodable message: {}", e);
+                            state.metrics.record_message_failed();
+                            close(&mut ws, CloseCode::Invalid, "invalid envelope").await;
+                            break;
+                        }
+                    }
+                }
+                Event::Incoming(Some(Ok(Message::Text(_)))) => {
+                    warn!("Closing connection after a text message");
+                    state.metrics.record_message_failed();
+                    close(&mut ws, CloseCode::Unsupported, "envelopes are sent as binary messages").await;
+                    break;
+                }
+                // Pings are answered by the WebSocket layer itself
+                Event::Incoming(Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_)))) => None,
+                Event::Incoming(Some(Ok(Message::Close(_))) | None) => break,
+                Event::Incoming(Some(Err(e))) => {
+                    debug!("WebSocket error: {}", e);
+                    break;
+                }
+                Event::Outgoing(Some(routable_msg)) => Some(routable_msg.message.to_envelope()),
+                Event::Outgoing(None) => {
+                    if draining {
+                        if send(&mut ws, state, GoAwayMessage::new(SHUTDOWN_REASON).into()).await {
+                            info!("🚪 Sent GoAway");
+                        }
+                        close(&mut ws, CloseCode::Away, SHUTDOWN_REASON).await;
+                    } else {
+                        // Replaced by a newer connection or disconnected by an operator
+                        close(&mut ws, CloseCode::Normal, "disconnected by the relay").await;
+                    }
+                    break;
+                }
+                Event::Draining => {
+                    // Queue new messages for the wallet's next connection, and
+                    // close the channel so it ends once it is empty
+                    draining = true;
+                    if let Some(tx) = weak_tx.upgrade() {
+                        if let Err(e) = state.router.unregister_client_channel(&wallet, &tx).await {
+                            error!("Failed to unregister client {}: {}", wallet, e);
+                        }
+                    }
+                    rx.close();
+                    None
+                }
+                Event::Keepalive => {
+                    if keepalive.idle_for() >= state.idle_timeout {
+                        info!("💤 Closing idle connection");
+                        state.metrics.record_idle_disconnect();
+                        close(&mut ws, CloseCode::Away, "idle timeout").await;
+                        break;
+                    }
+                    Some(keepalive.next_ping().into())
+                }
+            };
+
+            if let Some(envelope) = reply {
+                if !send(&mut ws, state, envelope).await {
+                    break;
+                }
+            }
+        }
+
+        if let Some(tx) = weak_tx.upgrade() {
+            if let Err(e) = state.router.unregister_client_channel(&wallet, &tx).await {
+                error!("Failed to unregister client {}: {}", wallet, e);
+            }
+        }
+    }
+}
+
+/// Authenticate the client with the same challenge-response as on QUIC,
+/// returning its wallet and device id
+async fn perform_handshake<S>(ws: &mut WebSocketStream<S>, relay_id: &str) -> Result<(WalletAddress, String)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let challenge = HandshakeChallenge::new(relay_id);
+    ws.send(Message::Binary(challenge.encode_to_vec())).await?;
+
+    let data = loop {
+        match ws.next().await {
+            Some(Ok(Message::Binary(data))) => break data,
+            Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+            Some(Ok(_)) => bail!("Expected a binary handshake proof"),
+            Some(Err(e)) => return Err(e.into()),
+            None => bail!("Connection closed during the handshake"),
+        }
+    };
+
+    let result = if data.len() > MAX_HANDSHAKE_SIZE {
+        Err(anyhow!("Handshake proof too large"))
+    } else {
+        HandshakeProof::decode(&data[..])
+            .map_err(anyhow::Error::from)
+            .and_then(|proof| {
+                let wallet = challenge.verify_proof(&proof, &[]).map_err(|e| anyhow!(e))?;
+                Ok((wallet, proof.device_id().to_string()))
+            })
+    };
+
+    let response = match &result {
+        Ok(_) => HandshakeResponse::success(),
+        Err(e) => HandshakeResponse::failure(e.to_string()),
+    };
+    ws.send(Message::Binary(response.encode_to_vec())).await?;
+
+    result
+}
+
+/// Send one envelope, returning whether it was written
+async fn send<S>(ws: &mut WebSocketStream<S>, state: &AppState, envelope: Envelope) -> bool
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let data = envelope.encode_to_vec();
+    let len = data.len();
+    match ws.send(Message::Binary(data)).await {
+        Ok(()) => {
+            state.metrics.record_bytes_sent(len);
+            debug!("📨 Sent message to client");
+            true
+        }
+        Err(e) => {
+            debug!("Failed to send to WebSocket client: {}", e);
+            false
+        }
+    }
+}
+
+/// Start the closing handshake and give the client a moment to answer it
+async fn close<S>(ws: &mut WebSocketStream<S>, code: CloseCode, reason: &str)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let frame = CloseFrame {
+        code,
+        reason: reason.to_string().into(),
+    };
+    if ws.close(Some(frame)).await.is_err() {
+        return;
+    }
+    let _ = tokio::time::timeout(CLOSE_TIMEOUT, async {
+        while let Some(Ok(_)) = ws.next().await {}
+    })
+    .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue_store::tests::FailingRemovals;
+    use crate::queue_store::QueueStore;
+    use crate::router::{MessageRouter, RelayMessage};
+    use crate::session::test_keypair;
+    use ed25519_dalek::Keypair;
+    use solchat_protocol::messages::{envelope::Body, AckMessage, AckStatus, ChatMessage};
+    use tokio_tungstenite::{connect_async, MaybeTlsStream};
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    /// Plain `ws://` gateway on a loopback port
+    async fn gateway(state: AppState) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(Arc::new(WebSocketGateway::new(state, None)).serve(listener));
+        addr
+    }
+
+    async fn next_binary(client: &mut Client) -> Vec<u8> {
+        loop {
+            match client.next().await.unwrap().unwrap() {
+                Message::Binary(data) => return data,
+                Message::Ping(_) | Message::Pong(_) => continue,
+                other => panic!("expected a binary message, got {:?}", other),
+            }
+        }
+    }
+
+    async fn next_envelope(client: &mut Client) -> Body {
+        Envelope::decode(&next_binary(client).await[..]).unwrap().body.unwrap()
+    }
+
+    /// Connect and answer the challenge, returning the relay's response
+    async fn connect(addr: SocketAddr, signer: &Keypair) -> (Client, HandshakeResponse) {
+        let (mut client, _) = connect_async(format!("ws://{}", addr)).await.unwrap();
+        let challenge = HandshakeChallenge::decode(&next_binary(&mut client).await[..]).unwrap();
+        let proof = HandshakeProof::sign(&challenge, "localhost", &[], signer).unwrap();
+        client.send(Message::Binary(proof.encode_to_vec())).await.unwrap();
+        let response = HandshakeResponse::decode(&next_binary(&mut client).await[..]).unwrap();
+        (client, response)
+    }
+
+    async fn wait_for_clients(state: &AppState, count: usize) {
+        while state.router.get_stats().await.connected_clients != count {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_browser_and_native_clients_message_each_other() {
+        let state = AppState::for_tests();
+        let addr = gateway(state.clone()).await;
+        let signer = test_keypair(7);
+        let browser = WalletAddress::new(signer.public.to_bytes());
+        let native = WalletAddress::test_address(2);
+
+        let (mut client, response) = connect(addr, &signer).await;
+        assert!(response.success);
+        let (native_tx, mut native_rx) = mpsc::channel(10);
+        state.router.register_client(native.clone(), native_tx, "10.0.0.1:4000".parse().unwrap()).await.unwrap();
+        wait_for_clients(&state, 2).await;
+
+        // Browser to native: delivered through the router and acked
+        let mut chat = ChatMessage::new(&browser, &native, b"from the browser".to_vec(), Vec::new());
+        chat.sign(&signer).unwrap();
+        let envelope: Envelope = RelayMessage::Chat(chat.clone()).to_envelope();
+        client.send(Message::Binary(envelope.encode_to_vec())).await.unwrap();
+        let delivered = native_rx.recv().await.unwrap();
+        assert_eq!(delivered.message.message_id(), Some(chat.id.as_str()));
+        match next_envelope(&mut client).await {
+            Body::Ack(ack) => assert_eq!((ack.status(), ack.ref_message_id), (AckStatus::Delivered, chat.id)),
+            other => panic!("expected an ack, got {:?}", other),
+        }
+
+        // Native to browser
+        let reply = ChatMessage::new(&native, &browser, b"from the app".to_vec(), Vec::new());
+        let outcome = state.router.route_message(RelayMessage::Chat(reply.clone()), "10.0.0.1:4000".parse().unwrap()).await.unwrap();
+        assert_eq!(outcome.status, AckStatus::Delivered);
+        assert!(matches!(next_envelope(&mut client).await, Body::Chat(chat) if chat.id == reply.id));
+    }
+
+    #[tokio::test]
+    async fn test_proof_for_another_binding_is_refused() {
+        let state = AppState::for_tests();
+        let addr = gateway(state.clone()).await;
+        let signer = test_keypair(7);
+
+        let (mut client, _) = connect_async(format!("ws://{}", addr)).await.unwrap();
+        let challenge = HandshakeChallenge::decode(&next_binary(&mut client).await[..]).unwrap();
+        let proof = HandshakeProof::sign(&challenge, "localhost", &[9u8; 32], &signer).unwrap();
+        client.send(Message::Binary(proof.encode_to_vec())).await.unwrap();
+        let response = HandshakeResponse::decode(&next_binary(&mut client).await[..]).unwrap();
+
+        assert!(!response.success);
+        assert!(matches!(client.next().await, Some(Ok(Message::Close(Some(frame)))) if frame.code == CloseCode::Policy));
+        assert_eq!(state.router.get_stats().await.connected_clients, 0);
+    }
+
+    #[tokio::test]
+    async fn test_drain_forwards_held_messages_then_goes_away() {
+        let state = AppState::for_tests();
+        let addr = gateway(state.clone()).await;
+        let signer = test_keypair(7);
+        let wallet = WalletAddress::new(signer.public.to_bytes());
+        let sender_addr: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+
+        let (mut client, _) = connect(addr, &signer).await;
+        wait_for_clients(&state, 1).await;
+        let held = ChatMessage::new(&WalletAddress::test_address(1), &wallet, b"held".to_vec(), Vec::new());
+        state.router.route_message(RelayMessage::Chat(held.clone()), sender_addr).await.unwrap();
+
+        state.shutdown.begin();
+        assert!(matches!(next_envelope(&mut client).await, Body::Chat(chat) if chat.id == held.id));
+        assert!(matches!(next_envelope(&mut client).await, Body::GoAway(_)));
+        assert!(matches!(client.next().await, Some(Ok(Message::Close(Some(frame)))) if frame.code == CloseCode::Away));
+        drop(client);
+        assert!(state.shutdown.wait_for_connections(Duration::from_secs(5)).await);
+
+        // The gateway stopped listening
+        assert!(connect_async(format!("ws://{}", addr)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_clients_that_cannot_be_registered_are_closed() {
+        let signer = test_keypair(7);
+        let wallet = WalletAddress::new(signer.public.to_bytes());
+
+        // An ack waiting for the wallet cannot be cleared once delivered
+        let store = Arc::new(FailingRemovals::default());
+        let ack = RoutableMessage {
+            message: RelayMessage::Ack(AckMessage::delivered("msg_1".to_string())),
+            sender_addr: "127.0.0.1:1234".parse().unwrap(),
+        };
+        store.push(&wallet.to_string(), ack).unwrap();
+        let state = AppState::for_tests();
+        let state = AppState {
+            router: Arc::new(MessageRouter::with_store(state.metrics.clone(), store)),
+            ..state
+        };
+
+        let addr = gateway(state.clone()).await;
+        let (mut client, response) = connect(addr, &signer).await;
+        assert!(response.success);
+        let close = loop {
+            match client.next().await.unwrap().unwrap() {
+                Message::Close(frame) => break frame.unwrap(),
+                _ => continue,
+            }
+        };
+        assert_eq!(close.code, CloseCode::Error);
+        assert_eq!(state.router.get_stats().await.connected_clients, 0);
+    }
+}
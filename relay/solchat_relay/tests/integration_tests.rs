@@ -1,7 +1,6 @@
 use anyhow::Result;
 use prost::Message;
 use solchat_protocol::{ChatMessage, AckMessage, AckStatus, WalletAddress};
-use std::time::Duration;
 
 // Testing is like debugging - it's what you should have done in the first place
 
@@ -236,7 +235,7 @@ mod stress_tests {
         let start = std::time::Instant::now();
         
         for i in 0..batch_size {
-            let payload = vec![((i % 256) as u8); message_size];
+            let payload = vec![(i % 256) as u8; message_size];
             let signature = format!("sig_{}", i).into_bytes();
             
             let msg = ChatMessage::new(&sender, &recipient, payload, signature);
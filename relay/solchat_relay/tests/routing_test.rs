@@ -1,10 +1,18 @@
 use anyhow::Result;
-use solchat_protocol::{ChatMessage, WalletAddress};
-use solchat_relay::router::{MessageRouter, RoutableMessage};
+use solchat_protocol::{AckMessage, AckStatus, ChatMessage, WalletAddress};
+use solchat_protocol::messages::ReadReceipt;
+use solchat_relay::queue_limits::{EvictionPolicy, QueueLimits};
+use solchat_relay::queue_store::{FileQueueStore, MemoryQueueStore, QueueStore};
+use solchat_relay::router::{queue_key, MessageRouter, RelayMessage, RoutableMessage, DEFAULT_DEVICE};
 use std::sync::Arc;
 use tokio::sync::mpsc;
 use std::net::SocketAddr;
 
+/// Address the test clients connect from
+fn client_addr() -> SocketAddr {
+    "127.0.0.1:1234".parse().unwrap()
+}
+
 #[tokio::test]
 async fn test_message_routing_between_clients() -> Result<()> {
     // Create router with metrics
@@ -16,12 +24,12 @@ async fn test_message_routing_between_clients() -> Result<()> {
     let bob = WalletAddress::test_address(2);
     
     // Create channels for both clients
-    let (alice_tx, mut alice_rx) = mpsc::channel::<RoutableMessage>(10);
+    let (alice_tx, _alice_rx) = mpsc::channel::<RoutableMessage>(10);
     let (bob_tx, mut bob_rx) = mpsc::channel::<RoutableMessage>(10);
     
     // Register both clients
-    router.register_client(alice.clone(), alice_tx).await?;
-    router.register_client(bob.clone(), bob_tx).await?;
+    router.register_client(alice.clone(), alice_tx, client_addr()).await?;
+    router.register_client(bob.clone(), bob_tx, client_addr()).await?;
     
     // Alice sends a message to Bob
     let message = ChatMessage::new(
@@ -34,18 +42,24 @@ async fn test_message_routing_between_clients() -> Result<()> {
     let sender_addr: SocketAddr = "127.0.0.1:1234".parse()?;
     
     // Route the message
-    let status = router.route_message(message.clone(), sender_addr).await?;
-    assert_eq!(status, solchat_protocol::AckStatus::Delivered);
+    let outcome = router.route_message(RelayMessage::Chat(message.clone()), sender_addr).await?;
+    assert_eq!(outcome.status, solchat_protocol::AckStatus::Delivered);
     
     // Bob should receive the message
     let received = bob_rx.recv().await.expect("Bob should receive message");
-    assert_eq!(received.message.id, message.id);
-    assert_eq!(received.message.encrypted_payload, b"Hello Bob!".to_vec());
+    let RelayMessage::Chat(received) = received.message else {
+        panic!("Bob should receive a chat message");
+    };
+    assert_eq!(received.id, message.id);
+    assert_eq!(received.encrypted_payload, b"Hello Bob!".to_vec());
     
-    // Verify stats
+    // Verify stats: the message is kept until Bob acks it
     let stats = router.get_stats().await;
     assert_eq!(stats.connected_clients, 2);
-    assert_eq!(stats.queued_messages, 0);
+    assert_eq!(stats.queued_messages, 1);
+    
+    router.route_message_from(RelayMessage::Ack(AckMessage::delivered(message.id.clone())), &bob, DEFAULT_DEVICE, sender_addr).await?;
+    assert_eq!(router.get_stats().await.queued_messages, 0);
     
     Ok(())
 }
@@ -60,7 +74,7 @@ async fn test_message_queueing_for_offline_recipient() -> Result<()> {
     
     // Only register Alice
     let (alice_tx, _alice_rx) = mpsc::channel::<RoutableMessage>(10);
-    router.register_client(alice.clone(), alice_tx).await?;
+    router.register_client(alice.clone(), alice_tx, client_addr()).await?;
     
     // Alice sends a message to offline Bob
     let message = ChatMessage::new(
@@ -73,8 +87,8 @@ async fn test_message_queueing_for_offline_recipient() -> Result<()> {
     let sender_addr: SocketAddr = "127.0.0.1:1234".parse()?;
     
     // Route the message - should be queued
-    let status = router.route_message(message.clone(), sender_addr).await?;
-    assert_eq!(status, solchat_protocol::AckStatus::Delivered);
+    let outcome = router.route_message(RelayMessage::Chat(message.clone()), sender_addr).await?;
+    assert_eq!(outcome.status, solchat_protocol::AckStatus::Queued);
     
     // Verify message is queued
     let stats = router.get_stats().await;
@@ -82,11 +96,18 @@ async fn test_message_queueing_for_offline_recipient() -> Result<()> {
     
     // Now Bob comes online
     let (bob_tx, mut bob_rx) = mpsc::channel::<RoutableMessage>(10);
-    router.register_client(bob.clone(), bob_tx).await?;
+    router.register_client(bob.clone(), bob_tx, client_addr()).await?;
     
     // Bob should receive the queued message
     let received = bob_rx.recv().await.expect("Bob should receive queued message");
-    assert_eq!(received.message.encrypted_payload, b"Hello offline Bob!".to_vec());
+    let RelayMessage::Chat(received) = received.message else {
+        panic!("Bob should receive a chat message");
+    };
+    assert_eq!(received.encrypted_payload, b"Hello offline Bob!".to_vec());
+    
+    // Delivery alone does not dequeue; Bob's ack does
+    assert_eq!(router.get_stats().await.queued_messages, 1);
+    router.route_message(RelayMessage::Ack(AckMessage::delivered(received.id.clone())), sender_addr).await?;
     
     // Queue should be empty now
     let stats = router.get_stats().await;
@@ -105,7 +126,7 @@ async fn test_multiple_queued_messages_delivery() -> Result<()> {
     
     // Only register Alice
     let (alice_tx, _alice_rx) = mpsc::channel::<RoutableMessage>(10);
-    router.register_client(alice.clone(), alice_tx).await?;
+    router.register_client(alice.clone(), alice_tx, client_addr()).await?;
     
     let sender_addr: SocketAddr = "127.0.0.1:1234".parse()?;
     
@@ -118,8 +139,8 @@ async fn test_multiple_queued_messages_delivery() -> Result<()> {
             b"alice_signature".to_vec(),
         );
         
-        let status = router.route_message(message, sender_addr).await?;
-        assert_eq!(status, solchat_protocol::AckStatus::Delivered);
+        let outcome = router.route_message(RelayMessage::Chat(message), sender_addr).await?;
+        assert_eq!(outcome.status, solchat_protocol::AckStatus::Queued);
     }
     
     // Verify 3 messages are queued
@@ -128,15 +149,19 @@ async fn test_multiple_queued_messages_delivery() -> Result<()> {
     
     // Bob comes online
     let (bob_tx, mut bob_rx) = mpsc::channel::<RoutableMessage>(10);
-    router.register_client(bob.clone(), bob_tx).await?;
+    router.register_client(bob.clone(), bob_tx, client_addr()).await?;
     
     // Bob should receive all 3 messages
     for i in 0..3 {
         let received = bob_rx.recv().await.expect("Bob should receive message");
+        let RelayMessage::Chat(received) = received.message else {
+            panic!("Bob should receive a chat message");
+        };
         assert_eq!(
-            received.message.encrypted_payload,
+            received.encrypted_payload,
             format!("Message {}", i).as_bytes().to_vec()
         );
+        router.route_message(RelayMessage::Ack(AckMessage::delivered(received.id.clone())), sender_addr).await?;
     }
     
     // Queue should be empty
@@ -144,4 +169,488 @@ async fn test_multiple_queued_messages_delivery() -> Result<()> {
     assert_eq!(stats.queued_messages, 0);
     
     Ok(())
-} 
\ No newline at end of file
+} 
+#[tokio::test]
+async fn test_stale_connection_does_not_unregister_reconnected_client() -> Result<()> {
+    let metrics = Arc::new(solchat_relay::metrics::Metrics::new());
+    let router = Arc::new(MessageRouter::new(metrics));
+    
+    let alice = WalletAddress::test_address(1);
+    
+    // Alice reconnects before her first connection has been torn down
+    let (old_tx, _old_rx) = mpsc::channel::<RoutableMessage>(10);
+    let (new_tx, _new_rx) = mpsc::channel::<RoutableMessage>(10);
+    router.register_client(alice.clone(), old_tx.clone(), client_addr()).await?;
+    router.register_client(alice.clone(), new_tx.clone(), client_addr()).await?;
+    
+    // Closing the old connection must leave the new one registered
+    router.unregister_client_channel(&alice, &old_tx).await?;
+    assert_eq!(router.get_stats().await.connected_clients, 1);
+    
+    router.unregister_client_channel(&alice, &new_tx).await?;
+    assert_eq!(router.get_stats().await.connected_clients, 0);
+    
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_acks_and_read_receipts_route_back_to_sender() -> Result<()> {
+    let metrics = Arc::new(solchat_relay::metrics::Metrics::new());
+    let router = Arc::new(MessageRouter::new(metrics));
+    
+    let alice = WalletAddress::test_address(1);
+    let bob = WalletAddress::test_address(2);
+    let sender_addr: SocketAddr = "127.0.0.1:1234".parse()?;
+    
+    let (alice_tx, mut alice_rx) = mpsc::channel::<RoutableMessage>(10);
+    router.register_client(alice.clone(), alice_tx.clone(), client_addr()).await?;
+    
+    let message = ChatMessage::new(&alice, &bob, b"Hello Bob!".to_vec(), Vec::new());
+    router.route_message(RelayMessage::Chat(message.clone()), sender_addr).await?;
+    
+    // Bob's ack finds Alice through the message id
+    let ack = AckMessage::delivered(message.id.clone());
+    let outcome = router.route_message(RelayMessage::Ack(ack.clone()), sender_addr).await?;
+    assert_eq!(outcome.status, AckStatus::Delivered);
+    
+    let received = alice_rx.recv().await.expect("Alice should receive the ack");
+    let RelayMessage::Ack(received) = received.message else {
+        panic!("Alice should receive an ack");
+    };
+    assert_eq!(received.ref_message_id, message.id);
+    
+    // A read receipt sent while Alice is offline waits for her to reconnect
+    router.unregister_client_channel(&alice, &alice_tx).await?;
+    let queued_before = router.get_stats().await.queued_messages;
+    let receipt = ReadReceipt {
+        id: "receipt_1".to_string(),
+        message_id: message.id.clone(),
+        reader_wallet: bob.to_string(),
+        timestamp: 0,
+    };
+    let outcome = router.route_message(RelayMessage::ReadReceipt(receipt), sender_addr).await?;
+    assert_eq!(outcome.status, AckStatus::Queued);
+    assert_eq!(router.get_stats().await.queued_messages, queued_before + 1);
+    
+    let (alice_tx, mut alice_rx) = mpsc::channel::<RoutableMessage>(10);
+    router.register_client(alice.clone(), alice_tx, client_addr()).await?;
+    let received = alice_rx.recv().await.expect("Alice should receive the read receipt");
+    assert!(matches!(received.message, RelayMessage::ReadReceipt(ref r) if r.message_id == message.id));
+    
+    // Acks for unknown messages go nowhere
+    let outcome = router.route_message(RelayMessage::Ack(AckMessage::delivered("msg_unknown".to_string())), sender_addr).await?;
+    assert_eq!(outcome.status, AckStatus::Failed);
+    assert_eq!(outcome.reason.as_deref(), Some("unknown message id"));
+    
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_only_the_recipient_can_ack() -> Result<()> {
+    let metrics = Arc::new(solchat_relay::metrics::Metrics::new());
+    let store = Arc::new(MemoryQueueStore::new());
+    let router = MessageRouter::with_store(metrics, store.clone());
+    
+    let alice = WalletAddress::test_address(1);
+    let bob = WalletAddress::test_address(2);
+    let mallory = WalletAddress::test_address(3);
+    
+    let (alice_tx, mut alice_rx) = mpsc::channel::<RoutableMessage>(10);
+    router.register_client(alice.clone(), alice_tx, client_addr()).await?;
+    let message = ChatMessage::new(&alice, &bob, b"for Bob only".to_vec(), Vec::new());
+    router.route_message(RelayMessage::Chat(message.clone()), client_addr()).await?;
+    assert_eq!(store.queued_for(&bob.to_string()), 1);
+    
+    // Another wallet's ack neither clears Bob's queue nor reaches Alice
+    let ack = RelayMessage::Ack(AckMessage::delivered(message.id.clone()));
+    let outcome = router.route_message_from(ack.clone(), &mallory, DEFAULT_DEVICE, client_addr()).await?;
+    assert_eq!(outcome.status, AckStatus::Rejected);
+    assert_eq!(store.queued_for(&bob.to_string()), 1);
+    assert!(alice_rx.try_recv().is_err());
+    
+    let outcome = router.route_message_from(ack, &bob, DEFAULT_DEVICE, client_addr()).await?;
+    assert_eq!(outcome.status, AckStatus::Delivered);
+    assert_eq!(store.queued_for(&bob.to_string()), 0);
+    assert!(matches!(alice_rx.recv().await.expect("ack").message, RelayMessage::Ack(ack) if ack.ref_message_id == message.id));
+    
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_only_the_recipient_can_send_read_receipts() -> Result<()> {
+    let router = MessageRouter::new(Arc::new(solchat_relay::metrics::Metrics::new()));
+    
+    let alice = WalletAddress::test_address(1);
+    let bob = WalletAddress::test_address(2);
+    let mallory = WalletAddress::test_address(3);
+    
+    let (alice_tx, mut alice_rx) = mpsc::channel::<RoutableMessage>(10);
+    router.register_client(alice.clone(), alice_tx, client_addr()).await?;
+    let message = ChatMessage::new(&alice, &bob, b"for Bob only".to_vec(), Vec::new());
+    router.route_message(RelayMessage::Chat(message.clone()), client_addr()).await?;
+    
+    let receipt = |reader: &WalletAddress| RelayMessage::ReadReceipt(ReadReceipt {
+        id: format!("receipt_{}", reader),
+        message_id: message.id.clone(),
+        reader_wallet: reader.to_string(),
+        timestamp: 0,
+    });
+    
+    // Another wallet cannot tell Alice her message was read
+    let outcome = router.route_message_from(receipt(&mallory), &mallory, DEFAULT_DEVICE, client_addr()).await?;
+    assert_eq!(outcome.status, AckStatus::Rejected);
+    assert_eq!(outcome.reason.as_deref(), Some("not the message's recipient"));
+    assert!(alice_rx.try_recv().is_err());
+    
+    let outcome = router.route_message_from(receipt(&bob), &bob, DEFAULT_DEVICE, client_addr()).await?;
+    assert_eq!(outcome.status, AckStatus::Delivered);
+    assert!(matches!(alice_rx.recv().await.expect("read receipt").message, RelayMessage::ReadReceipt(r) if r.message_id == message.id));
+    
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_queued_messages_survive_restart_until_acked() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("queue.log");
+    
+    let alice = WalletAddress::test_address(1);
+    let bob = WalletAddress::test_address(2);
+    let sender_addr: SocketAddr = "127.0.0.1:1234".parse()?;
+    
+    let open_router = || -> Result<(MessageRouter, Arc<FileQueueStore>)> {
+        let metrics = Arc::new(solchat_relay::metrics::Metrics::new());
+        let store = Arc::new(FileQueueStore::open(&path)?);
+        Ok((MessageRouter::with_store(metrics, store.clone()), store))
+    };
+    
+    {
+        let (router, _) = open_router()?;
+        for i in 0..3 {
+            let message = ChatMessage::new(&alice, &bob, format!("Message {}", i).into_bytes(), Vec::new());
+            router.route_message(RelayMessage::Chat(message), sender_addr).await?;
+        }
+    }
+    
+    // After a restart Bob gets all three, in order, and acks only the first
+    let (router, _) = open_router()?;
+    let (bob_tx, mut bob_rx) = mpsc::channel::<RoutableMessage>(10);
+    router.register_client(bob.clone(), bob_tx.clone(), client_addr()).await?;
+    
+    let mut received = Vec::new();
+    for _ in 0..3 {
+        let RelayMessage::Chat(message) = bob_rx.recv().await.expect("queued message").message else {
+            panic!("Bob should receive a chat message");
+        };
+        received.push(message);
+    }
+    let payloads: Vec<_> = received.iter().map(|m| m.encrypted_payload.clone()).collect();
+    assert_eq!(payloads, vec![b"Message 0".to_vec(), b"Message 1".to_vec(), b"Message 2".to_vec()]);
+    
+    router.route_message(RelayMessage::Ack(AckMessage::delivered(received[0].id.clone())), sender_addr).await?;
+    router.unregister_client_channel(&bob, &bob_tx).await?;
+    drop(router);
+    
+    // Unacknowledged messages are still there after another restart
+    let (router, store) = open_router()?;
+    assert_eq!(store.queued_for(&bob.to_string()), 2);
+    
+    let (bob_tx, mut bob_rx) = mpsc::channel::<RoutableMessage>(10);
+    router.register_client(bob.clone(), bob_tx, client_addr()).await?;
+    for expected in &received[1..] {
+        let RelayMessage::Chat(message) = bob_rx.recv().await.expect("queued message").message else {
+            panic!("Bob should receive a chat message");
+        };
+        assert_eq!(message.id, expected.id);
+        router.route_message(RelayMessage::Ack(AckMessage::delivered(message.id)), sender_addr).await?;
+    }
+    assert_eq!(store.queued_for(&bob.to_string()), 0);
+    
+    // Bob's acks wait for Alice
+    assert_eq!(store.queued_for(&alice.to_string()), 3);
+    
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_expired_messages_are_dropped_with_expired_acks() -> Result<()> {
+    let metrics = Arc::new(solchat_relay::metrics::Metrics::new());
+    let store = Arc::new(MemoryQueueStore::new());
+    let router = MessageRouter::with_store(metrics.clone(), store.clone());
+    
+    let alice = WalletAddress::test_address(1);
+    let bob = WalletAddress::test_address(2);
+    let carol = WalletAddress::test_address(3);
+    let sender_addr: SocketAddr = "127.0.0.1:1234".parse()?;
+    
+    let (alice_tx, mut alice_rx) = mpsc::channel::<RoutableMessage>(10);
+    router.register_client(alice.clone(), alice_tx, client_addr()).await?;
+    
+    // Messages whose TTL ran out while they sat in the queue
+    let mut stale = Vec::new();
+    for recipient in [&bob, &carol] {
+        let mut message = ChatMessage::new(&alice, recipient, b"too late".to_vec(), Vec::new()).with_ttl(1);
+        message.timestamp -= 10;
+        store.push(&recipient.to_string(), RoutableMessage {
+            message: RelayMessage::Chat(message.clone()),
+            sender_addr,
+        })?;
+        stale.push(message);
+    }
+    let fresh = ChatMessage::new(&alice, &bob, b"in time".to_vec(), Vec::new()).with_ttl(3600);
+    router.route_message(RelayMessage::Chat(fresh.clone()), sender_addr).await?;
+    
+    // An already expired message is refused outright
+    let outcome = router.route_message(RelayMessage::Chat(stale[0].clone()), sender_addr).await?;
+    assert_eq!(outcome.status, AckStatus::Expired);
+    
+    // The sweeper drops both stale messages and tells Alice
+    assert_eq!(router.sweep_expired().await?, 2);
+    for _ in 0..2 {
+        let RelayMessage::Ack(ack) = alice_rx.recv().await.expect("expired ack").message else {
+            panic!("Alice should receive an ack");
+        };
+        assert_eq!(ack.status(), AckStatus::Expired);
+        assert!(stale.iter().any(|message| message.id == ack.ref_message_id));
+    }
+    assert_eq!(router.get_stats().await.queued_messages, 1);
+    assert_eq!(metrics.messages_expired.get(), 3);
+    
+    // Delivery also checks expiry for anything the sweeper has not seen yet
+    let mut late = ChatMessage::new(&alice, &bob, b"also late".to_vec(), Vec::new()).with_ttl(1);
+    late.timestamp -= 10;
+    store.push(&bob.to_string(), RoutableMessage {
+        message: RelayMessage::Chat(late.clone()),
+        sender_addr,
+    })?;
+    
+    let (bob_tx, mut bob_rx) = mpsc::channel::<RoutableMessage>(10);
+    router.register_client(bob.clone(), bob_tx, client_addr()).await?;
+    let RelayMessage::Chat(received) = bob_rx.recv().await.expect("fresh message").message else {
+        panic!("Bob should receive a chat message");
+    };
+    assert_eq!(received.id, fresh.id);
+    assert!(bob_rx.try_recv().is_err());
+    
+    let RelayMessage::Ack(ack) = alice_rx.recv().await.expect("expired ack").message else {
+        panic!("Alice should receive an ack");
+    };
+    assert_eq!(ack.ref_message_id, late.id);
+    assert_eq!(ack.status(), AckStatus::Expired);
+    
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_full_queue_rejects_or_evicts_by_policy() -> Result<()> {
+    let metrics = Arc::new(solchat_relay::metrics::Metrics::new());
+    let store = Arc::new(MemoryQueueStore::new());
+    let router = MessageRouter::with_store(metrics.clone(), store.clone());
+    router.set_queue_limits(QueueLimits {
+        max_messages_per_recipient: 2,
+        policy: EvictionPolicy::RejectNew,
+        ..QueueLimits::default()
+    });
+    
+    let alice = WalletAddress::test_address(1);
+    let bob = WalletAddress::test_address(2);
+    let sender_addr: SocketAddr = "127.0.0.1:1234".parse()?;
+    
+    let (alice_tx, mut alice_rx) = mpsc::channel::<RoutableMessage>(10);
+    router.register_client(alice.clone(), alice_tx, client_addr()).await?;
+    
+    let mut sent = Vec::new();
+    for _ in 0..3 {
+        let message = ChatMessage::new(&alice, &bob, b"Hello Bob!".to_vec(), Vec::new());
+        let outcome = router.route_message(RelayMessage::Chat(message.clone()), sender_addr).await?;
+        sent.push((message, outcome));
+    }
+    
+    // Reject-new keeps what is queued and refuses the newcomer
+    assert_eq!(sent[1].1.status, AckStatus::Queued);
+    assert_eq!(sent[2].1.status, AckStatus::Rejected);
+    assert_eq!(sent[2].1.reason.as_deref(), Some("recipient queue full"));
+    assert_eq!(store.queued_for(&bob.to_string()), 2);
+    
+    // Drop-oldest makes room and tells the evicted message's sender
+    router.set_queue_limits(QueueLimits {
+        policy: EvictionPolicy::DropOldest,
+        ..router.queue_limits()
+    });
+    let outcome = router.route_message(RelayMessage::Chat(sent[2].0.clone()), sender_addr).await?;
+    assert_eq!(outcome.status, AckStatus::Queued);
+    
+    let RelayMessage::Ack(ack) = alice_rx.recv().await.expect("eviction ack").message else {
+        panic!("Alice should receive an ack");
+    };
+    assert_eq!(ack.ref_message_id, sent[0].0.id);
+    assert_eq!(ack.status(), AckStatus::Failed);
+    assert_eq!(ack.reason.as_deref(), Some("evicted from a full queue"));
+    
+    let queued: Vec<String> = store
+        .pending(&bob.to_string())
+        .iter()
+        .filter_map(|queued| queued.message.message_id().map(str::to_string))
+        .collect();
+    assert_eq!(queued, vec![sent[1].0.id.clone(), sent[2].0.id.clone()]);
+    assert_eq!(metrics.messages_evicted.get(), 1);
+    
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_byte_limits_prefer_expired_and_largest_queues() -> Result<()> {
+    let metrics = Arc::new(solchat_relay::metrics::Metrics::new());
+    let store = Arc::new(MemoryQueueStore::new());
+    let router = MessageRouter::with_store(metrics.clone(), store.clone());
+    
+    let alice = WalletAddress::test_address(1);
+    let bob = WalletAddress::test_address(2);
+    let carol = WalletAddress::test_address(3);
+    let dave = WalletAddress::test_address(4);
+    let sender_addr: SocketAddr = "127.0.0.1:1234".parse()?;
+    
+    let (alice_tx, mut alice_rx) = mpsc::channel::<RoutableMessage>(10);
+    router.register_client(alice.clone(), alice_tx, client_addr()).await?;
+    
+    let chat = |recipient: &WalletAddress| ChatMessage::new(&alice, recipient, vec![0u8; 100], Vec::new());
+    let size = RelayMessage::Chat(chat(&bob)).encoded_len();
+    
+    // Bob's queue holds an expired message behind a fresh one
+    let fresh = chat(&bob);
+    router.route_message(RelayMessage::Chat(fresh.clone()), sender_addr).await?;
+    let mut stale = chat(&bob).with_ttl(1);
+    stale.timestamp -= 10;
+    store.push(&bob.to_string(), RoutableMessage {
+        message: RelayMessage::Chat(stale.clone()),
+        sender_addr,
+    })?;
+    router.route_message(RelayMessage::Chat(chat(&carol)), sender_addr).await?;
+    
+    // Room for three messages in total, and at most two per recipient
+    router.set_queue_limits(QueueLimits {
+        max_messages_per_recipient: 10,
+        max_bytes_per_recipient: size * 2 + size / 2,
+        max_total_bytes: size * 3 + size / 2,
+        policy: EvictionPolicy::DropExpiredFirst,
+    });
+    
+    // Dave's message pushes the relay over its global cap; Bob has the most
+    // queued, and his expired message goes before the fresh one
+    let outcome = router.route_message(RelayMessage::Chat(chat(&dave)), sender_addr).await?;
+    assert_eq!(outcome.status, AckStatus::Queued);
+    let RelayMessage::Ack(ack) = alice_rx.recv().await.expect("expiry ack").message else {
+        panic!("Alice should receive an ack");
+    };
+    assert_eq!(ack.ref_message_id, stale.id);
+    assert_eq!(ack.status(), AckStatus::Expired);
+    assert_eq!(store.queued_for(&bob.to_string()), 1);
+    assert_eq!(store.total_bytes(), size * 3);
+    assert_eq!(metrics.queued_bytes.get(), (size * 3) as i64);
+    
+    // A message bigger than a whole recipient quota is never queued
+    let huge = ChatMessage::new(&alice, &bob, vec![0u8; size * 3], Vec::new());
+    let outcome = router.route_message(RelayMessage::Chat(huge), sender_addr).await?;
+    assert_eq!(outcome.status, AckStatus::Rejected);
+    assert_eq!(store.total_bytes(), size * 3);
+    
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_chat_fans_out_to_every_device_with_a_queue_each() -> Result<()> {
+    let metrics = Arc::new(solchat_relay::metrics::Metrics::new());
+    let store = Arc::new(MemoryQueueStore::new());
+    let router = MessageRouter::with_store(metrics, store.clone());
+    
+    let alice = WalletAddress::test_address(1);
+    let bob = WalletAddress::test_address(2);
+    let laptop_queue = queue_key(&bob.to_string(), "laptop");
+    
+    let (alice_tx, mut alice_rx) = mpsc::channel::<RoutableMessage>(10);
+    let (phone_tx, mut phone_rx) = mpsc::channel::<RoutableMessage>(10);
+    let (laptop_tx, mut laptop_rx) = mpsc::channel::<RoutableMessage>(10);
+    router.register_client(alice.clone(), alice_tx, client_addr()).await?;
+    router.register_device(bob.clone(), "phone", phone_tx, client_addr()).await?;
+    router.register_device(bob.clone(), "laptop", laptop_tx.clone(), client_addr()).await?;
+    assert_eq!(router.get_stats().await.connected_clients, 3);
+    
+    // Both of Bob's devices get the message
+    let first = ChatMessage::new(&alice, &bob, b"to both".to_vec(), Vec::new());
+    let outcome = router.route_message(RelayMessage::Chat(first.clone()), client_addr()).await?;
+    assert_eq!(outcome.status, AckStatus::Delivered);
+    for rx in [&mut phone_rx, &mut laptop_rx] {
+        assert_eq!(rx.recv().await.expect("fanned out").message.message_id(), Some(first.id.as_str()));
+    }
+    
+    // Each device keeps its copy until it acks it
+    assert_eq!(store.queued_for(&laptop_queue), 1);
+    for device_id in ["phone", "laptop"] {
+        router.route_message_from(RelayMessage::Ack(AckMessage::delivered(first.id.clone())), &bob, device_id, client_addr()).await?;
+        assert!(matches!(alice_rx.recv().await.expect("ack").message, RelayMessage::Ack(ack) if ack.ref_message_id == first.id));
+    }
+    assert_eq!(store.queued_for(&laptop_queue), 0);
+    
+    // With the laptop offline, the phone still gets it live and the laptop
+    // a queued copy of its own
+    router.unregister_client_channel(&bob, &laptop_tx).await?;
+    let second = ChatMessage::new(&alice, &bob, b"laptop was away".to_vec(), Vec::new());
+    let outcome = router.route_message(RelayMessage::Chat(second.clone()), client_addr()).await?;
+    assert_eq!(outcome.status, AckStatus::Delivered);
+    assert_eq!(phone_rx.recv().await.expect("live copy").message.message_id(), Some(second.id.as_str()));
+    assert_eq!(store.queued_for(&laptop_queue), 1);
+    assert_eq!(store.queued_for(&bob.to_string()), 0);
+    
+    // The phone's ack leaves the laptop's copy queued
+    router.route_message_from(RelayMessage::Ack(AckMessage::delivered(second.id.clone())), &bob, "phone", client_addr()).await?;
+    assert!(matches!(alice_rx.recv().await.expect("ack").message, RelayMessage::Ack(ack) if ack.ref_message_id == second.id));
+    assert_eq!(store.queued_for(&laptop_queue), 1);
+    
+    let (laptop_tx, mut laptop_rx) = mpsc::channel::<RoutableMessage>(10);
+    router.register_device(bob.clone(), "laptop", laptop_tx, client_addr()).await?;
+    assert_eq!(laptop_rx.recv().await.expect("queued copy").message.message_id(), Some(second.id.as_str()));
+    router.route_message_from(RelayMessage::Ack(AckMessage::delivered(second.id.clone())), &bob, "laptop", client_addr()).await?;
+    assert_eq!(store.queued_for(&laptop_queue), 0);
+    
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_devices_are_remembered_from_their_queues() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("queue.log");
+    let open_router = || -> Result<(MessageRouter, Arc<FileQueueStore>)> {
+        let metrics = Arc::new(solchat_relay::metrics::Metrics::new());
+        let store = Arc::new(FileQueueStore::open(&path)?);
+        Ok((MessageRouter::with_store(metrics, store.clone()), store))
+    };
+    
+    let alice = WalletAddress::test_address(1);
+    let bob = WalletAddress::test_address(2);
+    let chat = || RelayMessage::Chat(ChatMessage::new(&alice, &bob, b"Hello Bob!".to_vec(), Vec::new()));
+    
+    {
+        // Before any of Bob's devices is known, his messages wait under his
+        // wallet and go to the first device that connects
+        let (router, store) = open_router()?;
+        router.route_message(chat(), client_addr()).await?;
+        assert_eq!(store.queued_for(&bob.to_string()), 1);
+        
+        let (tablet_tx, mut tablet_rx) = mpsc::channel::<RoutableMessage>(10);
+        router.register_device(bob.clone(), "tablet", tablet_tx.clone(), client_addr()).await?;
+        assert!(matches!(tablet_rx.recv().await.expect("adopted").message, RelayMessage::Chat(_)));
+        assert_eq!(store.queued_for(&bob.to_string()), 0);
+        assert_eq!(store.queued_for(&queue_key(&bob.to_string(), "tablet")), 1);
+        router.unregister_client_channel(&bob, &tablet_tx).await?;
+    }
+    
+    // After a restart the tablet is known from its queue and keeps getting
+    // copies while Bob is offline
+    let (router, store) = open_router()?;
+    let outcome = router.route_message(chat(), client_addr()).await?;
+    assert_eq!(outcome.status, AckStatus::Queued);
+    assert_eq!(store.queued_for(&queue_key(&bob.to_string(), "tablet")), 2);
+    assert_eq!(store.queued_for(&bob.to_string()), 0);
+    
+    Ok(())
+}